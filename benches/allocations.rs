@@ -0,0 +1,171 @@
+//! allocations per operation, measured by a counting allocator in place of time
+//!
+//! the allocator is global to the binary, so it lives in its own bench target and
+//! leaves the timings of the other benches alone
+
+use criterion::{
+    measurement::{Measurement, ValueFormatter},
+    Criterion, Throughput,
+};
+use kvs::{KvStore, KvsEngine};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tempfile::TempDir;
+
+/// system allocator counting allocations
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// allocations made while a routine runs, reported by criterion in place of its time
+struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = usize;
+    type Value = usize;
+
+    fn start(&self) -> usize {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: usize) -> usize {
+        ALLOCATIONS.load(Ordering::Relaxed) - start
+    }
+
+    fn add(&self, v1: &usize, v2: &usize) -> usize {
+        v1 + v2
+    }
+
+    fn zero(&self) -> usize {
+        0
+    }
+
+    fn to_f64(&self, value: &usize) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &AllocationsFormatter
+    }
+}
+
+struct AllocationsFormatter;
+
+impl ValueFormatter for AllocationsFormatter {
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical_value: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        let (count, unit) = match *throughput {
+            Throughput::Bytes(bytes) => (bytes, "allocs/byte"),
+            Throughput::Elements(elements) => (elements, "allocs/elem"),
+        };
+        for value in values {
+            *value /= count as f64;
+        }
+        unit
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+fn random_pairs(n: usize) -> (Vec<String>, Vec<String>) {
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    let mut rng = thread_rng();
+    for _ in 0..n {
+        let (rand_key_len, rand_value_len) = (rng.gen_range(1, 1_000), rng.gen_range(1, 1_000));
+
+        let rand_key: String = rng.sample_iter(&Alphanumeric).take(rand_key_len).collect();
+        keys.push(rand_key);
+
+        let rand_value: String = rng
+            .sample_iter(&Alphanumeric)
+            .take(rand_value_len)
+            .collect();
+        values.push(rand_value);
+    }
+    (keys, values)
+}
+
+fn bench_writes(c: &mut Criterion<Allocations>) {
+    let (keys, values) = random_pairs(1000);
+    let dir = TempDir::new().unwrap();
+    let store = KvStore::open(dir.path()).unwrap();
+
+    let mut group = c.benchmark_group("kvs allocations");
+    group.throughput(Throughput::Elements(keys.len() as u64));
+    group.bench_function("set", |b| {
+        b.iter(|| {
+            keys.iter()
+                .zip(values.iter())
+                .for_each(|(k, v)| store.set(k.clone(), v.clone()).unwrap())
+        })
+    });
+    group.finish();
+}
+
+fn bench_reads(c: &mut Criterion<Allocations>) {
+    let (keys, values) = random_pairs(100);
+    let dir = TempDir::new().unwrap();
+    let store = KvStore::open(dir.path()).unwrap();
+    keys.iter()
+        .zip(values.iter())
+        .for_each(|(k, v)| store.set(k.clone(), v.clone()).unwrap());
+
+    let mut group = c.benchmark_group("kvs allocations");
+    group.throughput(Throughput::Elements(keys.len() as u64));
+    group.bench_function("get", |b| {
+        b.iter(|| {
+            keys.iter()
+                .for_each(|k| assert!(store.get(k.clone()).unwrap().is_some()))
+        })
+    });
+    // reading into a reused buffer instead of a new string per get, the key is cloned
+    // as for get, which takes it by value, so criterion never measures zero
+    let mut buf = String::new();
+    group.bench_function("get_into", |b| {
+        b.iter(|| {
+            keys.iter()
+                .for_each(|k| assert!(store.get_into(&k.clone(), &mut buf).unwrap()))
+        })
+    });
+    group.finish();
+}
+
+fn main() {
+    // counts barely vary between samples, which plotters can't draw, so plots stay off
+    // whatever the command line asks for
+    let mut c = Criterion::default()
+        .with_measurement(Allocations)
+        .configure_from_args()
+        .without_plots();
+    bench_writes(&mut c);
+    bench_reads(&mut c);
+    c.final_summary();
+}
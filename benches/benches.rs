@@ -1,13 +1,23 @@
-use criterion::{criterion_group, criterion_main, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kvs::{
+    Codec, CompactionPolicy, Compression, DurabilityMode, Encoding, IndexKind, KvStore,
+    KvStoreOptions, KvsClient, KvsEngine, MemKvsEngine, Request, SledKvsEngine,
+};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use std::{
+    net::TcpListener,
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
 use tempfile::TempDir;
 
-pub fn bench(c: &mut Criterion) {
+fn random_pairs(n: usize) -> (Vec<String>, Vec<String>) {
     let mut keys = Vec::new();
     let mut values = Vec::new();
     let mut rng = thread_rng();
-    for _ in 0..100 {
+    for _ in 0..n {
         let (rand_key_len, rand_value_len) = (rng.gen_range(1, 1_000), rng.gen_range(1, 1_000));
 
         let rand_key: String = rng.sample_iter(&Alphanumeric).take(rand_key_len).collect();
@@ -19,15 +29,25 @@ pub fn bench(c: &mut Criterion) {
             .collect();
         values.push(rand_value);
     }
+    (keys, values)
+}
+
+/// an address on a port free now, for a server spawned next
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+pub fn bench(c: &mut Criterion) {
+    let (keys, values) = random_pairs(100);
 
     let mut group = c.benchmark_group("engines read write bench");
 
     let (kvs_dir, sled_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
 
     let kvs = KvStore::open(kvs_dir.path()).unwrap();
-    let sled = SledKvsEngine {
-        db: sled::open(sled_dir.path()).unwrap(),
-    };
+    let sled = SledKvsEngine::new(sled::open(sled_dir.path()).unwrap());
+    let mem = MemKvsEngine::new();
 
     group.bench_function("kvs write", |b| {
         b.iter(|| {
@@ -45,6 +65,14 @@ pub fn bench(c: &mut Criterion) {
         })
     });
 
+    group.bench_function("mem write", |b| {
+        b.iter(|| {
+            keys.iter()
+                .zip(values.iter())
+                .for_each(|(k, v)| mem.set(k.clone(), v.clone()).unwrap())
+        })
+    });
+
     group.bench_function("kvs read", |b| {
         b.iter(|| {
             keys.iter()
@@ -61,8 +89,709 @@ pub fn bench(c: &mut Criterion) {
         })
     });
 
+    group.bench_function("mem read", |b| {
+        b.iter(|| {
+            keys.iter()
+                .zip(values.iter())
+                .for_each(|(k, v)| assert_eq!(mem.get(k.clone()).unwrap().unwrap(), v.clone()))
+        })
+    });
+
+    group.finish();
+}
+
+pub fn bench_encodings(c: &mut Criterion) {
+    let (keys, values) = random_pairs(100);
+
+    let mut group = c.benchmark_group("encodings");
+
+    for encoding in [Encoding::Json, Encoding::Bincode] {
+        let options = KvStoreOptions::new().encoding(encoding);
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open_with_options(dir.path(), options.clone()).unwrap();
+
+        group.bench_function(format!("{encoding:?} write"), |b| {
+            b.iter(|| {
+                keys.iter()
+                    .zip(values.iter())
+                    .for_each(|(k, v)| store.set(k.clone(), v.clone()).unwrap())
+            })
+        });
+        drop(store);
+
+        group.bench_function(format!("{encoding:?} load"), |b| {
+            b.iter(|| KvStore::open_with_options(dir.path(), options.clone()).unwrap())
+        });
+    }
+
     group.finish();
 }
 
-criterion_group!(benches, bench);
+pub fn bench_compression(c: &mut Criterion) {
+    // json blobs with repeated field names, compressing several times
+    let values: Vec<String> = (0..100)
+        .map(|i| {
+            let fields: Vec<String> = (0..100)
+                .map(|j| format!("\"field{}\":{{\"id\":{},\"name\":\"item{}\"}}", j, i * j, j))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("value compression");
+
+    for compression in [Compression::None, Compression::Lz4, Compression::Gzip] {
+        let options = KvStoreOptions::new()
+            .encoding(Encoding::Bincode)
+            .value_compression(compression);
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open_with_options(dir.path(), options).unwrap();
+
+        group.bench_function(format!("{compression:?} write"), |b| {
+            b.iter(|| {
+                for (i, value) in values.iter().enumerate() {
+                    store.set(format!("key{}", i), value.clone()).unwrap();
+                }
+            })
+        });
+        group.bench_function(format!("{compression:?} read"), |b| {
+            b.iter(|| {
+                for i in 0..values.len() {
+                    store.get(format!("key{}", i)).unwrap();
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+pub fn bench_read_heavy(c: &mut Criterion) {
+    let (keys, values) = random_pairs(100);
+
+    let mut group = c.benchmark_group("kvs read heavy");
+
+    for index in [IndexKind::SkipMap, IndexKind::HashMap] {
+        let dir = TempDir::new().unwrap();
+        let store =
+            KvStore::open_with_options(dir.path(), KvStoreOptions::new().index(index)).unwrap();
+        keys.iter()
+            .zip(values.iter())
+            .for_each(|(k, v)| store.set(k.clone(), v.clone()).unwrap());
+
+        group.bench_function(format!("{index:?} index"), |b| {
+            b.iter(|| {
+                for _ in 0..10 {
+                    keys.iter().zip(values.iter()).for_each(|(k, v)| {
+                        assert_eq!(store.get(k.clone()).unwrap().unwrap(), v.clone())
+                    })
+                }
+            })
+        });
+    }
+
+    // reading into a reused buffer instead of a new string per get
+    let dir = TempDir::new().unwrap();
+    let store = KvStore::open(dir.path()).unwrap();
+    keys.iter()
+        .zip(values.iter())
+        .for_each(|(k, v)| store.set(k.clone(), v.clone()).unwrap());
+    let mut buf = String::new();
+    group.bench_function("get_into", |b| {
+        b.iter(|| {
+            for _ in 0..10 {
+                keys.iter().zip(values.iter()).for_each(|(k, v)| {
+                    assert!(store.get_into(k, &mut buf).unwrap());
+                    assert_eq!(&buf, v)
+                })
+            }
+        })
+    });
+
+    group.finish();
+}
+
+/// latency of a single get of a small value whose generation file is in the page cache,
+/// so the cost measured is looking up the index and decoding the record
+pub fn bench_small_reads(c: &mut Criterion) {
+    const KEYS: usize = 10_000;
+    let mut rng = thread_rng();
+    // short keys and values of about the size of a typical user record
+    let keys: Vec<String> = (0..KEYS).map(|i| format!("user:{i:08}")).collect();
+    let values: Vec<String> = (0..KEYS)
+        .map(|_| {
+            let len = rng.gen_range(32, 256);
+            rng.sample_iter(&Alphanumeric).take(len).collect()
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("kvs small reads");
+    group.throughput(Throughput::Elements(1));
+
+    for encoding in [Encoding::Json, Encoding::Bincode] {
+        let dir = TempDir::new().unwrap();
+        let store =
+            KvStore::open_with_options(dir.path(), KvStoreOptions::new().encoding(encoding))
+                .unwrap();
+        keys.iter()
+            .zip(values.iter())
+            .for_each(|(k, v)| store.set(k.clone(), v.clone()).unwrap());
+        store.flush().unwrap();
+        // read every record once, so its pages and the file handle of the reader are cached
+        keys.iter()
+            .for_each(|k| assert!(store.get(k.clone()).unwrap().is_some()));
+
+        let mut i = 0;
+        group.bench_function(format!("{encoding:?} get"), |b| {
+            b.iter(|| {
+                i = (i + 1) % KEYS;
+                store.get(keys[i].clone()).unwrap().unwrap()
+            })
+        });
+        let mut buf = String::new();
+        group.bench_function(format!("{encoding:?} get_into"), |b| {
+            b.iter(|| {
+                i = (i + 1) % KEYS;
+                assert!(store.get_into(&keys[i], &mut buf).unwrap());
+            })
+        });
+    }
+
+    group.finish();
+}
+
+/// reads of keys picked by a zipf distribution, so a few hot keys take most reads,
+/// with and without a value cache holding a tenth of the bytes of values
+pub fn bench_value_cache(c: &mut Criterion) {
+    const KEYS: usize = 10_000;
+    const READS: usize = 1000;
+    let (_, values) = random_pairs(KEYS);
+    let keys: Vec<String> = (0..KEYS).map(|i| format!("key{i}")).collect();
+
+    // the key of rank i is picked with a weight of 1 / i, by a binary search of the
+    // cumulative weights
+    let mut weights = Vec::with_capacity(KEYS);
+    let mut total = 0.0;
+    for rank in 1..=KEYS {
+        total += 1.0 / rank as f64;
+        weights.push(total);
+    }
+    let mut rng = thread_rng();
+    let reads: Vec<&String> = (0..READS)
+        .map(|_| {
+            let weight = rng.gen::<f64>() * total;
+            &keys[weights.partition_point(|&w| w < weight).min(KEYS - 1)]
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("kvs zipf reads");
+
+    let bytes: usize = values.iter().map(String::len).sum();
+    for capacity in [0, bytes / 10] {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open_with_options(
+            dir.path(),
+            KvStoreOptions::new().value_cache_capacity(capacity),
+        )
+        .unwrap();
+        keys.iter()
+            .zip(values.iter())
+            .for_each(|(k, v)| store.set(k.clone(), v.clone()).unwrap());
+
+        group.bench_function(format!("value cache of {capacity} bytes"), |b| {
+            b.iter(|| {
+                for key in &reads {
+                    assert!(store.get_shared(key).unwrap().is_some());
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+pub fn bench_negative_lookup(c: &mut Criterion) {
+    let (keys, values) = random_pairs(1000);
+    let absent_keys: Vec<String> = random_pairs(1000)
+        .0
+        .into_iter()
+        .filter(|k| !keys.contains(k))
+        .collect();
+
+    let mut group = c.benchmark_group("kvs negative lookup");
+
+    for bloom_filter in [false, true] {
+        let dir = TempDir::new().unwrap();
+        let options = KvStoreOptions::new().bloom_filter(bloom_filter);
+        let store = KvStore::open_with_options(dir.path(), options.clone()).unwrap();
+        keys.iter()
+            .zip(values.iter())
+            .for_each(|(k, v)| store.set(k.clone(), v.clone()).unwrap());
+        // the filter is sized from keys found when opening
+        drop(store);
+        let store = KvStore::open_with_options(dir.path(), options).unwrap();
+
+        let name = if bloom_filter {
+            "bloom filter"
+        } else {
+            "no bloom filter"
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                absent_keys
+                    .iter()
+                    .for_each(|k| assert_eq!(store.get(k.clone()).unwrap(), None))
+            })
+        });
+    }
+
+    group.finish();
+}
+
+pub fn bench_durability(c: &mut Criterion) {
+    let (keys, values) = random_pairs(100);
+
+    let mut group = c.benchmark_group("durability");
+
+    for durability in [
+        DurabilityMode::None,
+        DurabilityMode::Buffered,
+        DurabilityMode::Fsync,
+    ] {
+        let (kvs_dir, sled_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let options = KvStoreOptions::new().durability(durability);
+        let kvs = KvStore::open_with_options(kvs_dir.path(), options).unwrap();
+        let sled = SledKvsEngine::new(sled::open(sled_dir.path()).unwrap()).durability(durability);
+
+        group.bench_function(format!("kvs {durability:?} write"), |b| {
+            b.iter(|| {
+                keys.iter()
+                    .zip(values.iter())
+                    .for_each(|(k, v)| kvs.set(k.clone(), v.clone()).unwrap())
+            })
+        });
+
+        group.bench_function(format!("sled {durability:?} write"), |b| {
+            b.iter(|| {
+                keys.iter()
+                    .zip(values.iter())
+                    .for_each(|(k, v)| sled.set(k.clone(), v.clone()).unwrap())
+            })
+        });
+    }
+
+    group.finish();
+}
+
+pub fn bench_compaction(c: &mut Criterion) {
+    let value = "v".repeat(100 * 1024);
+
+    // each iteration writes 10 MiB of large values, crossing the threshold once
+    let dir = TempDir::new().unwrap();
+    let options =
+        KvStoreOptions::new().compaction_policy(CompactionPolicy::Bytes(10 * 1024 * 1024));
+    let store = KvStore::open_with_options(dir.path(), options).unwrap();
+
+    c.bench_function("kvs compaction of large values", |b| {
+        b.iter(|| {
+            for i in 0..100 {
+                store.set(format!("key{}", i % 50), value.clone()).unwrap();
+            }
+        })
+    });
+}
+
+pub fn bench_write_heavy(c: &mut Criterion) {
+    let (keys, values) = random_pairs(1000);
+
+    let dir = TempDir::new().unwrap();
+    let store = KvStore::open(dir.path()).unwrap();
+
+    c.bench_function("kvs write heavy", |b| {
+        b.iter(|| {
+            keys.iter()
+                .zip(values.iter())
+                .for_each(|(k, v)| store.set(k.clone(), v.clone()).unwrap())
+        })
+    });
+}
+
+/// bulk load with unflushed writes, so writes reach the file once the buffer is full,
+/// overwrites trigger compactions which are buffered the same way
+pub fn bench_parallel_compaction(c: &mut Criterion) {
+    let value = "v".repeat(16 * 1024);
+
+    // each iteration rewrites the same 32 MiB of live records
+    let mut group = c.benchmark_group("kvs compaction of a large live set");
+    group.sample_size(10);
+    for parallel in [false, true] {
+        let dir = TempDir::new().unwrap();
+        let options = KvStoreOptions::new()
+            .durability(DurabilityMode::None)
+            .compaction_policy(CompactionPolicy::Bytes(u64::MAX))
+            .parallel_compaction(parallel);
+        let store = KvStore::open_with_options(dir.path(), options).unwrap();
+        for i in 0..2048 {
+            store.set(format!("key{}", i), value.clone()).unwrap();
+        }
+
+        group.bench_with_input(
+            BenchmarkId::new("parallel", parallel),
+            &store,
+            |b, store| b.iter(|| store.compact().unwrap()),
+        );
+    }
+    group.finish();
+}
+
+pub fn bench_buffer_sizes(c: &mut Criterion) {
+    let (keys, values) = random_pairs(1000);
+
+    let mut group = c.benchmark_group("kvs bulk load");
+    for size in [8 * 1024, 64 * 1024, 1024 * 1024] {
+        let dir = TempDir::new().unwrap();
+        let options = KvStoreOptions::new()
+            .durability(DurabilityMode::None)
+            .write_buffer_size(size)
+            .read_buffer_size(size);
+        let store = KvStore::open_with_options(dir.path(), options).unwrap();
+
+        group.bench_with_input(BenchmarkId::new("buffer size", size), &store, |b, store| {
+            b.iter(|| {
+                keys.iter()
+                    .zip(values.iter())
+                    .for_each(|(k, v)| store.set(k.clone(), v.clone()).unwrap())
+            })
+        });
+    }
+    group.finish();
+}
+
+/// sustained overwrites with generations rolled over at several segment sizes, the number
+/// of generation files and of compactions are printed once the writes are done
+pub fn bench_segment_size(c: &mut Criterion) {
+    let (keys, values) = random_pairs(1000);
+
+    let mut group = c.benchmark_group("kvs sustained writes");
+    for segment_size in [None, Some(1024 * 1024), Some(8 * 1024 * 1024)] {
+        let dir = TempDir::new().unwrap();
+        let mut options =
+            KvStoreOptions::new().compaction_policy(CompactionPolicy::Bytes(64 * 1024 * 1024));
+        if let Some(segment_size) = segment_size {
+            options = options.segment_size(segment_size);
+        }
+        let store = KvStore::open_with_options(dir.path(), options).unwrap();
+        let name = match segment_size {
+            Some(segment_size) => format!("{} KiB segments", segment_size / 1024),
+            None => "no segments".to_owned(),
+        };
+
+        group.bench_function(&name, |b| {
+            b.iter(|| {
+                keys.iter()
+                    .zip(values.iter())
+                    .for_each(|(k, v)| store.set(k.clone(), v.clone()).unwrap())
+            })
+        });
+    }
+    group.finish();
+}
+
+/// synced writes of concurrent threads, each write persisted alone or together with
+/// the writes of a window
+pub fn bench_group_commit(c: &mut Criterion) {
+    const WRITERS: usize = 8;
+    const WRITES: usize = 50;
+
+    let run_writers = |store: &KvStore| {
+        thread::scope(|s| {
+            for i in 0..WRITERS {
+                let store = store.clone();
+                s.spawn(move || {
+                    for j in 0..WRITES {
+                        store
+                            .set(format!("key{i}_{j}"), "value".to_owned())
+                            .unwrap();
+                    }
+                });
+            }
+        })
+    };
+
+    let mut group = c.benchmark_group("kvs group commit");
+    group.sample_size(10);
+    for window in [None, Some(100), Some(1000), Some(5000)] {
+        let dir = TempDir::new().unwrap();
+        let mut options = KvStoreOptions::new().durability(DurabilityMode::Fsync);
+        if let Some(window) = window {
+            options = options.group_commit(Duration::from_micros(window));
+        }
+        let store = KvStore::open_with_options(dir.path(), options).unwrap();
+        let name = match window {
+            Some(window) => format!("{window}us window"),
+            None => "off".to_owned(),
+        };
+        group.bench_function(name, |b| b.iter(|| run_writers(&store)));
+    }
+    group.finish();
+}
+
+pub fn bench_pipeline(c: &mut Criterion) {
+    let addr = free_addr();
+    let dir = TempDir::new().unwrap();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+        .args(["--engine", "kvs", "--addr", &addr])
+        .current_dir(dir.path())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let pairs: Vec<(String, String)> = (0..10_000)
+        .map(|i| (format!("key{i}"), format!("value{i}")))
+        .collect();
+    let mut client = KvsClient::connect(&addr).unwrap();
+
+    let mut group = c.benchmark_group("load 10k keys");
+    group.sample_size(10);
+
+    group.bench_function("serial", |b| {
+        b.iter(|| {
+            pairs
+                .iter()
+                .for_each(|(k, v)| client.set(k.clone(), v.clone()).unwrap())
+        })
+    });
+
+    group.bench_function("pipelined", |b| {
+        b.iter(|| {
+            let requests = pairs
+                .iter()
+                .map(|(k, v)| Request::Set {
+                    key: k.clone(),
+                    value: v.clone(),
+                })
+                .collect();
+            client.pipeline(requests).unwrap()
+        })
+    });
+
+    group.finish();
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+pub fn bench_codecs(c: &mut Criterion) {
+    const PAIRS: usize = 1000;
+
+    let addr = free_addr();
+    let dir = TempDir::new().unwrap();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+        .args(["--engine", "kvs", "--addr", &addr])
+        .current_dir(dir.path())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // a set and a get of each pair, pipelined so the codec rather than round trips counts
+    let requests = || -> Vec<Request> {
+        (0..PAIRS)
+            .flat_map(|i| {
+                [
+                    Request::Set {
+                        key: format!("key{i}"),
+                        value: "v".repeat(100),
+                    },
+                    Request::Get {
+                        key: format!("key{i}"),
+                    },
+                ]
+            })
+            .collect()
+    };
+
+    let mut group = c.benchmark_group("codecs");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(2 * PAIRS as u64));
+    for codec in [Codec::Json, Codec::Bincode, Codec::MessagePack] {
+        let mut client =
+            KvsClient::connect_with(&addr.parse().unwrap(), Duration::from_secs(5), None, codec)
+                .unwrap();
+        group.bench_function(format!("{codec:?}"), |b| {
+            b.iter_batched(
+                requests,
+                |requests| client.pipeline(requests).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+pub fn bench_open(c: &mut Criterion) {
+    const LOG_BYTES: usize = 1024 * 1024 * 1024;
+    let value = "v".repeat(1024);
+
+    // overwrites of a few keys, so replaying is slow while the checkpoint stays small
+    let dir = TempDir::new().unwrap();
+    let options = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::Bytes(u64::MAX))
+        .checkpoint(true);
+    let store = KvStore::open_with_options(dir.path(), options.clone()).unwrap();
+    for i in 0..LOG_BYTES / value.len() {
+        store
+            .set(format!("key{}", i % 10_000), value.clone())
+            .unwrap();
+    }
+    drop(store);
+
+    let mut group = c.benchmark_group("kvs open 1 GiB log");
+    group.sample_size(10);
+    group.bench_function("replay", |b| b.iter(|| KvStore::open(dir.path()).unwrap()));
+    group.bench_function("checkpoint", |b| {
+        b.iter(|| KvStore::open_with_options(dir.path(), options.clone()).unwrap())
+    });
+    group.finish();
+}
+
+/// read throughput of concurrent readers, alone and beside a thread writing
+/// as fast as it can, reads don't wait on the writer lock so they should barely slow down
+pub fn bench_read_beside_writes(c: &mut Criterion) {
+    const READERS: usize = 4;
+    let (keys, values) = random_pairs(100);
+
+    let dir = TempDir::new().unwrap();
+    let store = KvStore::open(dir.path()).unwrap();
+    keys.iter()
+        .zip(values.iter())
+        .for_each(|(k, v)| store.set(k.clone(), v.clone()).unwrap());
+    let read_all = || {
+        thread::scope(|s| {
+            for _ in 0..READERS {
+                // each thread reads through a clone, with file handles of its own
+                let store = store.clone();
+                let (keys, values) = (&keys, &values);
+                s.spawn(move || {
+                    keys.iter().zip(values.iter()).for_each(|(k, v)| {
+                        assert_eq!(store.get(k.clone()).unwrap().unwrap(), v.clone())
+                    })
+                });
+            }
+        })
+    };
+
+    let mut group = c.benchmark_group("kvs read beside writes");
+    group.bench_function("alone", |b| b.iter(read_all));
+
+    let stop = AtomicBool::new(false);
+    thread::scope(|s| {
+        let writer = store.clone();
+        let stop = &stop;
+        s.spawn(move || {
+            let mut i = 0;
+            while !stop.load(Ordering::Relaxed) {
+                writer
+                    .set(format!("write{}", i % 1000), "value".to_owned())
+                    .unwrap();
+                i += 1;
+            }
+        });
+        group.bench_function("beside writer", |b| b.iter(read_all));
+        stop.store(true, Ordering::Relaxed);
+    });
+    group.finish();
+}
+
+/// throughput of a server with each pool and thread count, under concurrent clients
+/// each sending a batch of requests over its own connection
+pub fn bench_thread_pools(c: &mut Criterion) {
+    const CLIENTS: usize = 8;
+    const REQUESTS: usize = 100;
+
+    let keys: Vec<Vec<String>> = (0..CLIENTS)
+        .map(|i| (0..REQUESTS).map(|j| format!("key{i}_{j}")).collect())
+        .collect();
+    let run_clients = |addr: &str, write: bool| {
+        thread::scope(|s| {
+            for keys in &keys {
+                s.spawn(move || {
+                    // a connection holds a pool thread, so later clients queue behind it
+                    let mut client = KvsClient::connect(addr).unwrap();
+                    for key in keys {
+                        if write {
+                            client.set(key.clone(), "value".to_owned()).unwrap();
+                        } else {
+                            assert!(client.get(key.clone()).unwrap().is_some());
+                        }
+                    }
+                });
+            }
+        })
+    };
+
+    let mut write_group = c.benchmark_group("write_queued_kvstore");
+    write_group.sample_size(10);
+    let mut servers = Vec::new();
+    for &pool in &["shared", "rayon"] {
+        for &threads in &[1, 2, 4, 8] {
+            let addr = free_addr();
+            let dir = TempDir::new().unwrap();
+            let server = Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+                .args(["--engine", "kvs", "--addr", &addr, "--pool", pool])
+                .args(["--threads", &threads.to_string()])
+                .current_dir(dir.path())
+                .stderr(std::process::Stdio::null())
+                .spawn()
+                .unwrap();
+            thread::sleep(Duration::from_secs(1));
+
+            write_group.bench_with_input(BenchmarkId::new(pool, threads), &addr, |b, addr| {
+                b.iter(|| run_clients(addr, true))
+            });
+            servers.push((pool, threads, addr, dir, server));
+        }
+    }
+    write_group.finish();
+
+    let mut read_group = c.benchmark_group("read_queued_kvstore");
+    read_group.sample_size(10);
+    for (pool, threads, addr, _, _) in &servers {
+        read_group.bench_with_input(BenchmarkId::new(*pool, threads), addr, |b, addr| {
+            b.iter(|| run_clients(addr, false))
+        });
+    }
+    read_group.finish();
+
+    for (_, _, _, _, mut server) in servers {
+        server.kill().unwrap();
+        server.wait().unwrap();
+    }
+}
+
+criterion_group!(
+    benches,
+    bench,
+    bench_encodings,
+    bench_compression,
+    bench_read_heavy,
+    bench_small_reads,
+    bench_value_cache,
+    bench_negative_lookup,
+    bench_durability,
+    bench_compaction,
+    bench_write_heavy,
+    bench_buffer_sizes,
+    bench_segment_size,
+    bench_parallel_compaction,
+    bench_group_commit,
+    bench_pipeline,
+    bench_codecs,
+    bench_open,
+    bench_read_beside_writes,
+    bench_thread_pools
+);
 criterion_main!(benches);
@@ -0,0 +1,166 @@
+/*!
+ * address of a kvs server
+ */
+
+use std::{
+    convert::TryInto,
+    fmt::{self, Display},
+    io,
+    net::{AddrParseError, SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    str::FromStr,
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use socket2::{Domain, Socket, Type};
+
+#[cfg(unix)]
+const UNIX_PREFIX: &str = "unix:";
+
+/// a tcp address like `127.0.0.1:4000`, or a unix domain socket like `unix:/path/to/sock`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerAddr {
+    /// tcp socket address
+    Tcp(SocketAddr),
+    /// path of a unix domain socket
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for ServerAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            return Ok(ServerAddr::Unix(path.into()));
+        }
+        Ok(ServerAddr::Tcp(s.parse()?))
+    }
+}
+
+impl Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerAddr::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            ServerAddr::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for ServerAddr {
+    fn from(value: SocketAddr) -> Self {
+        ServerAddr::Tcp(value)
+    }
+}
+
+/// written as a string, in the same form as parsed
+impl Serialize for ServerAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ServerAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// listen on `addr` with room for `backlog` pending connections,
+/// `None` keeps the backlog of [`TcpListener::bind`], which is 128
+pub fn bind_tcp(addr: SocketAddr, backlog: Option<u32>) -> io::Result<TcpListener> {
+    let backlog = match backlog {
+        Some(backlog) => backlog,
+        None => return TcpListener::bind(addr),
+    };
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    // as set by std, so a restarted server can bind while old connections time out
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog.try_into().unwrap_or(i32::MAX))?;
+    Ok(socket.into())
+}
+
+/// accept a connection with `TCP_NODELAY` set, requests and responses are small messages
+/// which would otherwise wait for the acknowledgement of the previous one
+///
+/// a connection on which it can't be set is kept, and the failure logged
+pub fn accept_tcp(listener: &TcpListener) -> io::Result<TcpStream> {
+    let (stream, peer_addr) = listener.accept()?;
+    if let Err(e) = stream.set_nodelay(true) {
+        log::warn!("can't set TCP_NODELAY on connection {}: {}", peer_addr, e);
+    }
+    Ok(stream)
+}
+
+/// a listener on a unix domain socket, see [`bind_unix`]
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    stopped: Arc<AtomicBool>,
+}
+
+/// listen on a unix domain socket at `path`, replacing a socket file nobody listens on,
+/// as left by a server which was killed
+///
+/// once the server is interrupted or terminated, a connection wakes the accept loop,
+/// which should stop when [`UnixSocket::is_stopped`]. The socket file is removed on drop
+#[cfg(unix)]
+pub fn bind_unix(path: &Path) -> io::Result<UnixSocket> {
+    if path.exists() && UnixStream::connect(path).is_err() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    ctrlc::set_handler({
+        let (path, stopped) = (path.to_owned(), stopped.clone());
+        move || {
+            stopped.store(true, Ordering::SeqCst);
+            let _ = UnixStream::connect(&path);
+        }
+    })
+    .map_err(io::Error::other)?;
+
+    Ok(UnixSocket {
+        listener,
+        path: path.to_owned(),
+        stopped,
+    })
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+    /// the listener, async servers accept on a nonblocking clone of it
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    /// `true` once the server was interrupted or terminated
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,213 @@
+/*!
+ * async client keeping one connection to a kvs server
+ */
+
+use std::{future::Future, time::Duration};
+
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+    time,
+};
+
+use crate::{
+    client::{closed_error, io_error},
+    Codec, Decoded, KvsError, MessageCodec, Request, Response, Result, ServerAddr,
+    CODEC_PROTOCOL_VERSION, PROTOCOL_VERSIONS,
+};
+
+/// a connection to a server, over tcp or a unix domain socket
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+/// a client sending requests over a single connection without blocking the runtime,
+/// requests and responses are the same as those of [`KvsClient`](crate::KvsClient)
+pub struct AsyncKvsClient {
+    stream: Box<dyn Stream>,
+    /// bytes received after the last parsed response
+    buf: Vec<u8>,
+    /// encoded request being written
+    encoded: Vec<u8>,
+    timeout: Option<Duration>,
+    version: u8,
+    codec: Codec,
+}
+
+impl AsyncKvsClient {
+    /// connect to a server at `addr`
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await.map_err(io_error)?;
+        Self::from_stream(Box::new(stream), None, Codec::Json).await
+    }
+
+    /// connect to a server at a tcp or unix domain socket `addr`, connecting and each later
+    /// request fail with [`KvsError::Timeout`] if they take longer than `timeout`
+    ///
+    /// the response of a request which timed out may still arrive, so the connection
+    /// should be dropped after a timeout
+    pub async fn connect_addr(addr: &ServerAddr, timeout: Option<Duration>) -> Result<Self> {
+        Self::connect_with(addr, timeout, Codec::Json).await
+    }
+
+    /// connect to a server at `addr` as with [`AsyncKvsClient::connect_addr`], and ask for
+    /// messages in `codec`, a server which doesn't speak it answers in json
+    pub async fn connect_with(
+        addr: &ServerAddr,
+        timeout: Option<Duration>,
+        codec: Codec,
+    ) -> Result<Self> {
+        with_timeout(timeout, async {
+            let stream: Box<dyn Stream> = match addr {
+                ServerAddr::Tcp(addr) => {
+                    Box::new(TcpStream::connect(addr).await.map_err(io_error)?)
+                }
+                #[cfg(unix)]
+                ServerAddr::Unix(path) => {
+                    Box::new(UnixStream::connect(path).await.map_err(io_error)?)
+                }
+            };
+            Self::from_stream(stream, timeout, codec).await
+        })
+        .await
+    }
+
+    async fn from_stream(
+        mut stream: Box<dyn Stream>,
+        timeout: Option<Duration>,
+        codec: Codec,
+    ) -> Result<Self> {
+        let mut advertised = [0u8; 2];
+        stream.read_exact(&mut advertised).await.map_err(io_error)?;
+        let [min, max] = advertised;
+        let version = max.min(*PROTOCOL_VERSIONS.end());
+        if version < min.max(*PROTOCOL_VERSIONS.start()) {
+            return Err(KvsError::ProtocolVersion { min, max });
+        }
+        // older servers only speak json, see `PROTOCOL_VERSIONS`
+        let codec = if version < CODEC_PROTOCOL_VERSION {
+            stream.write_all(&[version]).await.map_err(io_error)?;
+            stream.flush().await.map_err(io_error)?;
+            Codec::Json
+        } else {
+            stream
+                .write_all(&[version, codec.to_byte()])
+                .await
+                .map_err(io_error)?;
+            stream.flush().await.map_err(io_error)?;
+            let answered = stream.read_u8().await.map_err(io_error)?;
+            Codec::from_byte(answered).ok_or_else(|| {
+                KvsError::Server(format!("server answered unknown codec {answered}"))
+            })?
+        };
+
+        Ok(Self {
+            stream,
+            buf: Vec::new(),
+            encoded: Vec::new(),
+            timeout,
+            version,
+            codec,
+        })
+    }
+
+    /// protocol version agreed with the server on connect
+    pub fn protocol_version(&self) -> u8 {
+        self.version
+    }
+
+    /// codec of requests and responses agreed with the server on connect
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// get value for a key
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.send(Request::Get { key }).await?.value)
+    }
+
+    /// set a key-value pair
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.send(Request::Set { key, value }).await?;
+        Ok(())
+    }
+
+    /// remove a key
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.send(Request::Rm { key }).await?;
+        Ok(())
+    }
+
+    /// get values for several keys, in the order of `keys`
+    pub async fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        Ok(self
+            .send(Request::GetMany { keys })
+            .await?
+            .values
+            .unwrap_or_default())
+    }
+
+    /// check the server is alive, returns the server version
+    pub async fn ping(&mut self) -> Result<String> {
+        Ok(self.send(Request::Ping).await?.value.unwrap_or_default())
+    }
+
+    /// send a request and wait for its response,
+    /// an error reported by server is converted to the matching [`KvsError`]
+    pub async fn send(&mut self, request: Request) -> Result<Response> {
+        let response = with_timeout(self.timeout, self.round_trip(request)).await?;
+        match response.error {
+            Some(err) => Err(err.into()),
+            None => Ok(response),
+        }
+    }
+
+    async fn round_trip(&mut self, request: Request) -> Result<Response> {
+        self.encoded.clear();
+        self.codec.encode(&mut self.encoded, &request)?;
+        self.stream
+            .write_all(&self.encoded)
+            .await
+            .map_err(io_error)?;
+        self.stream.flush().await.map_err(io_error)?;
+
+        loop {
+            match self.codec.decode_buf(&self.buf) {
+                Some(Ok((consumed, decoded))) => {
+                    self.buf.drain(..consumed);
+                    return match decoded {
+                        Decoded::Message(response) => Ok(response),
+                        Decoded::Invalid(e) => Err(e),
+                    };
+                }
+                Some(Err(e)) => return Err(e),
+                // wait for the rest of a partially received response
+                None => {
+                    let n = self
+                        .stream
+                        .read_buf(&mut self.buf)
+                        .await
+                        .map_err(io_error)?;
+                    if n == 0 {
+                        return Err(closed_error());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// run `f`, failing with [`KvsError::Timeout`] if it takes longer than `timeout`
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    f: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => time::timeout(timeout, f)
+            .await
+            .map_err(|_| KvsError::Timeout)?,
+        None => f.await,
+    }
+}
@@ -0,0 +1,123 @@
+/*!
+ * async engine trait
+ */
+
+use std::future::Future;
+
+use crate::{KvStore, KvsEngine, KvsError, Result};
+
+/// async kv engine trait
+pub trait AsyncKvsEngine: Clone + Send + 'static {
+    /// set a key-value pair
+    fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send;
+    /// get value for a key
+    fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send;
+    /// remove a key
+    fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send;
+    /// persist all writes made so far to disk
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send;
+    /// atomically add `delta` to the integer value of a key, returns the new value
+    fn incr(&self, key: String, delta: i64) -> impl Future<Output = Result<i64>> + Send;
+    /// atomically append `suffix` to the value of a key
+    fn append(&self, key: String, suffix: String) -> impl Future<Output = Result<()>> + Send;
+    /// get values for several keys, in the order of `keys`
+    fn get_many(
+        &self,
+        keys: Vec<String>,
+    ) -> impl Future<Output = Result<Vec<Option<String>>>> + Send;
+    /// version stamp of the last write of a key, see [`KvsEngine::version`]
+    fn version(&self, key: String) -> impl Future<Output = Result<Option<u64>>> + Send;
+    /// set a key-value pair only if the version of the key is still `expected_version`,
+    /// see [`KvsEngine::set_if_version`]
+    fn set_if_version(
+        &self,
+        key: String,
+        value: String,
+        expected_version: u64,
+    ) -> impl Future<Output = Result<u64>> + Send;
+}
+
+/// an adapter running a blocking [`KvsEngine`] on tokio's blocking pool
+#[derive(Clone)]
+pub struct TokioEngine<E: KvsEngine> {
+    engine: E,
+}
+
+/// [`KvStore`] running on tokio's blocking pool
+/// ```rust
+/// use kvs::{AsyncKvsEngine, KvStore, TokioKvStore};
+/// # #[tokio::main]
+/// # async fn main() {
+/// let dir = tempfile::TempDir::new().unwrap();
+/// let store = TokioKvStore::new(KvStore::open(dir.path()).unwrap());
+/// store.set("key1".to_owned(), "value1".to_owned()).await.unwrap();
+/// assert_eq!(store.get("key1".to_owned()).await.unwrap(), Some("value1".to_owned()));
+/// # }
+/// ```
+pub type TokioKvStore = TokioEngine<KvStore>;
+
+impl<E: KvsEngine> TokioEngine<E> {
+    /// wrap a blocking engine
+    pub fn new(engine: E) -> Self {
+        Self { engine }
+    }
+
+    fn spawn_blocking<T, F>(&self, f: F) -> impl Future<Output = Result<T>> + Send
+    where
+        T: Send + 'static,
+        F: FnOnce(E) -> Result<T> + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        async move {
+            tokio::task::spawn_blocking(move || f(engine))
+                .await
+                .map_err(|_| KvsError::BlockingTask)?
+        }
+    }
+}
+
+impl<E: KvsEngine> AsyncKvsEngine for TokioEngine<E> {
+    fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send {
+        self.spawn_blocking(move |engine| engine.set(key, value))
+    }
+
+    fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send {
+        self.spawn_blocking(move |engine| engine.get(key))
+    }
+
+    fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send {
+        self.spawn_blocking(move |engine| engine.remove(key))
+    }
+
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send {
+        self.spawn_blocking(move |engine| engine.flush())
+    }
+
+    fn incr(&self, key: String, delta: i64) -> impl Future<Output = Result<i64>> + Send {
+        self.spawn_blocking(move |engine| engine.incr(key, delta))
+    }
+
+    fn append(&self, key: String, suffix: String) -> impl Future<Output = Result<()>> + Send {
+        self.spawn_blocking(move |engine| engine.append(key, suffix))
+    }
+
+    fn get_many(
+        &self,
+        keys: Vec<String>,
+    ) -> impl Future<Output = Result<Vec<Option<String>>>> + Send {
+        self.spawn_blocking(move |engine| engine.get_many(keys))
+    }
+
+    fn version(&self, key: String) -> impl Future<Output = Result<Option<u64>>> + Send {
+        self.spawn_blocking(move |engine| engine.version(key))
+    }
+
+    fn set_if_version(
+        &self,
+        key: String,
+        value: String,
+        expected_version: u64,
+    ) -> impl Future<Output = Result<u64>> + Send {
+        self.spawn_blocking(move |engine| engine.set_if_version(key, value, expected_version))
+    }
+}
@@ -1,88 +1,300 @@
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream},
+    fs::File,
+    io::{self, BufRead, BufReader},
+    mem,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
-use clap::{Parser, Subcommand};
-use kvs::{KvsError, Request, Response, Result};
-use serde_json::Deserializer;
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    error::ErrorKind,
+    CommandFactory, Parser, Subcommand,
+};
+use kvs::{tls, Codec, KvsClient, KvsError, Result, RetryPolicy, ServerAddr};
 
 #[derive(Parser)]
-#[command(version, about)]
+#[command(name = "kvs-client", version, about)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// timeout of connecting and of each response, in milliseconds
+    #[arg(long, global = true, default_value_t = 5000)]
+    timeout: u64,
+    /// times to retry when the server can't be reached or the connection fails
+    #[arg(long, global = true, default_value_t = 0)]
+    retries: u32,
+    /// wait before the first retry in milliseconds, doubled for each next retry
+    #[arg(long, global = true, default_value_t = 100)]
+    retry_backoff: u64,
+    /// connect over tls, verifying the server with --ca-cert
+    #[arg(long, global = true, requires = "ca_cert")]
+    tls: bool,
+    /// pem certificates trusted to sign the server's certificate
+    #[arg(long, global = true, requires = "tls")]
+    ca_cert: Option<PathBuf>,
+    /// serialization of requests and responses, a server which doesn't speak it answers
+    /// in json
+    #[arg(
+        long,
+        global = true,
+        default_value = "json",
+        value_parser = PossibleValuesParser::new(["json", "bincode", "msgpack"]).map(|codec| {
+            match codec.as_str() {
+                "bincode" => Codec::Bincode,
+                "msgpack" => Codec::MessagePack,
+                _ => Codec::Json,
+            }
+        }),
+    )]
+    codec: Codec,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     Get {
         key: String,
-        #[arg(long, default_value_t = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000))]
-        addr: SocketAddr,
+        #[arg(long, default_value_t = ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+        addr: ServerAddr,
     },
     Set {
         key: String,
         value: String,
-        #[arg(long, default_value_t = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000))]
-        addr: SocketAddr,
+        #[arg(long, default_value_t = ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+        addr: ServerAddr,
     },
     Rm {
         key: String,
-        #[arg(long, default_value_t = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000))]
-        addr: SocketAddr,
+        #[arg(long, default_value_t = ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+        addr: ServerAddr,
+    },
+    /// add delta to the integer value of a key, an absent key counts as 0
+    Incr {
+        key: String,
+        #[arg(allow_negative_numbers = true)]
+        delta: i64,
+        #[arg(long, default_value_t = ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+        addr: ServerAddr,
+    },
+    /// append suffix to the value of a key, an absent key is set to suffix
+    Append {
+        key: String,
+        suffix: String,
+        #[arg(long, default_value_t = ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+        addr: ServerAddr,
+    },
+    /// set pairs of key and value given in turn, in one round trip
+    Mset {
+        #[arg(required = true, value_name = "KEY VALUE")]
+        pairs: Vec<String>,
+        #[arg(long, default_value_t = ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+        addr: ServerAddr,
+    },
+    /// set pairs read from a file of `key<TAB>value` lines, streamed in pipelined batches
+    Load {
+        #[arg(long)]
+        file: PathBuf,
+        /// pairs set per round trip
+        #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+        batch_size: u64,
+        #[arg(long, default_value_t = ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+        addr: ServerAddr,
+    },
+    Mget {
+        #[arg(required = true)]
+        keys: Vec<String>,
+        #[arg(long, default_value_t = ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+        addr: ServerAddr,
+    },
+    Ping {
+        #[arg(long, default_value_t = ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+        addr: ServerAddr,
+    },
+    /// print counters of requests handled by the server
+    Stats {
+        #[arg(long, default_value_t = ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+        addr: ServerAddr,
+    },
+    /// persist all writes of the server's engine to disk
+    Flush {
+        #[arg(long, default_value_t = ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+        addr: ServerAddr,
     },
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    if let Commands::Mset { pairs, .. } = &cli.command {
+        if pairs.len() % 2 != 0 {
+            Cli::command()
+                .error(
+                    ErrorKind::WrongNumberOfValues,
+                    "mset takes pairs of key and value",
+                )
+                .exit();
+        }
+    }
 
-    match cli.command {
-        Commands::Get { key, addr } => {
-            let response = process_command(addr, Request::Get { key })?;
-
-            if let Some(err) = response.error {
-                eprintln!("error: {err}");
-                return Err(KvsError::ClientError);
-            }
+    let retry_policy = RetryPolicy::new(cli.retries, Duration::from_millis(cli.retry_backoff));
+    let timeout = Duration::from_millis(cli.timeout);
+    let tls = match &cli.ca_cert {
+        Some(ca_cert) => Some(tls::client_config(ca_cert)?),
+        None => None,
+    };
 
-            match response.value {
-                Some(value) => println!("{value}"),
-                None => println!("Key not found"),
-            }
+    let connect =
+        |addr: &ServerAddr| KvsClient::connect_with(addr, timeout, tls.clone(), cli.codec);
+    // a read is sent again on a new connection, while a write is only retried until
+    // connected, as a write whose response was lost may have been applied
+    let result = if cli.command.is_read() {
+        retry_policy.run(|| run(&cli.command, &connect))
+    } else {
+        run(&cli.command, &|addr| retry_policy.run(|| connect(addr)))
+    };
+    match result {
+        Err(KvsError::KeyNotFound) => {
+            eprintln!("Key not found");
+            Err(KvsError::ClientError)
         }
-        Commands::Set { key, value, addr } => {
-            let response = process_command(addr, Request::Set { key, value })?;
-
-            if let Some(err) = response.error {
-                eprintln!("error: {err}");
-                return Err(KvsError::ClientError);
-            }
+        Err(
+            err @ (KvsError::BadRequest(_)
+            | KvsError::Timeout
+            | KvsError::ProtocolVersion { .. }
+            | KvsError::NoResponse
+            | KvsError::NotAnInteger
+            | KvsError::ValueTooLarge
+            | KvsError::ReadOnly
+            | KvsError::Tls(_)
+            | KvsError::TlsConfig(_)),
+        ) => {
+            eprintln!("{err}");
+            Err(KvsError::ClientError)
+        }
+        Err(KvsError::Server(err)) => {
+            eprintln!("error: {err}");
+            Err(KvsError::ClientError)
         }
-        Commands::Rm { key, addr } => {
-            let response = process_command(addr, Request::Rm { key })?;
+        // the server can't be reached
+        Err(KvsError::StdIo(err)) => {
+            eprintln!("{err}");
+            Err(KvsError::ClientError)
+        }
+        other => other,
+    }
+}
 
-            if let Some(err) = response.error {
-                eprintln!("error: {err}");
-                return Err(KvsError::ClientError);
+impl Commands {
+    /// `true` if the command only reads, so it can be sent again, see [`kvs::Request::is_read`]
+    fn is_read(&self) -> bool {
+        matches!(
+            self,
+            Commands::Get { .. }
+                | Commands::Mget { .. }
+                | Commands::Ping { .. }
+                | Commands::Stats { .. }
+        )
+    }
+}
+
+/// run `command` on a connection from `connect`, output is printed once the request
+/// succeeds
+fn run(command: &Commands, connect: &dyn Fn(&ServerAddr) -> Result<KvsClient>) -> Result<()> {
+    match command {
+        Commands::Get { key, addr } => match connect(addr)?.get(key.clone())? {
+            Some(value) => println!("{value}"),
+            None => println!("Key not found"),
+        },
+        Commands::Set { key, value, addr } => connect(addr)?.set(key.clone(), value.clone())?,
+        Commands::Rm { key, addr } => connect(addr)?.remove(key.clone())?,
+        Commands::Incr { key, delta, addr } => {
+            println!("{}", connect(addr)?.incr(key.clone(), *delta)?)
+        }
+        Commands::Append { key, suffix, addr } => {
+            connect(addr)?.append(key.clone(), suffix.clone())?
+        }
+        Commands::Flush { addr } => connect(addr)?.flush()?,
+        Commands::Mset { pairs, addr } => {
+            let pairs = pairs
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            connect(addr)?.set_many(pairs)?
+        }
+        Commands::Load {
+            file,
+            batch_size,
+            addr,
+        } => {
+            let start = Instant::now();
+            let loaded = load(&mut connect(addr)?, file, *batch_size as usize)?;
+            let elapsed = start.elapsed();
+            println!(
+                "loaded {loaded} pairs in {elapsed:?} ({:.0} pairs/s)",
+                loaded as f64 / elapsed.as_secs_f64()
+            );
+        }
+        Commands::Mget { keys, addr } => {
+            for value in connect(addr)?.get_many(keys.clone())? {
+                match value {
+                    Some(value) => println!("{value}"),
+                    None => println!("Key not found"),
+                }
             }
         }
+        Commands::Ping { addr } => {
+            let mut client = connect(addr)?;
+            let start = Instant::now();
+            let version = client.ping()?;
+            println!(
+                "pong from {addr} (version {version}) in {:?}",
+                start.elapsed()
+            );
+        }
+        Commands::Stats { addr } => {
+            let stats = connect(addr)?.stats()?;
+            println!("connections: {}", stats.connections);
+            println!("requests: {}", stats.requests);
+            println!("gets: {}", stats.gets);
+            println!("sets: {}", stats.sets);
+            println!("removes: {}", stats.removes);
+            println!("errors: {}", stats.errors);
+        }
     };
 
     Ok(())
 }
 
-fn process_command(addr: SocketAddr, request: Request) -> Result<Response> {
-    let conn = TcpStream::connect(addr)?;
-    let reader = BufReader::new(&conn);
-    let mut writer = BufWriter::new(&conn);
-
-    let mut json = Vec::new();
-    serde_json::to_writer(&mut json, &request)?;
-    writer.write_all(&json)?;
-    writer.flush()?;
-
-    let mut response_iter = Deserializer::from_reader(reader).into_iter::<Response>();
-    let response = response_iter.next().expect("no response received")?;
-    Ok(response)
+/// set the pairs of the `key<TAB>value` lines of `file`, `batch_size` at a time,
+/// returns the number of pairs set
+///
+/// lines are read as they are sent, so the file is never held in memory, empty lines are
+/// skipped and a value keeps any further tabs
+fn load(client: &mut KvsClient, file: &Path, batch_size: usize) -> Result<u64> {
+    let mut loaded = 0;
+    let mut batch = Vec::with_capacity(batch_size);
+    for (i, line) in BufReader::new(File::open(file)?).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line.split_once('\t').ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}:{}: expected a key and a value separated by a tab",
+                    file.display(),
+                    i + 1
+                ),
+            )
+        })?;
+        batch.push((key.to_owned(), value.to_owned()));
+        if batch.len() == batch_size {
+            client.set_many(mem::replace(&mut batch, Vec::with_capacity(batch_size)))?;
+            loaded += batch_size as u64;
+        }
+    }
+    let rest = batch.len() as u64;
+    client.set_many(batch)?;
+    Ok(loaded + rest)
 }
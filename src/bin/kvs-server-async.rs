@@ -0,0 +1,462 @@
+use std::{future::Future, io, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use clap::{builder::PossibleValuesParser, builder::TypedValueParser, Parser};
+use kvs::{
+    codec::{Bincode, Json, MessagePack},
+    init_logger, AnyEngine, AsyncKvsEngine, Codec, Decoded, Engine, KvStoreOptions, KvsError,
+    KvsServer, MessageCodec, ReadRequest, Request, RequestKind, Response, Result, ServerAddr,
+    ServerConfig, ServerListener, ServerMetrics, TokioEngine, WriteRequest, CODEC_PROTOCOL_VERSION,
+    PROTOCOL_VERSIONS,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+#[cfg(unix)]
+use {
+    kvs::UnixSocket,
+    tokio::net::{UnixListener, UnixStream},
+};
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// toml config file, flags override its values, pool and threads are ignored
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// address to listen on [default: 127.0.0.1:4000]
+    #[arg(long)]
+    addr: Option<ServerAddr>,
+    /// pending tcp connections the listener holds [default: 128]
+    #[arg(long)]
+    backlog: Option<u32>,
+    /// engine storing pairs [default: kvs]
+    #[arg(long, value_enum)]
+    engine: Option<Engine>,
+    /// directory of the engine's files [default: .]
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// serve prometheus metrics over http at /metrics on this address
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// verbosity of logs
+    #[arg(
+        long,
+        default_value = "info",
+        value_parser = PossibleValuesParser::new(["error", "warn", "info", "debug", "trace"])
+            .map(|level| level.parse::<log::LevelFilter>().unwrap()),
+    )]
+    log_level: log::LevelFilter,
+    /// write logs to this file instead of stderr
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+}
+
+impl Cli {
+    /// the config file, or the default config, with values given by flags replaced
+    fn config(&self) -> Result<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+        if let Some(addr) = &self.addr {
+            config.addr = addr.clone();
+        }
+        if let Some(backlog) = self.backlog {
+            config.backlog = Some(backlog);
+        }
+        if let Some(engine) = self.engine {
+            config.engine = engine;
+        }
+        if let Some(data_dir) = &self.data_dir {
+            config.data_dir = data_dir.clone();
+        }
+        if let Some(metrics_addr) = self.metrics_addr {
+            config.metrics_addr = Some(metrics_addr);
+        }
+        Ok(config)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let config = cli.config()?;
+
+    init_logger(module_path!(), cli.log_level, cli.log_file.as_deref())?;
+    log::info!(
+        "version: {}, engine: {}, address: {}, data dir: {}",
+        env!("CARGO_PKG_VERSION"),
+        config.engine,
+        config.addr,
+        config.data_dir.display()
+    );
+
+    config.check_engine().inspect_err(|e| log::error!("{e}"))?;
+
+    // rather than accepting writes the config asks to refuse
+    if config.read_only {
+        log::error!("read-only mode is served by kvs-server only");
+        return Err(KvsError::ReadOnly);
+    }
+
+    // rather than serving in plain what the config asks to encrypt
+    if config.tls_cert.is_some() || config.tls_key.is_some() {
+        log::error!("tls is served by kvs-server only");
+        return Err(KvsError::TlsConfig(
+            "tls is served by kvs-server only".to_owned(),
+        ));
+    }
+
+    match KvsServer::bind(config.clone())?.into_listener() {
+        ServerListener::Tcp(listener) => {
+            listener.set_nonblocking(true)?;
+            serve(TcpListener::from_std(listener)?, &config).await
+        }
+        #[cfg(unix)]
+        ServerListener::Unix(socket) => {
+            socket.listener().set_nonblocking(true)?;
+            let listener = UnixListener::from_std(socket.listener().try_clone()?)?;
+            serve(UnixSocketListener { listener, socket }, &config).await
+        }
+    }
+}
+
+/// a listener accepting connections of one transport
+trait Listener: Send {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// accept a connection, with a description of the peer for logging
+    fn accept(&self) -> impl Future<Output = io::Result<(Self::Stream, String)>> + Send;
+
+    /// `true` once the server was interrupted or terminated, and should stop accepting
+    fn is_stopped(&self) -> bool {
+        false
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&self) -> io::Result<(TcpStream, String)> {
+        let (stream, peer_addr) = TcpListener::accept(self).await?;
+        // see `kvs::accept_tcp`
+        if let Err(e) = stream.set_nodelay(true) {
+            log::warn!("can't set TCP_NODELAY on connection {}: {}", peer_addr, e);
+        }
+        Ok((stream, peer_addr.to_string()))
+    }
+}
+
+/// a unix domain socket, accepted on by tokio
+#[cfg(unix)]
+struct UnixSocketListener {
+    listener: UnixListener,
+    socket: UnixSocket,
+}
+
+#[cfg(unix)]
+impl Listener for UnixSocketListener {
+    type Stream = UnixStream;
+
+    async fn accept(&self) -> io::Result<(UnixStream, String)> {
+        let (stream, peer_addr) = self.listener.accept().await?;
+        Ok((stream, format!("{:?}", peer_addr)))
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.socket.is_stopped()
+    }
+}
+
+async fn serve(listener: impl Listener, config: &ServerConfig) -> Result<()> {
+    let store = Arc::new(config.store.clone());
+    // a kvs engine is kept to report its stats to the metrics endpoint
+    let kvs = match config.engine {
+        Engine::Kvs => Some(config.open_store()?),
+        _ => None,
+    };
+    let kv = match &kvs {
+        Some(kvs) => AnyEngine::new(kvs.clone()),
+        None => config.open_engine()?,
+    };
+    let metrics = Arc::new(ServerMetrics::new());
+    config.spawn_metrics(metrics.clone(), kvs)?;
+    run_engine(listener, TokioEngine::new(kv), store, metrics).await
+}
+
+async fn run_engine(
+    listener: impl Listener,
+    kv: impl AsyncKvsEngine,
+    store: Arc<KvStoreOptions>,
+    metrics: Arc<ServerMetrics>,
+) -> Result<()> {
+    loop {
+        // a connection which fails to be accepted, such as one aborted by its peer,
+        // is dropped while the server goes on
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::error!("accepting a connection failed: {}", e);
+                continue;
+            }
+        };
+        // the connection waking the loop once stopped is dropped
+        if listener.is_stopped() {
+            return Ok(());
+        }
+        log::debug!("receive a connection {}", peer_addr);
+        metrics.connection();
+
+        let kv = kv.clone();
+        let metrics = metrics.clone();
+        let store = store.clone();
+        tokio::spawn(async move {
+            if let Err(e) = process(stream, kv, &store, &metrics).await {
+                log::error!("connection {} failed: {}", peer_addr, e);
+            }
+        });
+    }
+}
+
+async fn process(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    kv: impl AsyncKvsEngine,
+    store: &KvStoreOptions,
+    metrics: &ServerMetrics,
+) -> Result<()> {
+    stream
+        .write_all(&[*PROTOCOL_VERSIONS.start(), *PROTOCOL_VERSIONS.end()])
+        .await?;
+    stream.flush().await?;
+    let version = stream.read_u8().await?;
+    if !PROTOCOL_VERSIONS.contains(&version) {
+        return Err(KvsError::ProtocolVersion {
+            min: *PROTOCOL_VERSIONS.start(),
+            max: *PROTOCOL_VERSIONS.end(),
+        });
+    }
+    let codec = if version >= CODEC_PROTOCOL_VERSION {
+        let codec = Codec::from_byte(stream.read_u8().await?).unwrap_or_default();
+        stream.write_all(&[codec.to_byte()]).await?;
+        stream.flush().await?;
+        codec
+    } else {
+        Codec::Json
+    };
+
+    match codec {
+        Codec::Json => process_requests(Json, stream, kv, store, metrics).await,
+        Codec::Bincode => process_requests(Bincode, stream, kv, store, metrics).await,
+        Codec::MessagePack => process_requests(MessagePack, stream, kv, store, metrics).await,
+    }
+}
+
+/// answer the requests of a connection in `codec`
+async fn process_requests<C: MessageCodec>(
+    codec: C,
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    kv: impl AsyncKvsEngine,
+    store: &KvStoreOptions,
+    metrics: &ServerMetrics,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let mut encoded = Vec::new();
+
+    loop {
+        // a message which isn't a request is answered and skipped, while a stream which
+        // can't be split into messages anymore is answered and closed
+        let request = match codec.decode_buf::<Request>(&buf) {
+            Some(Ok((consumed, decoded))) => {
+                buf.drain(..consumed);
+                match decoded {
+                    Decoded::Message(request) => request,
+                    Decoded::Invalid(e) => {
+                        bad_request(&mut stream, codec, &mut encoded, metrics, &e).await?;
+                        continue;
+                    }
+                }
+            }
+            Some(Err(e)) => {
+                // the response tells the client why the connection is closed
+                bad_request(&mut stream, codec, &mut encoded, metrics, &e).await?;
+                return Err(e);
+            }
+            None => {
+                // wait for the rest of a partially received request
+                let n = stream.read(&mut chunk).await?;
+                if n == 0 {
+                    return Ok(());
+                }
+                buf.extend_from_slice(&chunk[..n]);
+                continue;
+            }
+        };
+        log::debug!("request {:?}", request);
+        metrics.request(Some(&request));
+
+        let response = match request.into_kind() {
+            RequestKind::Read(request) => read(request, kv.clone(), metrics).await,
+            RequestKind::Write(request) => write(request, kv.clone(), store).await,
+        };
+        log::debug!("response {:?}", response);
+        metrics.response(&response);
+
+        send(&mut stream, codec, &mut encoded, &response).await?;
+    }
+}
+
+/// answer a message which can't be decoded with a bad request error
+async fn bad_request(
+    stream: &mut (impl AsyncWrite + Unpin),
+    codec: impl MessageCodec,
+    buf: &mut Vec<u8>,
+    metrics: &ServerMetrics,
+    e: &KvsError,
+) -> Result<()> {
+    let response = Response {
+        error: Some(KvsError::BadRequest(e.to_string()).into()),
+        ..Default::default()
+    };
+    metrics.request(None);
+    metrics.response(&response);
+    send(stream, codec, buf, &response).await
+}
+
+/// write `response` in `codec`, encoding it in `buf` first
+async fn send(
+    stream: &mut (impl AsyncWrite + Unpin),
+    codec: impl MessageCodec,
+    buf: &mut Vec<u8>,
+    response: &Response,
+) -> Result<()> {
+    buf.clear();
+    codec.encode(buf, response)?;
+    stream.write_all(buf).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// answer a read request, without waiting on writes to the engine
+async fn read(request: ReadRequest, kv: impl AsyncKvsEngine, metrics: &ServerMetrics) -> Response {
+    match request {
+        ReadRequest::Get { key } => match kv.get(key).await {
+            Ok(value) => Response {
+                value,
+                error: None,
+                ..Default::default()
+            },
+            Err(e) => Response {
+                value: None,
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        ReadRequest::GetMany { keys } => match kv.get_many(keys).await {
+            Ok(values) => Response {
+                values: Some(values),
+                ..Default::default()
+            },
+            Err(e) => Response {
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        ReadRequest::Ping => Response {
+            value: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            error: None,
+            ..Default::default()
+        },
+        ReadRequest::Stats => Response {
+            stats: Some(metrics.snapshot()),
+            ..Default::default()
+        },
+        ReadRequest::Version { key } => match kv.version(key).await {
+            Ok(version) => Response {
+                value: version.map(|version| version.to_string()),
+                ..Default::default()
+            },
+            Err(e) => Response {
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+    }
+}
+
+/// answer a write request, writes are serialized by the engine
+///
+/// keys and values over the limits of the store options are rejected for any engine
+async fn write(request: WriteRequest, kv: impl AsyncKvsEngine, store: &KvStoreOptions) -> Response {
+    if let Err(e) = request.check_size(store) {
+        return Response {
+            error: Some(e.into()),
+            ..Default::default()
+        };
+    }
+    match request {
+        WriteRequest::Set { key, value } => match kv.set(key, value).await {
+            Ok(_) => Response {
+                value: None,
+                error: None,
+                ..Default::default()
+            },
+            Err(e) => Response {
+                value: None,
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        WriteRequest::Rm { key } => match kv.remove(key).await {
+            Ok(_) => Response {
+                value: None,
+                error: None,
+                ..Default::default()
+            },
+            Err(e) => Response {
+                value: None,
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        WriteRequest::Incr { key, delta } => match kv.incr(key, delta).await {
+            Ok(value) => Response {
+                value: Some(value.to_string()),
+                ..Default::default()
+            },
+            Err(e) => Response {
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        WriteRequest::Append { key, suffix } => match kv.append(key, suffix).await {
+            Ok(_) => Response::default(),
+            Err(e) => Response {
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        WriteRequest::SetIfVersion {
+            key,
+            value,
+            expected_version,
+        } => match kv.set_if_version(key, value, expected_version).await {
+            Ok(version) => Response {
+                value: Some(version.to_string()),
+                ..Default::default()
+            },
+            Err(e) => Response {
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        WriteRequest::Flush => match kv.flush().await {
+            Ok(_) => Response::default(),
+            Err(e) => Response {
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+    }
+}
@@ -1,153 +1,127 @@
-use std::{
-    env::current_dir,
-    fmt::Display,
-    fs,
-    io::{BufReader, BufWriter, Write},
-    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
-};
+use std::{net::SocketAddr, path::PathBuf};
 
-use clap::{Parser, ValueEnum};
-use kvs::{
-    thread_pool::{SharedQueueThreadPool, ThreadPool},
-    KvStore, KvsEngine, KvsError, Request, Response, Result, SledKvsEngine,
-};
-use serde_json::Deserializer;
+use clap::{builder::PossibleValuesParser, builder::TypedValueParser, Parser};
+#[cfg(not(feature = "tracing"))]
+use kvs::init_logger;
+#[cfg(feature = "tracing")]
+use kvs::init_tracing;
+use kvs::{Engine, KvsServer, Pool, Result, ServerAddr, ServerConfig};
 
 #[derive(Parser)]
 #[command(version, about)]
 struct Cli {
-    #[arg(long, default_value_t = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000))]
-    addr: SocketAddr,
-    #[arg(long, value_enum, default_value_t = Engine::Kvs)]
-    engine: Engine,
+    /// toml config file, flags override its values
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// address to listen on [default: 127.0.0.1:4000]
+    #[arg(long)]
+    addr: Option<ServerAddr>,
+    /// pending tcp connections the listener holds [default: 128]
+    #[arg(long)]
+    backlog: Option<u32>,
+    /// engine storing pairs [default: kvs]
+    #[arg(long, value_enum)]
+    engine: Option<Engine>,
+    /// thread pool handling connections [default: shared]
+    #[arg(long, value_enum)]
+    pool: Option<Pool>,
+    /// number of threads in the pool, default is the number of cpus
+    #[arg(long)]
+    threads: Option<u32>,
+    /// directory of the engine's files [default: .]
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// pem certificate chain to serve tls with, requires --tls-key
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// pem private key of the tls certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// answer writes with a read-only error, the data dir is never written
+    #[arg(long)]
+    readonly: bool,
+    /// serve prometheus metrics over http at /metrics on this address
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// verbosity of logs
+    #[arg(
+        long,
+        default_value = "info",
+        value_parser = PossibleValuesParser::new(["error", "warn", "info", "debug", "trace"])
+            .map(|level| level.parse::<log::LevelFilter>().unwrap()),
+    )]
+    log_level: log::LevelFilter,
+    /// write logs to this file instead of stderr
+    #[arg(long)]
+    log_file: Option<PathBuf>,
 }
 
-#[derive(Clone, Copy, ValueEnum, PartialEq, Eq)]
-enum Engine {
-    Kvs,
-    Sled,
-}
-
-impl Display for Engine {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Engine::Kvs => write!(f, "kvs"),
-            Engine::Sled => write!(f, "sled"),
+impl Cli {
+    /// the config file, or the default config, with values given by flags replaced
+    fn config(&self) -> Result<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+        if let Some(addr) = &self.addr {
+            config.addr = addr.clone();
         }
-    }
-}
-
-fn current_engine(cli_engine: Engine) -> Result<Engine> {
-    let config_file = current_dir()?.join("engine");
-
-    if !config_file.try_exists()? {
-        fs::write(config_file, format!("{cli_engine}"))?;
-        return Ok(cli_engine);
-    }
-
-    match fs::read_to_string(config_file)?.as_str() {
-        "kvs" => Ok(Engine::Kvs),
-        "sled" => Ok(Engine::Sled),
-        _ => unreachable!(),
+        if let Some(backlog) = self.backlog {
+            config.backlog = Some(backlog);
+        }
+        if let Some(engine) = self.engine {
+            config.engine = engine;
+        }
+        if let Some(pool) = self.pool {
+            config.pool = pool;
+        }
+        if let Some(threads) = self.threads {
+            config.threads = Some(threads);
+        }
+        if let Some(data_dir) = &self.data_dir {
+            config.data_dir = data_dir.clone();
+        }
+        if let Some(tls_cert) = &self.tls_cert {
+            config.tls_cert = Some(tls_cert.clone());
+        }
+        if let Some(tls_key) = &self.tls_key {
+            config.tls_key = Some(tls_key.clone());
+        }
+        if self.readonly {
+            config.read_only = true;
+        }
+        if let Some(metrics_addr) = self.metrics_addr {
+            config.metrics_addr = Some(metrics_addr);
+        }
+        Ok(config)
     }
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
-
-    stderrlog::new()
-        .verbosity(log::Level::Trace)
-        .timestamp(stderrlog::Timestamp::Second)
-        .module(module_path!())
-        .init()?;
-    log::debug!(
-        "version: {}, engine: {}, address: {}",
+    let config = cli.config()?;
+
+    #[cfg(not(feature = "tracing"))]
+    init_logger(module_path!(), cli.log_level, cli.log_file.as_deref())?;
+    #[cfg(feature = "tracing")]
+    init_tracing(module_path!(), cli.log_level, cli.log_file.as_deref())?;
+    log::info!(
+        "version: {}, engine: {}, pool: {}, address: {}, data dir: {}",
         env!("CARGO_PKG_VERSION"),
-        cli.engine,
-        cli.addr
+        config.engine,
+        config.pool,
+        config.addr,
+        config.data_dir.display()
     );
-
-    if current_engine(cli.engine)? != cli.engine {
-        log::error!("unmatched engine");
-        return Err(KvsError::UnmatchedEngine);
-    }
-
-    let listener = TcpListener::bind(cli.addr)?;
-    let thread_pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
-    match cli.engine {
-        Engine::Kvs => run_engine(listener, KvStore::open(current_dir()?)?, thread_pool),
-        Engine::Sled => run_engine(
-            listener,
-            SledKvsEngine {
-                db: sled::open(current_dir()?)?,
-            },
-            thread_pool,
-        ),
-    }
-}
-
-fn run_engine(
-    listener: TcpListener,
-    kv: impl KvsEngine,
-    thread_pool: impl ThreadPool,
-) -> Result<()> {
-    for stream in listener.incoming() {
-        let stream = stream?;
-        log::debug!("receive a connection {}", stream.peer_addr()?);
-
-        let kv = kv.clone();
-        thread_pool.spawn(move || process(stream, &kv).unwrap());
+    if config.read_only {
+        log::info!("read-only, writes are refused");
     }
+    config
+        .check_read_only()
+        .inspect_err(|e| log::error!("{e}"))?;
 
-    Ok(())
-}
-
-fn process(stream: TcpStream, kv: &impl KvsEngine) -> Result<()> {
-    let reader = BufReader::new(&stream);
-    let mut writer = BufWriter::new(&stream);
-
-    let req_iter = Deserializer::from_reader(reader).into_iter::<Request>();
-
-    for request in req_iter {
-        let request = request?;
-        log::debug!("request {:?}", request);
-
-        let response = match request {
-            Request::Get { key } => match kv.get(key) {
-                Ok(value) => Response { value, error: None },
-                Err(e) => Response {
-                    value: None,
-                    error: Some(e.to_string()),
-                },
-            },
-            Request::Set { key, value } => match kv.set(key, value) {
-                Ok(_) => Response {
-                    value: None,
-                    error: None,
-                },
-                Err(e) => Response {
-                    value: None,
-                    error: Some(e.to_string()),
-                },
-            },
-            Request::Rm { key } => match kv.remove(key) {
-                Ok(_) => Response {
-                    value: None,
-                    error: None,
-                },
-                Err(e) => Response {
-                    value: None,
-                    error: Some(e.to_string()),
-                },
-            },
-        };
-        log::debug!("response {:?}", response);
-
-        let mut json = Vec::new();
-        serde_json::to_writer(&mut json, &response)?;
-        writer.write_all(&json)?;
-        writer.flush()?;
-    }
+    config.check_engine().inspect_err(|e| log::error!("{e}"))?;
 
-    Ok(())
+    let server = KvsServer::bind(config).inspect_err(|e| log::error!("{e}"))?;
+    server.run()
 }
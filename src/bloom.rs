@@ -0,0 +1,65 @@
+/*!
+ * bloom filter for skipping lookups of absent keys
+ */
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// bits per expected key, gives about 1% false positives with `HASHES` hashes
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+/// expected keys of a filter created for an empty or small store
+const MIN_CAPACITY: usize = 1024;
+
+/// a filter which can tell a key was never inserted, keys can't be removed
+///
+/// bits are atomic so the writer can insert while readers are probing
+pub(crate) struct BloomFilter {
+    bits: Vec<AtomicU64>,
+}
+
+impl BloomFilter {
+    /// create a filter sized for `capacity` keys,
+    /// false positives increase when more keys are inserted
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        let words = (capacity.max(MIN_CAPACITY) * BITS_PER_KEY).div_ceil(64);
+        Self {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub(crate) fn insert(&self, key: &[u8]) {
+        for bit in self.bit_indexes(key) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::SeqCst);
+        }
+    }
+
+    /// forget all inserted keys
+    pub(crate) fn clear(&self) {
+        for word in &self.bits {
+            word.store(0, Ordering::SeqCst);
+        }
+    }
+
+    /// `false` if `key` was never inserted
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_indexes(key)
+            .all(|bit| self.bits[bit / 64].load(Ordering::SeqCst) & (1 << (bit % 64)) != 0)
+    }
+
+    /// double hashing, derives all bit indexes from two hashes of the key
+    fn bit_indexes(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let h1 = hasher.finish();
+        // continue from the first hash to get a second, independent one
+        h1.hash(&mut hasher);
+        let h2 = hasher.finish() | 1;
+
+        let len = self.bits.len() as u64 * 64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
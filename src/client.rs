@@ -0,0 +1,431 @@
+/*!
+ * client keeping one connection to a kvs server
+ */
+
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use rustls::{pki_types::ServerName, ClientConfig};
+
+use crate::{
+    tls, Codec, Decoded, KvsError, MessageCodec, MessageStream, Request, Response, Result,
+    ServerAddr, ServerStats, TlsStream, CODEC_PROTOCOL_VERSION, PROTOCOL_VERSIONS,
+};
+
+/// how often and how late a failed connection or request is tried again
+///
+/// only errors which may be transient are retried, see [`KvsError::is_retryable`]. A
+/// write whose connection failed may have been applied by the server, so only connecting
+/// and requests which only read, see [`Request::is_read`], should be run again
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    retries: u32,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// retry up to `retries` times, waiting `backoff` before the first retry
+    /// and twice as long before each next one
+    pub fn new(retries: u32, backoff: Duration) -> Self {
+        Self { retries, backoff }
+    }
+
+    /// call `f` until it succeeds, fails with an error which isn't retryable,
+    /// or all retries are used, returns the last result
+    pub fn run<T>(&self, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut backoff = self.backoff;
+        for retry in 1..=self.retries {
+            match f() {
+                Err(e) if e.is_retryable() => {
+                    log::debug!(
+                        "retry {} of {} in {:?}: {}",
+                        retry,
+                        self.retries,
+                        backoff,
+                        e
+                    );
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+        f()
+    }
+}
+
+/// a client sending requests over a single connection
+pub struct KvsClient {
+    reader: MessageStream<BufReader<Box<dyn Read + Send>>, Response>,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    /// encoded request being written, reused to avoid an allocation per request
+    buf: Vec<u8>,
+    /// `false` if the reader and the writer take turns on one connection, as over tls,
+    /// so they can't be used at once
+    split: bool,
+    version: u8,
+    codec: Codec,
+}
+
+/// bytes of requests a pipeline over tls sends before reading their responses, small
+/// enough for the socket buffers, so neither side blocks writing while the other writes
+const PIPELINE_WINDOW: usize = 16 * 1024;
+
+impl KvsClient {
+    /// connect to a server at `addr`
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Self::from_stream(stream.try_clone()?, stream, true, Codec::Json)
+    }
+
+    /// connect to a server at `addr`, connecting and each later read or write
+    /// fail with [`KvsError::Timeout`] if they take longer than `timeout`
+    pub fn connect_timeout(addr: impl ToSocketAddrs, timeout: Duration) -> Result<Self> {
+        let mut last_error = io::Error::from(io::ErrorKind::InvalidInput);
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    return Self::from_stream(stream.try_clone()?, stream, true, Codec::Json);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(io_error(last_error))
+    }
+
+    /// connect to a server at a tcp or unix domain socket `addr`,
+    /// reads and writes time out as with [`KvsClient::connect_timeout`]
+    pub fn connect_addr(addr: &ServerAddr, timeout: Duration) -> Result<Self> {
+        Self::connect_with(addr, timeout, None, Codec::Json)
+    }
+
+    /// connect to a server at a tcp `addr` over tls, the server's certificate is verified
+    /// with `config` and should name the ip of `addr`,
+    /// reads and writes time out as with [`KvsClient::connect_timeout`]
+    pub fn connect_tls(
+        addr: &ServerAddr,
+        timeout: Duration,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self> {
+        Self::connect_with(addr, timeout, Some(config), Codec::Json)
+    }
+
+    /// connect to a server at `addr`, over tls if `tls` is set as with
+    /// [`KvsClient::connect_tls`], and ask for messages in `codec`, a server which
+    /// doesn't speak it answers in json, see [`KvsClient::codec`]
+    pub fn connect_with(
+        addr: &ServerAddr,
+        timeout: Duration,
+        tls: Option<Arc<ClientConfig>>,
+        codec: Codec,
+    ) -> Result<Self> {
+        match (addr, tls) {
+            (ServerAddr::Tcp(addr), None) => {
+                let stream = TcpStream::connect_timeout(addr, timeout).map_err(io_error)?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                Self::from_stream(stream.try_clone()?, stream, true, codec)
+            }
+            #[cfg(unix)]
+            (ServerAddr::Unix(path), None) => {
+                let stream = UnixStream::connect(path).map_err(io_error)?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                Self::from_stream(stream.try_clone()?, stream, true, codec)
+            }
+            (ServerAddr::Tcp(addr), Some(config)) => {
+                let stream = TcpStream::connect_timeout(addr, timeout).map_err(io_error)?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                let stream = TlsStream::connect(stream, ServerName::from(addr.ip()), config)?;
+                Self::from_stream(stream.try_clone()?, stream, false, codec)
+            }
+            #[cfg(unix)]
+            (ServerAddr::Unix(_), Some(_)) => Err(tls::unix_error()),
+        }
+    }
+
+    fn from_stream(
+        mut reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+        split: bool,
+        codec: Codec,
+    ) -> Result<Self> {
+        let mut writer: BufWriter<Box<dyn Write + Send>> = BufWriter::new(Box::new(writer));
+
+        let mut advertised = [0u8; 2];
+        reader.read_exact(&mut advertised).map_err(io_error)?;
+        let [min, max] = advertised;
+        let version = max.min(*PROTOCOL_VERSIONS.end());
+        if version < min.max(*PROTOCOL_VERSIONS.start()) {
+            return Err(KvsError::ProtocolVersion { min, max });
+        }
+        // older servers only speak json, see `PROTOCOL_VERSIONS`
+        let codec = if version < CODEC_PROTOCOL_VERSION {
+            writer.write_all(&[version]).map_err(io_error)?;
+            writer.flush().map_err(io_error)?;
+            Codec::Json
+        } else {
+            writer
+                .write_all(&[version, codec.to_byte()])
+                .map_err(io_error)?;
+            writer.flush().map_err(io_error)?;
+            let mut answered = [0u8; 1];
+            reader.read_exact(&mut answered).map_err(io_error)?;
+            Codec::from_byte(answered[0]).ok_or_else(|| {
+                KvsError::Server(format!("server answered unknown codec {}", answered[0]))
+            })?
+        };
+
+        let reader: Box<dyn Read + Send> = Box::new(reader);
+        Ok(Self {
+            reader: codec.decode_stream(BufReader::new(reader)),
+            writer,
+            buf: Vec::new(),
+            split,
+            version,
+            codec,
+        })
+    }
+
+    /// protocol version agreed with the server on connect
+    pub fn protocol_version(&self) -> u8 {
+        self.version
+    }
+
+    /// codec of requests and responses agreed with the server on connect
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// get value for a key
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.send(Request::Get { key })?.value)
+    }
+
+    /// set a key-value pair
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.send(Request::Set { key, value })?;
+        Ok(())
+    }
+
+    /// remove a key
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.send(Request::Rm { key })?;
+        Ok(())
+    }
+
+    /// add `delta` to the integer value of a key, returns the new value
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let value = self.send(Request::Incr { key, delta })?.value;
+        value
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| KvsError::Server("invalid incr response".to_owned()))
+    }
+
+    /// append `suffix` to the value of a key, an absent key is set to `suffix`
+    pub fn append(&mut self, key: String, suffix: String) -> Result<()> {
+        self.send(Request::Append { key, suffix })?;
+        Ok(())
+    }
+
+    /// version stamp of the last write of a key, `None` if the key is absent,
+    /// see [`KvStore::version`](crate::KvStore::version)
+    pub fn version(&mut self, key: String) -> Result<Option<u64>> {
+        match self.send(Request::Version { key })?.value {
+            Some(value) => Ok(Some(parse_version(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// set a key-value pair only if the version of the key is still `expected_version`,
+    /// returns the new version
+    ///
+    /// fails with [`KvsError::VersionMismatch`] if the key was written since
+    pub fn set_if_version(
+        &mut self,
+        key: String,
+        value: String,
+        expected_version: u64,
+    ) -> Result<u64> {
+        let request = Request::SetIfVersion {
+            key,
+            value,
+            expected_version,
+        };
+        parse_version(&self.send(request)?.value.unwrap_or_default())
+    }
+
+    /// persist all writes of the server's engine to disk
+    pub fn flush(&mut self) -> Result<()> {
+        self.send(Request::Flush)?;
+        Ok(())
+    }
+
+    /// get values for several keys, in the order of `keys`
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        Ok(self
+            .send(Request::GetMany { keys })?
+            .values
+            .unwrap_or_default())
+    }
+
+    /// set several key-value pairs, pipelined in one round trip. Pairs are set in order,
+    /// the first error reported by the server is returned once all of them are answered
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let requests = pairs
+            .into_iter()
+            .map(|(key, value)| Request::Set { key, value })
+            .collect();
+        match self
+            .pipeline(requests)?
+            .into_iter()
+            .find_map(|response| response.error)
+        {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// check the server is alive, returns the server version
+    pub fn ping(&mut self) -> Result<String> {
+        Ok(self.send(Request::Ping)?.value.unwrap_or_default())
+    }
+
+    /// get counters of requests handled by the server
+    pub fn stats(&mut self) -> Result<ServerStats> {
+        Ok(self.send(Request::Stats)?.stats.unwrap_or_default())
+    }
+
+    /// send a request and return the value of its response, `None` for requests answered
+    /// without a value such as set, values of get many and stats are left out
+    ///
+    /// an error reported by the server is returned as the [`KvsError`] of its code, such as
+    /// [`KvsError::KeyNotFound`], so callers can handle each kind of error
+    pub fn process_command(&mut self, request: Request) -> Result<Option<String>> {
+        Ok(self.send(request)?.value)
+    }
+
+    /// send all requests before reading their responses, responses are in request order
+    ///
+    /// requests are written from another thread, so a server blocked on writing
+    /// responses back can't stall the client writing requests. Over tls, requests are
+    /// sent in windows instead, each answered before the next is sent
+    pub fn pipeline(&mut self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        if !self.split {
+            return self.pipeline_windows(requests);
+        }
+        let count = requests.len();
+        let (reader, writer, buf, codec) = (
+            &mut self.reader,
+            &mut self.writer,
+            &mut self.buf,
+            self.codec,
+        );
+
+        thread::scope(|s| {
+            let sending = s.spawn(move || -> Result<()> {
+                for request in requests {
+                    buf.clear();
+                    codec.encode(buf, &request)?;
+                    writer.write_all(buf).map_err(io_error)?;
+                }
+                writer.flush().map_err(io_error)?;
+                Ok(())
+            });
+
+            let responses = reader
+                .take(count)
+                .map(read_response)
+                .collect::<Result<Vec<Response>>>();
+            sending.join().map_err(|_| KvsError::ClientError)??;
+
+            let responses = responses?;
+            if responses.len() != count {
+                return Err(closed_error());
+            }
+            Ok(responses)
+        })
+    }
+
+    /// send requests in windows of [`PIPELINE_WINDOW`] bytes, reading the responses of
+    /// a window before sending the next, for a reader and a writer taking turns
+    fn pipeline_windows(&mut self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        let mut responses = Vec::with_capacity(requests.len());
+        let mut requests = requests.into_iter().peekable();
+        while requests.peek().is_some() {
+            self.buf.clear();
+            let mut count = 0;
+            while self.buf.len() < PIPELINE_WINDOW {
+                match requests.next() {
+                    Some(request) => self.codec.encode(&mut self.buf, &request)?,
+                    None => break,
+                }
+                count += 1;
+            }
+            self.writer.write_all(&self.buf).map_err(io_error)?;
+            self.writer.flush().map_err(io_error)?;
+            for _ in 0..count {
+                let decoded = self.reader.next().ok_or_else(closed_error)?;
+                responses.push(read_response(decoded)?);
+            }
+        }
+        Ok(responses)
+    }
+
+    /// send a request and wait for its response,
+    /// an error reported by server is converted to the matching [`KvsError`]
+    pub fn send(&mut self, request: Request) -> Result<Response> {
+        self.buf.clear();
+        self.codec.encode(&mut self.buf, &request)?;
+        self.writer.write_all(&self.buf).map_err(io_error)?;
+        self.writer.flush().map_err(io_error)?;
+
+        let response = read_response(self.reader.next().ok_or_else(closed_error)?)?;
+        match response.error {
+            Some(err) => Err(err.into()),
+            None => Ok(response),
+        }
+    }
+}
+
+/// the server closed the connection before answering
+pub(crate) fn closed_error() -> KvsError {
+    KvsError::NoResponse
+}
+
+pub(crate) fn io_error(e: io::Error) -> KvsError {
+    match e.kind() {
+        // a socket timeout is reported as `WouldBlock` on unix
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => KvsError::Timeout,
+        _ => e.into(),
+    }
+}
+
+/// a response read from the server, or why it can't be
+fn read_response(decoded: Result<Decoded<Response>>) -> Result<Response> {
+    match decoded {
+        Ok(Decoded::Message(response)) => Ok(response),
+        Ok(Decoded::Invalid(e)) => Err(e),
+        Err(KvsError::StdIo(e)) => Err(io_error(e)),
+        Err(e) => Err(e),
+    }
+}
+
+/// version stamp answered by the server as a decimal value
+fn parse_version(value: &str) -> Result<u64> {
+    value
+        .parse()
+        .map_err(|_| KvsError::Server("invalid version response".to_owned()))
+}
@@ -0,0 +1,271 @@
+/*!
+ * serialization of requests and responses on a connection
+ */
+
+use std::{
+    convert::TryFrom,
+    io::{self, Read},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{de::IoRead, value::RawValue, Deserializer, StreamDeserializer};
+
+use crate::{KvsError, Result};
+
+/// serialization of the messages of a connection
+///
+/// servers are generic over it, so each codec gets its own request loop
+pub trait MessageCodec: Copy + Send + 'static {
+    /// append an encoded message to `buf`
+    fn encode<T: Serialize>(self, buf: &mut Vec<u8>, message: &T) -> Result<()>;
+
+    /// decode a stream of messages from `reader`
+    fn decode_stream<T: DeserializeOwned, R: Read>(self, reader: R) -> MessageStream<R, T>;
+
+    /// decode the first message of `buf` along with the number of bytes it takes,
+    /// `None` while the message isn't whole
+    fn decode_buf<T: DeserializeOwned>(self, buf: &[u8]) -> Option<Result<(usize, Decoded<T>)>>;
+}
+
+/// concatenated json values, readable when debugging
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+/// u32 little-endian length followed by bincode bytes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+/// u32 little-endian length followed by messagepack bytes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePack;
+
+/// codec of the messages of a connection, asked for by the client on connect,
+/// see [`PROTOCOL_VERSIONS`](crate::PROTOCOL_VERSIONS)
+///
+/// it only applies to the network, generation files keep their [`Encoding`](crate::Encoding)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// see [`Json`]
+    #[default]
+    Json = 0,
+    /// see [`Bincode`]
+    Bincode = 1,
+    /// see [`MessagePack`]
+    MessagePack = 2,
+}
+
+/// a message read from a connection
+#[derive(Debug)]
+pub enum Decoded<T> {
+    /// the next message
+    Message(T),
+    /// a whole message which isn't a `T`, the connection goes on with the next one
+    Invalid(KvsError),
+}
+
+impl<T> From<Result<T>> for Decoded<T> {
+    fn from(value: Result<T>) -> Self {
+        match value {
+            Ok(message) => Decoded::Message(message),
+            Err(e) => Decoded::Invalid(e),
+        }
+    }
+}
+
+impl Codec {
+    /// codec of a byte sent on connect, `None` if this build doesn't speak it
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Codec::Json),
+            1 => Some(Codec::Bincode),
+            2 => Some(Codec::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// byte of the codec sent on connect
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+}
+
+/// dispatches to the codec chosen on connect, for clients which hold it at runtime
+impl MessageCodec for Codec {
+    fn encode<T: Serialize>(self, buf: &mut Vec<u8>, message: &T) -> Result<()> {
+        match self {
+            Codec::Json => Json.encode(buf, message),
+            Codec::Bincode => Bincode.encode(buf, message),
+            Codec::MessagePack => MessagePack.encode(buf, message),
+        }
+    }
+
+    fn decode_stream<T: DeserializeOwned, R: Read>(self, reader: R) -> MessageStream<R, T> {
+        match self {
+            Codec::Json => Json.decode_stream(reader),
+            Codec::Bincode => Bincode.decode_stream(reader),
+            Codec::MessagePack => MessagePack.decode_stream(reader),
+        }
+    }
+
+    fn decode_buf<T: DeserializeOwned>(self, buf: &[u8]) -> Option<Result<(usize, Decoded<T>)>> {
+        match self {
+            Codec::Json => Json.decode_buf(buf),
+            Codec::Bincode => Bincode.decode_buf(buf),
+            Codec::MessagePack => MessagePack.decode_buf(buf),
+        }
+    }
+}
+
+impl MessageCodec for Json {
+    fn encode<T: Serialize>(self, buf: &mut Vec<u8>, message: &T) -> Result<()> {
+        Ok(serde_json::to_writer(buf, message)?)
+    }
+
+    fn decode_stream<T: DeserializeOwned, R: Read>(self, reader: R) -> MessageStream<R, T> {
+        // each json value is read before being parsed as a message, so a value which
+        // isn't a message is skipped, while invalid json leaves no way to resync
+        MessageStream::new(
+            Frames::Json(Deserializer::from_reader(reader).into_iter()),
+            json_decode,
+        )
+    }
+
+    fn decode_buf<T: DeserializeOwned>(self, buf: &[u8]) -> Option<Result<(usize, Decoded<T>)>> {
+        let mut values = Deserializer::from_slice(buf).into_iter::<&RawValue>();
+        match values.next()? {
+            Ok(raw) => {
+                let decoded = json_decode(raw.get().as_bytes()).into();
+                Some(Ok((values.byte_offset(), decoded)))
+            }
+            Err(e) if e.is_eof() => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+impl MessageCodec for Bincode {
+    fn encode<T: Serialize>(self, buf: &mut Vec<u8>, message: &T) -> Result<()> {
+        encode_prefixed(buf, |buf| Ok(bincode::serialize_into(buf, message)?))
+    }
+
+    fn decode_stream<T: DeserializeOwned, R: Read>(self, reader: R) -> MessageStream<R, T> {
+        MessageStream::new(Frames::Prefixed(reader), bincode_decode)
+    }
+
+    fn decode_buf<T: DeserializeOwned>(self, buf: &[u8]) -> Option<Result<(usize, Decoded<T>)>> {
+        decode_prefixed(buf, bincode_decode)
+    }
+}
+
+impl MessageCodec for MessagePack {
+    // fields are written with their names, so missing ones can be defaulted
+    fn encode<T: Serialize>(self, buf: &mut Vec<u8>, message: &T) -> Result<()> {
+        encode_prefixed(buf, |buf| Ok(rmp_serde::encode::write_named(buf, message)?))
+    }
+
+    fn decode_stream<T: DeserializeOwned, R: Read>(self, reader: R) -> MessageStream<R, T> {
+        MessageStream::new(Frames::Prefixed(reader), msgpack_decode)
+    }
+
+    fn decode_buf<T: DeserializeOwned>(self, buf: &[u8]) -> Option<Result<(usize, Decoded<T>)>> {
+        decode_prefixed(buf, msgpack_decode)
+    }
+}
+
+fn json_decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    Ok(serde_json::from_slice(bytes)?)
+}
+
+fn bincode_decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    Ok(bincode::deserialize(bytes)?)
+}
+
+fn msgpack_decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    Ok(rmp_serde::from_slice(bytes)?)
+}
+
+/// append the message written by `write` to `buf`, after its u32 little-endian length
+fn encode_prefixed(
+    buf: &mut Vec<u8>,
+    write: impl FnOnce(&mut Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    write(buf)?;
+    let len = u32::try_from(buf.len() - start - 4).map_err(|_| KvsError::ValueTooLarge)?;
+    buf[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    Ok(())
+}
+
+fn decode_prefixed<T>(
+    buf: &[u8],
+    decode: fn(&[u8]) -> Result<T>,
+) -> Option<Result<(usize, Decoded<T>)>> {
+    let mut len = [0u8; 4];
+    len.copy_from_slice(buf.get(..4)?);
+    let len = u32::from_le_bytes(len) as usize;
+    let bytes = buf.get(4..4 + len)?;
+    Some(Ok((4 + len, decode(bytes).into())))
+}
+
+/// iterator over the messages of a connection, ends once it is closed between messages
+///
+/// an error ends the stream, with [`KvsError::StdIo`] if the connection failed,
+/// or with the codec's error if the rest of the stream can't be split into messages
+pub struct MessageStream<R: Read, T> {
+    frames: Frames<R>,
+    decode: fn(&[u8]) -> Result<T>,
+}
+
+enum Frames<R: Read> {
+    Json(StreamDeserializer<'static, IoRead<R>, Box<RawValue>>),
+    /// u32 little-endian length followed by the message
+    Prefixed(R),
+}
+
+impl<R: Read, T> MessageStream<R, T> {
+    fn new(frames: Frames<R>, decode: fn(&[u8]) -> Result<T>) -> Self {
+        MessageStream { frames, decode }
+    }
+}
+
+impl<R: Read, T: DeserializeOwned> Iterator for MessageStream<R, T> {
+    type Item = Result<Decoded<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let bytes = match &mut self.frames {
+            Frames::Json(values) => {
+                return Some(match values.next()? {
+                    Ok(raw) => Ok((self.decode)(raw.get().as_bytes()).into()),
+                    Err(e) if e.is_io() || e.is_eof() => Err(io::Error::from(e).into()),
+                    Err(e) => Err(e.into()),
+                })
+            }
+            Frames::Prefixed(reader) => match read_frame(reader) {
+                Ok(bytes) => bytes?,
+                Err(e) => return Some(Err(e.into())),
+            },
+        };
+        Some(Ok((self.decode)(&bytes).into()))
+    }
+}
+
+/// read the next length-prefixed message, `None` once the connection is closed
+/// between messages
+fn read_frame(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len) as u64;
+
+    // grown as bytes arrive, a bogus length can't allocate ahead of them
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(bytes))
+}
@@ -0,0 +1,75 @@
+/*!
+ * compression of values in generation files
+ */
+
+use std::io::{self, Read, Write};
+
+use flate2::{read::GzDecoder, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+const LZ4: u8 = 1;
+const GZIP: u8 = 2;
+
+/// compression of values in generation files, keys are never compressed
+///
+/// each compressed value starts with a flag byte naming its algorithm, so a log
+/// written under different settings is still readable. Compressed values are bytes,
+/// which [`Encoding::Json`](crate::Encoding::Json) stores as arrays of numbers,
+/// so compression pays off most with [`Encoding::Bincode`](crate::Encoding::Bincode)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// store values as they are
+    #[default]
+    None,
+    /// lz4, fast with a moderate ratio
+    Lz4,
+    /// gzip, slower with a better ratio
+    Gzip,
+}
+
+impl Compression {
+    /// flag byte followed by compressed `value`,
+    /// `None` if compression is off or doesn't make `value` smaller
+    pub(crate) fn compress(self, value: &str) -> Result<Option<Vec<u8>>> {
+        let compressed = match self {
+            Compression::None => return Ok(None),
+            Compression::Lz4 => {
+                let mut compressed = vec![LZ4];
+                compressed.extend_from_slice(&lz4_flex::compress_prepend_size(value.as_bytes()));
+                compressed
+            }
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(vec![GZIP], flate2::Compression::default());
+                encoder.write_all(value.as_bytes())?;
+                encoder.finish()?
+            }
+        };
+
+        Ok((compressed.len() < value.len()).then_some(compressed))
+    }
+
+    /// decompress bytes produced by [`Compression::compress`]
+    pub(crate) fn decompress(bytes: &[u8]) -> Result<String> {
+        let value = match bytes.split_first() {
+            Some((&LZ4, compressed)) => lz4_flex::decompress_size_prepended(compressed)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Some((&GZIP, compressed)) => {
+                let mut value = Vec::new();
+                GzDecoder::new(compressed).read_to_end(&mut value)?;
+                value
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown compression of value",
+                )
+                .into())
+            }
+        };
+
+        Ok(String::from_utf8(value)?)
+    }
+}
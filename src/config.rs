@@ -0,0 +1,242 @@
+/*!
+ * configuration of a kvs server
+ */
+
+use std::{
+    fmt::{self, Display},
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "sled-engine")]
+use crate::SledKvsEngine;
+use crate::{
+    serve_prometheus, tls, AnyEngine, DurabilityMode, KvStore, KvStoreOptions, KvsError,
+    MemKvsEngine, Result, ServerAddr, ServerMetrics,
+};
+
+/// engine storing the pairs of a server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Engine {
+    /// log-structured store of this crate
+    #[default]
+    Kvs,
+    /// sled database, with the `sled-engine` feature
+    #[cfg(feature = "sled-engine")]
+    Sled,
+    /// in memory, nothing is written to the data dir
+    Mem,
+}
+
+impl Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Engine::Kvs => write!(f, "kvs"),
+            #[cfg(feature = "sled-engine")]
+            Engine::Sled => write!(f, "sled"),
+            Engine::Mem => write!(f, "mem"),
+        }
+    }
+}
+
+/// thread pool handling the connections of a server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pool {
+    /// a new thread per connection
+    Naive,
+    /// fixed threads sharing a queue of connections
+    #[default]
+    Shared,
+    /// rayon thread pool
+    Rayon,
+}
+
+impl Display for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pool::Naive => write!(f, "naive"),
+            Pool::Shared => write!(f, "shared"),
+            Pool::Rayon => write!(f, "rayon"),
+        }
+    }
+}
+
+/// settings of a server, read from a toml file where missing keys keep their defaults
+/// ```toml
+/// addr = "127.0.0.1:4000"
+/// engine = "kvs"
+///
+/// [store]
+/// durability = "fsync"
+/// compaction_policy = { bytes = 1048576 }
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// address to listen on, default is `127.0.0.1:4000`
+    pub addr: ServerAddr,
+    /// pending tcp connections the listener holds, `None` for the default of std, 128
+    pub backlog: Option<u32>,
+    /// engine storing pairs, default is [`Engine::Kvs`]
+    pub engine: Engine,
+    /// thread pool of the blocking server, default is [`Pool::Shared`]
+    pub pool: Pool,
+    /// number of threads in the pool, `None` for the number of cpus
+    pub threads: Option<u32>,
+    /// directory of the engine's files, default is the working directory
+    pub data_dir: PathBuf,
+    /// pem certificate chain presented to clients over tls, set along with `tls_key`,
+    /// kvs-server-async refuses to start with it
+    pub tls_cert: Option<PathBuf>,
+    /// pem private key of `tls_cert`
+    pub tls_key: Option<PathBuf>,
+    /// serve prometheus metrics over http at `/metrics` on this address, `None` for
+    /// no endpoint, see [`serve_prometheus`](crate::serve_prometheus)
+    pub metrics_addr: Option<SocketAddr>,
+    /// options of [`KvStore`](crate::KvStore), the durability applies to sled as well
+    pub store: KvStoreOptions,
+    /// answer writes with [`ErrorCode::ReadOnly`](crate::ErrorCode::ReadOnly), and open
+    /// a kvs engine with [`KvStore::open_read_only`], kvs-server-async refuses to start
+    /// with it
+    pub read_only: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4000)),
+            backlog: None,
+            engine: Engine::default(),
+            pool: Pool::default(),
+            threads: None,
+            data_dir: PathBuf::from("."),
+            tls_cert: None,
+            tls_key: None,
+            metrics_addr: None,
+            store: KvStoreOptions::default(),
+            read_only: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// read a config from the toml file at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// fails if `read_only` is set for sled, which has no read-only mode and would write
+    /// the data dir
+    pub fn check_read_only(&self) -> Result<()> {
+        #[cfg(feature = "sled-engine")]
+        if self.read_only && self.engine == Engine::Sled {
+            return Err(KvsError::Unsupported("read-only mode"));
+        }
+        Ok(())
+    }
+
+    /// fails with [`KvsError::UnmatchedEngine`] if the data dir was written by another
+    /// engine, or records the configured engine in it, unless it is read-only
+    ///
+    /// the in-memory engine leaves the data dir to any engine
+    pub fn check_engine(&self) -> Result<()> {
+        if self.engine == Engine::Mem {
+            return Ok(());
+        }
+        let config_file = self.data_dir.join("engine");
+
+        if !config_file.try_exists()? {
+            if !self.read_only {
+                // the engine creates the data dir only once it is opened, after this check
+                fs::create_dir_all(&self.data_dir)?;
+                fs::write(config_file, format!("{}", self.engine))?;
+            }
+            return Ok(());
+        }
+
+        // a file edited by hand may end with a newline
+        let contents = fs::read_to_string(config_file)?;
+        let engine = match contents.trim() {
+            "kvs" => Engine::Kvs,
+            #[cfg(feature = "sled-engine")]
+            "sled" => Engine::Sled,
+            // written by a build with the sled engine
+            #[cfg(not(feature = "sled-engine"))]
+            "sled" => return Err(KvsError::UnmatchedEngine),
+            _ => return Err(KvsError::InvalidEngineConfig(contents)),
+        };
+        if engine != self.engine {
+            return Err(KvsError::UnmatchedEngine);
+        }
+        Ok(())
+    }
+
+    /// open the configured engine in the data dir
+    pub fn open_engine(&self) -> Result<AnyEngine> {
+        self.check_read_only()?;
+        Ok(match self.engine {
+            Engine::Kvs => AnyEngine::new(self.open_store()?),
+            #[cfg(feature = "sled-engine")]
+            Engine::Sled => AnyEngine::new(
+                SledKvsEngine::new(sled::open(&self.data_dir)?).durability(self.durability()),
+            ),
+            Engine::Mem => AnyEngine::new(MemKvsEngine::new()),
+        })
+    }
+
+    /// open the kvs engine in the data dir, read-only if set, whatever the configured engine
+    pub fn open_store(&self) -> Result<KvStore> {
+        if self.read_only {
+            KvStore::open_read_only(&self.data_dir, self.store.clone())
+        } else {
+            KvStore::open_with_options(&self.data_dir, self.store.clone())
+        }
+    }
+
+    /// serve `metrics`, and the stats of `store` for the kvs engine, at `metrics_addr`
+    /// on a new thread, nothing is served without an address
+    pub fn spawn_metrics(&self, metrics: Arc<ServerMetrics>, store: Option<KvStore>) -> Result<()> {
+        let Some(addr) = self.metrics_addr else {
+            return Ok(());
+        };
+        let listener = TcpListener::bind(addr)?;
+        log::info!(
+            "serving metrics on http://{}/metrics",
+            listener.local_addr()?
+        );
+        thread::spawn(move || {
+            if let Err(e) = serve_prometheus(listener, metrics, store) {
+                log::error!("metrics endpoint failed: {e}");
+            }
+        });
+        Ok(())
+    }
+
+    /// tls config of the server, `None` to serve plain tcp when no certificate is set
+    pub fn tls(&self) -> Result<Option<Arc<rustls::ServerConfig>>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (None, None) => Ok(None),
+            #[cfg(unix)]
+            (Some(_), Some(_)) if matches!(self.addr, ServerAddr::Unix(_)) => {
+                Err(tls::unix_error())
+            }
+            (Some(cert), Some(key)) => Ok(Some(tls::server_config(cert, key)?)),
+            _ => Err(KvsError::TlsConfig(
+                "tls_cert and tls_key should be set together".to_owned(),
+            )),
+        }
+    }
+
+    /// durability of writes, set in the store options and applied to any engine
+    pub fn durability(&self) -> DurabilityMode {
+        self.store.durability
+    }
+}
@@ -0,0 +1,192 @@
+/*!
+ * on-disk encoding of generation files
+ */
+
+use std::{
+    convert::TryFrom,
+    io::{self, Read},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{de::IoRead, Deserializer, StreamDeserializer};
+
+use crate::{KvsError, Result};
+
+/// encoding of records in generation files
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// concatenated json values, stored in `{generation}.json`
+    #[default]
+    Json,
+    /// u32 little-endian length followed by bincode bytes, stored in `{generation}.bin`
+    Bincode,
+}
+
+impl Encoding {
+    /// file extension of generation files in this encoding
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Bincode => "bin",
+        }
+    }
+
+    /// append an encoded record to `buf`, returns where its value lies in the encoded
+    /// record if the value is stored as plain bytes, which json escapes
+    ///
+    /// fails with [`KvsError::ValueTooLarge`] if a bincode record doesn't fit its u32 length
+    pub(crate) fn encode<T: Record>(
+        &self,
+        buf: &mut Vec<u8>,
+        record: &T,
+    ) -> Result<Option<ValueSpan>> {
+        match self {
+            Encoding::Json => {
+                serde_json::to_writer(buf, record)?;
+                Ok(None)
+            }
+            Encoding::Bincode => {
+                let len = u32::try_from(bincode::serialized_size(record)?)
+                    .map_err(|_| KvsError::ValueTooLarge)?;
+                buf.extend_from_slice(&len.to_le_bytes());
+                bincode::serialize_into(buf, record)?;
+                Ok(self.value_span(record))
+            }
+        }
+    }
+
+    /// where the value of `record` lies once encoded, as returned by [`Encoding::encode`],
+    /// without encoding it
+    pub(crate) fn value_span<T: Record>(&self, record: &T) -> Option<ValueSpan> {
+        match self {
+            Encoding::Json => None,
+            Encoding::Bincode => {
+                let (key, value) = record.key_value()?;
+                // the record length, the variant tag, then the key and the value,
+                // each after its u64 length
+                let offset = 4 + 4 + 8 + key.len() as u64 + 8;
+                // a span which doesn't fit is left out, the value is decoded instead
+                Some(ValueSpan {
+                    offset: u32::try_from(offset).ok()?,
+                    len: u32::try_from(value.len()).ok()?,
+                })
+            }
+        }
+    }
+
+    /// decode the record making up `bytes`, borrowing from them where the record allows
+    pub(crate) fn decode<'a, T: Deserialize<'a>>(&self, bytes: &'a [u8]) -> Result<T> {
+        Ok(match self {
+            Encoding::Json => serde_json::from_slice(bytes)?,
+            // skip the length in front of the record
+            Encoding::Bincode => bincode::deserialize(
+                bytes
+                    .get(4..)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?,
+            )?,
+        })
+    }
+
+    /// decode a stream of records from `reader`, a bincode record longer than `max_len`
+    /// fails to decode
+    pub(crate) fn decode_stream<T: DeserializeOwned, R: Read>(
+        &self,
+        reader: R,
+        max_len: u64,
+    ) -> DecodeStream<R, T> {
+        match self {
+            Encoding::Json => {
+                DecodeStream::Json(Deserializer::from_reader(reader).into_iter::<T>())
+            }
+            Encoding::Bincode => DecodeStream::Bincode {
+                reader,
+                offset: 0,
+                max_len,
+            },
+        }
+    }
+}
+
+/// a record of generation files, which may carry a value
+pub(crate) trait Record: Serialize {
+    /// the key and the value of the record, if it holds a value as a string and is
+    /// serialized as a variant whose first fields are the key and the value
+    fn key_value(&self) -> Option<(&[u8], &str)> {
+        None
+    }
+}
+
+/// offset from the start of an encoded record and length of its value
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ValueSpan {
+    pub(crate) offset: u32,
+    pub(crate) len: u32,
+}
+
+/// iterator over records of a generation file, yields the start offset with each record
+pub(crate) enum DecodeStream<R: Read, T> {
+    Json(StreamDeserializer<'static, IoRead<R>, T>),
+    Bincode {
+        reader: R,
+        offset: u64,
+        max_len: u64,
+    },
+}
+
+impl<R: Read, T: DeserializeOwned> DecodeStream<R, T> {
+    /// offset just after the last decoded record
+    pub(crate) fn byte_offset(&self) -> u64 {
+        match self {
+            DecodeStream::Json(iter) => iter.byte_offset() as u64,
+            DecodeStream::Bincode { offset, .. } => *offset,
+        }
+    }
+}
+
+impl<R: Read, T: DeserializeOwned> Iterator for DecodeStream<R, T> {
+    type Item = Result<(u64, T)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.byte_offset();
+        match self {
+            DecodeStream::Json(iter) => iter.next().map(|record| Ok((start, record?))),
+            DecodeStream::Bincode {
+                reader,
+                offset,
+                max_len,
+            } => {
+                let mut len = [0u8; 4];
+                match reader.read_exact(&mut len) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+                    Err(e) => return Some(Err(e.into())),
+                }
+                let len = u32::from_le_bytes(len) as u64;
+                if len > *max_len {
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("record of {} bytes is longer than {} bytes", len, max_len),
+                    )
+                    .into()));
+                }
+
+                // grown as bytes arrive, a torn length can't allocate ahead of them
+                let mut bytes = Vec::new();
+                if let Err(e) = reader.take(len).read_to_end(&mut bytes) {
+                    return Some(Err(e.into()));
+                }
+                if (bytes.len() as u64) < len {
+                    return Some(Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()));
+                }
+                *offset += 4 + len;
+
+                Some(
+                    bincode::deserialize(&bytes)
+                        .map(|record| (start, record))
+                        .map_err(Into::into),
+                )
+            }
+        }
+    }
+}
@@ -2,9 +2,13 @@
  * engine trait
  */
 
-use crate::Result;
+use crate::{KvsError, Result};
 
 /// kv engine trait
+///
+/// clones share the same pairs and are used from many threads. Engines may serialize
+/// writes, but `get` and `get_many` must not wait on them, so servers answer reads
+/// concurrently beside a stream of writes
 pub trait KvsEngine: Clone + Send + 'static {
     /// set a key-value pair
     fn set(&self, key: String, value: String) -> Result<()>;
@@ -12,4 +16,283 @@ pub trait KvsEngine: Clone + Send + 'static {
     fn get(&self, key: String) -> Result<Option<String>>;
     /// remove a key
     fn remove(&self, key: String) -> Result<()>;
+    /// set a key-value pair only if the key is absent, returns whether it was set
+    ///
+    /// fails with [`KvsError::Unsupported`] by default, as a get followed by a set
+    /// isn't atomic
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        let _ = (key, value);
+        Err(KvsError::Unsupported("set_if_absent"))
+    }
+    /// persist all writes made so far to disk, whatever durability the engine is
+    /// configured with
+    ///
+    /// fails with [`KvsError::Unsupported`] by default
+    fn flush(&self) -> Result<()> {
+        Err(KvsError::Unsupported("flush"))
+    }
+    /// atomically add `delta` to the integer value of a key, an absent key counts as 0,
+    /// returns the new value
+    ///
+    /// fails with [`KvsError::NotAnInteger`] if the value isn't an integer, and with
+    /// [`KvsError::Unsupported`] by default
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let _ = (key, delta);
+        Err(KvsError::Unsupported("incr"))
+    }
+    /// atomically append `suffix` to the value of a key, an absent key is set to `suffix`
+    ///
+    /// fails with [`KvsError::Unsupported`] by default
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        let _ = (key, suffix);
+        Err(KvsError::Unsupported("append"))
+    }
+    /// get values for several keys, in the order of `keys`
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+    /// version stamp of the last write of a key, `None` if the key is absent, see
+    /// [`KvStore::version`](crate::KvStore::version)
+    ///
+    /// fails with [`KvsError::Unsupported`] by default
+    fn version(&self, key: String) -> Result<Option<u64>> {
+        let _ = key;
+        Err(KvsError::Unsupported("version"))
+    }
+    /// set a key-value pair only if the version of the key is still `expected_version`,
+    /// returns the new version
+    ///
+    /// fails with [`KvsError::VersionMismatch`] if the key was written since, with
+    /// [`KvsError::KeyNotFound`] if it is absent, and with [`KvsError::Unsupported`]
+    /// by default
+    fn set_if_version(&self, key: String, value: String, expected_version: u64) -> Result<u64> {
+        let _ = (key, value, expected_version);
+        Err(KvsError::Unsupported("set_if_version"))
+    }
+    /// get the value for a key, or set it to the value computed by `f` if absent,
+    /// returns the resulting value
+    ///
+    /// the default implementation isn't atomic, `f` may run in several threads
+    /// racing on the same key and the last value set wins
+    fn get_or_insert_with<F: FnOnce() -> String>(&self, key: String, f: F) -> Result<String> {
+        if let Some(value) = self.get(key.clone())? {
+            return Ok(value);
+        }
+        let value = f();
+        self.set(key, value.clone())?;
+        Ok(value)
+    }
+}
+
+/// engine whose keys are any bytes, for binary or composite keys which aren't utf-8
+///
+/// a string key of [`KvsEngine`] is the same key as its utf-8 bytes, so pairs written
+/// through either trait are read through the other, while methods listing string keys
+/// skip keys which aren't utf-8
+pub trait BytesKvsEngine: Clone + Send + 'static {
+    /// set a key-value pair
+    fn set_bytes(&self, key: Vec<u8>, value: String) -> Result<()>;
+    /// get value for a key
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<String>>;
+    /// remove a key
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+}
+
+/// object-safe form of [`KvsEngine`], implemented by every engine,
+/// so engines can be picked at runtime and held as `Box<dyn DynKvsEngine>`
+///
+/// calls through [`AnyEngine`] are simpler, as it implements [`KvsEngine`]. Methods of
+/// `dyn DynKvsEngine` are callable without importing this trait, importing it along with
+/// [`KvsEngine`] makes calls on engines ambiguous
+pub trait DynKvsEngine: Send {
+    /// see [`KvsEngine::set`]
+    fn set(&self, key: String, value: String) -> Result<()>;
+    /// see [`KvsEngine::get`]
+    fn get(&self, key: String) -> Result<Option<String>>;
+    /// see [`KvsEngine::remove`]
+    fn remove(&self, key: String) -> Result<()>;
+    /// see [`KvsEngine::set_if_absent`]
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool>;
+    /// see [`KvsEngine::flush`]
+    fn flush(&self) -> Result<()>;
+    /// see [`KvsEngine::incr`]
+    fn incr(&self, key: String, delta: i64) -> Result<i64>;
+    /// see [`KvsEngine::append`]
+    fn append(&self, key: String, suffix: String) -> Result<()>;
+    /// see [`KvsEngine::get_many`]
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>>;
+    /// see [`KvsEngine::version`]
+    fn version(&self, key: String) -> Result<Option<u64>>;
+    /// see [`KvsEngine::set_if_version`]
+    fn set_if_version(&self, key: String, value: String, expected_version: u64) -> Result<u64>;
+    /// see [`KvsEngine::get_or_insert_with`]
+    fn get_or_insert_with<'a>(
+        &self,
+        key: String,
+        f: Box<dyn FnOnce() -> String + 'a>,
+    ) -> Result<String>;
+    /// a clone sharing the same pairs
+    fn clone_box(&self) -> Box<dyn DynKvsEngine>;
+}
+
+impl<E: KvsEngine> DynKvsEngine for E {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvsEngine::set(self, key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvsEngine::get(self, key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        KvsEngine::remove(self, key)
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        KvsEngine::set_if_absent(self, key, value)
+    }
+
+    fn flush(&self) -> Result<()> {
+        KvsEngine::flush(self)
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        KvsEngine::incr(self, key, delta)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        KvsEngine::append(self, key, suffix)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        KvsEngine::get_many(self, keys)
+    }
+
+    fn version(&self, key: String) -> Result<Option<u64>> {
+        KvsEngine::version(self, key)
+    }
+
+    fn set_if_version(&self, key: String, value: String, expected_version: u64) -> Result<u64> {
+        KvsEngine::set_if_version(self, key, value, expected_version)
+    }
+
+    fn get_or_insert_with<'a>(
+        &self,
+        key: String,
+        f: Box<dyn FnOnce() -> String + 'a>,
+    ) -> Result<String> {
+        KvsEngine::get_or_insert_with(self, key, f)
+    }
+
+    fn clone_box(&self) -> Box<dyn DynKvsEngine> {
+        Box::new(self.clone())
+    }
+}
+
+/// any engine behind a box, which is itself a [`KvsEngine`],
+/// for code picking the engine at runtime instead of being generic over it
+/// ```rust
+/// use kvs::{AnyEngine, KvStore, KvsEngine, MemKvsEngine};
+/// let dir = tempfile::TempDir::new().unwrap();
+/// let engines = [
+///     AnyEngine::new(KvStore::open(dir.path()).unwrap()),
+///     AnyEngine::new(MemKvsEngine::new()),
+/// ];
+/// for engine in &engines {
+///     engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+///     assert_eq!(engine.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+/// }
+/// ```
+pub struct AnyEngine(Box<dyn DynKvsEngine>);
+
+impl AnyEngine {
+    /// box `engine`
+    pub fn new(engine: impl KvsEngine) -> Self {
+        Self(Box::new(engine))
+    }
+}
+
+impl From<Box<dyn DynKvsEngine>> for AnyEngine {
+    fn from(engine: Box<dyn DynKvsEngine>) -> Self {
+        Self(engine)
+    }
+}
+
+impl Clone for AnyEngine {
+    fn clone(&self) -> Self {
+        Self(self.0.clone_box())
+    }
+}
+
+impl KvsEngine for AnyEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.0.set_if_absent(key, value)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.0.incr(key, delta)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        self.0.append(key, suffix)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.0.get_many(keys)
+    }
+
+    fn version(&self, key: String) -> Result<Option<u64>> {
+        self.0.version(key)
+    }
+
+    fn set_if_version(&self, key: String, value: String, expected_version: u64) -> Result<u64> {
+        self.0.set_if_version(key, value, expected_version)
+    }
+
+    fn get_or_insert_with<F: FnOnce() -> String>(&self, key: String, f: F) -> Result<String> {
+        self.0.get_or_insert_with(key, Box::new(f))
+    }
+}
+
+/// a write applied by [`KvStore::write_batch`](crate::KvStore::write_batch)
+/// or [`SledKvsEngine::batch`](crate::SledKvsEngine::batch)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchOp {
+    /// set a key-value pair
+    Set {
+        /// key
+        key: String,
+        /// value
+        value: String,
+    },
+    /// remove a key, removing an absent key is not an error
+    Remove {
+        /// key
+        key: String,
+    },
+}
+
+/// `value` parsed as an integer plus `delta`, an absent value counts as 0
+pub(crate) fn incremented(value: Option<&str>, delta: i64) -> Result<i64> {
+    let current = match value {
+        Some(value) => value.parse::<i64>().map_err(|_| KvsError::NotAnInteger)?,
+        None => 0,
+    };
+    current.checked_add(delta).ok_or(KvsError::NotAnInteger)
 }
@@ -0,0 +1,184 @@
+/*!
+ * in-memory index from key to the position of its latest record
+ */
+
+use std::{
+    collections::HashMap,
+    ops::Bound,
+    sync::{Arc, RwLock},
+};
+
+use crossbeam::atomic::AtomicCell;
+use crossbeam_skiplist::SkipMap;
+use serde::{Deserialize, Serialize};
+
+/// data structure of the in-memory index
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexKind {
+    /// ordered lock-free skip list, keys are kept sorted
+    #[default]
+    SkipMap,
+    /// hash map behind a read-write lock, cheaper for point lookups
+    HashMap,
+}
+
+impl IndexKind {
+    pub(crate) fn build<V: Copy + Send + Sync + 'static>(self) -> Arc<dyn Index<V>> {
+        match self {
+            IndexKind::SkipMap => Arc::new(SkipMap::<Vec<u8>, AtomicCell<V>>::new()),
+            IndexKind::HashMap => Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+}
+
+/// operations of the index used by [`KvStore`](crate::KvStore), keys are bytes so they
+/// need not be utf-8
+///
+/// readers may look up keys at any time, while modifications come from one thread
+/// at a time, as they are made under the writer lock
+pub(crate) trait Index<V>: Send + Sync {
+    /// insert or overwrite, a key being overwritten stays visible to readers
+    fn insert(&self, key: Vec<u8>, value: V);
+    fn get(&self, key: &[u8]) -> Option<V>;
+    fn remove(&self, key: &[u8]) -> Option<V>;
+    fn contains_key(&self, key: &[u8]) -> bool;
+    fn len(&self) -> usize;
+    fn clear(&self);
+    /// call `f` with each entry without copying the index, `f` must not use the index
+    /// as the hash map is locked meanwhile
+    fn for_each(&self, f: &mut dyn FnMut(&[u8], V));
+    /// replace the value of each entry for which `f` returns a new one, readers see
+    /// either value meanwhile, `f` must not use the index
+    fn update_each(&self, f: &mut dyn FnMut(&[u8], V) -> Option<V>);
+    /// keys starting with `prefix`
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Vec<Vec<u8>>;
+    /// up to `limit` keys in order after `start_after`, or from the first key
+    fn keys_after(&self, start_after: Option<&[u8]>, limit: usize) -> Vec<Vec<u8>>;
+}
+
+/// replacing an entry of a skip list unlinks the old node before linking the new one,
+/// so a concurrent lookup may miss the key, values are overwritten in place instead
+impl<V: Copy + Send + Sync + 'static> Index<V> for SkipMap<Vec<u8>, AtomicCell<V>> {
+    fn insert(&self, key: Vec<u8>, value: V) {
+        match SkipMap::get(self, &key) {
+            Some(entry) => entry.value().store(value),
+            None => {
+                SkipMap::insert(self, key, AtomicCell::new(value));
+            }
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<V> {
+        SkipMap::get(self, key).map(|entry| entry.value().load())
+    }
+
+    fn remove(&self, key: &[u8]) -> Option<V> {
+        SkipMap::remove(self, key).map(|entry| entry.value().load())
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        SkipMap::contains_key(self, key)
+    }
+
+    fn len(&self) -> usize {
+        SkipMap::len(self)
+    }
+
+    fn clear(&self) {
+        SkipMap::clear(self);
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&[u8], V)) {
+        for entry in self.iter() {
+            f(entry.key(), entry.value().load());
+        }
+    }
+
+    fn update_each(&self, f: &mut dyn FnMut(&[u8], V) -> Option<V>) {
+        for entry in self.iter() {
+            if let Some(value) = f(entry.key(), entry.value().load()) {
+                entry.value().store(value);
+            }
+        }
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
+        // keys are sorted, so the matching keys follow the prefix itself
+        self.range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            .map(|entry| entry.key().clone())
+            .take_while(|key| key.starts_with(prefix))
+            .collect()
+    }
+
+    fn keys_after(&self, start_after: Option<&[u8]>, limit: usize) -> Vec<Vec<u8>> {
+        let start = start_after.map_or(Bound::Unbounded, Bound::Excluded);
+        self.range::<[u8], _>((start, Bound::Unbounded))
+            .take(limit)
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+}
+
+impl<V: Copy + Send + Sync> Index<V> for RwLock<HashMap<Vec<u8>, V>> {
+    fn insert(&self, key: Vec<u8>, value: V) {
+        self.write().unwrap().insert(key, value);
+    }
+
+    fn get(&self, key: &[u8]) -> Option<V> {
+        self.read().unwrap().get(key).copied()
+    }
+
+    fn remove(&self, key: &[u8]) -> Option<V> {
+        self.write().unwrap().remove(key)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.read().unwrap().contains_key(key)
+    }
+
+    fn len(&self) -> usize {
+        self.read().unwrap().len()
+    }
+
+    fn clear(&self) {
+        self.write().unwrap().clear();
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&[u8], V)) {
+        for (key, value) in self.read().unwrap().iter() {
+            f(key, *value);
+        }
+    }
+
+    fn update_each(&self, f: &mut dyn FnMut(&[u8], V) -> Option<V>) {
+        for (key, value) in self.write().unwrap().iter_mut() {
+            if let Some(new) = f(key, *value) {
+                *value = new;
+            }
+        }
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
+        self.read()
+            .unwrap()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// keys are unordered, so every call sorts the keys after `start_after`
+    fn keys_after(&self, start_after: Option<&[u8]>, limit: usize) -> Vec<Vec<u8>> {
+        let mut keys: Vec<Vec<u8>> = self
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|key| start_after.is_none_or(|start| key.as_slice() > start))
+            .cloned()
+            .collect();
+        keys.sort_unstable();
+        keys.truncate(limit);
+        keys
+    }
+}
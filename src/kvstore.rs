@@ -2,25 +2,81 @@
  * kvstore: key-value store
 */
 
-use crate::{KvsEngine, KvsError, Result};
-use crossbeam_skiplist::SkipMap;
+use crate::{
+    bloom::BloomFilter,
+    encoding::{DecodeStream, Record, ValueSpan},
+    engine,
+    index::Index,
+    layout,
+    options::{ProgressHook, SizeLimits},
+    ttl,
+    value_cache::ValueCache,
+    BatchOp, BytesKvsEngine, CompactionPolicy, Compression, DurabilityMode, Encoding, FlatLayout,
+    KvStoreOptions, KvsEngine, KvsError, LayoutContext, LayoutStrategy, Result, Txn,
+};
+use crossbeam::channel::{self, Receiver, Sender};
+use key_bytes::SkippedKey;
+use memmap2::Mmap;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
 use std::{
-    collections::HashSet,
-    ffi::OsStr,
+    borrow::Cow,
+    cell::RefCell,
+    cmp,
+    collections::{btree_map::Entry, BTreeMap, HashMap, HashSet},
+    fmt,
     fs::{self, File},
-    io::{self, BufReader, BufWriter, Seek, Write},
+    io::{self, BufReader, BufWriter, Read, Seek, Write},
+    iter, mem,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, Weak,
+    },
+    thread,
+    time::Duration,
 };
 
-const COMPACTION_THRESHOLD: u64 = 4 * 1024 * 1024;
+/// garbage below this size is never compacted by [`CompactionPolicy::GarbageRatio`]
+const MIN_COMPACTION_GARBAGE: u64 = 1024 * 1024;
+
+/// capacity of buffers of generation files unless set in [`KvStoreOptions`],
+/// the default capacity of std's buffers
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// bytes processed between calls of the hook of
+/// [`KvStoreOptions::progress`](crate::KvStoreOptions::progress)
+const PROGRESS_STEP: u64 = 1024 * 1024;
+
+/// records read at once by parallel compaction are buffered up to about this many bytes
+const PARALLEL_COMPACTION_BATCH: u64 = 8 * 1024 * 1024;
+
+/// stores open for writing in this process by directory and namespace, so opening
+/// a store again returns a clone of it rather than a second writer
+static OPEN_STORES: Mutex<BTreeMap<StoreKey, OpenStore>> = Mutex::new(BTreeMap::new());
+
+/// notified whenever a store of `OPEN_STORES` is done loading
+static STORE_LOADED: Condvar = Condvar::new();
+
+/// canonical directory and namespace of a store
+type StoreKey = (PathBuf, Option<String>);
+
+/// version of the format of generation files, recorded in the manifest of a store
+/// and bumped whenever records written by this build can't be read by older builds
+///
+/// version 2 adds the records enclosing a transaction
+/// version 3 adds the records setting the version stamp of the next record
+pub const FORMAT_VERSION: u32 = 3;
 
 /// key-value store, both key and value are [`String`]
+///
+/// writes are serialized by a writer lock, while reads look up the lock-free index and
+/// read with a file handle of their own, so they never wait on writes. The exception is
+/// [`DurabilityMode::None`], where a read may flush records still buffered by the writer
 /// ```rust
 /// use kvs::{KvStore, Result, KvsEngine};
-/// let mut store = KvStore::open(".").unwrap();
+/// let dir = tempfile::TempDir::new().unwrap();
+/// let mut store = KvStore::open(dir.path()).unwrap();
 /// assert!(store.set("key1".to_owned(), "value1".to_owned()).is_ok());
 /// assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
 /// assert!(store.remove("key1".to_owned()).is_ok());
@@ -28,295 +84,3287 @@ const COMPACTION_THRESHOLD: u64 = 4 * 1024 * 1024;
 /// ```
 #[derive(Clone)]
 pub struct KvStore {
-    kv: Arc<SkipMap<String, CommandOffset>>,
+    kv: Arc<dyn Index<CommandOffset>>,
+    bloom: Option<Arc<BloomFilter>>,
+    durability: DurabilityMode,
     reader: KvStoreReader,
     writer: Arc<Mutex<KvStoreWriter>>,
+    group_commit: Option<Arc<GroupCommit>>,
+    options: KvStoreOptions,
 }
 
-#[derive(Clone)]
-struct KvStoreReader {
-    dir_path: Arc<PathBuf>,
+/// pairs of a page of [`KvStore::scan_paginated`], and the cursor of the next page
+pub type ScanPage = (Vec<(String, String)>, Option<String>);
+
+/// runtime statistics of a [`KvStore`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KvStoreStats {
+    /// number of keys in the index, expired keys not yet removed are included
+    pub live_keys: usize,
+    /// total bytes of generation files
+    pub disk_bytes: u64,
+    /// bytes written since the last compaction
+    pub uncompaction_size: u64,
+    /// bytes of records overwritten or removed, reclaimed by compaction
+    pub garbage_size: u64,
+    /// generation of the file being written
+    pub writer_generation: u64,
+    /// number of compactions since the store was opened
+    pub compaction_count: u64,
 }
 
-struct KvStoreWriter {
-    kv: Arc<SkipMap<String, CommandOffset>>,
-    writer: BufWriter<File>,
-    writer_offset: CommandOffset,
-    uncompaction_size: u64,
-    dir_path: Arc<PathBuf>,
+/// what a compaction of a [`KvStore`] did
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionReport {
+    /// number of keys copied to the compacted generation
+    pub live_keys: usize,
+    /// bytes of generation files before compaction
+    pub bytes_before: u64,
+    /// bytes of the compacted generation
+    pub bytes_after: u64,
+    /// number of generation files deleted
+    pub generations_removed: usize,
 }
 
-#[derive(Clone, Copy)]
-struct CommandOffset {
-    generation: u64,
-    offset: u64,
+/// what a compaction of a [`KvStore`] would do, from [`KvStore::compaction_estimate`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionEstimate {
+    /// number of keys which would be copied to the compacted generation
+    pub live_keys: usize,
+    /// bytes of generation files
+    pub bytes_before: u64,
+    /// bytes of the records of live keys and of the version records between them, which
+    /// would make up the compacted generation
+    pub live_bytes: u64,
+    /// bytes compaction would reclaim
+    pub reclaimable_bytes: u64,
+    /// number of generation files which would be deleted
+    pub generations: usize,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+/// result of [`KvStore::verify`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyReport {
+    /// number of generation files
+    pub generations: usize,
+    /// number of keys with a readable value
+    pub live_keys: usize,
+    /// records which can't be decoded, and live values which can't be read
+    pub corrupt_records: Vec<CorruptRecord>,
+    /// generations with records, none of which is live
+    pub orphaned_generations: Vec<u64>,
+    /// bytes of records which are no longer live, or follow a corrupt record
+    pub unreachable_bytes: u64,
 }
 
-impl KvStoreReader {
-    fn new(dir_path: Arc<PathBuf>) -> Self {
-        Self { dir_path }
+impl VerifyReport {
+    /// `true` if no record is corrupt
+    pub fn is_clean(&self) -> bool {
+        self.corrupt_records.is_empty()
     }
+}
 
-    fn get(&self, command_offset: CommandOffset) -> Result<String> {
-        let command_path =
-            convert_command_generation_path(&self.dir_path, command_offset.generation);
-
-        let mut reader: BufReader<File> =
-            BufReader::new(File::options().read(true).open(command_path)?);
-        reader.seek(io::SeekFrom::Start(command_offset.offset))?;
-
-        let mut command_iter = Deserializer::from_reader(reader).into_iter::<Command>();
-        Ok(match command_iter.next() {
-            Some(command) => match command? {
-                Command::Set { key: _, value } => value,
-                _ => unreachable!("should not be other command kinds"),
-            },
-            None => unreachable!("should not be None"),
-        })
-    }
+/// a corrupt record found by [`KvStore::verify`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorruptRecord {
+    /// generation of the record
+    pub generation: u64,
+    /// offset of the record in its generation file
+    pub offset: u64,
+    /// key of a record which can be decoded but whose value can't be read,
+    /// `None` if the record can't be decoded, the rest of its file is unreadable then
+    ///
+    /// a key which isn't utf-8 is converted lossily, see [`CorruptRecord::key_bytes`]
+    pub key: Option<String>,
+    #[serde(default)]
+    key_bytes: Option<Vec<u8>>,
 }
 
-impl KvStoreWriter {
-    fn new(
-        kv: Arc<SkipMap<String, CommandOffset>>,
-        dir_path: Arc<PathBuf>,
-        writer_generation: u64,
-        uncompaction_size: u64,
-    ) -> Result<Self> {
-        Ok(Self {
-            kv,
-            writer: Self::create_command_file(&dir_path, writer_generation)?,
-            writer_offset: CommandOffset {
-                generation: writer_generation,
-                offset: 0,
-            },
-            uncompaction_size,
-            dir_path,
-        })
+impl CorruptRecord {
+    fn new(generation: u64, offset: u64, key: Option<Vec<u8>>) -> Self {
+        Self {
+            generation,
+            offset,
+            key: key
+                .as_deref()
+                .map(|key| String::from_utf8_lossy(key).into_owned()),
+            key_bytes: key,
+        }
     }
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let mut json = Vec::new();
-        let command = Command::Set { key, value };
-        serde_json::to_writer(&mut json, &command)?;
+    /// the key of the record as written, see [`CorruptRecord::key`]
+    pub fn key_bytes(&self) -> Option<&[u8]> {
+        self.key_bytes.as_deref()
+    }
+}
 
-        self.writer.write_all(&json)?;
-        self.writer.flush()?;
+/// a write of a key found by [`KvStore::history`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HistoryEntry {
+    /// the key was set to `value`
+    Set {
+        /// value
+        value: String,
+        /// absolute expiry timestamp in milliseconds, `None` never expires
+        expire_at: Option<u64>,
+    },
+    /// the key was removed
+    Remove,
+}
 
-        let key = match command {
-            Command::Set { key, .. } => key,
-            _ => unreachable!(),
-        };
-        self.kv.insert(key, self.writer_offset);
-        self.writer_offset.offset += json.len() as u64;
+/// generation and offset in its file of a point in the log of a [`KvStore`],
+/// see [`KvStore::read_from`]
+pub type LogPosition = (u64, u64);
 
-        self.uncompaction_size += json.len() as u64;
-        if self.uncompaction_size >= COMPACTION_THRESHOLD {
-            self.compaction()?;
-        }
+/// a write read back from the log by [`KvStore::read_from`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogRecord {
+    /// `key` was set to `value`
+    Set {
+        /// key
+        key: Vec<u8>,
+        /// value, decompressed if stored compressed
+        value: String,
+        /// absolute expiry timestamp in milliseconds, `None` never expires
+        expire_at: Option<u64>,
+    },
+    /// `key` was removed
+    Remove {
+        /// key
+        key: Vec<u8>,
+    },
+}
 
-        Ok(())
+/// a change of a key watched with [`KvStore::watch`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// the key was set to `value`
+    Set {
+        /// the new value
+        value: String,
+    },
+    /// the key was removed, or cleared with the store
+    Remove,
+}
+
+/// entry of `OPEN_STORES`
+enum OpenStore {
+    /// reserved by a thread loading the store, others wait for it on `STORE_LOADED`
+    Loading,
+    Open(WeakStore),
+}
+
+/// reservation of a store in `OPEN_STORES` while it is loaded without holding the lock,
+/// withdrawn unless a store is published in its place, so waiters never hang
+struct LoadingStore {
+    key: Option<StoreKey>,
+}
+
+impl LoadingStore {
+    fn publish(mut self, store: &KvStore) {
+        let key = self.key.take().unwrap();
+        OPEN_STORES.lock().unwrap().insert(
+            key,
+            OpenStore::Open(WeakStore {
+                writer: Arc::downgrade(&store.writer),
+                group_commit: store.group_commit.as_ref().map(Arc::downgrade),
+                options: store.options.clone(),
+            }),
+        );
+        STORE_LOADED.notify_all();
     }
+}
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if !self.kv.contains_key(&key) {
-            return Err(KvsError::KeyNotFound);
+impl Drop for LoadingStore {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            // don't panic again if the lock was poisoned while loading
+            if let Ok(mut open_stores) = OPEN_STORES.lock() {
+                open_stores.remove(&key);
+            }
+            STORE_LOADED.notify_all();
         }
+    }
+}
 
-        let mut json = Vec::new();
-        let command = Command::Remove { key };
-        serde_json::to_writer(&mut json, &command)?;
+/// entry of `OPEN_STORES`, dead once every clone of the store is dropped
+struct WeakStore {
+    writer: Weak<Mutex<KvStoreWriter>>,
+    group_commit: Option<Weak<GroupCommit>>,
+    options: KvStoreOptions,
+}
 
-        self.writer.write_all(&json)?;
-        self.writer_offset.offset += json.len() as u64;
-        self.writer.flush()?;
+impl WeakStore {
+    /// a new clone of the store, with its own file handles
+    fn upgrade(&self) -> Option<KvStore> {
+        let writer = self.writer.upgrade()?;
+        let (kv, bloom, reader) = {
+            let writer = writer.lock().unwrap();
+            let reader = KvStoreReader::new(
+                writer.files.clone(),
+                writer.use_mmap,
+                writer.safe_generation.clone(),
+                writer.values.clone(),
+            );
+            (writer.kv.clone(), writer.bloom.clone(), reader)
+        };
+        Some(KvStore {
+            kv,
+            bloom,
+            durability: self.options.durability,
+            reader,
+            writer,
+            group_commit: self.group_commit.as_ref().and_then(Weak::upgrade),
+            options: self.options.clone(),
+        })
+    }
+}
 
-        let key = match command {
-            Command::Remove { key } => key,
-            _ => unreachable!(),
+/// reader of a [`KvStore`] which doesn't keep the store open, from [`KvStore::weak_reader`]
+///
+/// each clone owns its file handles, as a clone of the store does
+#[derive(Clone)]
+pub struct WeakReader {
+    kv: Weak<dyn Index<CommandOffset>>,
+    bloom: Option<Weak<BloomFilter>>,
+    writer: Weak<Mutex<KvStoreWriter>>,
+    durability: DurabilityMode,
+    reader: KvStoreReader,
+}
+
+impl WeakReader {
+    /// get value for a key, fails with [`KvsError::StoreClosed`] once every handle of the
+    /// store is dropped
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let (kv, writer) = match (self.kv.upgrade(), self.writer.upgrade()) {
+            (Some(kv), Some(writer)) => (kv, writer),
+            _ => {
+                // generations may be deleted by a later store in the same dir
+                self.reader.readers.borrow_mut().clear();
+                return Err(KvsError::StoreClosed);
+            }
         };
-        self.kv.remove(&key);
+        let bloom = self.bloom.as_ref().and_then(Weak::upgrade);
+        if bloom.is_some_and(|bloom| !bloom.may_contain(key.as_bytes())) {
+            return Ok(None);
+        }
+        match kv.get(key.as_bytes()) {
+            Some(command_offset) if !command_offset.is_expired() => read_value(
+                &*kv,
+                &self.reader,
+                &writer,
+                self.durability,
+                key.as_bytes(),
+                command_offset,
+            ),
+            _ => Ok(None),
+        }
+    }
+}
 
-        self.uncompaction_size += json.len() as u64;
-        if self.uncompaction_size >= COMPACTION_THRESHOLD {
-            self.compaction()?;
+/// each clone of reader owns its file handles, generations below `safe_generation`
+/// are removed by compaction and their handles are closed lazily
+struct KvStoreReader {
+    files: Arc<GenerationFiles>,
+    use_mmap: bool,
+    safe_generation: Arc<AtomicU64>,
+    /// shared by clones, `None` unless a capacity is set in the options
+    values: Option<Arc<ValueCache>>,
+    readers: RefCell<BTreeMap<u64, GenerationReader>>,
+    /// bytes of the record read by `get_into` or by `get` of a small record, reused to
+    /// avoid an allocation per read
+    record: RefCell<Vec<u8>>,
+}
+
+/// an opened generation file
+///
+/// generation files are append-only until they are deleted by compaction, so bytes
+/// inside a mapping never change. A record appended after the file was mapped is
+/// out of the mapping, and the file is remapped to read it. Deleting a mapped file
+/// keeps the mapping valid until it is dropped.
+enum GenerationReader {
+    File(BufReader<File>),
+    Mmap(Mmap),
+}
+
+/// records of a generation read by [`KvStore::read_from`], each with the position
+/// following it, records of a transaction once its commit record is read
+struct GenerationRecords {
+    generation: u64,
+    start: u64,
+    commands: DecodeStream<BufReader<io::Take<File>>, Command>,
+    /// records after a begin record, returned once its commit record is read
+    staged: Option<Vec<(LogPosition, Command)>>,
+    committed: std::vec::IntoIter<(LogPosition, Command)>,
+    /// nothing after a record which can't be read is returned
+    failed: bool,
+}
+
+impl Iterator for GenerationRecords {
+    type Item = Result<(LogPosition, LogRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((position, command)) = self.committed.next() {
+                return Some(log_record(command).map(|record| (position, record)));
+            }
+            if self.failed {
+                return None;
+            }
+
+            let command = match self.commands.next()? {
+                Ok((_, command)) => command,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            };
+            let position = (self.generation, self.start + self.commands.byte_offset());
+            match command {
+                Command::Begin => self.staged = Some(Vec::new()),
+                Command::Commit => {
+                    self.committed = self.staged.take().unwrap_or_default().into_iter()
+                }
+                Command::Version { .. } => {}
+                command => match &mut self.staged {
+                    Some(staged) => staged.push((position, command)),
+                    None => return Some(log_record(command).map(|record| (position, record))),
+                },
+            }
         }
+    }
+}
 
-        Ok(())
+/// bytes of the version records compaction writes before the records stamped `versions`,
+/// in increasing order, and after them for `next_version`
+fn version_records_len(
+    encoding: Encoding,
+    versions: impl Iterator<Item = u64>,
+    next_version: u64,
+) -> Result<u64> {
+    let (mut len, mut expected, mut buf) = (0, 0, Vec::new());
+    for version in versions.chain(iter::once(next_version)) {
+        if version != expected {
+            buf.clear();
+            encoding.encode(&mut buf, &Command::Version { version })?;
+            len += buf.len() as u64;
+        }
+        expected = version + 1;
     }
+    Ok(len)
+}
 
-    fn compaction(&mut self) -> Result<()> {
-        let mut to_delete_generations: HashSet<u64> = HashSet::new();
+fn log_record(command: Command) -> Result<LogRecord> {
+    Ok(match command {
+        Command::Set { key, value } => LogRecord::Set {
+            key,
+            value,
+            expire_at: None,
+        },
+        Command::SetEx {
+            key,
+            value,
+            expire_at,
+        } => LogRecord::Set {
+            key,
+            value,
+            expire_at: Some(expire_at),
+        },
+        Command::SetCompressed {
+            key,
+            value,
+            expire_at,
+        } => LogRecord::Set {
+            key,
+            value: Compression::decompress(&value)?,
+            expire_at,
+        },
+        Command::Remove { key } => LogRecord::Remove { key },
+        Command::Begin | Command::Commit | Command::Version { .. } => unreachable!(),
+    })
+}
 
-        let compaction_generation = self.writer_offset.generation + 1;
-        let mut compaction_offset = CommandOffset {
-            generation: compaction_generation,
-            offset: 0,
-        };
-        let mut compaction_writer =
-            Self::create_command_file(&self.dir_path, compaction_generation)?;
-        let compaction_reader = KvStoreReader::new(self.dir_path.clone());
+/// value streamed by [`KvStore::get_reader`]
+enum ValueReader {
+    File(io::Take<BufReader<File>>),
+    Memory(io::Cursor<Vec<u8>>),
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ValueReader::File(reader) => reader.read(buf),
+            ValueReader::Memory(reader) => reader.read(buf),
+        }
+    }
+}
 
-        for pair in self.kv.iter() {
-            let command_offset = *pair.value();
-            to_delete_generations.insert(command_offset.generation);
+struct KvStoreWriter {
+    kv: Arc<dyn Index<CommandOffset>>,
+    bloom: Option<Arc<BloomFilter>>,
+    /// `None` in a store opened read-only, where writes fail
+    writer: Option<BufWriter<File>>,
+    /// encoded record being written, reused to avoid an allocation per write
+    buf: Vec<u8>,
+    writer_offset: CommandOffset,
+    /// numbers of the generations written after the current one
+    generations: GenerationAllocator,
+    uncompaction_size: u64,
+    log_size: LogSize,
+    compaction_policy: CompactionPolicy,
+    compaction_count: u64,
+    /// the writer moves to a new generation once its generation reaches this size
+    segment_size: Option<u64>,
+    files: Arc<GenerationFiles>,
+    use_mmap: bool,
+    durability: DurabilityMode,
+    safe_generation: Arc<AtomicU64>,
+    checkpoint: bool,
+    parallel_compaction: bool,
+    compression: Compression,
+    size_limits: SizeLimits,
+    /// writes appended by group commit, see `GroupCommit::persisted`
+    appended_writes: u64,
+    compaction_hook: Option<CompactionHook>,
+    progress: Option<ProgressHook>,
+    /// value cache of the readers, emptied of generations deleted by compaction or clear
+    values: Option<Arc<ValueCache>>,
+    /// senders of `KvStore::watch` by key, a sender is dropped once its receiver is
+    watchers: HashMap<Vec<u8>, Vec<Sender<WatchEvent>>>,
+    /// version stamp of the next set or remove record, see `Command::Version`
+    next_version: u64,
+}
 
-            let value = compaction_reader.get(command_offset)?;
+/// bytes processed by a replay or a compaction, reported to the hook of
+/// [`KvStoreOptions::progress`](crate::KvStoreOptions::progress) every `PROGRESS_STEP` bytes
+struct Progress<'a> {
+    hook: Option<&'a ProgressHook>,
+    processed: u64,
+    reported: u64,
+    total: u64,
+}
 
-            let mut json = Vec::new();
-            let command = Command::Set {
-                key: pair.key().clone(),
-                value,
-            };
-            serde_json::to_writer(&mut json, &command)?;
+impl<'a> Progress<'a> {
+    fn new(hook: Option<&'a ProgressHook>, total: u64) -> Self {
+        Self {
+            hook,
+            processed: 0,
+            reported: 0,
+            total,
+        }
+    }
 
-            compaction_writer.write_all(&json)?;
+    fn advance(&mut self, bytes: u64) {
+        self.processed += bytes;
+        if self.processed - self.reported >= PROGRESS_STEP {
+            self.reported = self.processed;
+            self.report(self.processed.min(self.total));
+        }
+    }
 
-            let key = match command {
-                Command::Set { key, .. } => key,
-                _ => unreachable!(),
-            };
+    /// report all bytes as processed, records skipped as corrupt are never reached
+    fn finish(&mut self) {
+        self.report(self.total);
+    }
 
-            self.kv.insert(key, compaction_offset);
-            compaction_offset.offset += json.len() as u64;
+    fn report(&self, processed: u64) {
+        if let Some(hook) = self.hook {
+            (hook.0)(processed, self.total);
         }
+    }
+}
 
-        compaction_writer.flush()?;
+/// called after each compaction, see [`KvStore::on_compaction`]
+type CompactionHook = Arc<dyn Fn(&CompactionReport) + Send + Sync>;
 
-        for generation in to_delete_generations {
-            fs::remove_file(convert_command_generation_path(
-                self.dir_path.as_path(),
-                generation,
-            ))?;
+/// writes of concurrent threads persisted together, see
+/// [`KvStoreOptions::group_commit`](crate::KvStoreOptions::group_commit)
+struct GroupCommit {
+    window: Duration,
+    state: Mutex<GroupCommitState>,
+    /// notified when the leader is done
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct GroupCommitState {
+    /// writes persisted so far, counted by `KvStoreWriter::appended_writes`
+    persisted: u64,
+    /// a thread is waiting for the window to end, then persists the writes of all threads
+    leader: bool,
+}
+
+/// numbers of new generations of a store, strictly increasing and never reused
+///
+/// numbering starts after the largest generation on disk, so gaps left by deleted
+/// generations or by a crash are skipped rather than filled, and a number still cached
+/// by a reader never names another file
+#[derive(Debug)]
+struct GenerationAllocator {
+    next: u64,
+}
+
+impl GenerationAllocator {
+    /// allocator of numbers after the largest of `generations`, in any order
+    fn after(generations: &[u64]) -> Self {
+        Self {
+            next: generations.iter().max().map_or(0, |last| last + 1),
         }
+    }
 
-        let writer_offset = CommandOffset {
-            generation: compaction_generation + 1,
-            offset: 0,
-        };
-        let writer = Self::create_command_file(&self.dir_path, writer_offset.generation)?;
+    /// the number `allocate` returns next
+    fn peek(&self) -> u64 {
+        self.next
+    }
 
-        (self.writer, self.writer_offset, self.uncompaction_size) = (writer, writer_offset, 0);
-        Ok(())
+    fn allocate(&mut self) -> u64 {
+        let generation = self.next;
+        self.next += 1;
+        generation
     }
+}
 
-    fn create_command_file(dir_path: &Path, generation: u64) -> Result<BufWriter<File>> {
-        let path = convert_command_generation_path(dir_path, generation);
-        let writer = BufWriter::new(
-            File::options()
-                .create(true)
-                .append(true)
-                .open(path.as_path())?,
-        );
+/// generation files of one namespace, named by the layout of the store
+struct GenerationFiles {
+    dir_path: PathBuf,
+    namespace: Option<String>,
+    encoding: Encoding,
+    layout: Arc<dyn LayoutStrategy>,
+    write_buffer_size: usize,
+    read_buffer_size: usize,
+    /// longest bincode record decoded, a longer length prefix is corrupt
+    max_record_len: u64,
+}
 
-        Ok(writer)
+/// saved index, which is the result of loading generation files up to
+/// `offset` in `generation`
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    /// generations on disk up to `generation` when saved, the checkpoint is stale
+    /// once any of them is deleted by compaction
+    generations: Vec<u64>,
+    generation: u64,
+    offset: u64,
+    log_size: LogSize,
+    /// version stamp of the record at `offset`
+    next_version: u64,
+    entries: Vec<CheckpointEntry>,
+}
+
+/// key and offset of a checkpoint, keys are encoded as in records
+#[derive(Serialize, Deserialize)]
+struct CheckpointEntry(#[serde(with = "key_bytes")] Vec<u8>, CommandOffset);
+
+/// format and options of the generation files of a store, written as json on first open
+/// so any later build can read the version
+#[derive(Serialize)]
+struct Manifest {
+    format_version: u32,
+    encoding: Encoding,
+    value_compression: Compression,
+}
+
+/// the part of a manifest checked on open, other fields may change between versions
+#[derive(Deserialize)]
+struct ManifestVersion {
+    format_version: u32,
+}
+
+/// bytes in generation files
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct LogSize {
+    total: u64,
+    /// bytes of records which are no longer live
+    garbage: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct CommandOffset {
+    generation: u64,
+    offset: u64,
+    /// length of the record in bytes
+    len: u64,
+    /// absolute expiry timestamp in milliseconds, `None` never expires
+    expire_at: Option<u64>,
+    /// stamp of the write of the record, see [`KvStore::version`]
+    version: u64,
+    /// where the value lies in the record when stored as plain bytes, see
+    /// [`KvStoreReader::open_value`]
+    value: Option<ValueSpan>,
+}
+
+impl CommandOffset {
+    fn is_expired(&self) -> bool {
+        self.expire_at.is_some_and(ttl::is_expired)
     }
 }
 
-fn convert_command_generation_path(dir_path: &Path, generation: u64) -> PathBuf {
-    dir_path.join(format!("{generation}.json"))
+#[derive(Serialize, Deserialize, Debug)]
+enum Command {
+    Set {
+        #[serde(with = "key_bytes")]
+        key: Vec<u8>,
+        value: String,
+    },
+    Remove {
+        #[serde(with = "key_bytes")]
+        key: Vec<u8>,
+    },
+    SetEx {
+        #[serde(with = "key_bytes")]
+        key: Vec<u8>,
+        value: String,
+        expire_at: u64,
+    },
+    /// value compressed by [`Compression::compress`]
+    SetCompressed {
+        #[serde(with = "key_bytes")]
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expire_at: Option<u64>,
+    },
+    /// the records up to the next `Commit` belong to one transaction, and are
+    /// dropped on load if it is missing
+    Begin,
+    Commit,
+    /// version stamp of the next set or remove record, each of which otherwise gets
+    /// the stamp after the one before, starting from 0 in a store's first generation
+    ///
+    /// written where records aren't in the order of their stamps, by compaction
+    /// and after clear
+    Version {
+        version: u64,
+    },
 }
 
-impl KvStore {
-    /// open a new [`KvStoreInner`]
-    /// `path` is a directory path
-    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let path: Arc<PathBuf> = Arc::new(path.into());
-        fs::create_dir_all(path.as_path())?;
+/// a set record decoded by `KvStoreReader::get_into` and `KvStoreReader::get`, borrowing
+/// the value from the record's bytes unless json escapes it, variants follow [`Command`]
+/// so bincode tags match
+///
+/// fields after the value are left undecoded, bincode reaches the value only through the
+/// key in front of it, which is skipped
+#[derive(Deserialize)]
+enum SetRecord<'a> {
+    Set {
+        #[serde(rename = "key")]
+        _key: SkippedKey,
+        #[serde(borrow)]
+        value: Cow<'a, str>,
+    },
+    Remove {},
+    SetEx {
+        #[serde(rename = "key")]
+        _key: SkippedKey,
+        #[serde(borrow)]
+        value: Cow<'a, str>,
+    },
+    SetCompressed {
+        #[serde(rename = "key")]
+        _key: SkippedKey,
+        value: Vec<u8>,
+    },
+}
+
+impl Record for Command {
+    fn key_value(&self) -> Option<(&[u8], &str)> {
+        match self {
+            Command::Set { key, value } | Command::SetEx { key, value, .. } => Some((key, value)),
+            _ => None,
+        }
+    }
+}
 
-        let kv = Arc::new(SkipMap::new());
-        let mut uncompaction_size = 0;
-        let generations = Self::get_generations(path.as_path())?;
-        let writer_generation = generations.iter().max().map_or(0, |x| x + 1);
+impl Record for Checkpoint {}
 
-        for generation in generations {
-            Self::load_command_file(&path, generation, &kv, &mut uncompaction_size)?
+impl Command {
+    fn set(key: Vec<u8>, value: String, expire_at: Option<u64>) -> Self {
+        match expire_at {
+            Some(expire_at) => Command::SetEx {
+                key,
+                value,
+                expire_at,
+            },
+            None => Command::Set { key, value },
         }
+    }
+}
 
-        Ok(Self {
-            kv: kv.clone(),
-            reader: KvStoreReader::new(path.clone()),
-            writer: Arc::new(Mutex::new(KvStoreWriter::new(
-                kv,
-                path,
-                writer_generation,
-                uncompaction_size,
-            )?)),
+impl Clone for KvStoreReader {
+    fn clone(&self) -> Self {
+        Self::new(
+            self.files.clone(),
+            self.use_mmap,
+            self.safe_generation.clone(),
+            self.values.clone(),
+        )
+    }
+}
+
+impl KvStoreReader {
+    fn new(
+        files: Arc<GenerationFiles>,
+        use_mmap: bool,
+        safe_generation: Arc<AtomicU64>,
+        values: Option<Arc<ValueCache>>,
+    ) -> Self {
+        Self {
+            files,
+            use_mmap,
+            safe_generation,
+            values,
+            readers: RefCell::new(BTreeMap::new()),
+            record: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn open_generation(&self, generation: u64) -> Result<GenerationReader> {
+        let file = File::options()
+            .read(true)
+            .open(self.files.path(generation))?;
+
+        Ok(if self.use_mmap {
+            // SAFETY: generation files are append-only until deletion, see `GenerationReader`
+            GenerationReader::Mmap(unsafe { Mmap::map(&file)? })
+        } else {
+            GenerationReader::File(BufReader::with_capacity(self.files.read_buffer_size, file))
         })
     }
 
-    fn load_command_file(
-        dir_path: &Path,
-        generation: u64,
-        kv: &SkipMap<String, CommandOffset>,
-        uncompaction_size: &mut u64,
-    ) -> Result<()> {
-        let mut reader: BufReader<File> = BufReader::new(
-            File::options()
-                .read(true)
-                .open(convert_command_generation_path(dir_path, generation))?,
+    /// records of `generation` from `start` up to `end`, read as they are iterated
+    fn records(&self, generation: u64, start: u64, end: u64) -> Result<GenerationRecords> {
+        let mut file = File::options()
+            .read(true)
+            .open(self.files.path(generation))?;
+        file.seek(io::SeekFrom::Start(start))?;
+        let reader = BufReader::with_capacity(
+            self.files.read_buffer_size,
+            file.take(end.saturating_sub(start)),
         );
-        reader.seek(io::SeekFrom::Start(0))?;
 
-        let mut command_iter = Deserializer::from_reader(&mut reader).into_iter::<Command>();
+        Ok(GenerationRecords {
+            generation,
+            start,
+            commands: self
+                .files
+                .encoding
+                .decode_stream(reader, self.files.max_record_len),
+            staged: None,
+            committed: Vec::new().into_iter(),
+            failed: false,
+        })
+    }
+
+    fn close_stale_handles(&self) {
+        let safe_generation = self.safe_generation.load(Ordering::SeqCst);
+        let mut readers = self.readers.borrow_mut();
+        if readers
+            .first_key_value()
+            .is_some_and(|(&generation, _)| generation < safe_generation)
+        {
+            *readers = readers.split_off(&safe_generation);
+        }
+    }
+
+    fn get(&self, command_offset: CommandOffset) -> Result<String> {
+        match self.values {
+            Some(_) => Ok(self.get_shared(command_offset)?.to_string()),
+            None => self.read(command_offset),
+        }
+    }
+
+    /// the value of a set record from the value cache, read and cached on a miss
+    fn get_shared(&self, command_offset: CommandOffset) -> Result<Arc<str>> {
+        let position = (command_offset.generation, command_offset.offset);
+        if let Some(value) = self.values.as_ref().and_then(|values| values.get(position)) {
+            return Ok(value);
+        }
+        let value: Arc<str> = self.read(command_offset)?.into();
+        if let Some(values) = &self.values {
+            values.insert(position, value.clone());
+        }
+        Ok(value)
+    }
 
-        let mut offset = 0;
-        while let Some(command) = command_iter.next() {
-            match command? {
-                Command::Set { key, .. } => {
-                    kv.insert(key, CommandOffset { generation, offset });
+    /// decode the value of a set record from its generation file
+    fn read(&self, command_offset: CommandOffset) -> Result<String> {
+        // a record fitting in the read buffer is read at once and decoded from memory,
+        // larger ones are decoded from the file rather than copied whole into `record`
+        if command_offset.len <= self.files.read_buffer_size as u64 {
+            let mut record = self.record.borrow_mut();
+            self.read_record(command_offset, &mut record)?;
+            return match self.files.encoding.decode::<SetRecord<'_>>(&record)? {
+                SetRecord::Set { value, .. } | SetRecord::SetEx { value, .. } => {
+                    Ok(value.into_owned())
                 }
-                Command::Remove { key } => {
-                    kv.remove(&key);
+                SetRecord::SetCompressed { value, .. } => Compression::decompress(&value),
+                SetRecord::Remove { .. } => unreachable!("should be a set record"),
+            };
+        }
+
+        self.close_stale_handles();
+
+        let mut readers = self.readers.borrow_mut();
+        let reader = match readers.entry(command_offset.generation) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(self.open_generation(command_offset.generation)?),
+        };
+
+        let command = match reader {
+            GenerationReader::File(reader) => {
+                reader.seek(io::SeekFrom::Start(command_offset.offset))?;
+                self.decode_command(reader)?
+            }
+            GenerationReader::Mmap(mmap) => {
+                match self.decode_command(mmap_tail(mmap, command_offset.offset)) {
+                    Ok(command) => command,
+                    Err(_) => {
+                        // the record may be appended after the file was mapped
+                        *reader = self.open_generation(command_offset.generation)?;
+                        match reader {
+                            GenerationReader::Mmap(mmap) => {
+                                self.decode_command(mmap_tail(mmap, command_offset.offset))?
+                            }
+                            GenerationReader::File(_) => unreachable!("should be mmap"),
+                        }
+                    }
                 }
             }
-            offset = command_iter.byte_offset() as u64;
-        }
-        *uncompaction_size += offset;
+        };
+
+        Ok(match command {
+            Command::Set { value, .. } | Command::SetEx { value, .. } => value,
+            Command::SetCompressed { value, .. } => Compression::decompress(&value)?,
+            _ => unreachable!("should not be other command kinds"),
+        })
+    }
 
+    /// decode the value of a set record into `buf`, without allocating for the record
+    /// unless the value is compressed
+    fn get_into(&self, command_offset: CommandOffset, buf: &mut String) -> Result<()> {
+        let mut record = self.record.borrow_mut();
+        self.read_record(command_offset, &mut record)?;
+        match self.files.encoding.decode::<SetRecord<'_>>(&record)? {
+            SetRecord::Set { value, .. } | SetRecord::SetEx { value, .. } => {
+                buf.clear();
+                buf.push_str(&value);
+            }
+            SetRecord::SetCompressed { value, .. } => *buf = Compression::decompress(&value)?,
+            SetRecord::Remove { .. } => unreachable!("should be a set record"),
+        }
         Ok(())
     }
 
-    fn get_generations(dir_path: &Path) -> Result<Vec<u64>> {
-        let mut result: Vec<u64> = fs::read_dir(dir_path)?
-            .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-            .filter(|path| path.is_file() && path.extension() == Some(OsStr::new("json")))
-            .flat_map(|path| {
-                path.file_name()
-                    .and_then(OsStr::to_str)
-                    .map(|s| s.trim_end_matches(".json"))
-                    .map(str::parse::<u64>)
-            })
-            .flatten()
-            .collect();
+    /// copy the encoded bytes of a record into `buf` without decoding it
+    fn read_record(&self, command_offset: CommandOffset, buf: &mut Vec<u8>) -> Result<()> {
+        self.close_stale_handles();
 
-        result.sort_unstable();
-        Ok(result)
-    }
-}
+        let mut readers = self.readers.borrow_mut();
+        let reader = match readers.entry(command_offset.generation) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(self.open_generation(command_offset.generation)?),
+        };
 
-impl KvsEngine for KvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let mut writer = self.writer.lock().unwrap();
-        writer.set(key, value)
+        let range =
+            command_offset.offset as usize..(command_offset.offset + command_offset.len) as usize;
+        buf.resize(range.len(), 0);
+        if let GenerationReader::Mmap(mmap) = reader {
+            if mmap.len() < range.end {
+                // the record may be appended after the file was mapped
+                *reader = self.open_generation(command_offset.generation)?;
+            }
+        }
+
+        match reader {
+            GenerationReader::File(reader) => {
+                reader.seek(io::SeekFrom::Start(command_offset.offset))?;
+                reader.read_exact(buf)?;
+            }
+            GenerationReader::Mmap(mmap) => buf.copy_from_slice(
+                mmap.get(range)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?,
+            ),
+        }
+        Ok(())
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let command_offset = match self.kv.get(&key) {
-            Some(o) => *o.value(),
+    /// open a new handle positioned at the value of a record, limited to the value,
+    /// `None` if the value isn't stored as plain bytes
+    fn open_value(
+        &self,
+        command_offset: CommandOffset,
+    ) -> Result<Option<io::Take<BufReader<File>>>> {
+        let value = match command_offset.value {
+            Some(value) => value,
             None => return Ok(None),
         };
-        Ok(Some(self.reader.get(command_offset)?))
+        let mut file = File::open(self.files.path(command_offset.generation))?;
+        file.seek(io::SeekFrom::Start(
+            command_offset.offset + u64::from(value.offset),
+        ))?;
+        let reader = BufReader::with_capacity(self.files.read_buffer_size, file);
+        Ok(Some(reader.take(u64::from(value.len))))
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        let mut writer = self.writer.lock().unwrap();
-        writer.remove(key)
+    fn decode_command(&self, reader: impl io::Read) -> Result<Command> {
+        let mut command_iter = self
+            .files
+            .encoding
+            .decode_stream::<Command, _>(reader, self.files.max_record_len);
+        match command_iter.next() {
+            Some(command) => Ok(command?.1),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+}
+
+impl KvStoreWriter {
+    fn new(
+        kv: Arc<dyn Index<CommandOffset>>,
+        bloom: Option<Arc<BloomFilter>>,
+        files: Arc<GenerationFiles>,
+        options: &KvStoreOptions,
+        safe_generation: Arc<AtomicU64>,
+        writer_offset: (u64, u64),
+        // size of the replayed generations and the stamp of the next record
+        (log_size, next_version): (LogSize, u64),
+    ) -> Result<Self> {
+        let writer = match options.read_only {
+            true => None,
+            false => Some(Self::create_command_file(&files, writer_offset.0)?),
+        };
+        Ok(Self {
+            kv,
+            bloom,
+            writer,
+            buf: Vec::new(),
+            writer_offset: CommandOffset {
+                generation: writer_offset.0,
+                offset: writer_offset.1,
+                len: 0,
+                expire_at: None,
+                version: 0,
+                value: None,
+            },
+            generations: GenerationAllocator::after(&[writer_offset.0]),
+            uncompaction_size: log_size.total,
+            log_size,
+            compaction_policy: options.compaction_policy,
+            compaction_count: 0,
+            segment_size: options.segment_size,
+            files,
+            use_mmap: options.use_mmap,
+            durability: options.durability,
+            safe_generation,
+            checkpoint: options.checkpoint,
+            parallel_compaction: options.parallel_compaction,
+            compression: options.value_compression,
+            size_limits: options.size_limits(),
+            appended_writes: 0,
+            compaction_hook: None,
+            progress: options.progress.clone(),
+            values: (options.value_cache_capacity > 0)
+                .then(|| Arc::new(ValueCache::new(options.value_cache_capacity))),
+            watchers: HashMap::new(),
+            next_version,
+        })
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: String, expire_at: Option<u64>) -> Result<()> {
+        let watched = self.watched(&key, &value);
+        let command = self.set_command(key, value, expire_at)?;
+        self.buf.clear();
+        let value = self.files.encoding.encode(&mut self.buf, &command)?;
+        let len = self.buf.len() as u64;
+
+        Self::file(&mut self.writer)?.write_all(&self.buf)?;
+        let version = self.next_version;
+        self.next_version += 1;
+        self.sync()?;
+
+        self.index_set(command, self.writer_offset.offset, len, version, value);
+        self.writer_offset.offset += len;
+        if let Some((key, value)) = watched {
+            self.notify(&key, WatchEvent::Set { value });
+        }
+
+        self.uncompaction_size += len;
+        self.log_size.total += len;
+        self.written()
+    }
+
+    /// record setting a key, with the value compressed if enabled
+    fn set_command(&self, key: Vec<u8>, value: String, expire_at: Option<u64>) -> Result<Command> {
+        self.size_limits.check(&key, &value)?;
+        Ok(match self.compression.compress(&value)? {
+            Some(value) => Command::SetCompressed {
+                key,
+                value,
+                expire_at,
+            },
+            None => Command::set(key, value, expire_at),
+        })
+    }
+
+    /// point the key of a written set record at `offset` of the current generation
+    fn index_set(
+        &mut self,
+        command: Command,
+        offset: u64,
+        len: u64,
+        version: u64,
+        value: Option<ValueSpan>,
+    ) {
+        let (key, expire_at) = match command {
+            Command::Set { key, .. } => (key, None),
+            Command::SetEx { key, expire_at, .. } => (key, Some(expire_at)),
+            Command::SetCompressed { key, expire_at, .. } => (key, expire_at),
+            _ => unreachable!(),
+        };
+        // before the index, so a key found in the index always passes the filter
+        if let Some(bloom) = &self.bloom {
+            bloom.insert(&key);
+        }
+        if let Some(old) = self.kv.get(&key) {
+            self.log_size.garbage += old.len;
+        }
+        self.kv.insert(
+            key,
+            CommandOffset {
+                offset,
+                len,
+                expire_at,
+                version,
+                value,
+                ..self.writer_offset
+            },
+        );
+    }
+
+    /// write `ops` enclosed by begin and commit records with a single write, then apply
+    /// them to the index, a reload applies them only if the commit record was written
+    fn commit(&mut self, ops: Vec<BatchOp>) -> Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        let encoding = self.files.encoding;
+        self.buf.clear();
+        encoding.encode(&mut self.buf, &Command::Begin)?;
+        let mut records = Vec::with_capacity(ops.len());
+        let mut events = Vec::new();
+        for op in ops {
+            let command = match op {
+                BatchOp::Set { key, value } => {
+                    let key = key.into_bytes();
+                    events.extend(
+                        self.watched(&key, &value)
+                            .map(|(key, value)| (key, WatchEvent::Set { value })),
+                    );
+                    self.set_command(key, value, None)?
+                }
+                BatchOp::Remove { key } => Command::Remove {
+                    key: key.into_bytes(),
+                },
+            };
+            let start = self.buf.len() as u64;
+            let value = encoding.encode(&mut self.buf, &command)?;
+            records.push((start, self.buf.len() as u64 - start, value, command));
+        }
+        let records_end = self.buf.len() as u64;
+        encoding.encode(&mut self.buf, &Command::Commit)?;
+        let len = self.buf.len() as u64;
+
+        Self::file(&mut self.writer)?.write_all(&self.buf)?;
+        // each record of the transaction gets its own stamp, in order
+        let versions = self.next_version..;
+        self.next_version += records.len() as u64;
+        self.sync()?;
+
+        let base = self.writer_offset.offset;
+        // the begin and commit records are garbage from the start
+        let mut garbage = len - records_end + records.first().map_or(0, |(start, ..)| *start);
+        for ((start, record_len, value, command), version) in records.into_iter().zip(versions) {
+            match command {
+                Command::Remove { key } => {
+                    if let Some(old) = self.kv.remove(&key) {
+                        garbage += old.len;
+                        if self.watchers.contains_key(&key) {
+                            events.push((key, WatchEvent::Remove));
+                        }
+                    }
+                    garbage += record_len;
+                }
+                command => self.index_set(command, base + start, record_len, version, value),
+            }
+        }
+        self.writer_offset.offset += len;
+        for (key, event) in events {
+            self.notify(&key, event);
+        }
+
+        self.uncompaction_size += len;
+        self.log_size.total += len;
+        self.log_size.garbage += garbage;
+        self.written()
+    }
+
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        let old = match self.kv.get(&key) {
+            Some(command_offset) if !command_offset.is_expired() => command_offset,
+            _ => return Err(KvsError::KeyNotFound),
+        };
+
+        let command = Command::Remove { key };
+        self.buf.clear();
+        self.files.encoding.encode(&mut self.buf, &command)?;
+        let len = self.buf.len() as u64;
+
+        Self::file(&mut self.writer)?.write_all(&self.buf)?;
+        self.writer_offset.offset += len;
+        self.next_version += 1;
+        self.sync()?;
+
+        let key = match command {
+            Command::Remove { key } => key,
+            _ => unreachable!(),
+        };
+        self.kv.remove(&key);
+        self.notify(&key, WatchEvent::Remove);
+
+        self.uncompaction_size += len;
+        self.log_size.total += len;
+        // the remove record itself is garbage once the set it cancels is compacted
+        self.log_size.garbage += old.len + len;
+        self.written()
+    }
+
+    /// roll over to a new generation once the current one is full, and compact as
+    /// required by the compaction policy
+    fn written(&mut self) -> Result<()> {
+        if self
+            .segment_size
+            .is_some_and(|segment_size| self.writer_offset.offset >= segment_size)
+        {
+            self.roll_over()?;
+        }
+        if self.should_compact() {
+            self.compaction()?;
+        }
+        Ok(())
+    }
+
+    /// write further records to the next generation, the full one is synced first so it
+    /// never needs syncing again, whatever the durability mode
+    fn roll_over(&mut self) -> Result<()> {
+        self.sync_all()?;
+        let generation = self.generations.allocate();
+        self.writer = Some(Self::create_command_file(&self.files, generation)?);
+        self.writer_offset = CommandOffset {
+            generation,
+            offset: 0,
+            len: 0,
+            expire_at: None,
+            version: 0,
+            value: None,
+        };
+        Ok(())
+    }
+
+    fn should_compact(&self) -> bool {
+        match self.compaction_policy {
+            CompactionPolicy::Bytes(threshold) => self.uncompaction_size >= threshold,
+            CompactionPolicy::GarbageRatio(ratio) => {
+                self.log_size.garbage >= MIN_COMPACTION_GARBAGE
+                    && self.log_size.garbage as f64 >= ratio * self.log_size.total as f64
+            }
+        }
+    }
+
+    /// run `f` without syncing each of its writes, they are left to the caller
+    ///
+    /// records are still flushed before the index points at them, unless the durability
+    /// mode is [`DurabilityMode::None`], so concurrent reads find them in the file
+    fn unsynced<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let unsynced = match self.durability {
+            DurabilityMode::None => DurabilityMode::None,
+            DurabilityMode::Buffered | DurabilityMode::Fsync => DurabilityMode::Buffered,
+        };
+        let durability = mem::replace(&mut self.durability, unsynced);
+        let result = f(self);
+        self.durability = durability;
+        result
+    }
+
+    /// persist written records as required by the durability mode
+    fn sync(&mut self) -> Result<()> {
+        let durability = self.durability;
+        let writer = Self::file(&mut self.writer)?;
+        match durability {
+            DurabilityMode::None => {}
+            DurabilityMode::Buffered => writer.flush()?,
+            DurabilityMode::Fsync => {
+                writer.flush()?;
+                writer.get_ref().sync_all()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// write buffered records and sync the file, whatever the durability mode,
+    /// nothing to do in a read-only store
+    fn sync_all(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// write buffered records to the file, so readers can read them
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.as_mut().map_or(Ok(()), Write::flush)
+    }
+
+    /// the generation file being written, [`KvsError::ReadOnly`] in a read-only store
+    fn file(writer: &mut Option<BufWriter<File>>) -> Result<&mut BufWriter<File>> {
+        writer.as_mut().ok_or(KvsError::ReadOnly)
+    }
+
+    /// drop an expired key from the index if it was not overwritten since it was read,
+    /// the record on disk is skipped on load and by compaction
+    fn remove_expired(&mut self, key: &[u8], command_offset: CommandOffset) {
+        if self.kv.get(key) == Some(command_offset) {
+            self.kv.remove(key);
+            self.log_size.garbage += command_offset.len;
+        }
+    }
+
+    /// copies of `key` and `value` for the event of a set, `None` if `key` isn't watched
+    fn watched(&self, key: &[u8], value: &str) -> Option<(Vec<u8>, String)> {
+        self.watchers
+            .contains_key(key)
+            .then(|| (key.to_vec(), value.to_owned()))
+    }
+
+    /// send `event` to the watchers of `key`, forgetting those whose receiver is dropped
+    fn notify(&mut self, key: &[u8], event: WatchEvent) {
+        if let Some(senders) = self.watchers.get_mut(key) {
+            senders.retain(|sender| sender.send(event.clone()).is_ok());
+            if senders.is_empty() {
+                self.watchers.remove(key);
+            }
+        }
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn compaction(&mut self) -> Result<CompactionReport> {
+        // records still buffered must be readable by the compaction reader
+        Self::file(&mut self.writer)?.flush()?;
+
+        // allocated once renamed into place, a failed compaction is retried in the same
+        // temporary file, which is also the one removed on open after a crash
+        let compaction_generation = self.generations.peek();
+        // a generation holding only garbage is referenced by no key but is removed as well
+        let to_delete_generations = self.files.generations()?;
+        let mut compaction_offset = CommandOffset {
+            generation: compaction_generation,
+            offset: 0,
+            len: 0,
+            expire_at: None,
+            version: 0,
+            value: None,
+        };
+        // written to a temporary file first, so a crash never leaves a partial generation
+        let tmp_path = self.files.tmp_path(compaction_generation);
+        if let Some(parent) = tmp_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut compaction_writer =
+            BufWriter::with_capacity(self.files.write_buffer_size, File::create(&tmp_path)?);
+        let compaction_reader = KvStoreReader::new(
+            self.files.clone(),
+            self.use_mmap,
+            self.safe_generation.clone(),
+            None,
+        );
+
+        // writes are excluded by the writer lock, so entries stay unchanged until replaced
+        let (mut entries, mut expired) = (Vec::with_capacity(self.kv.len()), Vec::new());
+        self.kv.for_each(&mut |key, command_offset| {
+            if command_offset.is_expired() {
+                expired.push(key.to_vec());
+            } else {
+                entries.push(command_offset);
+            }
+        });
+        for key in expired {
+            self.kv.remove(&key);
+        }
+        // records are copied in the order they were written, so only the stamps skipped
+        // by overwritten and removed keys need a version record, and a live record is
+        // found by its version, which no other live record has
+        entries.sort_unstable_by_key(|command_offset| command_offset.version);
+        let encoding = self.files.encoding;
+        let versions = entries.iter().map(|o| o.version);
+        let live_bytes = entries.iter().map(|o| o.len).sum::<u64>()
+            + version_records_len(encoding, versions, self.next_version)?;
+        let mut progress = Progress::new(self.progress.as_ref(), live_bytes);
+        // the compacted generation is replayed from stamp 0, so a record not stamped right
+        // after the one before follows a version record
+        let mut stamp_buf = Vec::new();
+        let mut next_version = 0;
+        let mut stamp = |writer: &mut BufWriter<File>, offset: &mut u64, version: u64| {
+            let mut len = 0;
+            if version != next_version {
+                stamp_buf.clear();
+                encoding.encode(&mut stamp_buf, &Command::Version { version })?;
+                writer.write_all(&stamp_buf)?;
+                len = stamp_buf.len() as u64;
+                *offset += len;
+            }
+            next_version = version + 1;
+            Result::Ok(len)
+        };
+        // each record is written at the end of the compacted generation, its new offset
+        // is at the index of the old one in `entries`
+        let mut relocated = Vec::with_capacity(entries.len());
+        let mut copy = |record: &[u8], command_offset: &CommandOffset| {
+            // moving a record isn't a write, so its version is kept
+            let offset = &mut compaction_offset.offset;
+            progress.advance(stamp(
+                &mut compaction_writer,
+                offset,
+                command_offset.version,
+            )?);
+            compaction_writer.write_all(record)?;
+            relocated.push(CommandOffset {
+                len: command_offset.len,
+                expire_at: command_offset.expire_at,
+                version: command_offset.version,
+                value: command_offset.value,
+                ..compaction_offset
+            });
+            compaction_offset.offset += command_offset.len;
+            progress.advance(command_offset.len);
+            Result::Ok(())
+        };
+        // a record is self-contained, so its bytes are copied as they are
+        if self.parallel_compaction {
+            let mut start = 0;
+            while start < entries.len() {
+                let (mut end, mut batch_len) = (start, 0);
+                while end < entries.len() && batch_len < PARALLEL_COMPACTION_BATCH {
+                    batch_len += entries[end].len;
+                    end += 1;
+                }
+                let records = self.read_records(&entries[start..end])?;
+                for (record, command_offset) in records.iter().zip(&entries[start..end]) {
+                    copy(record, command_offset)?;
+                }
+                start = end;
+            }
+        } else {
+            for command_offset in &entries {
+                compaction_reader.read_record(*command_offset, &mut self.buf)?;
+                copy(&self.buf, command_offset)?;
+            }
+        }
+        // records written after compaction are stamped after the last write
+        progress.advance(stamp(
+            &mut compaction_writer,
+            &mut compaction_offset.offset,
+            self.next_version,
+        )?);
+        let live_keys = entries.len();
+        progress.finish();
+
+        // old generations are deleted next, so the compacted file must be on disk
+        // regardless of the durability mode before it replaces them
+        compaction_writer.flush()?;
+        compaction_writer.get_ref().sync_all()?;
+        drop(compaction_writer);
+        fs::rename(&tmp_path, self.files.path(compaction_generation))?;
+        self.generations.allocate();
+
+        // readers look up offsets without the writer lock, so the steps are ordered:
+        // - the compacted file is renamed into place before any offset into it is
+        //   published, a reader finding a new offset can read its record
+        // - all offsets are republished before `safe_generation` is raised and old
+        //   generations are deleted, a reader failing to open a deleted generation
+        //   looks the key up again and finds the new offset, see `KvStore::read_value`
+        // the index publishes entries with release and reads them with acquire ordering,
+        // and `safe_generation` is sequentially consistent
+        self.kv.update_each(&mut |_, command_offset| {
+            let i = entries
+                .binary_search_by_key(&command_offset.version, |o| o.version)
+                .ok()?;
+            (entries[i] == command_offset).then(|| relocated[i])
+        });
+        self.safe_generation
+            .store(compaction_generation, Ordering::SeqCst);
+        if let Some(values) = &self.values {
+            values.remove_before(compaction_generation);
+        }
+
+        for &generation in &to_delete_generations {
+            fs::remove_file(self.files.path(generation))?;
+        }
+
+        // new records are appended to the compacted generation
+        let writer = Self::create_command_file(&self.files, compaction_generation)?;
+        let writer_offset = CommandOffset {
+            len: 0,
+            ..compaction_offset
+        };
+
+        (self.writer, self.writer_offset, self.uncompaction_size) =
+            (Some(writer), writer_offset, 0);
+        let report = CompactionReport {
+            live_keys,
+            bytes_before: self.log_size.total,
+            bytes_after: compaction_offset.offset,
+            generations_removed: to_delete_generations.len(),
+        };
+        self.log_size = LogSize {
+            total: compaction_offset.offset,
+            garbage: 0,
+        };
+        self.compaction_count += 1;
+        if self.checkpoint {
+            self.save_checkpoint()?;
+        }
+        if let Some(hook) = &self.compaction_hook {
+            hook(&report);
+        }
+        Ok(report)
+    }
+
+    /// read the records of `entries` on the rayon thread pool, each thread with its
+    /// own file handles
+    fn read_records(&self, entries: &[CommandOffset]) -> Result<Vec<Vec<u8>>> {
+        let (files, use_mmap, safe_generation) =
+            (&self.files, self.use_mmap, &self.safe_generation);
+        entries
+            .par_iter()
+            .map_init(
+                || KvStoreReader::new(files.clone(), use_mmap, safe_generation.clone(), None),
+                |reader, command_offset| {
+                    let mut record = Vec::new();
+                    reader.read_record(*command_offset, &mut record)?;
+                    Ok(record)
+                },
+            )
+            .collect()
+    }
+
+    /// write the index to the checkpoint file, replacing the old one atomically
+    fn save_checkpoint(&mut self) -> Result<()> {
+        Self::file(&mut self.writer)?.flush()?;
+
+        let mut checkpoint = Checkpoint {
+            generations: self.files.generations()?,
+            generation: self.writer_offset.generation,
+            offset: self.writer_offset.offset,
+            log_size: self.log_size,
+            next_version: self.next_version,
+            entries: Vec::with_capacity(self.kv.len()),
+        };
+        self.kv.for_each(&mut |key, command_offset| {
+            (checkpoint.entries).push(CheckpointEntry(key.to_vec(), command_offset))
+        });
+        let mut buf = Vec::new();
+        self.files.encoding.encode(&mut buf, &checkpoint)?;
+
+        let path = self.files.checkpoint_path();
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        if self.durability == DurabilityMode::Fsync {
+            file.sync_all()?;
+        }
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// drop all keys and generation files, the new writer generation follows the old one
+    /// so handles of deleted generations cached by readers are never reused
+    fn clear(&mut self) -> Result<()> {
+        Self::file(&mut self.writer)?;
+        let generations = self.files.generations()?;
+
+        let mut writer_offset = CommandOffset {
+            generation: self.generations.allocate(),
+            offset: 0,
+            len: 0,
+            expire_at: None,
+            version: 0,
+            value: None,
+        };
+        let mut writer = Self::create_command_file(&self.files, writer_offset.generation)?;
+        // stamps keep growing, so a stamp read before the clear never matches a new write
+        self.buf.clear();
+        let version = self.next_version;
+        self.files
+            .encoding
+            .encode(&mut self.buf, &Command::Version { version })?;
+        writer.write_all(&self.buf)?;
+        writer_offset.offset = self.buf.len() as u64;
+
+        let removed: Vec<Vec<u8>> = (self.watchers.keys())
+            .filter(|key| self.kv.contains_key(key))
+            .cloned()
+            .collect();
+        self.kv.clear();
+        if let Some(bloom) = &self.bloom {
+            bloom.clear();
+        }
+        for key in removed {
+            self.notify(&key, WatchEvent::Remove);
+        }
+        self.safe_generation
+            .store(writer_offset.generation, Ordering::SeqCst);
+        if let Some(values) = &self.values {
+            values.remove_before(writer_offset.generation);
+        }
+
+        for generation in generations {
+            fs::remove_file(self.files.path(generation))?;
+        }
+
+        (self.writer, self.writer_offset, self.uncompaction_size) =
+            (Some(writer), writer_offset, 0);
+        self.log_size = LogSize {
+            total: writer_offset.offset,
+            garbage: 0,
+        };
+        Ok(())
+    }
+
+    fn create_command_file(files: &GenerationFiles, generation: u64) -> Result<BufWriter<File>> {
+        let path = files.path(generation);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let writer = BufWriter::with_capacity(
+            files.write_buffer_size,
+            File::options()
+                .create(true)
+                .append(true)
+                .open(path.as_path())?,
+        );
+
+        Ok(writer)
+    }
+}
+
+/// runs when the last clone of the store is dropped, as the writer is shared by all clones
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        // records are left in the buffer under `DurabilityMode::None`
+        let durability = self.durability;
+        let result = writer.flush().and_then(|_| match durability {
+            DurabilityMode::Fsync => writer.get_ref().sync_all(),
+            _ => Ok(()),
+        });
+        if let Err(err) = result {
+            log::error!("failed to flush generation file on drop: {err}");
+        }
+        if self.checkpoint {
+            if let Err(err) = self.save_checkpoint() {
+                log::error!("failed to save index checkpoint on drop: {err}");
+            }
+        }
+    }
+}
+
+fn mmap_tail(mmap: &Mmap, offset: u64) -> &[u8] {
+    mmap.get(offset as usize..).unwrap_or_default()
+}
+
+impl GenerationFiles {
+    fn new(dir_path: PathBuf, namespace: Option<String>, options: &KvStoreOptions) -> Self {
+        Self {
+            dir_path,
+            namespace,
+            encoding: options.encoding,
+            layout: options
+                .layout
+                .clone()
+                .unwrap_or_else(|| Arc::new(FlatLayout)),
+            write_buffer_size: options.write_buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            read_buffer_size: options.read_buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            max_record_len: options.size_limits().max_record_len(),
+        }
+    }
+
+    fn context(&self) -> LayoutContext<'_> {
+        LayoutContext {
+            dir: &self.dir_path,
+            namespace: self.namespace.as_deref(),
+            extension: self.encoding.extension(),
+        }
+    }
+
+    fn path(&self, generation: u64) -> PathBuf {
+        self.layout.generation_path(&self.context(), generation)
+    }
+
+    /// temporary file of a generation being compacted, named by adding `.tmp`
+    /// to the generation's path
+    fn tmp_path(&self, generation: u64) -> PathBuf {
+        let mut path = self.path(generation).into_os_string();
+        path.push(".tmp");
+        path.into()
+    }
+
+    /// temporary files of this namespace in the directories of its generation files and of
+    /// generation `next`, and the temporary file of its index checkpoint
+    ///
+    /// a `.tmp` file is a generation's if its path without `.tmp` is the path of a generation
+    /// numbered by one of the runs of digits in it, so temporary files of other namespaces
+    /// are left alone
+    fn stale_tmp_paths(&self, generations: &[u64], next: u64) -> Result<Vec<PathBuf>> {
+        let dirs: HashSet<PathBuf> = generations
+            .iter()
+            .chain(iter::once(&next))
+            .filter_map(|&generation| self.path(generation).parent().map(Path::to_owned))
+            .collect();
+
+        let mut tmp_paths = vec![self.checkpoint_path().with_extension("tmp")];
+        for dir in dirs {
+            let entries = match fs::read_dir(&dir) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                entries => entries?,
+            };
+            for entry in entries {
+                let path = entry?.path();
+                let Some(generation_path) = path.to_str().and_then(|p| p.strip_suffix(".tmp"))
+                else {
+                    continue;
+                };
+                if generation_path
+                    .split(|c: char| !c.is_ascii_digit())
+                    .filter_map(|digits| digits.parse().ok())
+                    .any(|generation| self.path(generation) == Path::new(generation_path))
+                {
+                    tmp_paths.push(path);
+                }
+            }
+        }
+        Ok(tmp_paths)
+    }
+
+    fn checkpoint_path(&self) -> PathBuf {
+        self.dir_path.join(match &self.namespace {
+            Some(namespace) => format!("{namespace}-index.ckpt"),
+            None => "index.ckpt".to_owned(),
+        })
+    }
+
+    /// `{extension}.manifest`, there is one manifest for each encoding as generation files
+    /// of different encodings are separate stores
+    fn manifest_path(&self) -> PathBuf {
+        let extension = self.encoding.extension();
+        self.dir_path.join(match &self.namespace {
+            Some(namespace) => format!("{namespace}-{extension}.manifest"),
+            None => format!("{extension}.manifest"),
+        })
+    }
+
+    /// fail if the manifest records a newer format version, a missing or older manifest
+    /// is written with the version of this build when `options` is given
+    fn check_manifest(&self, options: Option<&KvStoreOptions>) -> Result<()> {
+        let found = match fs::read(self.manifest_path()) {
+            Ok(manifest) => {
+                Some(serde_json::from_slice::<ManifestVersion>(&manifest)?.format_version)
+            }
+            // generation files written before manifests existed are version 1
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        match found {
+            Some(found) if found > FORMAT_VERSION => Err(KvsError::IncompatibleFormat {
+                found,
+                expected: FORMAT_VERSION,
+            }),
+            Some(FORMAT_VERSION) => Ok(()),
+            // older files are readable, while records written from now on may not be
+            // readable by older builds
+            _ => {
+                if let Some(options) = options {
+                    let manifest = Manifest {
+                        format_version: FORMAT_VERSION,
+                        encoding: options.encoding,
+                        value_compression: options.value_compression,
+                    };
+                    fs::write(self.manifest_path(), serde_json::to_vec_pretty(&manifest)?)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// the checkpoint if it matches generation files on disk, `None` if it is missing,
+    /// stale or corrupt
+    fn load_checkpoint(&self, generations: &[u64]) -> Option<Checkpoint> {
+        let file = File::open(self.checkpoint_path()).ok()?;
+        let (_, checkpoint) = self
+            .encoding
+            // a checkpoint holds every key, so it isn't bound by the record length
+            .decode_stream::<Checkpoint, _>(BufReader::new(file), u64::MAX)
+            .next()?
+            .ok()?;
+
+        let covered: Vec<u64> = generations
+            .iter()
+            .copied()
+            .take_while(|&generation| generation <= checkpoint.generation)
+            .collect();
+        let file_len = fs::metadata(self.path(checkpoint.generation)).ok()?.len();
+        (covered == checkpoint.generations && file_len >= checkpoint.offset).then_some(checkpoint)
+    }
+
+    /// sorted generations of this namespace, files of other namespaces are skipped
+    fn generations(&self) -> Result<Vec<u64>> {
+        let mut result = self.layout.generations(&self.context())?;
+        result.sort_unstable();
+        result.dedup();
+        Ok(result)
+    }
+}
+
+impl KvStore {
+    /// open a new [`KvStore`] with default options
+    /// `path` is a directory path
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// open a new [`KvStore`] with `options`
+    /// `path` is a directory path
+    ///
+    /// a store already open in this process is shared rather than opened again, opening it
+    /// with other options fails with [`KvsError::OptionsMismatch`]
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
+        Self::open_namespace(path.into(), None, options)
+    }
+
+    /// open the [`KvStore`] in `path` without ever writing to its directory,
+    /// writes and compactions fail with [`KvsError::ReadOnly`]
+    ///
+    /// no generation file is created, so reads see the files as they were on opening,
+    /// the checkpoint is loaded if enabled but never saved
+    pub fn open_read_only(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
+        Self::open_namespace(
+            path.into(),
+            None,
+            KvStoreOptions {
+                read_only: true,
+                ..options
+            },
+        )
+    }
+
+    /// open the namespace `name` in the directory of this store with the same options,
+    /// a namespace is a separate store with its own index and generation files
+    ///
+    /// `name` contains only ascii letters, digits and `_`
+    pub fn namespace(&self, name: &str) -> Result<Self> {
+        if !layout::is_valid_namespace(name) {
+            return Err(KvsError::InvalidNamespace(name.to_owned()));
+        }
+
+        Self::open_namespace(
+            self.reader.files.dir_path.clone(),
+            Some(name.to_owned()),
+            self.options.clone(),
+        )
+    }
+
+    /// open a namespace, or clone it if it is open already, read-only stores are never
+    /// shared as they never write
+    fn open_namespace(
+        path: PathBuf,
+        namespace: Option<String>,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
+        if options.read_only {
+            return Self::load(path, namespace, options);
+        }
+        fs::create_dir_all(path.as_path())?;
+
+        // the store is reserved while loading, so a store opened by two threads at once
+        // is loaded once, while other stores open meanwhile
+        let key = (fs::canonicalize(&path)?, namespace.clone());
+        let mut open_stores = OPEN_STORES.lock().unwrap();
+        loop {
+            open_stores.retain(|_, store| match store {
+                OpenStore::Loading => true,
+                OpenStore::Open(store) => store.writer.strong_count() > 0,
+            });
+            match open_stores.get(&key) {
+                Some(OpenStore::Loading) => open_stores = STORE_LOADED.wait(open_stores).unwrap(),
+                Some(OpenStore::Open(store)) => match store.upgrade() {
+                    Some(store) if !store.options.same_as(&options) => {
+                        return Err(KvsError::OptionsMismatch)
+                    }
+                    Some(store) => return Ok(store),
+                    // dropped since
+                    None => break,
+                },
+                None => break,
+            }
+        }
+        open_stores.insert(key.clone(), OpenStore::Loading);
+        drop(open_stores);
+
+        let loading = LoadingStore { key: Some(key) };
+        let store = Self::load(path, namespace, options)?;
+        loading.publish(&store);
+        Ok(store)
+    }
+
+    /// load the index of a namespace from its files
+    fn load(path: PathBuf, namespace: Option<String>, options: KvStoreOptions) -> Result<Self> {
+        let read_only = options.read_only;
+        let files = Arc::new(GenerationFiles::new(path, namespace, &options));
+        files.check_manifest((!read_only).then_some(&options))?;
+        let safe_generation = Arc::new(AtomicU64::new(0));
+        let kv = options.index.build();
+        let mut log_size = LogSize::default();
+        let generations = files.generations()?;
+        let mut allocator = GenerationAllocator::after(&generations);
+        // a crash during a compaction or a checkpoint may leave its temporary file partial
+        if !read_only {
+            for tmp_path in files.stale_tmp_paths(&generations, allocator.peek())? {
+                match fs::remove_file(tmp_path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        // a read-only store stays at the end of the last generation
+        let writer_offset = match generations.last() {
+            Some(&last) if read_only => (last, fs::metadata(files.path(last))?.len()),
+            _ => (allocator.allocate(), 0),
+        };
+
+        let mut start = (0, 0);
+        let mut next_version = 0;
+        if let Some(checkpoint) = options
+            .checkpoint
+            .then(|| files.load_checkpoint(&generations))
+            .flatten()
+        {
+            for CheckpointEntry(key, command_offset) in checkpoint.entries {
+                kv.insert(key, command_offset);
+            }
+            log_size = checkpoint.log_size;
+            start = (checkpoint.generation, checkpoint.offset);
+            next_version = checkpoint.next_version;
+        }
+
+        let mut replayed = Vec::new();
+        for generation in generations {
+            let offset = match generation.cmp(&start.0) {
+                cmp::Ordering::Less => continue,
+                cmp::Ordering::Equal => start.1,
+                cmp::Ordering::Greater => 0,
+            };
+            let len = fs::metadata(files.path(generation))?.len();
+            replayed.push((generation, offset, len.saturating_sub(offset)));
+        }
+        let mut progress = Progress::new(
+            options.progress.as_ref(),
+            replayed.iter().map(|(_, _, len)| len).sum(),
+        );
+        for (generation, offset, _) in replayed {
+            Self::load_command_file(
+                &files,
+                (generation, offset),
+                &*kv,
+                &mut log_size,
+                &mut next_version,
+                None,
+                &mut progress,
+            )?;
+        }
+        progress.finish();
+
+        let bloom = options.bloom_filter.then(|| {
+            // leave room for keys written after opening
+            let bloom = BloomFilter::with_capacity(kv.len() * 2);
+            kv.for_each(&mut |key, _| bloom.insert(key));
+            Arc::new(bloom)
+        });
+
+        // nothing to persist together when records are left in the buffer
+        let group_commit = options
+            .group_commit
+            .filter(|_| options.durability != DurabilityMode::None)
+            .map(|window| {
+                Arc::new(GroupCommit {
+                    window,
+                    state: Mutex::default(),
+                    done: Condvar::new(),
+                })
+            });
+
+        let writer = KvStoreWriter::new(
+            kv.clone(),
+            bloom.clone(),
+            files.clone(),
+            &options,
+            safe_generation.clone(),
+            writer_offset,
+            (log_size, next_version),
+        )?;
+        Ok(Self {
+            kv,
+            bloom,
+            durability: options.durability,
+            reader: KvStoreReader::new(
+                files,
+                options.use_mmap,
+                safe_generation,
+                writer.values.clone(),
+            ),
+            writer: Arc::new(Mutex::new(writer)),
+            group_commit,
+            options,
+        })
+    }
+
+    /// replay the records of a generation from `(generation, start)` into `kv`, stamped
+    /// from `next_version`, a record which can't be decoded fails loading, or ends it
+    /// and is pushed to `corrupt` if given
+    fn load_command_file(
+        files: &GenerationFiles,
+        (generation, start): LogPosition,
+        kv: &dyn Index<CommandOffset>,
+        log_size: &mut LogSize,
+        next_version: &mut u64,
+        corrupt: Option<&mut Vec<CorruptRecord>>,
+        progress: &mut Progress<'_>,
+    ) -> Result<()> {
+        let mut applied = LogSize::default();
+        let mut end = start;
+        let scanned = Self::scan_command_file(
+            files,
+            generation,
+            start,
+            corrupt,
+            next_version,
+            |offset, len, version, command| {
+                progress.advance((offset + len).saturating_sub(end));
+                end = end.max(offset + len);
+                let command_offset = CommandOffset {
+                    generation,
+                    offset,
+                    len,
+                    expire_at: None,
+                    version,
+                    // without a span the value is read through the whole record
+                    value: files.encoding.value_span(&command),
+                };
+                Self::replay_command(command_offset, command, kv, &mut applied)
+            },
+        )?;
+        log_size.total += scanned.total;
+        log_size.garbage += scanned.garbage + applied.garbage;
+
+        Ok(())
+    }
+
+    /// call `f` with the offset, length, version stamp and record of each set or remove
+    /// of `generation` from `start`, records of a transaction once its commit record
+    /// is read, stamps are counted from `next_version`, see `Command::Version`
+    ///
+    /// returns the bytes scanned, where the transaction records and records of transactions
+    /// missing their commit record are garbage
+    fn scan_command_file(
+        files: &GenerationFiles,
+        generation: u64,
+        start: u64,
+        mut corrupt: Option<&mut Vec<CorruptRecord>>,
+        next_version: &mut u64,
+        mut f: impl FnMut(u64, u64, u64, Command),
+    ) -> Result<LogSize> {
+        let mut reader = BufReader::with_capacity(
+            files.read_buffer_size,
+            File::options().read(true).open(files.path(generation))?,
+        );
+        reader.seek(io::SeekFrom::Start(start))?;
+
+        let mut command_iter = files
+            .encoding
+            .decode_stream::<Command, _>(&mut reader, files.max_record_len);
+        let mut log_size = LogSize::default();
+        // records after a begin record, applied once its commit record is read
+        let mut staged: Option<Vec<(u64, u64, u64, Command)>> = None;
+
+        loop {
+            let record_start = start + command_iter.byte_offset();
+            let (offset, command) = match (command_iter.next(), corrupt.as_deref_mut()) {
+                (None, _) => break,
+                (Some(Ok(command)), _) => command,
+                (Some(Err(_)), Some(corrupt)) => {
+                    // nothing after a corrupt record can be trusted
+                    corrupt.push(CorruptRecord::new(generation, record_start, None));
+                    break;
+                }
+                (Some(Err(e)), None) => return Err(e),
+            };
+            let len = command_iter.byte_offset() - offset;
+            let offset = start + offset;
+            match command {
+                Command::Begin => {
+                    log_size.garbage += len;
+                    staged = Some(Vec::new());
+                }
+                Command::Commit => {
+                    log_size.garbage += len;
+                    for (offset, len, version, command) in staged.take().unwrap_or_default() {
+                        f(offset, len, version, command);
+                    }
+                }
+                Command::Version { version } => *next_version = version,
+                command => {
+                    // as stamped by the writer, records of a transaction are stamped in order
+                    let version = *next_version;
+                    *next_version += 1;
+                    match &mut staged {
+                        Some(staged) => staged.push((offset, len, version, command)),
+                        None => f(offset, len, version, command),
+                    }
+                }
+            }
+        }
+        // a transaction cut short before its commit record never happened
+        if let Some(staged) = staged {
+            log_size.garbage += staged.iter().map(|(_, len, ..)| len).sum::<u64>();
+        }
+        log_size.total = command_iter.byte_offset();
+
+        Ok(log_size)
+    }
+
+    /// apply a set or remove record at `command_offset` to `kv`, its expiry is the one
+    /// of the record
+    fn replay_command(
+        command_offset: CommandOffset,
+        command: Command,
+        kv: &dyn Index<CommandOffset>,
+        log_size: &mut LogSize,
+    ) {
+        let (key, expire_at) = match command {
+            Command::Set { key, .. } => (key, None),
+            Command::SetEx { key, expire_at, .. } => (key, Some(expire_at)),
+            Command::SetCompressed { key, expire_at, .. } => (key, expire_at),
+            Command::Remove { key } => {
+                if let Some(old) = kv.remove(&key) {
+                    log_size.garbage += old.len;
+                }
+                log_size.garbage += command_offset.len;
+                return;
+            }
+            Command::Begin | Command::Commit | Command::Version { .. } => unreachable!(),
+        };
+
+        if let Some(old) = kv.get(&key) {
+            log_size.garbage += old.len;
+        }
+        if expire_at.is_some_and(ttl::is_expired) {
+            kv.remove(&key);
+            log_size.garbage += command_offset.len;
+        } else {
+            kv.insert(
+                key,
+                CommandOffset {
+                    expire_at,
+                    ..command_offset
+                },
+            );
+        }
+    }
+
+    /// check the store in `path` opened with default options, see
+    /// [`KvStore::verify_with_options`]
+    pub fn verify(path: impl Into<PathBuf>) -> Result<VerifyReport> {
+        Self::verify_with_options(path, &KvStoreOptions::default())
+    }
+
+    /// replay all generations of the store in `path`, checking each record can be decoded
+    /// and each live value can be read, nothing is written
+    ///
+    /// `options` are those the store is opened with, its checkpoint is ignored
+    /// and only the default namespace is checked
+    pub fn verify_with_options(
+        path: impl Into<PathBuf>,
+        options: &KvStoreOptions,
+    ) -> Result<VerifyReport> {
+        let files = Arc::new(GenerationFiles::new(path.into(), None, options));
+        files.check_manifest(None)?;
+        let generations = files.generations()?;
+        let kv = options.index.build();
+        let mut log_size = LogSize::default();
+        let mut corrupt_records = Vec::new();
+        let mut corrupt_bytes = 0;
+        let mut written_generations = Vec::new();
+        let mut next_version = 0;
+
+        for &generation in &generations {
+            let loaded = log_size.total;
+            Self::load_command_file(
+                &files,
+                (generation, 0),
+                &*kv,
+                &mut log_size,
+                &mut next_version,
+                Some(&mut corrupt_records),
+                &mut Progress::new(None, 0),
+            )?;
+            let file_len = fs::metadata(files.path(generation))?.len();
+            corrupt_bytes += file_len - (log_size.total - loaded);
+            if file_len > 0 {
+                written_generations.push(generation);
+            }
+        }
+
+        let reader = KvStoreReader::new(files, false, Arc::new(AtomicU64::new(0)), None);
+        let mut live_generations = HashSet::new();
+        let mut live_keys = 0;
+        kv.for_each(
+            &mut |key, command_offset| match reader.get(command_offset) {
+                Ok(_) => {
+                    live_keys += 1;
+                    live_generations.insert(command_offset.generation);
+                }
+                Err(_) => {
+                    corrupt_records.push(CorruptRecord::new(
+                        command_offset.generation,
+                        command_offset.offset,
+                        Some(key.to_vec()),
+                    ));
+                    log_size.garbage += command_offset.len;
+                }
+            },
+        );
+
+        Ok(VerifyReport {
+            generations: generations.len(),
+            live_keys,
+            corrupt_records,
+            orphaned_generations: written_generations
+                .into_iter()
+                .filter(|generation| !live_generations.contains(generation))
+                .collect(),
+            unreachable_bytes: log_size.garbage + corrupt_bytes,
+        })
+    }
+
+    /// repair the store in `path` opened with default options, see
+    /// [`KvStore::repair_with_options`]
+    pub fn repair(path: impl Into<PathBuf>) -> Result<VerifyReport> {
+        Self::repair_with_options(path, KvStoreOptions::default())
+    }
+
+    /// discard corrupt records of the store in `path` and compact it,
+    /// returns what [`KvStore::verify_with_options`] found before the repair
+    ///
+    /// a generation file is cut at its first record which can't be decoded, which loses
+    /// all records after it, and keys whose value can't be read are removed.
+    /// The store must not be open meanwhile
+    pub fn repair_with_options(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<VerifyReport> {
+        let path = path.into();
+        let report = Self::verify_with_options(path.clone(), &options)?;
+        if report.is_clean() {
+            return Ok(report);
+        }
+
+        let files = GenerationFiles::new(path.clone(), None, &options);
+        for record in &report.corrupt_records {
+            if record.key.is_none() {
+                File::options()
+                    .write(true)
+                    .open(files.path(record.generation))?
+                    .set_len(record.offset)?;
+            }
+        }
+        // the checkpoint may cover records which were cut
+        match fs::remove_file(files.checkpoint_path()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+
+        let store = Self::open_with_options(path, options)?;
+        for record in &report.corrupt_records {
+            if let Some(key) = record.key_bytes() {
+                store.remove_bytes(key.to_vec())?;
+            }
+        }
+        store.compact()?;
+        Ok(report)
+    }
+
+    /// compact generation files now regardless of the compaction policy
+    pub fn compact(&self) -> Result<CompactionReport> {
+        self.writer.lock().unwrap().compaction()
+    }
+
+    /// what [`KvStore::compact`] would reclaim now, without reading or writing records
+    ///
+    /// records are copied as they are by compaction, so the estimate matches its report
+    /// unless keys are written or expire in between
+    pub fn compaction_estimate(&self) -> Result<CompactionEstimate> {
+        let writer = self.writer.lock().unwrap();
+        let mut versions = Vec::new();
+        let mut live_bytes = 0;
+        self.kv.for_each(&mut |_, command_offset| {
+            if !command_offset.is_expired() {
+                versions.push(command_offset.version);
+                live_bytes += command_offset.len;
+            }
+        });
+        let live_keys = versions.len();
+        versions.sort_unstable();
+        live_bytes += version_records_len(
+            writer.files.encoding,
+            versions.into_iter(),
+            writer.next_version,
+        )?;
+        Ok(CompactionEstimate {
+            live_keys,
+            bytes_before: writer.log_size.total,
+            live_bytes,
+            reclaimable_bytes: writer.log_size.total.saturating_sub(live_bytes),
+            generations: writer.files.generations()?.len(),
+        })
+    }
+
+    /// call `hook` with the report of each compaction from now on, whether triggered by
+    /// the compaction policy or by [`KvStore::compact`], replacing an earlier hook
+    ///
+    /// the hook is shared by all clones and runs under the writer lock once compaction
+    /// is done, writing to the store from it deadlocks
+    pub fn on_compaction(&self, hook: Arc<dyn Fn(&CompactionReport) + Send + Sync>) {
+        self.writer.lock().unwrap().compaction_hook = Some(hook);
+    }
+
+    /// remove all keys and generation files
+    ///
+    /// a reader which looked up a key just before the store is cleared
+    /// may still return its old value
+    pub fn clear(&self) -> Result<()> {
+        self.writer.lock().unwrap().clear()
+    }
+
+    /// collect runtime statistics
+    pub fn stats(&self) -> KvStoreStats {
+        let writer = self.writer.lock().unwrap();
+
+        let disk_bytes = writer
+            .files
+            .generations()
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|generation| fs::metadata(writer.files.path(generation)))
+            .map(|metadata| metadata.len())
+            .sum();
+
+        KvStoreStats {
+            live_keys: self.kv.len(),
+            disk_bytes,
+            uncompaction_size: writer.uncompaction_size,
+            garbage_size: writer.log_size.garbage,
+            writer_generation: writer.writer_offset.generation,
+            compaction_count: writer.compaction_count,
+        }
+    }
+
+    /// every set and remove of `key` in the generation files, oldest first
+    ///
+    /// this scans the whole log under the writer lock, so it takes time in the size of the
+    /// log and is meant for debugging. Compaction keeps only the live value of each key,
+    /// so writes before the last compaction are lost, and writes of a transaction are
+    /// listed once committed
+    pub fn history(&self, key: &str) -> Result<Vec<HistoryEntry>> {
+        let mut writer = self.writer.lock().unwrap();
+        // buffered records are part of the history too
+        writer.flush()?;
+
+        let mut commands = Vec::new();
+        for generation in writer.files.generations()? {
+            // stamps aren't listed
+            let mut next_version = 0;
+            let files = &writer.files;
+            Self::scan_command_file(
+                files,
+                generation,
+                0,
+                None,
+                &mut next_version,
+                |_, _, _, command| {
+                    let found = match &command {
+                        Command::Set { key: k, .. }
+                        | Command::SetEx { key: k, .. }
+                        | Command::SetCompressed { key: k, .. }
+                        | Command::Remove { key: k } => k == key.as_bytes(),
+                        Command::Begin | Command::Commit | Command::Version { .. } => false,
+                    };
+                    if found {
+                        commands.push(command);
+                    }
+                },
+            )?;
+        }
+
+        commands
+            .into_iter()
+            .map(|command| {
+                Ok(match command {
+                    Command::Set { value, .. } => HistoryEntry::Set {
+                        value,
+                        expire_at: None,
+                    },
+                    Command::SetEx {
+                        value, expire_at, ..
+                    } => HistoryEntry::Set {
+                        value,
+                        expire_at: Some(expire_at),
+                    },
+                    Command::SetCompressed {
+                        value, expire_at, ..
+                    } => HistoryEntry::Set {
+                        value: Compression::decompress(&value)?,
+                        expire_at,
+                    },
+                    _ => HistoryEntry::Remove,
+                })
+            })
+            .collect()
+    }
+
+    /// position right after the last record written, see [`KvStore::read_from`]
+    pub fn current_position(&self) -> LogPosition {
+        let writer = self.writer.lock().unwrap();
+        (writer.writer_offset.generation, writer.writer_offset.offset)
+    }
+
+    /// a channel receiving a [`WatchEvent`] for each set or removal of `key` from now on,
+    /// in the order of the writes
+    ///
+    /// events are sent under the writer lock once a write is applied, expiry of a key
+    /// sends no event. The channel is unbounded, so a receiver left unread buffers
+    /// events, a dropped receiver is forgotten on the next write of the key
+    pub fn watch(&self, key: String) -> Receiver<WatchEvent> {
+        let (sender, receiver) = channel::unbounded();
+        self.writer
+            .lock()
+            .unwrap()
+            .watchers
+            .entry(key.into_bytes())
+            .or_default()
+            .push(sender);
+        receiver
+    }
+
+    /// records written after `position`, in order, each with the position following it
+    /// to resume from. Records of a transaction are read once it is committed
+    ///
+    /// the end of each generation is taken under the writer lock when reached, so records written
+    /// meanwhile may or may not be read. Positions only hold until the next compaction,
+    /// which moves live records to a new generation and deletes the older ones, reading
+    /// from a deleted generation fails with [`KvsError::StalePosition`], and a follower
+    /// has to copy the whole store again
+    pub fn read_from(
+        &self,
+        position: LogPosition,
+    ) -> Result<impl Iterator<Item = Result<(LogPosition, LogRecord)>> + '_> {
+        let (first, offset) = position;
+        let generations = self.writer.lock().unwrap().files.generations()?;
+        if !generations.contains(&first) {
+            return Err(KvsError::StalePosition);
+        }
+
+        Ok(generations
+            .into_iter()
+            .filter(move |&generation| generation >= first)
+            .flat_map(move |generation| {
+                let start = if generation == first { offset } else { 0 };
+                let records: Box<dyn Iterator<Item = _>> =
+                    match self.read_generation(generation, start) {
+                        Ok(records) => Box::new(records),
+                        Err(e) => Box::new(iter::once(Err(e))),
+                    };
+                records
+            }))
+    }
+
+    /// records of `generation` from `start`, see [`KvStore::read_from`]
+    ///
+    /// only the end of the generation is taken under the writer lock, its records are
+    /// read lazily after it is released
+    fn read_generation(&self, generation: u64, start: u64) -> Result<GenerationRecords> {
+        let end = {
+            let mut writer = self.writer.lock().unwrap();
+            // buffered records are part of the log too
+            writer.flush()?;
+            if generation == writer.writer_offset.generation {
+                writer.writer_offset.offset
+            } else {
+                // no longer written
+                u64::MAX
+            }
+        };
+
+        match self.reader.records(generation, start, end) {
+            Err(KvsError::StdIo(e)) if e.kind() == io::ErrorKind::NotFound => {
+                Err(KvsError::StalePosition)
+            }
+            records => records,
+        }
+    }
+
+    /// dump live key-value pairs to `w` as a stream of json records,
+    /// which is independent of generation files and can be loaded by [`KvStore::import`]
+    pub fn export<W: Write>(&self, w: W) -> Result<()> {
+        let mut w = BufWriter::new(w);
+        let mut buf = Vec::new();
+
+        // values are read outside of the index, which they may look keys up in,
+        // so the keys are collected first
+        let mut keys = Vec::with_capacity(self.kv.len());
+        self.kv.for_each(&mut |key, _| keys.push(key.to_vec()));
+        for key in keys {
+            let command_offset = match self.kv.get(&key) {
+                Some(command_offset) if !command_offset.is_expired() => command_offset,
+                _ => continue,
+            };
+
+            let value = match self.read_value(&key, command_offset)? {
+                Some(value) => value,
+                None => continue,
+            };
+            let command = Command::set(key, value, command_offset.expire_at);
+
+            buf.clear();
+            Encoding::Json.encode(&mut buf, &command)?;
+            w.write_all(&buf)?;
+        }
+
+        w.flush()?;
+        Ok(())
+    }
+
+    /// open a [`KvStore`] at `path` and load records produced by [`KvStore::export`] into it
+    pub fn import<R: Read>(path: impl Into<PathBuf>, reader: R) -> Result<Self> {
+        let store = Self::open(path)?;
+
+        {
+            let mut writer = store.writer.lock().unwrap();
+            let max_record_len = writer.files.max_record_len;
+            for command in
+                Encoding::Json.decode_stream::<Command, _>(BufReader::new(reader), max_record_len)
+            {
+                match command?.1 {
+                    Command::Set { key, value } => writer.set(key, value, None)?,
+                    Command::SetEx {
+                        key,
+                        value,
+                        expire_at,
+                    } if !ttl::is_expired(expire_at) => writer.set(key, value, Some(expire_at))?,
+                    Command::SetEx { .. } => {}
+                    Command::SetCompressed {
+                        key,
+                        value,
+                        expire_at,
+                    } if !expire_at.is_some_and(ttl::is_expired) => {
+                        writer.set(key, Compression::decompress(&value)?, expire_at)?
+                    }
+                    Command::SetCompressed { .. } => {}
+                    Command::Remove { key } => match writer.remove(key) {
+                        Ok(()) | Err(KvsError::KeyNotFound) => {}
+                        Err(e) => return Err(e),
+                    },
+                    // exports hold no transactions or stamps, each record stands alone
+                    Command::Begin | Command::Commit | Command::Version { .. } => {}
+                }
+            }
+        }
+
+        Ok(store)
+    }
+
+    /// set a key-value pair which expires after `ttl`
+    ///
+    /// expired keys are removed lazily when accessed and dropped by compaction
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expire_at = ttl::expire_at(ttl);
+        self.write(|writer| writer.set(key.into_bytes(), value, Some(expire_at)))
+    }
+
+    /// apply all `ops` in order under one acquisition of the writer lock, and persist them
+    /// together as required by the durability mode, removing an absent key does nothing
+    ///
+    /// ops before a failed one are kept, readers may see some ops before the batch returns
+    pub fn write_batch(&self, ops: impl IntoIterator<Item = BatchOp>) -> Result<()> {
+        self.write(|writer| {
+            let result = writer.unsynced(|writer| {
+                for op in ops {
+                    match op {
+                        BatchOp::Set { key, value } => {
+                            self.set_locked(writer, key.into_bytes(), value)?
+                        }
+                        BatchOp::Remove { key } => match writer.remove(key.into_bytes()) {
+                            Err(KvsError::KeyNotFound) => {}
+                            result => result?,
+                        },
+                    }
+                }
+                Ok(())
+            });
+            writer.sync()?;
+            result
+        })
+    }
+
+    /// remove every key starting with `prefix`, returns the number of keys removed
+    ///
+    /// keys are removed under the writer lock, so no other write slips in between,
+    /// and the remove records are persisted together as required by the durability mode
+    pub fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        self.write(|writer| {
+            let result = writer.unsynced(|writer| {
+                let mut removed = 0;
+                for key in self.kv.keys_with_prefix(prefix.as_bytes()) {
+                    match writer.remove(key) {
+                        Ok(()) => removed += 1,
+                        // expired
+                        Err(KvsError::KeyNotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(removed)
+            });
+            writer.sync()?;
+            result
+        })
+    }
+
+    /// run `f` with a transaction, and apply its buffered writes once `f` returns `Ok`,
+    /// nothing is written if it fails
+    ///
+    /// `f` runs under the writer lock, so no other write slips in between its reads and
+    /// the commit. The writes are enclosed by begin and commit records written at once,
+    /// readers and a reload see either none or all of them
+    pub fn transaction<F: FnOnce(&mut Txn<'_>) -> Result<()>>(&self, f: F) -> Result<()> {
+        self.write(|writer| {
+            let mut txn = Txn::new(|key| self.get_locked(writer, key.as_bytes()));
+            f(&mut txn)?;
+            let ops = txn.into_ops();
+            let ops = self.dedup_ops(writer, ops)?;
+            writer.commit(ops)
+        })
+    }
+
+    /// run `f` under the writer lock, with group commit its writes are persisted together
+    /// with the writes of other threads arriving within the window
+    ///
+    /// every write goes through here, so that group commit holds for all of them
+    fn write<T>(&self, f: impl FnOnce(&mut KvStoreWriter) -> Result<T>) -> Result<T> {
+        let group_commit = match &self.group_commit {
+            Some(group_commit) => group_commit,
+            None => return f(&mut self.writer.lock().unwrap()),
+        };
+
+        let (result, appended) = {
+            let mut writer = self.writer.lock().unwrap();
+            let result = writer.unsynced(f)?;
+            writer.appended_writes += 1;
+            (result, writer.appended_writes)
+        };
+        let mut state = group_commit.state.lock().unwrap();
+        while state.persisted < appended {
+            if state.leader {
+                state = group_commit.done.wait(state).unwrap();
+                continue;
+            }
+
+            // writes of other threads join until the window ends
+            state.leader = true;
+            drop(state);
+            thread::sleep(group_commit.window);
+            let synced = {
+                let mut writer = self.writer.lock().unwrap();
+                writer.sync().map(|_| writer.appended_writes)
+            };
+
+            state = group_commit.state.lock().unwrap();
+            state.leader = false;
+            group_commit.done.notify_all();
+            // the next waiting thread leads another try
+            state.persisted = synced?;
+        }
+        Ok(result)
+    }
+
+    /// replace the value of `key` by the result of `f` called with the current value,
+    /// the key is removed if `f` returns `None`
+    ///
+    /// `f` is called under the writer lock, so no other write can slip in between,
+    /// an expiry of the key is dropped when it is set
+    pub fn update<F: FnOnce(Option<String>) -> Option<String>>(
+        &self,
+        key: String,
+        f: F,
+    ) -> Result<()> {
+        self.write(|writer| {
+            let current = self.get_locked(writer, key.as_bytes())?;
+            let existed = current.is_some();
+            match f(current) {
+                Some(value) => self.set_locked(writer, key.into_bytes(), value),
+                None if existed => writer.remove(key.into_bytes()),
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// swap the values of `key_a` and `key_b`, both sets are written at once as with
+    /// [`transaction`](Self::transaction)
+    ///
+    /// both values are read under the writer lock, so no other write slips in between,
+    /// fails with [`KvsError::KeyNotFound`] before writing if either key is absent,
+    /// expiries of the keys are dropped
+    pub fn swap(&self, key_a: String, key_b: String) -> Result<()> {
+        self.write(|writer| {
+            let value_a = self
+                .get_locked(writer, key_a.as_bytes())?
+                .ok_or(KvsError::KeyNotFound)?;
+            let value_b = self
+                .get_locked(writer, key_b.as_bytes())?
+                .ok_or(KvsError::KeyNotFound)?;
+            let ops = vec![
+                BatchOp::Set {
+                    key: key_a,
+                    value: value_b,
+                },
+                BatchOp::Set {
+                    key: key_b,
+                    value: value_a,
+                },
+            ];
+            let ops = self.dedup_ops(writer, ops)?;
+            writer.commit(ops)
+        })
+    }
+
+    /// set `key` while holding the writer lock, skipped with
+    /// [`dedup_writes`](crate::KvStoreOptions::dedup_writes) if it is unchanged
+    fn set_locked(&self, writer: &mut KvStoreWriter, key: Vec<u8>, value: String) -> Result<()> {
+        if self.options.dedup_writes && self.is_unchanged(writer, &key, &value)? {
+            return Ok(());
+        }
+        writer.set(key, value, None)
+    }
+
+    /// `ops` without the sets left unchanged, with
+    /// [`dedup_writes`](crate::KvStoreOptions::dedup_writes), a key written by several ops
+    /// keeps all of them as they change it in turn
+    fn dedup_ops(&self, writer: &mut KvStoreWriter, ops: Vec<BatchOp>) -> Result<Vec<BatchOp>> {
+        if !self.options.dedup_writes {
+            return Ok(ops);
+        }
+        let mut writes = HashMap::<&str, usize>::new();
+        for op in &ops {
+            let (BatchOp::Set { key, .. } | BatchOp::Remove { key }) = op;
+            *writes.entry(key).or_default() += 1;
+        }
+        let mut unchanged = Vec::with_capacity(ops.len());
+        for op in &ops {
+            unchanged.push(match op {
+                BatchOp::Set { key, value } if writes[key.as_str()] == 1 => {
+                    self.is_unchanged(writer, key.as_bytes(), value)?
+                }
+                _ => false,
+            });
+        }
+        Ok(ops
+            .into_iter()
+            .zip(unchanged)
+            .filter_map(|(op, unchanged)| (!unchanged).then_some(op))
+            .collect())
+    }
+
+    /// `true` if setting `key` to `value` would write the same pair again
+    fn is_unchanged(&self, writer: &mut KvStoreWriter, key: &[u8], value: &str) -> Result<bool> {
+        // a set drops the expiry, so it changes a key with one
+        if self.kv.get(key).is_none_or(|o| o.expire_at.is_some()) {
+            return Ok(false);
+        }
+        Ok(self.get_locked(writer, key)?.as_deref() == Some(value))
+    }
+
+    /// live value of `key` read while holding the writer lock
+    fn get_locked(&self, writer: &mut KvStoreWriter, key: &[u8]) -> Result<Option<String>> {
+        let command_offset = match self.kv.get(key) {
+            Some(o) if !o.is_expired() => o,
+            _ => return Ok(None),
+        };
+        // compaction is excluded by the lock, only a buffered record can fail to read
+        match self.reader.get(command_offset) {
+            Err(_) if self.durability == DurabilityMode::None => {
+                writer.flush()?;
+                self.reader.get(command_offset)
+            }
+            result => result,
+        }
+        .map(Some)
+    }
+
+    /// iterate over all live key-value pairs, in key order with [`IndexKind::SkipMap`]
+    ///
+    /// keys are collected when called and each value is read when reached,
+    /// so writes made during the iteration may or may not be observed,
+    /// keys removed meanwhile are skipped, as are keys set by [`BytesKvsEngine`] which
+    /// aren't utf-8
+    ///
+    /// [`IndexKind::SkipMap`]: crate::IndexKind::SkipMap
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let mut keys = Vec::with_capacity(self.kv.len());
+        self.kv.for_each(&mut |key, _| keys.push(key.to_vec()));
+        keys.into_iter().filter_map(move |key| {
+            let key = String::from_utf8(key).ok()?;
+            match self.get(key.clone()) {
+                Ok(Some(value)) => Some(Ok((key, value))),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            }
+        })
+    }
+
+    /// up to `limit` live pairs in key order after the key `start_after`, or from the first
+    /// key, with the cursor to pass as `start_after` for the next page, `None` once every
+    /// key was returned
+    ///
+    /// the cursor is the last key of the page, so writes made between pages are seen by
+    /// later pages only if their keys are after it. With [`IndexKind::HashMap`] each page
+    /// sorts the keys of the index. Keys set by [`BytesKvsEngine`] which aren't utf-8
+    /// are skipped
+    ///
+    /// [`IndexKind::HashMap`]: crate::IndexKind::HashMap
+    pub fn scan_paginated(&self, start_after: Option<String>, limit: usize) -> Result<ScanPage> {
+        let mut pairs = Vec::with_capacity(limit);
+        let mut cursor = start_after.map(String::into_bytes);
+        // keys removed or expired after being listed are skipped, and more are listed
+        while pairs.len() < limit {
+            let wanted = limit - pairs.len();
+            let keys = self.kv.keys_after(cursor.as_deref(), wanted);
+            let exhausted = keys.len() < wanted;
+            for key in keys {
+                if let Ok(utf8_key) = String::from_utf8(key.clone()) {
+                    if let Some(value) = self.get_bytes(key.clone())? {
+                        pairs.push((utf8_key, value));
+                    }
+                }
+                cursor = Some(key);
+            }
+            if exhausted {
+                return Ok((pairs, None));
+            }
+        }
+        // a full page returned its last key, which is utf-8
+        Ok((
+            pairs,
+            cursor.map(|key| String::from_utf8_lossy(&key).into_owned()),
+        ))
+    }
+
+    /// get a reader over the value of `key`, which streams the value from its
+    /// generation file with [`Encoding::Bincode`] instead of holding it in memory
+    ///
+    /// values encoded as json or compressed are read into memory. A streamed value stays
+    /// readable when its generation is compacted meanwhile, as the reader has its own
+    /// handle of the file, but this is platform dependent
+    pub fn get_reader(&self, key: String) -> Result<Option<impl Read + Send>> {
+        let key = key.as_bytes();
+        let command_offset = match self.live_offset(key) {
+            Some(o) => o,
+            None => return Ok(None),
+        };
+
+        if self.options.encoding == Encoding::Bincode {
+            if self.durability == DurabilityMode::None {
+                // the record may still be buffered
+                self.writer.lock().unwrap().flush()?;
+            }
+            match self.follow_moves(key, command_offset, |o| self.reader.open_value(o))? {
+                Some(Some(reader)) => return Ok(Some(ValueReader::File(reader))),
+                Some(None) => {}
+                None => return Ok(None),
+            }
+        }
+
+        Ok(self
+            .read_value(key, command_offset)?
+            .map(|value| ValueReader::Memory(io::Cursor::new(value.into_bytes()))))
+    }
+
+    /// read the value of `key` into `buf`, returns `false` and leaves `buf` empty if the
+    /// key is absent or expired
+    ///
+    /// the record is decoded in a buffer kept by this handle, so a caller reusing `buf`
+    /// reads without allocating, unless the value is compressed or escaped in json
+    pub fn get_into(&self, key: &str, buf: &mut String) -> Result<bool> {
+        let key = key.as_bytes();
+        buf.clear();
+        let command_offset = match self.live_offset(key) {
+            Some(command_offset) => command_offset,
+            None => return Ok(false),
+        };
+        let mut read = |command_offset| self.reader.get_into(command_offset, buf);
+        match self.follow_moves(key, command_offset, &mut read) {
+            // the record may be buffered by the writer, see `KvStore::read_value`
+            Err(_) if self.durability == DurabilityMode::None => {
+                self.writer.lock().unwrap().flush()?;
+                Ok(self.follow_moves(key, command_offset, read)?.is_some())
+            }
+            result => Ok(result?.is_some()),
+        }
+    }
+
+    /// get the value of `key` as a string shared with the value cache, so reading a cached
+    /// value again copies nothing, see
+    /// [`KvStoreOptions::value_cache_capacity`](crate::KvStoreOptions::value_cache_capacity)
+    ///
+    /// without a cache, each call reads the value into a new string
+    pub fn get_shared(&self, key: &str) -> Result<Option<Arc<str>>> {
+        let key = key.as_bytes();
+        let command_offset = match self.live_offset(key) {
+            Some(command_offset) => command_offset,
+            None => return Ok(None),
+        };
+        let read = |command_offset| self.reader.get_shared(command_offset);
+        match self.follow_moves(key, command_offset, read) {
+            // the record may be buffered by the writer, see `KvStore::read_value`
+            Err(_) if self.durability == DurabilityMode::None => {
+                self.writer.lock().unwrap().flush()?;
+                self.follow_moves(key, command_offset, read)
+            }
+            result => result,
+        }
+    }
+
+    /// a reader which doesn't keep the store open, its reads fail with
+    /// [`KvsError::StoreClosed`] once this handle and all its clones are dropped
+    pub fn weak_reader(&self) -> WeakReader {
+        WeakReader {
+            kv: Arc::downgrade(&self.kv),
+            bloom: self.bloom.as_ref().map(Arc::downgrade),
+            writer: Arc::downgrade(&self.writer),
+            durability: self.durability,
+            reader: self.reader.clone(),
+        }
+    }
+
+    /// generation and offset of the record holding the value of `key`, `None` if the key
+    /// is absent or expired
+    ///
+    /// only the index is looked up, the position changes when the key is written again
+    /// or moved by compaction
+    pub fn locate(&self, key: &str) -> Option<(u64, u64)> {
+        self.live_offset(key.as_bytes())
+            .map(|command_offset| (command_offset.generation, command_offset.offset))
+    }
+
+    /// version stamp of the last write of `key`, `None` if the key is absent or expired
+    ///
+    /// stamps count the writes of the store, so a key written again gets a greater one.
+    /// They are recorded in the log, compaction and restarts keep them, see
+    /// [`KvsEngine::set_if_version`]
+    pub fn version(&self, key: &str) -> Option<u64> {
+        self.live_offset(key.as_bytes())
+            .map(|command_offset| command_offset.version)
+    }
+
+    /// byte length of the value of `key`, `None` if the key is absent
+    ///
+    /// a value stored as plain bytes with [`Encoding::Bincode`] isn't read, its length
+    /// is probed from the record's header. Values encoded as json or compressed are read
+    pub fn value_len(&self, key: String) -> Result<Option<u64>> {
+        let key = key.as_bytes();
+        let command_offset = match self.live_offset(key) {
+            Some(o) => o,
+            None => return Ok(None),
+        };
+
+        // known from the index when the value is stored as plain bytes
+        if let Some(value) = command_offset.value {
+            return Ok(Some(u64::from(value.len)));
+        }
+        Ok(self
+            .read_value(key, command_offset)?
+            .map(|value| value.len() as u64))
+    }
+
+    /// offset of the record of `key` unless the key is absent or expired
+    fn live_offset(&self, key: &[u8]) -> Option<CommandOffset> {
+        if !self.may_contain(key) {
+            return None;
+        }
+        let command_offset = self.kv.get(key)?;
+        if command_offset.is_expired() {
+            self.try_remove_expired(key, command_offset);
+            return None;
+        }
+        Some(command_offset)
+    }
+
+    /// call `f` with the offset of the record of `key`, again with the key's new offset
+    /// while the generation was deleted by compaction or clear, see `KvStore::read_value`.
+    /// `None` once the key is cleared
+    fn follow_moves<T>(
+        &self,
+        key: &[u8],
+        mut command_offset: CommandOffset,
+        mut f: impl FnMut(CommandOffset) -> Result<T>,
+    ) -> Result<Option<T>> {
+        loop {
+            match f(command_offset) {
+                Ok(t) => return Ok(Some(t)),
+                Err(KvsError::StdIo(e)) if e.kind() == io::ErrorKind::NotFound => {
+                    match self.kv.get(key) {
+                        Some(moved) if moved != command_offset => command_offset = moved,
+                        Some(_) => return Err(e.into()),
+                        None => return Ok(None),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// read the value of `key` from the record at `command_offset`, see [`read_value`]
+    fn read_value(&self, key: &[u8], command_offset: CommandOffset) -> Result<Option<String>> {
+        read_value(
+            &*self.kv,
+            &self.reader,
+            &self.writer,
+            self.durability,
+            key,
+            command_offset,
+        )
+    }
+
+    /// drop an expired entry found by a read, unless a write holds the writer lock,
+    /// so reads never wait on writes. The entry is dropped by a later read or compaction
+    fn try_remove_expired(&self, key: &[u8], command_offset: CommandOffset) {
+        if let Ok(mut writer) = self.writer.try_lock() {
+            writer.remove_expired(key, command_offset);
+        }
+    }
+
+    /// `false` if `key` is definitely absent, always `true` without a bloom filter
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.bloom
+            .as_ref()
+            .is_none_or(|bloom| bloom.may_contain(key))
+    }
+}
+
+/// read the value of `key` from the record at `command_offset`, which may still be
+/// buffered by the writer with [`DurabilityMode::None`]
+///
+/// the generation of the record may be deleted by compaction or clear after the offset
+/// is looked up, the index holds the key's new offset by then, or no offset if cleared
+fn read_value(
+    kv: &dyn Index<CommandOffset>,
+    reader: &KvStoreReader,
+    writer: &Mutex<KvStoreWriter>,
+    durability: DurabilityMode,
+    key: &[u8],
+    mut command_offset: CommandOffset,
+) -> Result<Option<String>> {
+    loop {
+        match reader.get(command_offset) {
+            Ok(value) => return Ok(Some(value)),
+            Err(KvsError::StdIo(e)) if e.kind() == io::ErrorKind::NotFound => match kv.get(key) {
+                Some(moved) if moved != command_offset => command_offset = moved,
+                Some(_) => return Err(e.into()),
+                None => return Ok(None),
+            },
+            Err(_) if durability == DurabilityMode::None => {
+                writer.lock().unwrap().flush()?;
+                return reader.get(command_offset).map(Some);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// summary of the store from its in-memory state, keys aren't listed.
+/// Writer fields are omitted while another thread holds the writer lock
+/// ```rust
+/// use kvs::{KvStore, KvsEngine};
+/// let dir = tempfile::TempDir::new().unwrap();
+/// let store = KvStore::open(dir.path()).unwrap();
+/// store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+/// let debug = format!("{:?}", store);
+/// assert!(debug.starts_with("KvStore { dir: "));
+/// assert!(debug.ends_with("live_keys: 1, writer_generation: 0, uncompaction_size: 39, .. }"));
+/// ```
+impl fmt::Debug for KvStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let files = &self.reader.files;
+        let mut f = f.debug_struct("KvStore");
+        f.field("dir", &files.dir_path);
+        if let Some(namespace) = &files.namespace {
+            f.field("namespace", namespace);
+        }
+        f.field("live_keys", &self.kv.len());
+        // never blocks, so the store can be printed while a write is in progress
+        if let Ok(writer) = self.writer.try_lock() {
+            f.field("writer_generation", &writer.writer_offset.generation)
+                .field("uncompaction_size", &writer.uncompaction_size);
+        }
+        f.finish_non_exhaustive()
+    }
+}
+
+impl KvsEngine for KvStore {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key_len = key.len()))
+    )]
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key_len = key.len()))
+    )]
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.into_bytes())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(key_len = key.len()))
+    )]
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        // checked under the writer lock, so no other write can slip in between
+        self.write(|writer| {
+            if self.kv.get(key.as_bytes()).is_some_and(|o| !o.is_expired()) {
+                return Ok(false);
+            }
+            writer.set(key.into_bytes(), value, None)?;
+            Ok(true)
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync_all()
+    }
+
+    fn version(&self, key: String) -> Result<Option<u64>> {
+        Ok(KvStore::version(self, &key))
+    }
+
+    fn set_if_version(&self, key: String, value: String, expected_version: u64) -> Result<u64> {
+        // compared and set under the writer lock, so no other write can slip in between
+        self.write(|writer| {
+            let current = (self.kv.get(key.as_bytes()))
+                .filter(|o| !o.is_expired())
+                .ok_or(KvsError::KeyNotFound)?;
+            if current.version != expected_version {
+                return Err(KvsError::VersionMismatch);
+            }
+            self.set_locked(writer, key.clone().into_bytes(), value)?;
+            Ok(self.kv.get(key.as_bytes()).map_or(0, |o| o.version))
+        })
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        // read and set under the writer lock, so concurrent increments all add up
+        self.write(|writer| {
+            let current = self.get_locked(writer, key.as_bytes())?;
+            let sum = engine::incremented(current.as_deref(), delta)?;
+            self.set_locked(writer, key.into_bytes(), sum.to_string())?;
+            Ok(sum)
+        })
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        // read and set under the writer lock, so concurrent appends are all kept
+        self.write(|writer| {
+            let mut value = self.get_locked(writer, key.as_bytes())?.unwrap_or_default();
+            value.push_str(&suffix);
+            self.set_locked(writer, key.into_bytes(), value)
+        })
+    }
+
+    fn get_or_insert_with<F: FnOnce() -> String>(&self, key: String, f: F) -> Result<String> {
+        // checked and set under the writer lock, so `f` runs at most once for an absent key
+        self.write(|writer| {
+            if let Some(value) = self.get_locked(writer, key.as_bytes())? {
+                return Ok(value);
+            }
+            let value = f();
+            writer.set(key.into_bytes(), value.clone(), None)?;
+            Ok(value)
+        })
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        let mut command_offsets = Vec::new();
+        let mut expired = Vec::new();
+
+        for (index, key) in keys.iter().enumerate() {
+            if !self.may_contain(key.as_bytes()) {
+                continue;
+            }
+            if let Some(command_offset) = self.kv.get(key.as_bytes()) {
+                if command_offset.is_expired() {
+                    expired.push((index, command_offset));
+                } else {
+                    command_offsets.push((command_offset, index));
+                }
+            }
+        }
+
+        if !expired.is_empty() {
+            // see `KvStore::try_remove_expired`
+            if let Ok(mut writer) = self.writer.try_lock() {
+                for (index, command_offset) in expired {
+                    writer.remove_expired(keys[index].as_bytes(), command_offset);
+                }
+            }
+        }
+
+        // read in file order, so each generation file is read front to back
+        command_offsets.sort_unstable_by_key(|(o, _)| (o.generation, o.offset));
+        for (command_offset, index) in command_offsets {
+            values[index] = self.read_value(keys[index].as_bytes(), command_offset)?;
+        }
+
+        Ok(values)
+    }
+}
+
+impl BytesKvsEngine for KvStore {
+    fn set_bytes(&self, key: Vec<u8>, value: String) -> Result<()> {
+        self.write(|writer| self.set_locked(writer, key, value))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<String>> {
+        match self.live_offset(&key) {
+            Some(command_offset) => self.read_value(&key, command_offset),
+            None => Ok(None),
+        }
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.write(|writer| writer.remove(key))
+    }
+}
+
+/// keys of records are bytes, written as strings when they are utf-8, so records of
+/// string keys are encoded as before, and as bytes otherwise
+mod key_bytes {
+    use std::{fmt, str};
+
+    use serde::{
+        de::{SeqAccess, Visitor},
+        Deserialize, Deserializer, Serializer,
+    };
+
+    pub fn serialize<S: Serializer>(key: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match str::from_utf8(key) {
+            Ok(key) => serializer.serialize_str(key),
+            Err(_) => serializer.serialize_bytes(key),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_byte_buf(KeyVisitor)
+    }
+
+    struct KeyVisitor;
+
+    impl<'de> Visitor<'de> for KeyVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a string or bytes")
+        }
+
+        fn visit_str<E>(self, key: &str) -> Result<Vec<u8>, E> {
+            Ok(key.as_bytes().to_vec())
+        }
+
+        fn visit_string<E>(self, key: String) -> Result<Vec<u8>, E> {
+            Ok(key.into_bytes())
+        }
+
+        fn visit_bytes<E>(self, key: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(key.to_vec())
+        }
+
+        fn visit_byte_buf<E>(self, key: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(key)
+        }
+
+        /// json writes bytes as an array of numbers
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut key = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                key.push(byte);
+            }
+            Ok(key)
+        }
+    }
+    /// a key decoded without being kept, see `SetRecord`
+    pub struct SkippedKey;
+
+    impl<'de> Deserialize<'de> for SkippedKey {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_bytes(SkipVisitor)
+        }
+    }
+
+    struct SkipVisitor;
+
+    impl<'de> Visitor<'de> for SkipVisitor {
+        type Value = SkippedKey;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a string or bytes")
+        }
+
+        fn visit_str<E>(self, _: &str) -> Result<SkippedKey, E> {
+            Ok(SkippedKey)
+        }
+
+        fn visit_bytes<E>(self, _: &[u8]) -> Result<SkippedKey, E> {
+            Ok(SkippedKey)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<SkippedKey, A::Error> {
+            while seq.next_element::<u8>()?.is_some() {}
+            Ok(SkippedKey)
+        }
     }
 }
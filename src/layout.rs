@@ -0,0 +1,121 @@
+/*!
+ * naming and discovery of generation files
+ */
+
+use std::{
+    ffi::OsStr,
+    fmt::Debug,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::Result;
+
+/// generation files of one store, passed to a [`LayoutStrategy`]
+#[derive(Clone, Copy, Debug)]
+pub struct LayoutContext<'a> {
+    /// directory the store is opened in
+    pub dir: &'a Path,
+    /// namespace of the store, `None` for the default namespace
+    pub namespace: Option<&'a str>,
+    /// file extension of the encoding, see [`Encoding::extension`](crate::Encoding::extension)
+    pub extension: &'a str,
+}
+
+/// how generation numbers map to file paths, set with
+/// [`KvStoreOptions::layout`](crate::KvStoreOptions::layout)
+///
+/// files of different namespaces and encodings must map to different paths,
+/// and `generations` must find exactly the files created at `generation_path`,
+/// compaction writes to the path of a generation followed by `.tmp`, which isn't one
+pub trait LayoutStrategy: Debug + Send + Sync {
+    /// path of the file of `generation`, missing parent directories are created
+    fn generation_path(&self, context: &LayoutContext<'_>, generation: u64) -> PathBuf;
+    /// generations with a file on disk, in any order
+    fn generations(&self, context: &LayoutContext<'_>) -> Result<Vec<u64>>;
+}
+
+/// the default layout, files are named `N.json` in the store directory,
+/// and `{namespace}-N.json` in other namespaces
+///
+/// other files in the directory are never taken for generations:
+/// - files with another extension, such as manifests, index checkpoints, the `engine`
+///   file of a server and `.tmp` files left by an interrupted compaction
+/// - hidden files and directories
+/// - generation files of other namespaces
+///
+/// any other file with the extension, like `1.2.json` or `01.json`, is skipped with
+/// a warning
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FlatLayout;
+
+/// what a file with the extension of the encoding is to [`FlatLayout`]
+enum FlatFile {
+    Generation(u64),
+    OtherNamespace,
+    Unexpected,
+}
+
+impl FlatLayout {
+    /// classify a file by its stem, as seen from `namespace`
+    fn parse_stem(namespace: Option<&str>, stem: &str) -> FlatFile {
+        let (file_namespace, generation) = match stem.split_once('-') {
+            Some((file_namespace, generation)) if is_valid_namespace(file_namespace) => {
+                (Some(file_namespace), generation)
+            }
+            Some(_) => return FlatFile::Unexpected,
+            None => (None, stem),
+        };
+        match parse_generation(generation) {
+            Some(generation) if file_namespace == namespace => FlatFile::Generation(generation),
+            Some(_) => FlatFile::OtherNamespace,
+            None => FlatFile::Unexpected,
+        }
+    }
+}
+
+impl LayoutStrategy for FlatLayout {
+    fn generation_path(&self, context: &LayoutContext<'_>, generation: u64) -> PathBuf {
+        let extension = context.extension;
+        context.dir.join(match context.namespace {
+            Some(namespace) => format!("{namespace}-{generation}.{extension}"),
+            None => format!("{generation}.{extension}"),
+        })
+    }
+
+    fn generations(&self, context: &LayoutContext<'_>) -> Result<Vec<u64>> {
+        let mut generations = Vec::new();
+        for entry in fs::read_dir(context.dir)? {
+            let path = entry?.path();
+            let hidden = path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .is_some_and(|name| name.starts_with('.'));
+            if hidden || !path.is_file() || path.extension() != Some(OsStr::new(context.extension))
+            {
+                continue;
+            }
+            let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+            match Self::parse_stem(context.namespace, stem) {
+                FlatFile::Generation(generation) => generations.push(generation),
+                FlatFile::OtherNamespace => {}
+                FlatFile::Unexpected => {
+                    log::warn!("skipping {}, not a generation file", path.display());
+                }
+            }
+        }
+        Ok(generations)
+    }
+}
+
+/// `true` if `name` can name a namespace, made of ascii letters, digits and `_`
+pub(crate) fn is_valid_namespace(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// a generation written in decimal digits the way it is formatted, so `+1` or `01`
+/// aren't taken for the file of generation 1
+fn parse_generation(s: &str) -> Option<u64> {
+    let canonical = s.bytes().all(|b| b.is_ascii_digit()) && (s == "0" || !s.starts_with('0'));
+    canonical.then(|| s.parse().ok()).flatten()
+}
@@ -4,17 +4,82 @@
 
 #![deny(missing_docs)]
 pub mod engine;
-pub use engine::KvsEngine;
+pub use engine::{AnyEngine, BatchOp, BytesKvsEngine, DynKvsEngine, KvsEngine};
+pub mod async_engine;
+pub use async_engine::{AsyncKvsEngine, TokioEngine, TokioKvStore};
 pub mod thread_pool;
 
 pub mod result;
 pub use result::{KvsError, Result};
 
 pub mod kvstore;
-pub use kvstore::KvStore;
+pub use kvstore::{
+    CompactionEstimate, CompactionReport, CorruptRecord, HistoryEntry, KvStore, KvStoreStats,
+    LogPosition, LogRecord, VerifyReport, WatchEvent, WeakReader, FORMAT_VERSION,
+};
+
+pub mod encoding;
+pub use encoding::Encoding;
+
+pub mod layout;
+pub use layout::{FlatLayout, LayoutContext, LayoutStrategy};
+
+pub mod compression;
+pub use compression::Compression;
+
+pub mod options;
+pub use options::{CompactionPolicy, DurabilityMode, KvStoreOptions};
+
+pub mod config;
+pub use config::{Engine, Pool, ServerConfig};
+
+pub mod index;
+pub use index::IndexKind;
+
+pub mod codec;
+pub use codec::{Codec, Decoded, MessageCodec, MessageStream};
 
 pub mod req_resp;
-pub use req_resp::{Request, Response};
+pub use req_resp::{
+    ErrorCode, ReadRequest, Request, RequestKind, Response, ResponseError, ServerStats,
+    WriteRequest, CODEC_PROTOCOL_VERSION, PROTOCOL_VERSIONS,
+};
+
+pub mod metrics;
+pub use metrics::{serve_prometheus, ServerMetrics};
 
+pub mod server;
+pub use server::{KvsServer, ServerListener};
+
+pub mod addr;
+pub use addr::{accept_tcp, bind_tcp, ServerAddr};
+#[cfg(unix)]
+pub use addr::{bind_unix, UnixSocket};
+
+pub mod tls;
+pub use tls::TlsStream;
+
+pub mod logger;
+pub use logger::init_logger;
+#[cfg(feature = "tracing")]
+pub use logger::init_tracing;
+
+pub mod client;
+pub use client::{KvsClient, RetryPolicy};
+pub mod async_client;
+pub use async_client::AsyncKvsClient;
+
+mod bloom;
+mod ttl;
+mod value_cache;
+
+#[cfg(feature = "sled-engine")]
 pub mod sled_kvs_engine;
+#[cfg(feature = "sled-engine")]
 pub use sled_kvs_engine::SledKvsEngine;
+
+pub mod mem_kvs_engine;
+pub use mem_kvs_engine::MemKvsEngine;
+
+pub mod txn;
+pub use txn::Txn;
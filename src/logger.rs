@@ -0,0 +1,85 @@
+/*!
+ * logs of the servers
+ */
+
+use std::{io, path::Path, time::SystemTime};
+
+use crate::Result;
+
+/// log records of `target`, the module of a server, and of the kvs library at `level`
+/// or above, to `log_file` if given, otherwise to stderr
+pub fn init_logger(
+    target: &'static str,
+    level: log::LevelFilter,
+    log_file: Option<&Path>,
+) -> Result<()> {
+    let dispatch = fern::Dispatch::new()
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "{} - {} - {}",
+                humantime::format_rfc3339_seconds(SystemTime::now()),
+                record.level(),
+                message
+            ))
+        })
+        .level(log::LevelFilter::Off)
+        .level_for(target, level)
+        .level_for("kvs", level);
+
+    match log_file {
+        Some(path) => dispatch.chain(fern::log_file(path)?),
+        None => dispatch.chain(io::stderr()),
+    }
+    .apply()?;
+    Ok(())
+}
+
+/// json lines of events and closed spans of `target`, the module of a server, and of
+/// the kvs library at `level` or above, records of `log` are events as well
+#[cfg(feature = "tracing")]
+pub fn init_tracing(
+    target: &'static str,
+    level: log::LevelFilter,
+    log_file: Option<&Path>,
+) -> Result<()> {
+    use std::{fs, sync::Mutex};
+    use tracing_subscriber::{
+        filter::{LevelFilter, Targets},
+        fmt::{format::FmtSpan, writer::BoxMakeWriter},
+        prelude::*,
+    };
+
+    let level = match level {
+        log::LevelFilter::Off => LevelFilter::OFF,
+        log::LevelFilter::Error => LevelFilter::ERROR,
+        log::LevelFilter::Warn => LevelFilter::WARN,
+        log::LevelFilter::Info => LevelFilter::INFO,
+        log::LevelFilter::Debug => LevelFilter::DEBUG,
+        log::LevelFilter::Trace => LevelFilter::TRACE,
+    };
+    let writer = match log_file {
+        Some(path) => {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            BoxMakeWriter::new(Mutex::new(file))
+        }
+        None => BoxMakeWriter::new(io::stderr),
+    };
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_span_events(FmtSpan::CLOSE)
+                .with_writer(writer),
+        )
+        .with(
+            Targets::new()
+                .with_target(target, level)
+                .with_target("kvs", level),
+        )
+        .try_init()
+        .map_err(io::Error::other)?;
+    Ok(())
+}
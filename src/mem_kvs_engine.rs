@@ -0,0 +1,115 @@
+/*!
+ * in-memory engine
+ */
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use crossbeam_skiplist::SkipMap;
+
+use crate::{engine, KvsEngine, KvsError, Result};
+
+/// an engine keeping pairs in memory only, for tests and caches,
+/// clones share the same pairs and everything is lost when the last clone is dropped
+///
+/// reads are lock-free, while writes are serialized. Values are overwritten in place,
+/// as replacing an entry of a skip list makes the key briefly invisible to lookups
+#[derive(Clone, Default)]
+pub struct MemKvsEngine {
+    map: Arc<SkipMap<String, RwLock<String>>>,
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl MemKvsEngine {
+    /// create an empty engine
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// number of keys
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// `true` if there are no keys
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl KvsEngine for MemKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        match self.map.get(&key) {
+            Some(entry) => *entry.value().write().unwrap() = value,
+            None => {
+                self.map.insert(key, RwLock::new(value));
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .map
+            .get(&key)
+            .map(|entry| entry.value().read().unwrap().clone()))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        self.map.remove(&key).ok_or(KvsError::KeyNotFound)?;
+        Ok(())
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        let _guard = self.write_lock.lock().unwrap();
+        if self.map.contains_key(&key) {
+            return Ok(false);
+        }
+        self.map.insert(key, RwLock::new(value));
+        Ok(true)
+    }
+
+    /// nothing is written to disk
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let _guard = self.write_lock.lock().unwrap();
+        match self.map.get(&key) {
+            Some(entry) => {
+                let mut value = entry.value().write().unwrap();
+                let sum = engine::incremented(Some(&value), delta)?;
+                *value = sum.to_string();
+                Ok(sum)
+            }
+            None => {
+                let sum = engine::incremented(None, delta)?;
+                self.map.insert(key, RwLock::new(sum.to_string()));
+                Ok(sum)
+            }
+        }
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        match self.map.get(&key) {
+            Some(entry) => entry.value().write().unwrap().push_str(&suffix),
+            None => {
+                self.map.insert(key, RwLock::new(suffix));
+            }
+        }
+        Ok(())
+    }
+
+    fn get_or_insert_with<F: FnOnce() -> String>(&self, key: String, f: F) -> Result<String> {
+        let _guard = self.write_lock.lock().unwrap();
+        if let Some(entry) = self.map.get(&key) {
+            return Ok(entry.value().read().unwrap().clone());
+        }
+        let value = f();
+        self.map.insert(key, RwLock::new(value.clone()));
+        Ok(value)
+    }
+}
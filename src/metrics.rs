@@ -0,0 +1,183 @@
+/*!
+ * request counters of a server, and an http endpoint serving them to prometheus
+ */
+
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{KvStore, KvStoreStats, Request, Response, Result, ServerStats};
+
+/// a scraper sending no request in this long is dropped, so it can't hold up others
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// counters updated by all connections of a server, a snapshot is sent
+/// in response to [`Request::Stats`]
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    connections: AtomicU64,
+    requests: AtomicU64,
+    gets: AtomicU64,
+    sets: AtomicU64,
+    removes: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl ServerMetrics {
+    /// create metrics with all counters at zero
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// count an accepted connection
+    pub fn connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// count a received request, `None` if it couldn't be parsed
+    pub fn request(&self, request: Option<&Request>) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let counter = match request {
+            Some(Request::Get { .. } | Request::GetMany { .. }) => &self.gets,
+            Some(
+                Request::Set { .. }
+                | Request::Incr { .. }
+                | Request::Append { .. }
+                | Request::SetIfVersion { .. },
+            ) => &self.sets,
+            Some(Request::Rm { .. }) => &self.removes,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// count a sent response
+    pub fn response(&self, response: &Response) {
+        if response.error.is_some() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// current values of all counters, each counter is read separately
+    pub fn snapshot(&self) -> ServerStats {
+        ServerStats {
+            connections: self.connections.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            gets: self.gets.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            removes: self.removes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// the counters, and the stats of `store` if given, in the prometheus text format
+    pub fn prometheus(&self, store: Option<&KvStoreStats>) -> String {
+        let stats = self.snapshot();
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(text, "{name}{labels} {value}");
+            }
+        };
+        let other = stats.requests - stats.gets - stats.sets - stats.removes;
+        metric(
+            "requests_total",
+            "counter",
+            "requests received, malformed ones are counted as other",
+            &[
+                ("{op=\"get\"}", stats.gets),
+                ("{op=\"set\"}", stats.sets),
+                ("{op=\"rm\"}", stats.removes),
+                ("{op=\"other\"}", other),
+            ],
+        );
+        metric(
+            "errors_total",
+            "counter",
+            "responses reporting an error",
+            &[("", stats.errors)],
+        );
+        metric(
+            "connections_total",
+            "counter",
+            "connections accepted",
+            &[("", stats.connections)],
+        );
+        if let Some(store) = store {
+            metric(
+                "compactions_total",
+                "counter",
+                "compactions since the store was opened",
+                &[("", store.compaction_count)],
+            );
+            metric(
+                "db_bytes",
+                "gauge",
+                "bytes of generation files",
+                &[("", store.disk_bytes)],
+            );
+            metric(
+                "live_keys",
+                "gauge",
+                "keys in the index, including expired keys not yet removed",
+                &[("", store.live_keys as u64)],
+            );
+        }
+        text
+    }
+}
+
+/// answer http requests for `/metrics` on `listener` with the counters of `metrics`, and
+/// the stats of `store` for the kvs engine, until accepting a connection fails
+///
+/// scrapers are answered one at a time on the calling thread, each on a new connection
+pub fn serve_prometheus(
+    listener: TcpListener,
+    metrics: Arc<ServerMetrics>,
+    store: Option<KvStore>,
+) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        if let Err(e) = scrape(&stream, &metrics, store.as_ref()) {
+            log::warn!("failed to answer scrape of metrics: {e}");
+        }
+    }
+    Ok(())
+}
+
+fn scrape(stream: &TcpStream, metrics: &ServerMetrics, store: Option<&KvStore>) -> Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are read and ignored, so the scraper never sees its request cut off
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, body) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", "/metrics", _] => (
+            "200 OK",
+            metrics.prometheus(store.map(KvStore::stats).as_ref()),
+        ),
+        _ => ("404 Not Found", String::new()),
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()?;
+    Ok(())
+}
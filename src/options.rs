@@ -0,0 +1,327 @@
+/*!
+ * options for opening a [`KvStore`](crate::KvStore)
+ */
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Compression, Encoding, IndexKind, KvsError, LayoutStrategy};
+
+/// default limit of the size of keys and of values, 1 GiB
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// bytes of a record besides its key and value, such as its tag, lengths and expiry
+const RECORD_OVERHEAD: u64 = 64;
+
+/// options used by [`KvStore::open_with_options`](crate::KvStore::open_with_options)
+/// ```rust
+/// use kvs::{Encoding, KvStore, KvStoreOptions, KvsEngine};
+/// let dir = tempfile::TempDir::new().unwrap();
+/// let options = KvStoreOptions::new().encoding(Encoding::Bincode);
+/// let store = KvStore::open_with_options(dir.path(), options).unwrap();
+/// assert!(store.set("key1".to_owned(), "value1".to_owned()).is_ok());
+/// ```
+///
+/// options can be read from a file, fields missing there keep their defaults,
+/// the layout is never read and is [`FlatLayout`](crate::FlatLayout)
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KvStoreOptions {
+    pub(crate) encoding: Encoding,
+    pub(crate) use_mmap: bool,
+    pub(crate) index: IndexKind,
+    pub(crate) bloom_filter: bool,
+    pub(crate) durability: DurabilityMode,
+    pub(crate) compaction_policy: CompactionPolicy,
+    pub(crate) checkpoint: bool,
+    pub(crate) parallel_compaction: bool,
+    pub(crate) dedup_writes: bool,
+    pub(crate) value_compression: Compression,
+    pub(crate) write_buffer_size: Option<usize>,
+    pub(crate) read_buffer_size: Option<usize>,
+    pub(crate) value_cache_capacity: usize,
+    pub(crate) segment_size: Option<u64>,
+    pub(crate) max_key_size: Option<u64>,
+    pub(crate) max_value_size: Option<u64>,
+    #[serde(with = "humantime_duration")]
+    pub(crate) group_commit: Option<Duration>,
+    #[serde(skip)]
+    pub(crate) layout: Option<Arc<dyn LayoutStrategy>>,
+    #[serde(skip)]
+    pub(crate) progress: Option<ProgressHook>,
+    /// set by [`KvStore::open_read_only`](crate::KvStore::open_read_only), so namespaces
+    /// are opened read-only as well
+    #[serde(skip)]
+    pub(crate) read_only: bool,
+}
+
+/// called with the bytes processed so far and the total bytes,
+/// see [`KvStoreOptions::progress`]
+#[derive(Clone)]
+pub(crate) struct ProgressHook(pub(crate) Arc<dyn Fn(u64, u64) + Send + Sync>);
+
+impl fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressHook")
+    }
+}
+
+/// largest keys and values accepted by writes, see [`KvStoreOptions::max_value_size`]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SizeLimits {
+    max_key_size: u64,
+    max_value_size: u64,
+}
+
+impl SizeLimits {
+    pub(crate) fn check(&self, key: &[u8], value: &str) -> crate::Result<()> {
+        if key.len() as u64 > self.max_key_size || value.len() as u64 > self.max_value_size {
+            return Err(KvsError::ValueTooLarge);
+        }
+        Ok(())
+    }
+
+    /// length of the longest record of a key and value within the limits
+    pub(crate) fn max_record_len(&self) -> u64 {
+        self.max_key_size
+            .saturating_add(self.max_value_size)
+            .saturating_add(RECORD_OVERHEAD)
+    }
+}
+
+/// when a store rewrites its live records and deletes older generation files
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompactionPolicy {
+    /// compact after this many bytes are written since the last compaction
+    Bytes(u64),
+    /// compact when overwritten and removed records reach this fraction of all bytes,
+    /// once there is at least 1 MiB of them
+    GarbageRatio(f64),
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy::Bytes(4 * 1024 * 1024)
+    }
+}
+
+/// how far a write is persisted before it returns
+///
+/// stronger modes survive more failures but make every write slower:
+/// `Fsync` survives power loss, `Buffered` survives a crash of the process,
+/// `None` may lose recent writes even when the process exits abnormally
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DurabilityMode {
+    /// keep writes in the process buffer, flushed when full, on compaction and on drop
+    None,
+    /// flush each write to the operating system
+    #[default]
+    Buffered,
+    /// flush and sync each write to disk
+    Fsync,
+}
+
+impl KvStoreOptions {
+    /// create options with default values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// set the encoding of generation files, default is [`Encoding::Json`]
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// read generation files through memory mapping instead of buffered reads,
+    /// suitable for large read-mostly datasets
+    pub fn use_mmap(mut self, use_mmap: bool) -> Self {
+        self.use_mmap = use_mmap;
+        self
+    }
+
+    /// set the data structure of the in-memory index, default is [`IndexKind::SkipMap`]
+    pub fn index(mut self, index: IndexKind) -> Self {
+        self.index = index;
+        self
+    }
+
+    /// keep a bloom filter of keys, so lookups of absent keys mostly skip the index,
+    /// it is sized from the number of keys found when opening
+    pub fn bloom_filter(mut self, bloom_filter: bool) -> Self {
+        self.bloom_filter = bloom_filter;
+        self
+    }
+
+    /// set how far each write is persisted, default is [`DurabilityMode::Buffered`]
+    pub fn durability(mut self, durability: DurabilityMode) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// set when to compact, default is [`CompactionPolicy::Bytes`] of 4 MiB
+    pub fn compaction_policy(mut self, compaction_policy: CompactionPolicy) -> Self {
+        self.compaction_policy = compaction_policy;
+        self
+    }
+
+    /// compress values before writing them, default is [`Compression::None`]
+    pub fn value_compression(mut self, value_compression: Compression) -> Self {
+        self.value_compression = value_compression;
+        self
+    }
+
+    /// set the capacity of the buffer of writes to generation files, default is 8 KiB,
+    /// larger buffers make fewer writes to the operating system during bulk loads and
+    /// compaction, and hold more records which aren't flushed with [`DurabilityMode::None`]
+    pub fn write_buffer_size(mut self, write_buffer_size: usize) -> Self {
+        self.write_buffer_size = Some(write_buffer_size);
+        self
+    }
+
+    /// set the capacity of the buffers of reads from generation files, default is 8 KiB,
+    /// used when replaying generations on open and by reads without memory mapping
+    pub fn read_buffer_size(mut self, read_buffer_size: usize) -> Self {
+        self.read_buffer_size = Some(read_buffer_size);
+        self
+    }
+
+    /// keep up to this many bytes of the values read last in memory, so reading an unchanged
+    /// value again skips the generation file, default is 0 for no cache
+    ///
+    /// values are shared by [`KvStore::get_shared`](crate::KvStore::get_shared) without
+    /// copying, and by all clones of the store
+    pub fn value_cache_capacity(mut self, value_cache_capacity: usize) -> Self {
+        self.value_cache_capacity = value_cache_capacity;
+        self
+    }
+
+    /// start a new generation file once the one being written reaches this many bytes,
+    /// by default a generation grows until the next compaction
+    ///
+    /// rolling over is independent of the compaction policy, compaction still merges all
+    /// generations into one, so fewer compactions leave more generations behind. The
+    /// generation being left is synced to disk whatever the durability mode
+    pub fn segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = Some(segment_size);
+        self
+    }
+
+    /// set the largest key in bytes accepted by a write, default is 1 GiB
+    pub fn max_key_size(mut self, max_key_size: u64) -> Self {
+        self.max_key_size = Some(max_key_size);
+        self
+    }
+
+    /// set the largest value in bytes accepted by a write, default is 1 GiB
+    ///
+    /// the size is taken before compression, servers check it for every engine. A bincode
+    /// record longer than the limits allow is read as corrupt, so they shouldn't be lowered
+    /// below records already written
+    pub fn max_value_size(mut self, max_value_size: u64) -> Self {
+        self.max_value_size = Some(max_value_size);
+        self
+    }
+
+    /// fail with [`KvsError::ValueTooLarge`] if `key` or `value` is larger than allowed
+    pub fn check_size(&self, key: &str, value: &str) -> crate::Result<()> {
+        self.size_limits().check(key.as_bytes(), value)
+    }
+
+    /// whether a store opened with `other` behaves as one opened with these options,
+    /// the layout and the progress hook aren't compared
+    pub(crate) fn same_as(&self, other: &Self) -> bool {
+        self.read_only == other.read_only
+            && serde_json::to_value(self).ok() == serde_json::to_value(other).ok()
+    }
+
+    pub(crate) fn size_limits(&self) -> SizeLimits {
+        SizeLimits {
+            max_key_size: self.max_key_size.unwrap_or(DEFAULT_MAX_SIZE),
+            max_value_size: self.max_value_size.unwrap_or(DEFAULT_MAX_SIZE),
+        }
+    }
+
+    /// save the index to a checkpoint file after compaction and when the store is dropped,
+    /// so opening replays only records written after the checkpoint
+    pub fn checkpoint(mut self, checkpoint: bool) -> Self {
+        self.checkpoint = checkpoint;
+        self
+    }
+
+    /// read live records on the rayon thread pool during compaction, default is off
+    ///
+    /// records are still written one by one in index order, so only reading is spread
+    /// over threads, which helps when reads wait on a disk serving many requests at once
+    pub fn parallel_compaction(mut self, parallel_compaction: bool) -> Self {
+        self.parallel_compaction = parallel_compaction;
+        self
+    }
+
+    /// skip a [`set`](crate::KvsEngine::set) which doesn't change the value, default is off
+    ///
+    /// the current value is read before each set, which makes sets slower but keeps
+    /// repeated identical sets from growing the log, a key with an expiry is always written
+    pub fn dedup_writes(mut self, dedup_writes: bool) -> Self {
+        self.dedup_writes = dedup_writes;
+        self
+    }
+
+    /// persist writes of concurrent threads together, default is off
+    ///
+    /// each [`set`](crate::KvsEngine::set) and [`remove`](crate::KvsEngine::remove)
+    /// still returns once its record is persisted as required by the durability mode,
+    /// but the first waiting thread waits for `window`, then flushes or syncs the records
+    /// written meanwhile by all threads at once. Throughput under many concurrent writers
+    /// goes up at the cost of latency, there is no effect with [`DurabilityMode::None`]
+    pub fn group_commit(mut self, window: Duration) -> Self {
+        self.group_commit = Some(window);
+        self
+    }
+
+    /// call `progress` with the bytes processed so far and the total bytes while
+    /// generations are replayed on open and while the store is compacted, default is none
+    ///
+    /// it is called about every MiB, and once more with both equal when done.
+    /// During compaction it runs under the writer lock, writing to the store from it deadlocks
+    pub fn progress(mut self, progress: impl Fn(u64, u64) + Send + Sync + 'static) -> Self {
+        self.progress = Some(ProgressHook(Arc::new(progress)));
+        self
+    }
+
+    /// set how generation files are named and found, default is [`FlatLayout`](crate::FlatLayout)
+    ///
+    /// a store must be reopened with the layout it was written with
+    pub fn layout(mut self, layout: impl LayoutStrategy + 'static) -> Self {
+        self.layout = Some(Arc::new(layout));
+        self
+    }
+}
+
+/// optional durations written as strings such as `1ms`, see [`humantime`]
+mod humantime_duration {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => {
+                serializer.serialize_some(&humantime::format_duration(*duration).to_string())
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|duration| humantime::parse_duration(&duration).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
@@ -1,7 +1,25 @@
 /*!
  * request and response in network
  */
-use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{KvStoreOptions, KvsError};
+
+/// protocol versions spoken by this build
+///
+/// on connect the server sends the oldest and the newest version it speaks as two bytes,
+/// and the client answers with one byte, the newest version both sides speak
+///
+/// from [`CODEC_PROTOCOL_VERSION`] on, the client then sends the byte of the
+/// [`Codec`](crate::Codec) it asks for, and the server answers with the byte of the codec
+/// it speaks from then on, json if it doesn't speak the one asked for. Older versions
+/// speak json
+pub const PROTOCOL_VERSIONS: RangeInclusive<u8> = 1..=2;
+
+/// first protocol version negotiating a codec on connect, see [`PROTOCOL_VERSIONS`]
+pub const CODEC_PROTOCOL_VERSION: u8 = 2;
 
 /// request in network
 #[derive(Serialize, Deserialize, Debug)]
@@ -23,13 +41,368 @@ pub enum Request {
         /// key
         key: String,
     },
+    /// liveness probe, answered with server version without touching engine
+    Ping,
+    /// get values for several keys
+    GetMany {
+        /// keys
+        keys: Vec<String>,
+    },
+    /// counters of requests handled by the server, answered without touching engine
+    Stats,
+    /// persist all writes of the engine to disk
+    Flush,
+    /// add delta to the integer value of key, answered with the new value
+    Incr {
+        /// key
+        key: String,
+        /// added to the value, may be negative
+        delta: i64,
+    },
+    /// append suffix to the value of key
+    Append {
+        /// key
+        key: String,
+        /// appended to the value
+        suffix: String,
+    },
+    /// version stamp of the last write of key, answered as a decimal value, none if the
+    /// key is absent
+    Version {
+        /// key
+        key: String,
+    },
+    /// set key-value pair only if the version of key is still expected_version,
+    /// answered with the new version as a decimal value
+    SetIfVersion {
+        /// key
+        key: String,
+        /// value
+        value: String,
+        /// version read by the client
+        expected_version: u64,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// a request by whether it modifies the engine, see [`Request::into_kind`]
+#[derive(Debug)]
+pub enum RequestKind {
+    /// answered without waiting on writes
+    Read(ReadRequest),
+    /// serialized by the engine
+    Write(WriteRequest),
+}
+
+/// a request which doesn't modify the engine, as in [`Request`]
+#[derive(Debug)]
+pub enum ReadRequest {
+    /// get value for key
+    Get {
+        /// key
+        key: String,
+    },
+    /// get values for several keys
+    GetMany {
+        /// keys
+        keys: Vec<String>,
+    },
+    /// liveness probe
+    Ping,
+    /// counters of requests handled by the server
+    Stats,
+    /// version stamp of the last write of key
+    Version {
+        /// key
+        key: String,
+    },
+}
+
+/// a request which modifies the engine, as in [`Request`]
+#[derive(Debug)]
+pub enum WriteRequest {
+    /// set key-value pair
+    Set {
+        /// key
+        key: String,
+        /// value
+        value: String,
+    },
+    /// remove key
+    Rm {
+        /// key
+        key: String,
+    },
+    /// persist all writes of the engine to disk
+    Flush,
+    /// add delta to the integer value of key
+    Incr {
+        /// key
+        key: String,
+        /// added to the value, may be negative
+        delta: i64,
+    },
+    /// append suffix to the value of key
+    Append {
+        /// key
+        key: String,
+        /// appended to the value
+        suffix: String,
+    },
+    /// set key-value pair only if the version of key is still expected_version
+    SetIfVersion {
+        /// key
+        key: String,
+        /// value
+        value: String,
+        /// version read by the client
+        expected_version: u64,
+    },
+}
+
+impl WriteRequest {
+    /// fails with [`KvsError::ValueTooLarge`] if the key or value written is larger than
+    /// the limits of `store`
+    pub fn check_size(&self, store: &KvStoreOptions) -> crate::Result<()> {
+        match self {
+            WriteRequest::Set { key, value } | WriteRequest::SetIfVersion { key, value, .. } => {
+                store.check_size(key, value)
+            }
+            WriteRequest::Incr { key, .. } => store.check_size(key, ""),
+            // only the suffix is checked here, a kvs engine checks the whole value
+            WriteRequest::Append { key, suffix } => store.check_size(key, suffix),
+            WriteRequest::Rm { .. } | WriteRequest::Flush => Ok(()),
+        }
+    }
+}
+
+impl Request {
+    /// `true` if the request doesn't modify the engine, servers answer reads without
+    /// waiting on writes, see [`KvsEngine`](crate::KvsEngine)
+    pub fn is_read(&self) -> bool {
+        match self {
+            Request::Get { .. }
+            | Request::GetMany { .. }
+            | Request::Ping
+            | Request::Stats
+            | Request::Version { .. } => true,
+            Request::Set { .. }
+            | Request::Rm { .. }
+            | Request::Flush
+            | Request::Incr { .. }
+            | Request::Append { .. }
+            | Request::SetIfVersion { .. } => false,
+        }
+    }
+
+    /// the request as a read or a write, servers answer reads without waiting on writes
+    pub fn into_kind(self) -> RequestKind {
+        match self {
+            Request::Get { key } => RequestKind::Read(ReadRequest::Get { key }),
+            Request::GetMany { keys } => RequestKind::Read(ReadRequest::GetMany { keys }),
+            Request::Ping => RequestKind::Read(ReadRequest::Ping),
+            Request::Stats => RequestKind::Read(ReadRequest::Stats),
+            Request::Version { key } => RequestKind::Read(ReadRequest::Version { key }),
+            Request::Set { key, value } => RequestKind::Write(WriteRequest::Set { key, value }),
+            Request::Rm { key } => RequestKind::Write(WriteRequest::Rm { key }),
+            Request::Flush => RequestKind::Write(WriteRequest::Flush),
+            Request::Incr { key, delta } => RequestKind::Write(WriteRequest::Incr { key, delta }),
+            Request::Append { key, suffix } => {
+                RequestKind::Write(WriteRequest::Append { key, suffix })
+            }
+            Request::SetIfVersion {
+                key,
+                value,
+                expected_version,
+            } => RequestKind::Write(WriteRequest::SetIfVersion {
+                key,
+                value,
+                expected_version,
+            }),
+        }
+    }
+
+    /// name of the operation, as in logs of the server
+    pub fn op(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::Rm { .. } => "rm",
+            Request::Ping => "ping",
+            Request::GetMany { .. } => "get_many",
+            Request::Stats => "stats",
+            Request::Flush => "flush",
+            Request::Incr { .. } => "incr",
+            Request::Append { .. } => "append",
+            Request::Version { .. } => "version",
+            Request::SetIfVersion { .. } => "set_if_version",
+        }
+    }
+
+    /// byte length of the key of a request on a single key
+    pub fn key_len(&self) -> Option<usize> {
+        match self {
+            Request::Get { key }
+            | Request::Set { key, .. }
+            | Request::Rm { key }
+            | Request::Incr { key, .. }
+            | Request::Append { key, .. }
+            | Request::Version { key }
+            | Request::SetIfVersion { key, .. } => Some(key.len()),
+            Request::Ping | Request::GetMany { .. } | Request::Stats | Request::Flush => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
 /// response in network
 pub struct Response {
     /// return value for get
     pub value: Option<String>,
-    /// error string
-    pub error: Option<String>,
+    /// error reported by server
+    pub error: Option<ResponseError>,
+    /// return values for get many, in the order of requested keys
+    #[serde(default)]
+    pub values: Option<Vec<Option<String>>>,
+    /// return value for stats
+    #[serde(default)]
+    pub stats: Option<ServerStats>,
+}
+
+/// json leaves out the fields of other requests, while binary codecs write every field,
+/// as bincode reads them by position
+impl Serialize for Response {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            JsonResponse {
+                value: &self.value,
+                error: &self.error,
+                values: self.values.as_ref(),
+                stats: self.stats.as_ref(),
+            }
+            .serialize(serializer)
+        } else {
+            BinaryResponse {
+                value: &self.value,
+                error: &self.error,
+                values: &self.values,
+                stats: &self.stats,
+            }
+            .serialize(serializer)
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename = "Response")]
+struct JsonResponse<'a> {
+    value: &'a Option<String>,
+    error: &'a Option<ResponseError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    values: Option<&'a Vec<Option<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<&'a ServerStats>,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "Response")]
+struct BinaryResponse<'a> {
+    value: &'a Option<String>,
+    error: &'a Option<ResponseError>,
+    values: &'a Option<Vec<Option<String>>>,
+    stats: &'a Option<ServerStats>,
+}
+
+/// counters of requests handled by a server since it started
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    /// connections accepted
+    pub connections: u64,
+    /// requests received, including malformed ones
+    pub requests: u64,
+    /// get and get many requests
+    pub gets: u64,
+    /// set, incr, append and set-if-version requests
+    pub sets: u64,
+    /// remove requests
+    pub removes: u64,
+    /// responses reporting an error
+    pub errors: u64,
+}
+
+/// kind of an error reported by server
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// key not found
+    KeyNotFound,
+    /// server failed to handle a valid request
+    Internal,
+    /// request could not be parsed
+    BadRequest,
+    /// value of the key isn't an integer
+    NotAnInteger,
+    /// key or value is larger than the limit of the server
+    ValueTooLarge,
+    /// write to a read-only server
+    ReadOnly,
+    /// key was written since the version expected by a set-if-version
+    VersionMismatch,
+}
+
+/// error in response
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResponseError {
+    /// error kind
+    pub code: ErrorCode,
+    /// human readable detail
+    pub message: Option<String>,
+}
+
+impl From<KvsError> for ResponseError {
+    fn from(value: KvsError) -> Self {
+        match value {
+            KvsError::KeyNotFound => Self {
+                code: ErrorCode::KeyNotFound,
+                message: None,
+            },
+            KvsError::BadRequest(message) => Self {
+                code: ErrorCode::BadRequest,
+                message: Some(message),
+            },
+            KvsError::NotAnInteger => Self {
+                code: ErrorCode::NotAnInteger,
+                message: None,
+            },
+            KvsError::ValueTooLarge => Self {
+                code: ErrorCode::ValueTooLarge,
+                message: None,
+            },
+            KvsError::ReadOnly => Self {
+                code: ErrorCode::ReadOnly,
+                message: None,
+            },
+            KvsError::VersionMismatch => Self {
+                code: ErrorCode::VersionMismatch,
+                message: None,
+            },
+            e => Self {
+                code: ErrorCode::Internal,
+                message: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+impl From<ResponseError> for KvsError {
+    fn from(value: ResponseError) -> Self {
+        let message = value.message.unwrap_or_default();
+        match value.code {
+            ErrorCode::KeyNotFound => Self::KeyNotFound,
+            ErrorCode::BadRequest => Self::BadRequest(message),
+            ErrorCode::NotAnInteger => Self::NotAnInteger,
+            ErrorCode::ValueTooLarge => Self::ValueTooLarge,
+            ErrorCode::ReadOnly => Self::ReadOnly,
+            ErrorCode::VersionMismatch => Self::VersionMismatch,
+            ErrorCode::Internal => Self::Server(message),
+        }
+    }
 }
@@ -4,41 +4,169 @@
 
 use std::{io, string};
 
-use failure::Fail;
-
 /// result type
 pub type Result<T> = std::result::Result<T, KvsError>;
 
-/// error kind in [`kvstore`]
-#[derive(Fail, Debug)]
-pub enum KvsError {
-    /// key not found
-    #[fail(display = "Key not found")]
-    KeyNotFound,
-    /// unmatched engine
-    #[fail(display = "Unmatched engine")]
-    UnmatchedEngine,
-    /// serde_json error
-    #[fail(display = "{}", _0)]
-    SerdeJson(#[cause] serde_json::Error),
-    /// std io error
-    #[fail(display = "{}", _0)]
-    StdIo(#[cause] io::Error),
-    /// stderrlog error
-    #[fail(display = "{}", _0)]
-    StdErrLog(#[cause] log::SetLoggerError),
-    /// sled error
-    #[fail(display = "{}", _0)]
-    Sled(#[cause] sled::Error),
-    /// from utf8 error
-    #[fail(display = "{}", _0)]
-    FromUtf8(#[cause] string::FromUtf8Error),
-    /// client error
-    #[fail(display = "Client error")]
-    ClientError,
-    /// rayon thread pool error
-    #[fail(display = "{}", _0)]
-    RayonThreadPool(#[cause] rayon::ThreadPoolBuildError),
+pub use self::kind::KvsError;
+
+// failure's derive puts its impls inside named constants, which rustc warns about, so the
+// lint is allowed around the enum alone
+#[allow(non_local_definitions)]
+mod kind {
+    use std::{io, string};
+
+    use failure::Fail;
+
+    /// error kind in [`kvstore`]
+    #[derive(Fail, Debug)]
+    pub enum KvsError {
+        /// key not found
+        #[fail(display = "Key not found")]
+        KeyNotFound,
+        /// unmatched engine
+        #[fail(display = "Unmatched engine")]
+        UnmatchedEngine,
+        /// serde_json error
+        #[fail(display = "{}", _0)]
+        SerdeJson(#[cause] serde_json::Error),
+        /// bincode error
+        #[fail(display = "{}", _0)]
+        Bincode(#[cause] bincode::Error),
+        /// messagepack encoding error
+        #[fail(display = "{}", _0)]
+        MessagePackEncode(#[cause] rmp_serde::encode::Error),
+        /// messagepack decoding error
+        #[fail(display = "{}", _0)]
+        MessagePackDecode(#[cause] rmp_serde::decode::Error),
+        /// std io error
+        #[fail(display = "{}", _0)]
+        StdIo(#[cause] io::Error),
+        /// logger is already initialized
+        #[fail(display = "{}", _0)]
+        StdErrLog(#[cause] log::SetLoggerError),
+        /// sled error
+        #[cfg(feature = "sled-engine")]
+        #[fail(display = "{}", _0)]
+        Sled(#[cause] sled::Error),
+        /// from utf8 error
+        #[fail(display = "{}", _0)]
+        FromUtf8(#[cause] string::FromUtf8Error),
+        /// client error
+        #[fail(display = "Client error")]
+        ClientError,
+        /// server closed the connection before answering a request, which it may have
+        /// applied
+        #[fail(display = "Server closed the connection without responding")]
+        NoResponse,
+        /// internal error reported by server
+        #[fail(display = "{}", _0)]
+        Server(String),
+        /// request rejected by server as malformed
+        #[fail(display = "Bad request: {}", _0)]
+        BadRequest(String),
+        /// server did not answer in time
+        #[fail(display = "Timed out waiting for server")]
+        Timeout,
+        /// blocking task panicked or was cancelled
+        #[fail(display = "Blocking task failed")]
+        BlockingTask,
+        /// thread pool can't be built with the threads asked for
+        #[fail(display = "Thread pool error: {}", _0)]
+        ThreadPool(String),
+        /// rayon thread pool error
+        #[fail(display = "{}", _0)]
+        RayonThreadPool(#[cause] rayon::ThreadPoolBuildError),
+        /// client and server speak no protocol version in common
+        #[fail(
+            display = "No common protocol version, server speaks versions {} to {}",
+            min, max
+        )]
+        ProtocolVersion {
+            /// oldest version of server
+            min: u8,
+            /// newest version of server
+            max: u8,
+        },
+        /// config file can't be parsed
+        #[fail(display = "{}", _0)]
+        Toml(#[cause] toml::de::Error),
+        /// namespace name which can't be part of a file name
+        #[fail(display = "Invalid namespace: {}", _0)]
+        InvalidNamespace(String),
+        /// generation files are written in a format version this build can't read
+        #[fail(
+            display = "Incompatible data format version {}, expected version {}",
+            found, expected
+        )]
+        IncompatibleFormat {
+            /// version recorded in the manifest
+            found: u32,
+            /// version of this build, see [`FORMAT_VERSION`](crate::FORMAT_VERSION)
+            expected: u32,
+        },
+        /// value of a key being incremented isn't an integer, or the sum overflows an `i64`
+        #[fail(display = "Value is not an integer")]
+        NotAnInteger,
+        /// key or value is larger than the limit of the store or server, see
+        /// [`KvStoreOptions::max_value_size`](crate::KvStoreOptions::max_value_size)
+        #[fail(display = "Key or value too large")]
+        ValueTooLarge,
+        /// `engine` file of a data dir names no engine, holding the contents of the file
+        #[fail(display = "Invalid engine file: {:?}", _0)]
+        InvalidEngineConfig(String),
+        /// every handle of the store read by a [`WeakReader`](crate::WeakReader) is dropped
+        #[fail(display = "Store is closed")]
+        StoreClosed,
+        /// position read by [`KvStore::read_from`](crate::KvStore::read_from) is in a
+        /// generation deleted by compaction or clear
+        #[fail(display = "Position is no longer in the log")]
+        StalePosition,
+        /// write to a store opened by [`KvStore::open_read_only`](crate::KvStore::open_read_only)
+        /// or to a server started with `--readonly`
+        #[fail(display = "Store is read-only")]
+        ReadOnly,
+        /// version of a key written by a set-if-version isn't the expected one, the key was
+        /// written since its version was read, see
+        /// [`KvsEngine::set_if_version`](crate::KvsEngine::set_if_version)
+        #[fail(display = "Version mismatch")]
+        VersionMismatch,
+        /// store opened with options other than those of the same store already open in
+        /// this process, which is shared rather than opened again
+        #[fail(display = "Store is already open with other options")]
+        OptionsMismatch,
+        /// the engine doesn't implement the operation
+        #[fail(display = "Unsupported by the engine: {}", _0)]
+        Unsupported(&'static str),
+        /// tls failed, or its config or certificates can't be used
+        #[fail(display = "{}", _0)]
+        Tls(#[cause] rustls::Error),
+        /// tls is asked for with settings which can't be served, such as a missing
+        /// certificate or a unix domain socket
+        #[fail(display = "Invalid tls config: {}", _0)]
+        TlsConfig(String),
+    }
+}
+
+impl KvsError {
+    /// `true` if the error may be transient, as the connection failed or timed out,
+    /// errors reported by the server and the absence of a key are final
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            KvsError::Timeout | KvsError::NoResponse => true,
+            KvsError::StdIo(e) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+                    // the socket file of a restarting server
+                    | io::ErrorKind::NotFound
+            ),
+            _ => false,
+        }
+    }
 }
 
 impl From<serde_json::Error> for KvsError {
@@ -47,6 +175,30 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<toml::de::Error> for KvsError {
+    fn from(value: toml::de::Error) -> Self {
+        Self::Toml(value)
+    }
+}
+
+impl From<bincode::Error> for KvsError {
+    fn from(value: bincode::Error) -> Self {
+        Self::Bincode(value)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for KvsError {
+    fn from(value: rmp_serde::encode::Error) -> Self {
+        Self::MessagePackEncode(value)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for KvsError {
+    fn from(value: rmp_serde::decode::Error) -> Self {
+        Self::MessagePackDecode(value)
+    }
+}
+
 impl From<io::Error> for KvsError {
     fn from(value: io::Error) -> Self {
         Self::StdIo(value)
@@ -59,12 +211,19 @@ impl From<log::SetLoggerError> for KvsError {
     }
 }
 
+#[cfg(feature = "sled-engine")]
 impl From<sled::Error> for KvsError {
     fn from(value: sled::Error) -> Self {
         Self::Sled(value)
     }
 }
 
+impl From<rustls::Error> for KvsError {
+    fn from(value: rustls::Error) -> Self {
+        Self::Tls(value)
+    }
+}
+
 impl From<string::FromUtf8Error> for KvsError {
     fn from(value: string::FromUtf8Error) -> Self {
         Self::FromUtf8(value)
@@ -0,0 +1,461 @@
+/*!
+ * blocking kvs server, answering connections on a thread pool
+ */
+
+use std::{
+    fmt::Debug,
+    io::{self, BufReader, BufWriter, Read, Write},
+    iter,
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+};
+
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+use crate::{
+    accept_tcp, bind_tcp,
+    codec::{Bincode, Json, MessagePack},
+    thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool},
+    tls::TlsStream,
+    AnyEngine, Codec, Decoded, Engine, KvStoreOptions, KvsEngine, KvsError, MessageCodec, Pool,
+    ReadRequest, Request, RequestKind, Response, Result, ServerAddr, ServerConfig, ServerMetrics,
+    WriteRequest, CODEC_PROTOCOL_VERSION, PROTOCOL_VERSIONS,
+};
+#[cfg(unix)]
+use crate::{bind_unix, UnixSocket};
+
+/// a listener bound by [`KvsServer::bind`]
+#[derive(Debug)]
+pub enum ServerListener {
+    /// a tcp listener
+    Tcp(TcpListener),
+    /// a unix domain socket
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+/// a server listening on the address of its config, connections wait in the backlog
+/// until it is run
+/// ```rust,no_run
+/// use kvs::{KvsServer, ServerConfig};
+/// let config = ServerConfig {
+///     addr: "127.0.0.1:0".parse().unwrap(),
+///     ..Default::default()
+/// };
+/// let server = KvsServer::bind(config).unwrap();
+/// println!("listening on {}", server.local_addr().unwrap());
+/// server.run().unwrap();
+/// ```
+pub struct KvsServer {
+    config: ServerConfig,
+    listener: ServerListener,
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl KvsServer {
+    /// listen on the address of `config`, with its tls certificate if it has one
+    pub fn bind(config: ServerConfig) -> Result<Self> {
+        let tls = config.tls()?;
+        let listener = match &config.addr {
+            ServerAddr::Tcp(addr) => {
+                let listener = bind_tcp(*addr, config.backlog)?;
+                // the port picked by the os when binding to port 0
+                log::info!("listening on {}", listener.local_addr()?);
+                ServerListener::Tcp(listener)
+            }
+            #[cfg(unix)]
+            ServerAddr::Unix(path) => ServerListener::Unix(bind_unix(path)?),
+        };
+        Ok(Self {
+            config,
+            listener,
+            tls,
+        })
+    }
+
+    /// the address the server listens on, with the port picked by the os when bound to
+    /// port 0, fails for a unix domain socket
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        match &self.listener {
+            ServerListener::Tcp(listener) => Ok(listener.local_addr()?),
+            #[cfg(unix)]
+            ServerListener::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "a unix domain socket has no socket address",
+            )
+            .into()),
+        }
+    }
+
+    /// the listener, for a server accepting connections some other way
+    pub fn into_listener(self) -> ServerListener {
+        self.listener
+    }
+
+    /// open the engine of the config and answer connections on its thread pool,
+    /// a server on a tcp address runs until the process exits, one on a unix domain
+    /// socket until it is interrupted or terminated
+    pub fn run(self) -> Result<()> {
+        let config = &self.config;
+        match (&self.listener, self.tls) {
+            // the handshake is done by the thread handling the connection
+            (ServerListener::Tcp(listener), Some(tls)) => serve(
+                iter::repeat_with(|| {
+                    accept_tcp(listener).and_then(|tcp| TlsStream::accept(tcp, tls.clone()))
+                }),
+                config,
+            ),
+            (ServerListener::Tcp(listener), None) => {
+                serve(iter::repeat_with(|| accept_tcp(listener)), config)
+            }
+            #[cfg(unix)]
+            (ServerListener::Unix(socket), _) => {
+                // the connection waking the loop once stopped is dropped
+                let incoming = socket.listener().incoming();
+                serve(incoming.take_while(|_| !socket.is_stopped()), config)
+            }
+        }
+    }
+}
+
+fn serve<S>(incoming: impl Iterator<Item = io::Result<S>>, config: &ServerConfig) -> Result<()>
+where
+    S: Debug + Send + 'static,
+    for<'a> &'a S: Read + Write,
+{
+    let threads = config.threads.unwrap_or(num_cpus::get() as u32);
+    match config.pool {
+        Pool::Naive => serve_with_pool(incoming, config, NaiveThreadPool::new(threads)?),
+        Pool::Shared => serve_with_pool(incoming, config, SharedQueueThreadPool::new(threads)?),
+        Pool::Rayon => serve_with_pool(incoming, config, RayonThreadPool::new(threads)?),
+    }
+}
+
+fn serve_with_pool<S>(
+    incoming: impl Iterator<Item = io::Result<S>>,
+    config: &ServerConfig,
+    thread_pool: impl ThreadPool,
+) -> Result<()>
+where
+    S: Debug + Send + 'static,
+    for<'a> &'a S: Read + Write,
+{
+    let store = Arc::new(config.store.clone());
+    // a kvs engine is kept to report its stats to the metrics endpoint
+    let kvs = match config.engine {
+        Engine::Kvs => Some(config.open_store()?),
+        _ => None,
+    };
+    let kv = match &kvs {
+        Some(kvs) => AnyEngine::new(kvs.clone()),
+        None => config.open_engine()?,
+    };
+    let metrics = Arc::new(ServerMetrics::new());
+    config.spawn_metrics(metrics.clone(), kvs)?;
+    run_engine(incoming, kv, store, config.read_only, metrics, thread_pool)
+}
+
+fn run_engine<S>(
+    incoming: impl Iterator<Item = io::Result<S>>,
+    kv: impl KvsEngine,
+    store: Arc<KvStoreOptions>,
+    read_only: bool,
+    metrics: Arc<ServerMetrics>,
+    thread_pool: impl ThreadPool,
+) -> Result<()>
+where
+    S: Debug + Send + 'static,
+    for<'a> &'a S: Read + Write,
+{
+    for stream in incoming {
+        // a connection which fails to be accepted, such as one aborted by its peer or
+        // whose tls session can't be set up, is dropped while the server goes on
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("accepting a connection failed: {}", e);
+                continue;
+            }
+        };
+        log::debug!("receive a connection {:?}", stream);
+        metrics.connection();
+
+        let kv = kv.clone();
+        let metrics = metrics.clone();
+        let store = store.clone();
+        thread_pool.spawn(move || {
+            let peer = format!("{:?}", stream);
+            if let Err(e) = process(stream, &kv, &store, read_only, &metrics) {
+                log::error!("connection {} failed: {}", peer, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "connection", skip_all, fields(peer = ?stream))
+)]
+fn process<S>(
+    stream: S,
+    kv: &impl KvsEngine,
+    store: &KvStoreOptions,
+    read_only: bool,
+    metrics: &ServerMetrics,
+) -> Result<()>
+where
+    S: Debug,
+    for<'a> &'a S: Read + Write,
+{
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+
+    writer.write_all(&[*PROTOCOL_VERSIONS.start(), *PROTOCOL_VERSIONS.end()])?;
+    writer.flush()?;
+    let mut version = [0u8; 1];
+    reader.read_exact(&mut version)?;
+    let version = version[0];
+    if !PROTOCOL_VERSIONS.contains(&version) {
+        return Err(KvsError::ProtocolVersion {
+            min: *PROTOCOL_VERSIONS.start(),
+            max: *PROTOCOL_VERSIONS.end(),
+        });
+    }
+    let codec = if version >= CODEC_PROTOCOL_VERSION {
+        let mut asked = [0u8; 1];
+        reader.read_exact(&mut asked)?;
+        let codec = Codec::from_byte(asked[0]).unwrap_or_default();
+        writer.write_all(&[codec.to_byte()])?;
+        writer.flush()?;
+        codec
+    } else {
+        Codec::Json
+    };
+
+    match codec {
+        Codec::Json => process_requests(Json, reader, writer, kv, store, read_only, metrics),
+        Codec::Bincode => process_requests(Bincode, reader, writer, kv, store, read_only, metrics),
+        Codec::MessagePack => {
+            process_requests(MessagePack, reader, writer, kv, store, read_only, metrics)
+        }
+    }
+}
+
+/// answer the requests of a connection in `codec`
+fn process_requests<C: MessageCodec>(
+    codec: C,
+    reader: impl Read,
+    mut writer: impl Write,
+    kv: &impl KvsEngine,
+    store: &KvStoreOptions,
+    read_only: bool,
+    metrics: &ServerMetrics,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    for decoded in codec.decode_stream::<Request, _>(reader) {
+        // a message which isn't a request is answered and skipped, while a stream which
+        // can't be split into messages anymore is answered and closed
+        let request = match decoded {
+            Ok(Decoded::Message(request)) => request,
+            Ok(Decoded::Invalid(e)) => {
+                bad_request(&mut writer, codec, &mut buf, metrics, &e)?;
+                continue;
+            }
+            Err(e @ KvsError::StdIo(_)) => return Err(e),
+            Err(e) => {
+                // the response tells the client why the connection is closed
+                bad_request(&mut writer, codec, &mut buf, metrics, &e)?;
+                return Err(e);
+            }
+        };
+        #[cfg(feature = "tracing")]
+        let (span, start) = (request_span(&request).entered(), Instant::now());
+        log::debug!("request {:?}", request);
+        metrics.request(Some(&request));
+
+        let response = match request.into_kind() {
+            RequestKind::Read(request) => read(request, kv, metrics),
+            RequestKind::Write(_) if read_only => Response {
+                error: Some(KvsError::ReadOnly.into()),
+                ..Default::default()
+            },
+            RequestKind::Write(request) => write(request, kv, store),
+        };
+        log::debug!("response {:?}", response);
+        #[cfg(feature = "tracing")]
+        span.record("latency_us", start.elapsed().as_micros() as u64);
+        metrics.response(&response);
+
+        send(&mut writer, codec, &mut buf, &response)?;
+    }
+
+    Ok(())
+}
+
+/// answer a message which can't be decoded with a bad request error
+fn bad_request(
+    writer: &mut impl Write,
+    codec: impl MessageCodec,
+    buf: &mut Vec<u8>,
+    metrics: &ServerMetrics,
+    e: &KvsError,
+) -> Result<()> {
+    let response = Response {
+        error: Some(KvsError::BadRequest(e.to_string()).into()),
+        ..Default::default()
+    };
+    metrics.request(None);
+    metrics.response(&response);
+    send(writer, codec, buf, &response)
+}
+
+/// write `response` in `codec`, encoding it in `buf` first
+fn send(
+    writer: &mut impl Write,
+    codec: impl MessageCodec,
+    buf: &mut Vec<u8>,
+    response: &Response,
+) -> Result<()> {
+    buf.clear();
+    codec.encode(buf, response)?;
+    writer.write_all(buf)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// span of a request, closed once it is answered
+#[cfg(feature = "tracing")]
+fn request_span(request: &Request) -> tracing::Span {
+    tracing::info_span!(
+        "request",
+        op = request.op(),
+        key_len = request.key_len(),
+        latency_us = tracing::field::Empty
+    )
+}
+
+/// answer a read request, without waiting on writes to the engine
+fn read(request: ReadRequest, kv: &impl KvsEngine, metrics: &ServerMetrics) -> Response {
+    match request {
+        ReadRequest::Get { key } => match kv.get(key) {
+            Ok(value) => Response {
+                value,
+                error: None,
+                ..Default::default()
+            },
+            Err(e) => Response {
+                value: None,
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        ReadRequest::GetMany { keys } => match kv.get_many(keys) {
+            Ok(values) => Response {
+                values: Some(values),
+                ..Default::default()
+            },
+            Err(e) => Response {
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        ReadRequest::Ping => Response {
+            value: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            error: None,
+            ..Default::default()
+        },
+        ReadRequest::Stats => Response {
+            stats: Some(metrics.snapshot()),
+            ..Default::default()
+        },
+        ReadRequest::Version { key } => match kv.version(key) {
+            Ok(version) => Response {
+                value: version.map(|version| version.to_string()),
+                ..Default::default()
+            },
+            Err(e) => Response {
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+    }
+}
+
+/// answer a write request, writes are serialized by the engine
+///
+/// keys and values over the limits of the store options are rejected for any engine
+fn write(request: WriteRequest, kv: &impl KvsEngine, store: &KvStoreOptions) -> Response {
+    if let Err(e) = request.check_size(store) {
+        return Response {
+            error: Some(e.into()),
+            ..Default::default()
+        };
+    }
+    match request {
+        WriteRequest::Set { key, value } => match kv.set(key, value) {
+            Ok(_) => Response {
+                value: None,
+                error: None,
+                ..Default::default()
+            },
+            Err(e) => Response {
+                value: None,
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        WriteRequest::Rm { key } => match kv.remove(key) {
+            Ok(_) => Response {
+                value: None,
+                error: None,
+                ..Default::default()
+            },
+            Err(e) => Response {
+                value: None,
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        WriteRequest::Incr { key, delta } => match kv.incr(key, delta) {
+            Ok(value) => Response {
+                value: Some(value.to_string()),
+                ..Default::default()
+            },
+            Err(e) => Response {
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        WriteRequest::Append { key, suffix } => match kv.append(key, suffix) {
+            Ok(_) => Response::default(),
+            Err(e) => Response {
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        WriteRequest::SetIfVersion {
+            key,
+            value,
+            expected_version,
+        } => match kv.set_if_version(key, value, expected_version) {
+            Ok(version) => Response {
+                value: Some(version.to_string()),
+                ..Default::default()
+            },
+            Err(e) => Response {
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+        WriteRequest::Flush => match kv.flush() {
+            Ok(_) => Response::default(),
+            Err(e) => Response {
+                error: Some(e.into()),
+                ..Default::default()
+            },
+        },
+    }
+}
@@ -2,34 +2,299 @@
  * sled wrapper
  */
 
-use sled::Db;
+use std::{
+    cell::{Cell, RefCell},
+    convert::TryInto,
+    str,
+    time::Duration,
+};
 
-use crate::{KvsEngine, KvsError, Result};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError, UnabortableTransactionError},
+    Batch, Db, IVec,
+};
+
+use crate::{
+    engine, ttl, BatchOp, BytesKvsEngine, DurabilityMode, KvsEngine, KvsError, Result, Txn,
+};
+
+/// marks a value stored with an expiry, it never starts a valid utf8 value
+/// so values written by [`KvsEngine::set`] are unaffected
+const EXPIRE_MARKER: u8 = 0xff;
 
 /// A wrapper for sled
 #[derive(Clone)]
 pub struct SledKvsEngine {
     /// sled db
     pub db: Db,
+    durability: DurabilityMode,
+}
+
+impl SledKvsEngine {
+    /// wrap a sled db, flushing it after each write
+    pub fn new(db: Db) -> Self {
+        Self {
+            db,
+            durability: DurabilityMode::default(),
+        }
+    }
+
+    /// set whether each write flushes the db, sled always syncs when flushing,
+    /// so [`DurabilityMode::None`] leaves flushing to sled's background thread
+    pub fn durability(mut self, durability: DurabilityMode) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// flush the db after a write as required by the durability mode
+    fn flush_written(&self) -> Result<()> {
+        if self.durability != DurabilityMode::None {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    /// remove all keys
+    pub fn clear(&self) -> Result<()> {
+        self.db.clear()?;
+        self.flush_written()
+    }
+
+    /// set a key-value pair which expires after `ttl`
+    ///
+    /// the expiry is stored in front of the value bytes,
+    /// expired keys are removed lazily when accessed
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let mut bytes = Vec::with_capacity(9 + value.len());
+        bytes.push(EXPIRE_MARKER);
+        bytes.extend_from_slice(&ttl::expire_at(ttl).to_be_bytes());
+        bytes.extend_from_slice(value.as_bytes());
+
+        self.db.insert(key.as_bytes(), bytes)?;
+        self.flush_written()?;
+        Ok(())
+    }
+
+    /// replace the value of `key` by the result of `f` called with the current value,
+    /// the key is removed if `f` returns `None`
+    ///
+    /// the update is atomic, `f` is called again if the key is changed concurrently,
+    /// an expiry of the key is dropped when it is set
+    pub fn update<F: FnMut(Option<String>) -> Option<String>>(
+        &self,
+        key: String,
+        mut f: F,
+    ) -> Result<()> {
+        let mut error = None;
+        self.db.update_and_fetch(key, |bytes| {
+            let current = match bytes.and_then(Self::live_value) {
+                Some(value) => match String::from_utf8(value.to_vec()) {
+                    Ok(value) => Some(value),
+                    Err(e) => {
+                        // keep the entry unchanged
+                        error = Some(e);
+                        return bytes.map(<[u8]>::to_vec);
+                    }
+                },
+                None => None,
+            };
+            error = None;
+            f(current).map(String::into_bytes)
+        })?;
+        if let Some(e) = error {
+            return Err(e.into());
+        }
+        self.flush_written()
+    }
+
+    /// live key-value pairs whose key starts with `prefix`, in key order
+    pub fn scan_prefix(&self, prefix: &str) -> impl Iterator<Item = Result<(String, String)>> {
+        self.db
+            .scan_prefix(prefix)
+            .filter_map(|res| -> Option<Result<_>> {
+                let (key, bytes) = match res {
+                    Ok(pair) => pair,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                let value = Self::live_value(&bytes)?;
+                Some(
+                    String::from_utf8(key.to_vec())
+                        .and_then(|key| Ok((key, String::from_utf8(value.to_vec())?)))
+                        .map_err(KvsError::from),
+                )
+            })
+    }
+
+    /// apply all `ops` atomically, in order, readers see either none or all of them
+    pub fn batch(&self, ops: impl IntoIterator<Item = BatchOp>) -> Result<()> {
+        self.db.apply_batch(Self::to_batch(ops))?;
+        self.flush_written()
+    }
+
+    fn to_batch(ops: impl IntoIterator<Item = BatchOp>) -> Batch {
+        let mut batch = Batch::default();
+        for op in ops {
+            match op {
+                BatchOp::Set { key, value } => batch.insert(key.as_bytes(), value.as_bytes()),
+                BatchOp::Remove { key } => batch.remove(key.as_bytes()),
+            }
+        }
+        batch
+    }
+
+    /// run `f` with a transaction, and apply its buffered writes atomically once `f`
+    /// returns `Ok`, nothing is written if it fails
+    ///
+    /// this is a sled transaction, `f` is called again if a key it read is changed concurrently
+    pub fn transaction<F: FnMut(&mut Txn<'_>) -> Result<()>>(&self, f: F) -> Result<()> {
+        let f = RefCell::new(f);
+        let result = self.db.transaction(|tx| {
+            let conflict = Cell::new(false);
+            let mut txn = Txn::new(|key| match tx.get(key) {
+                Ok(bytes) => Ok(match bytes.as_deref().and_then(Self::live_value) {
+                    Some(value) => Some(String::from_utf8(value.to_vec())?),
+                    None => None,
+                }),
+                Err(UnabortableTransactionError::Conflict) => {
+                    // whatever `f` does with the error, the transaction is retried
+                    conflict.set(true);
+                    Err(sled::Error::Unsupported("transaction conflict".to_owned()).into())
+                }
+                Err(UnabortableTransactionError::Storage(e)) => Err(e.into()),
+            });
+            let result = (f.borrow_mut())(&mut txn);
+            let ops = txn.into_ops();
+            if conflict.get() {
+                return Err(UnabortableTransactionError::Conflict.into());
+            }
+            result.map_err(ConflictableTransactionError::Abort)?;
+            tx.apply_batch(&Self::to_batch(ops))?;
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => self.flush_written(),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    /// value bytes of a stored entry, `None` if it is expired
+    fn live_value(bytes: &[u8]) -> Option<&[u8]> {
+        match bytes.split_first() {
+            Some((&EXPIRE_MARKER, rest)) if rest.len() >= 8 => {
+                let (expire_at, value) = rest.split_at(8);
+                let expire_at = u64::from_be_bytes(expire_at.try_into().unwrap());
+                (!ttl::is_expired(expire_at)).then_some(value)
+            }
+            _ => Some(bytes),
+        }
+    }
 }
 
 impl KvsEngine for SledKvsEngine {
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(key.as_bytes(), value.as_bytes())?;
-        self.db.flush()?;
-        Ok(())
+        self.set_bytes(key.into_bytes(), value)
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        self.db
-            .get(key)?
-            .map(|x| -> Result<_> { Ok(String::from_utf8(x.to_vec())?) })
-            .transpose()
+        self.get_bytes(key.into_bytes())
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        let mut current = self.db.get(&key)?;
+        loop {
+            if current
+                .as_ref()
+                .is_some_and(|bytes| Self::live_value(bytes).is_some())
+            {
+                return Ok(false);
+            }
+            // an expired entry counts as absent and is replaced
+            match self
+                .db
+                .compare_and_swap(&key, current, Some(value.as_bytes()))?
+            {
+                Ok(()) => break,
+                Err(e) => current = e.current,
+            }
+        }
+        self.flush_written()?;
+        Ok(true)
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        self.remove_bytes(key.into_bytes())
+    }
+
+    fn flush(&self) -> Result<()> {
         self.db.flush()?;
         Ok(())
     }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let mut result = Ok(0);
+        self.db.update_and_fetch(key, |bytes| {
+            let current = bytes.and_then(Self::live_value).map(str::from_utf8);
+            result = match current.transpose() {
+                Ok(value) => engine::incremented(value, delta),
+                Err(_) => Err(KvsError::NotAnInteger),
+            };
+            match &result {
+                // an expiry of the key is dropped, as with `set`
+                Ok(sum) => Some(sum.to_string().into_bytes()),
+                // keep the entry unchanged
+                Err(_) => bytes.map(<[u8]>::to_vec),
+            }
+        })?;
+        let sum = result?;
+        self.flush_written()?;
+        Ok(sum)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        self.db.update_and_fetch(key, |bytes| {
+            // an expiry of the key is dropped, as with `set`
+            let mut value = bytes
+                .and_then(Self::live_value)
+                .unwrap_or_default()
+                .to_vec();
+            value.extend_from_slice(suffix.as_bytes());
+            Some(value)
+        })?;
+        self.flush_written()
+    }
+}
+
+impl BytesKvsEngine for SledKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: String) -> Result<()> {
+        self.db.insert(key, value.as_bytes())?;
+        self.flush_written()?;
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<String>> {
+        let bytes = match self.db.get(&key)? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+
+        match Self::live_value(&bytes) {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => {
+                // only remove the entry if it was not overwritten in the meantime
+                let _ = self
+                    .db
+                    .compare_and_swap(key, Some(bytes), None as Option<IVec>)?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let bytes = self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        self.flush_written()?;
+        Self::live_value(&bytes).ok_or(KvsError::KeyNotFound)?;
+        Ok(())
+    }
 }
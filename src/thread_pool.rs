@@ -1,9 +1,21 @@
 /*! thread pool */
-use std::thread::{self};
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
 
-use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+
+use crate::{KvsError, Result};
+
+/// a job queued in a thread pool
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
 
-use crate::Result;
 /// thread pool trait
 pub trait ThreadPool: Sized {
     /// init naive thread pool
@@ -19,6 +31,7 @@ pub trait ThreadPool: Sized {
 pub struct NaiveThreadPool;
 
 impl ThreadPool for NaiveThreadPool {
+    /// `threads` is ignored, as a thread is spawned for each job
     fn new(_threads: u32) -> Result<Self> {
         Ok(NaiveThreadPool)
     }
@@ -27,43 +40,100 @@ impl ThreadPool for NaiveThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        thread::spawn(move || run_catching_panic(job));
     }
 }
 
 /// a shared queue thread pool
+///
+/// dropping the pool closes the queue and waits for queued jobs to finish
 pub struct SharedQueueThreadPool {
-    sender: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    active_workers: Arc<AtomicUsize>,
 }
 
-struct QueueReceiver {
-    receiver: Receiver<Box<dyn FnOnce() + Send + 'static>>,
+/// marks a worker as active while a job runs, also when the job panics
+struct ActiveGuard<'a>(&'a AtomicUsize);
+
+impl<'a> ActiveGuard<'a> {
+    fn new(active_workers: &'a AtomicUsize) -> Self {
+        active_workers.fetch_add(1, Ordering::SeqCst);
+        Self(active_workers)
+    }
 }
 
-impl Drop for QueueReceiver {
+impl Drop for ActiveGuard<'_> {
     fn drop(&mut self) {
-        if thread::panicking() {
-            let r = Self {
-                receiver: self.receiver.clone(),
-            };
-            thread::spawn(move || run_job(r));
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+
+        // workers catch panics of jobs, so each runs until the queue is closed and empty
+        for handle in self.workers.drain(..) {
+            let _ = handle.join();
         }
     }
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: u32) -> Result<Self> {
-        let (sender, receiver) = channel::unbounded();
+impl SharedQueueThreadPool {
+    /// init a thread pool whose queue holds at most `queue_cap` jobs
+    ///
+    /// an unbounded queue lets a fast producer queue jobs until memory runs out,
+    /// a bounded one applies backpressure instead: [`ThreadPool::spawn`] blocks
+    /// while the queue is full and [`SharedQueueThreadPool::try_spawn`] hands the job back
+    pub fn with_capacity(threads: u32, queue_cap: usize) -> Result<Self> {
+        check_threads(threads)?;
+        Ok(Self::with_channel(threads, channel::bounded(queue_cap)))
+    }
+
+    /// spawn a job without blocking, the job is returned if the queue is full
+    pub fn try_spawn<F>(&self, job: F) -> std::result::Result<(), Job>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .as_ref()
+            .expect("thread pool is shut down")
+            .try_send(Box::new(job))
+            .map_err(TrySendError::into_inner)
+    }
 
-        for _ in 0..threads {
-            let receiver = QueueReceiver {
-                receiver: receiver.clone(),
-            };
+    /// number of jobs waiting in the queue
+    pub fn queued_jobs(&self) -> usize {
+        self.sender.as_ref().map_or(0, Sender::len)
+    }
+
+    /// number of workers running a job
+    pub fn active_workers(&self) -> usize {
+        self.active_workers.load(Ordering::SeqCst)
+    }
 
-            thread::spawn(move || run_job(receiver));
+    fn with_channel(threads: u32, (sender, receiver): (Sender<Job>, Receiver<Job>)) -> Self {
+        let active_workers = Arc::new(AtomicUsize::new(0));
+        let workers = (0..threads)
+            .map(|_| {
+                let (receiver, active_workers) = (receiver.clone(), active_workers.clone());
+                thread::spawn(move || run_jobs(&receiver, &active_workers))
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            workers,
+            active_workers,
         }
+    }
+}
 
-        Ok(Self { sender })
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        check_threads(threads)?;
+        Ok(Self::with_channel(threads, channel::unbounded()))
     }
 
     fn spawn<F>(&self, job: F)
@@ -71,24 +141,61 @@ impl ThreadPool for SharedQueueThreadPool {
         F: FnOnce() + Send + 'static,
     {
         self.sender
+            .as_ref()
+            .expect("thread pool is shut down")
             .send(Box::new(job))
             .expect("send job in thread pool failed");
     }
 }
 
-fn run_job(r: QueueReceiver) {
-    for job in r.receiver.iter() {
-        job()
+/// a pool of a fixed number of workers without threads would queue jobs forever
+fn check_threads(threads: u32) -> Result<()> {
+    if threads == 0 {
+        return Err(KvsError::ThreadPool(
+            "at least one thread is required".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+fn run_jobs(receiver: &Receiver<Job>, active_workers: &AtomicUsize) {
+    for job in receiver.iter() {
+        let _active = ActiveGuard::new(active_workers);
+        run_catching_panic(job)
     }
 }
 
+/// run a job and log its panic instead of unwinding the worker
+fn run_catching_panic<F: FnOnce()>(job: F) {
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+        log::error!("job panicked: {}", panic_message(payload.as_ref()));
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic payload")
+}
+
 /// a thread pool based on rayon
 pub struct RayonThreadPool {
     pool: rayon::ThreadPool,
 }
 
+impl RayonThreadPool {
+    /// number of threads in the pool
+    pub fn current_num_threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+}
+
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
+        // rayon takes 0 as the default number of threads
+        check_threads(threads)?;
         Ok(Self {
             pool: rayon::ThreadPoolBuilder::new()
                 .num_threads(threads as usize)
@@ -100,6 +207,6 @@ impl ThreadPool for RayonThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        self.pool.spawn(job)
+        self.pool.spawn(move || run_catching_panic(job))
     }
 }
@@ -0,0 +1,184 @@
+/*!
+ * tls over tcp connections between client and server
+ */
+
+use std::{
+    fmt::{self, Debug},
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, ServerName},
+    ClientConfig, ClientConnection, ConnectionCommon, RootCertStore, ServerConfig,
+    ServerConnection, SideData, StreamOwned,
+};
+
+use crate::{KvsError, Result};
+
+/// tls config of a server presenting the pem certificate chain at `cert`
+/// and the pem private key at `key`
+pub fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| KvsError::TlsConfig(format!("no private key in {}", key.display())))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert)?, key)?;
+    Ok(Arc::new(config))
+}
+
+/// tls config of a client trusting the pem certificates at `ca_cert`
+pub fn client_config(ca_cert: &Path) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_cert)? {
+        roots.add(cert)?;
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(KvsError::TlsConfig(format!(
+            "no certificates in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+/// tls is only spoken over tcp
+#[cfg(unix)]
+pub(crate) fn unix_error() -> KvsError {
+    KvsError::TlsConfig("tls is not supported on unix domain sockets".to_owned())
+}
+
+/// a tls connection over a tcp stream, read and written like a [`TcpStream`]
+///
+/// reads and writes of all handles from [`TlsStream::try_clone`] take turns on the one
+/// connection, a read holds it until it returns. So a handle shouldn't wait for
+/// data in a read while another handle has a request to write, as a server answering
+/// a request at a time doesn't. The handshake is done by the first read or write
+pub struct TlsStream {
+    stream: Arc<Mutex<Stream>>,
+    tcp: TcpStream,
+}
+
+enum Stream {
+    Client(StreamOwned<ClientConnection, TcpStream>),
+    Server(StreamOwned<ServerConnection, TcpStream>),
+}
+
+impl TlsStream {
+    /// serve tls on an accepted `tcp` stream
+    pub fn accept(tcp: TcpStream, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let conn = ServerConnection::new(config).map_err(io::Error::other)?;
+        Self::new(tcp, |tcp| Stream::Server(StreamOwned::new(conn, tcp)))
+    }
+
+    /// speak tls over a connected `tcp` stream to a server with a certificate for `name`
+    pub fn connect(
+        tcp: TcpStream,
+        name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+    ) -> io::Result<Self> {
+        let conn = ClientConnection::new(config, name).map_err(io::Error::other)?;
+        Self::new(tcp, |tcp| Stream::Client(StreamOwned::new(conn, tcp)))
+    }
+
+    fn new(tcp: TcpStream, stream: impl FnOnce(TcpStream) -> Stream) -> io::Result<Self> {
+        Ok(Self {
+            stream: Arc::new(Mutex::new(stream(tcp.try_clone()?))),
+            tcp,
+        })
+    }
+
+    /// another handle to the same connection
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            stream: self.stream.clone(),
+            tcp: self.tcp.try_clone()?,
+        })
+    }
+
+    /// the underlying tcp stream
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.tcp
+    }
+}
+
+impl Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsStream").field("tcp", &self.tcp).finish()
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut *self.stream.lock().unwrap() {
+            Stream::Client(stream) => stream.read(buf),
+            Stream::Server(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut *self.stream.lock().unwrap() {
+            Stream::Client(stream) => stream.write(buf),
+            Stream::Server(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut *self.stream.lock().unwrap() {
+            Stream::Client(stream) => stream.flush(),
+            Stream::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Drop for Stream {
+    /// tell the peer the stream ends here, rather than being cut off
+    fn drop(&mut self) {
+        match self {
+            Stream::Client(StreamOwned { conn, sock }) => close_notify(conn, sock),
+            Stream::Server(StreamOwned { conn, sock }) => close_notify(conn, sock),
+        }
+    }
+}
+
+fn close_notify<S: SideData>(conn: &mut ConnectionCommon<S>, sock: &mut TcpStream) {
+    conn.send_close_notify();
+    while conn.wants_write() {
+        if conn.write_tls(sock).is_err() {
+            break;
+        }
+    }
+}
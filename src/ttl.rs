@@ -0,0 +1,21 @@
+/*!
+ * helpers for key expiration
+ */
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// absolute expiry timestamp in milliseconds since unix epoch for a `ttl` from now
+pub(crate) fn expire_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// whether an absolute expiry timestamp has passed
+pub(crate) fn is_expired(expire_at: u64) -> bool {
+    expire_at <= now_millis()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
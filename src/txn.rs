@@ -0,0 +1,55 @@
+/*!
+ * transactions buffering writes until they commit
+ */
+
+use crate::{BatchOp, KvsError, Result};
+
+/// live value of a key in the engine
+type ReadFn<'a> = Box<dyn FnMut(&str) -> Result<Option<String>> + 'a>;
+
+/// writes of a transaction, buffered until the closure passed to
+/// [`KvStore::transaction`](crate::KvStore::transaction) or
+/// [`SledKvsEngine::transaction`](crate::SledKvsEngine::transaction) returns
+pub struct Txn<'a> {
+    ops: Vec<BatchOp>,
+    read: ReadFn<'a>,
+}
+
+impl<'a> Txn<'a> {
+    pub(crate) fn new(read: impl FnMut(&str) -> Result<Option<String>> + 'a) -> Self {
+        Self {
+            ops: Vec::new(),
+            read: Box::new(read),
+        }
+    }
+
+    /// value of `key`, seeing the writes buffered so far
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        let buffered = self.ops.iter().rev().find(|op| match op {
+            BatchOp::Set { key: k, .. } | BatchOp::Remove { key: k } => k == key,
+        });
+        match buffered {
+            Some(BatchOp::Set { value, .. }) => Ok(Some(value.clone())),
+            Some(BatchOp::Remove { .. }) => Ok(None),
+            None => (self.read)(key),
+        }
+    }
+
+    /// buffer setting a key-value pair
+    pub fn set(&mut self, key: String, value: String) {
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    /// buffer removing a key, fails with [`KvsError::KeyNotFound`] if it is absent
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.ops.push(BatchOp::Remove { key });
+        Ok(())
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
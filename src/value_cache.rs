@@ -0,0 +1,139 @@
+/*!
+ * cache of values read from generation files
+ */
+
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap},
+    hash::BuildHasher,
+    sync::{Arc, Mutex},
+};
+
+/// generation and offset of a set record
+type Position = (u64, u64);
+
+/// most shards of a cache, so concurrent reads of different values rarely wait on each other
+const SHARDS: usize = 16;
+
+/// least capacity of a shard, a smaller cache has fewer shards so a shard holds a few values
+const MIN_SHARD_CAPACITY: usize = 64 * 1024;
+
+/// the least recently read values, by the position of their record, up to a number of
+/// bytes of values
+///
+/// records are never rewritten in place, so an entry stays valid until its generation
+/// is deleted, a key written again is read at a new position and its old entry is evicted
+/// once it is the least recently read
+///
+/// positions are split among shards, each with its own lock and its share of the capacity,
+/// so the least recently read value is evicted from the shard of the value inserted
+pub(crate) struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    hasher: RandomState,
+}
+
+struct Shard {
+    /// bytes of values held at most
+    capacity: usize,
+    /// bytes of values held
+    size: usize,
+    /// value and the tick of its last read
+    values: HashMap<Position, (Arc<str>, u64)>,
+    /// positions by the tick of their last read, the first is evicted
+    order: BTreeMap<u64, Position>,
+    tick: u64,
+}
+
+impl ValueCache {
+    /// create a cache holding up to `capacity` bytes of values
+    pub(crate) fn new(capacity: usize) -> Self {
+        let shards = (capacity / MIN_SHARD_CAPACITY).clamp(1, SHARDS);
+        Self {
+            shards: (0..shards)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        capacity: capacity / shards,
+                        size: 0,
+                        values: HashMap::new(),
+                        order: BTreeMap::new(),
+                        tick: 0,
+                    })
+                })
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, position: Position) -> &Mutex<Shard> {
+        let hash = self.hasher.hash_one(position) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+
+    /// the value of the record at `position`, which becomes the most recently read
+    pub(crate) fn get(&self, position: Position) -> Option<Arc<str>> {
+        let mut shard = self.shard(position).lock().unwrap();
+        let Shard {
+            values,
+            order,
+            tick,
+            ..
+        } = &mut *shard;
+        let (value, last_read) = values.get_mut(&position)?;
+        *tick += 1;
+        order.remove(last_read);
+        order.insert(*tick, position);
+        *last_read = *tick;
+        Some(value.clone())
+    }
+
+    /// cache the value of the record at `position`, evicting the least recently read values
+    /// of its shard to make room, a value larger than a shard isn't cached
+    pub(crate) fn insert(&self, position: Position, value: Arc<str>) {
+        let mut shard = self.shard(position).lock().unwrap();
+        if value.len() > shard.capacity {
+            return;
+        }
+        let Shard {
+            capacity,
+            size,
+            values,
+            order,
+            tick,
+        } = &mut *shard;
+        *tick += 1;
+        *size += value.len();
+        if let Some((old, last_read)) = values.insert(position, (value, *tick)) {
+            *size -= old.len();
+            order.remove(&last_read);
+        }
+        order.insert(*tick, position);
+        while *size > *capacity {
+            let Some((_, evicted)) = order.pop_first() else {
+                break;
+            };
+            if let Some((value, _)) = values.remove(&evicted) {
+                *size -= value.len();
+            }
+        }
+    }
+
+    /// drop the values of generations below `generation`, which are deleted
+    pub(crate) fn remove_before(&self, generation: u64) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let Shard {
+                size,
+                values,
+                order,
+                ..
+            } = &mut *shard;
+            values.retain(|&(value_generation, _), (value, _)| {
+                let keep = value_generation >= generation;
+                if !keep {
+                    *size -= value.len();
+                }
+                keep
+            });
+            order.retain(|_, (value_generation, _)| *value_generation >= generation);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+#[cfg(feature = "sled-engine")]
+use kvs::SledKvsEngine;
+use kvs::{AnyEngine, KvStore, KvsEngine, KvsError, MemKvsEngine, Result};
+use std::thread;
+use tempfile::TempDir;
+
+// Engines behind the same boxed type should behave the same through `KvsEngine`
+#[test]
+fn any_engine() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    #[cfg(feature = "sled-engine")]
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines = vec![
+        AnyEngine::new(KvStore::open(kvs_dir.path())?),
+        #[cfg(feature = "sled-engine")]
+        AnyEngine::new(SledKvsEngine::new(sled::open(sled_dir.path())?)),
+    ];
+
+    for engine in engines {
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        // clones share the same pairs
+        let clone = engine.clone();
+        clone.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(
+            engine.get_many(vec![
+                "key1".to_owned(),
+                "key2".to_owned(),
+                "key3".to_owned()
+            ])?,
+            vec![Some("value1".to_owned()), Some("value2".to_owned()), None]
+        );
+
+        assert!(!engine.set_if_absent("key1".to_owned(), "other".to_owned())?);
+        assert_eq!(
+            engine.get_or_insert_with("key3".to_owned(), || "value3".to_owned())?,
+            "value3"
+        );
+        assert_eq!(
+            engine.get_or_insert_with("key3".to_owned(), || unreachable!())?,
+            "value3"
+        );
+
+        engine.remove("key1".to_owned())?;
+        assert_eq!(clone.get("key1".to_owned())?, None);
+        assert!(matches!(
+            clone.remove("key1".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+    }
+
+    Ok(())
+}
+
+// Concurrent increments should all add up in every engine
+#[test]
+fn concurrent_incr() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    #[cfg(feature = "sled-engine")]
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines = vec![
+        AnyEngine::new(KvStore::open(kvs_dir.path())?),
+        #[cfg(feature = "sled-engine")]
+        AnyEngine::new(SledKvsEngine::new(sled::open(sled_dir.path())?)),
+        AnyEngine::new(MemKvsEngine::new()),
+    ];
+
+    for engine in engines {
+        let handles: Vec<_> = (1..=8)
+            .map(|delta| {
+                let engine = engine.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        engine.incr("counter".to_owned(), delta).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // 100 times each delta from 1 to 8
+        assert_eq!(engine.get("counter".to_owned())?, Some("3600".to_owned()));
+        assert_eq!(engine.incr("counter".to_owned(), -3600)?, 0);
+
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        assert!(matches!(
+            engine.incr("key1".to_owned(), 1),
+            Err(KvsError::NotAnInteger)
+        ));
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        engine.set("key1".to_owned(), i64::MAX.to_string())?;
+        assert!(matches!(
+            engine.incr("key1".to_owned(), 1),
+            Err(KvsError::NotAnInteger)
+        ));
+    }
+
+    Ok(())
+}
+
+// Appends should be concatenated in order, and from many threads keep every suffix
+#[test]
+fn append() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    #[cfg(feature = "sled-engine")]
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines = vec![
+        AnyEngine::new(KvStore::open(kvs_dir.path())?),
+        #[cfg(feature = "sled-engine")]
+        AnyEngine::new(SledKvsEngine::new(sled::open(sled_dir.path())?)),
+        AnyEngine::new(MemKvsEngine::new()),
+    ];
+
+    for engine in engines {
+        // an absent key is set to the suffix
+        for i in 0..10 {
+            engine.append("log".to_owned(), format!("{},", i))?;
+        }
+        assert_eq!(
+            engine.get("log".to_owned())?,
+            Some("0,1,2,3,4,5,6,7,8,9,".to_owned())
+        );
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let engine = engine.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        engine.append("counter".to_owned(), "x".to_owned()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(engine.get("counter".to_owned())?, Some("x".repeat(200)));
+    }
+
+    Ok(())
+}
+
+// An engine should be usable as a trait object, and boxed into `AnyEngine`
+#[test]
+fn dyn_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // methods of a trait object are callable without importing the trait
+    let engine: Box<dyn kvs::DynKvsEngine> = Box::new(KvStore::open(temp_dir.path())?);
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let clone = engine.clone_box();
+    assert_eq!(clone.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let engine = AnyEngine::from(clone);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+/// an engine implementing only the required methods
+#[derive(Clone)]
+struct MinimalEngine(MemKvsEngine);
+
+impl KvsEngine for MinimalEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+}
+
+// Methods added after set, get and remove should default to unsupported
+#[test]
+fn default_methods() -> Result<()> {
+    let engine = AnyEngine::new(MinimalEngine(MemKvsEngine::new()));
+    engine.set("key1".to_owned(), "1".to_owned())?;
+    assert_eq!(
+        engine.get_many(vec!["key1".to_owned()])?,
+        vec![Some("1".to_owned())]
+    );
+    assert!(matches!(
+        engine.set_if_absent("key1".to_owned(), "2".to_owned()),
+        Err(KvsError::Unsupported("set_if_absent"))
+    ));
+    assert!(matches!(
+        engine.flush(),
+        Err(KvsError::Unsupported("flush"))
+    ));
+    assert!(matches!(
+        engine.incr("key1".to_owned(), 1),
+        Err(KvsError::Unsupported("incr"))
+    ));
+    assert!(matches!(
+        engine.append("key1".to_owned(), "2".to_owned()),
+        Err(KvsError::Unsupported("append"))
+    ));
+    assert_eq!(engine.get("key1".to_owned())?, Some("1".to_owned()));
+    Ok(())
+}
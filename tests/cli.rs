@@ -1,6 +1,10 @@
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::TcpStream;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -20,28 +24,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +56,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +95,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +127,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +138,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +149,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +161,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -172,21 +177,23 @@ fn cli_log_configuration() {
 }
 
 #[test]
+#[cfg(feature = "sled-engine")]
 fn cli_wrong_engine() {
     // sled first, kvs second
     {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,39 +204,82 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
     }
 }
 
+// Data and the engine marker should be stored in `--data-dir`, not the working directory
+#[test]
+#[cfg(feature = "sled-engine")]
+fn cli_data_dir() {
+    let work_dir = TempDir::new().unwrap();
+    let data_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4009", "--data-dir"])
+        .arg(data_dir.path())
+        .current_dir(&work_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert_eq!(
+        fs::read_to_string(data_dir.path().join("engine")).unwrap(),
+        "sled"
+    );
+    assert_eq!(fs::read_dir(work_dir.path()).unwrap().count(), 0);
+
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4009", "--data-dir"])
+        .arg(data_dir.path())
+        .current_dir(&work_dir)
+        .assert()
+        .failure();
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
+    cli_access_server_bin("kvs-server", engine, addr)
+}
+
+fn cli_access_server_bin(bin: &str, engine: &str, addr: &str) {
+    cli_access_server_args(bin, &["--engine", engine], addr)
+}
+
+fn cli_access_server_args(bin: &str, args: &[&str], addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
-    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut server = Command::cargo_bin(bin).unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(args)
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // the data dir is reopened next, once the killed server has released it
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +287,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +295,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +303,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +311,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +319,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +327,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +335,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -296,28 +346,31 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     // Reopen and check value
     let (sender, receiver) = mpsc::sync_channel(0);
-    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut server = Command::cargo_bin(bin).unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(args)
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // the data dir is reopened next, once the killed server has released it
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -332,6 +385,624 @@ fn cli_access_server_kvs_engine() {
 }
 
 #[test]
+#[cfg(feature = "sled-engine")]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_async_server_kvs_engine() {
+    cli_access_server_bin("kvs-server-async", "kvs", "127.0.0.1:4006");
+}
+
+#[test]
+#[cfg(feature = "sled-engine")]
+fn cli_access_async_server_sled_engine() {
+    cli_access_server_bin("kvs-server-async", "sled", "127.0.0.1:4007");
+}
+
+#[test]
+fn cli_access_server_pools() {
+    let combinations = [
+        ("kvs", "naive", "127.0.0.1:4020"),
+        ("kvs", "shared", "127.0.0.1:4021"),
+        ("kvs", "rayon", "127.0.0.1:4022"),
+        ("sled", "naive", "127.0.0.1:4023"),
+        ("sled", "shared", "127.0.0.1:4024"),
+        ("sled", "rayon", "127.0.0.1:4025"),
+    ];
+    for (engine, pool, addr) in combinations {
+        if engine == "sled" && cfg!(not(feature = "sled-engine")) {
+            continue;
+        }
+        cli_access_server_args("kvs-server", &["--engine", engine, "--pool", pool], addr);
+    }
+}
+
+// the socket path is relative to the working directory shared by server and client
+#[test]
+fn cli_access_server_unix_socket() {
+    cli_access_server_bin("kvs-server", "kvs", "unix:kvs.sock");
+}
+
+#[test]
+fn cli_access_async_server_unix_socket() {
+    cli_access_server_bin("kvs-server-async", "kvs", "unix:kvs.sock");
+}
+
+// The socket file should be removed on termination, and a stale one replaced on start
+#[test]
+fn cli_unix_socket_cleanup() {
+    for bin in ["kvs-server", "kvs-server-async"] {
+        let temp_dir = TempDir::new().unwrap();
+        let socket = temp_dir.path().join("kvs.sock");
+        let addr = format!("unix:{}", socket.display());
+
+        // a server killed without cleanup leaves the socket file behind
+        let mut child = Command::cargo_bin(bin)
+            .unwrap()
+            .args(["--addr", &addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+        assert!(socket.exists());
+
+        let mut child = Command::cargo_bin(bin)
+            .unwrap()
+            .args(["--addr", &addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", &addr])
+            .assert()
+            .success();
+
+        Command::new("kill")
+            .arg(child.id().to_string())
+            .assert()
+            .success();
+        child.wait().unwrap();
+        assert!(!socket.exists());
+    }
+}
+
+#[test]
+fn cli_ping_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ping", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("pong").and(contains(env!("CARGO_PKG_VERSION"))));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// Logs should go to the log file, with per-request lines only at debug level
+#[test]
+fn cli_log_file() {
+    for (level, logs_requests) in [("info", false), ("debug", true)] {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("server.log");
+        let stderr_path = temp_dir.path().join("stderr");
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args([
+                "--addr",
+                "127.0.0.1:4026",
+                "--log-level",
+                level,
+                "--log-file",
+            ])
+            .arg(&log_path)
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", "127.0.0.1:4026"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        thread::sleep(Duration::from_millis(200));
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+
+        let content = fs::read_to_string(&log_path).expect("unable to read from log file");
+        assert!(content.contains(env!("CARGO_PKG_VERSION")));
+        assert!(content.contains("127.0.0.1:4026"));
+        assert_eq!(content.contains("key1"), logs_requests);
+        assert!(fs::read_to_string(&stderr_path).unwrap().is_empty());
+    }
+}
+
+// The in-memory engine should serve requests without touching the data dir
+#[test]
+fn cli_mem_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "mem", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+// A pool without threads should be refused at startup
+#[test]
+fn cli_zero_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4028", "--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("at least one thread is required"));
+}
+
+// Stats should count the connections and requests of earlier client commands
+#[test]
+fn cli_stats_async_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4029";
+    let mut server = Command::cargo_bin("kvs-server-async")
+        .unwrap()
+        .args(["--engine", "mem", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    client(&["set", "key1", "value1"]).success();
+    client(&["get", "key1"]).success();
+    client(&["rm", "missing"]).failure();
+    client(&["stats"])
+        .success()
+        .stdout("connections: 4\nrequests: 4\ngets: 1\nsets: 1\nremoves: 1\nerrors: 1\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// Values of a config file should be used, and flags should override them
+#[test]
+#[cfg(feature = "sled-engine")]
+fn cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("server.toml"),
+        "addr = \"127.0.0.1:4030\"\nengine = \"sled\"\n",
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "server.toml", "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4030"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+        "kvs"
+    );
+}
+
+// Both servers should listen with the backlog given by the flag
+#[test]
+fn cli_backlog() {
+    for (bin, addr) in [
+        ("kvs-server", "127.0.0.1:4032"),
+        ("kvs-server-async", "127.0.0.1:4033"),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin(bin)
+            .unwrap()
+            .args(["--engine", "mem", "--addr", addr, "--backlog", "16"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+
+        server.kill().expect("server exited before killed");
+        server.wait().unwrap();
+    }
+}
+
+// Without the sled feature the servers should only offer the other engines
+#[test]
+#[cfg(not(feature = "sled-engine"))]
+fn cli_no_sled_engine() {
+    for bin in ["kvs-server", "kvs-server-async"] {
+        let temp_dir = TempDir::new().unwrap();
+        Command::cargo_bin(bin)
+            .unwrap()
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4034"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("invalid value 'sled'"));
+    }
+}
+
+// Writes flushed by `kvs-client flush` should survive the server being killed,
+// even when the server doesn't persist writes by itself
+#[test]
+fn cli_flush() {
+    for (bin, addr) in [
+        ("kvs-server", "127.0.0.1:4036"),
+        ("kvs-server-async", "127.0.0.1:4037"),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(
+            temp_dir.path().join("server.toml"),
+            format!("addr = \"{addr}\"\nengine = \"kvs\"\n\n[store]\ndurability = \"none\"\n"),
+        )
+        .unwrap();
+        let mut server = Command::cargo_bin(bin)
+            .unwrap()
+            .args(["--config", "server.toml"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        let client = |args: &[&str]| {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(args)
+                .args(["--addr", addr])
+                .current_dir(&temp_dir)
+                .assert()
+                .success()
+                .stdout(is_empty());
+        };
+        client(&["set", "key1", "value1"]);
+        client(&["set", "key2", "value2"]);
+        client(&["rm", "key1"]);
+        client(&["flush"]);
+
+        // killed without any chance to write out buffered records
+        server.kill().expect("server exited before killed");
+        server.wait().unwrap();
+
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        assert_eq!(store.get("key1".to_owned()).unwrap(), None);
+        assert_eq!(
+            store.get("key2".to_owned()).unwrap(),
+            Some("value2".to_owned())
+        );
+    }
+}
+
+// `kvs-client incr` should print the new value, and fail on a value which isn't an integer
+#[test]
+fn cli_incr() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4038";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    client(&["incr", "counter", "5"]).success().stdout("5\n");
+    client(&["incr", "counter", "-7"]).success().stdout("-2\n");
+    client(&["get", "counter"]).success().stdout("-2\n");
+    client(&["set", "key1", "value1"]).success();
+    client(&["incr", "key1", "1"])
+        .failure()
+        .stderr(contains("Value is not an integer"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// An `engine` file naming no engine should fail both servers with its contents,
+// while a trailing newline added by hand is accepted
+#[test]
+fn cli_invalid_engine_file() {
+    for bin in ["kvs-server", "kvs-server-async"] {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("engine"), "bogus\n").unwrap();
+        // escaped again in json logs
+        let logged = if cfg!(feature = "tracing") && bin == "kvs-server" {
+            r#"Invalid engine file: \"bogus\\n\""#
+        } else {
+            "Invalid engine file: \"bogus\\n\""
+        };
+        Command::cargo_bin(bin)
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4041"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains(logged));
+
+        fs::write(temp_dir.path().join("engine"), "kvs\n").unwrap();
+        let mut server = Command::cargo_bin(bin)
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4041"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", "127.0.0.1:4041"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        server.kill().expect("server exited before killed");
+        server.wait().unwrap();
+    }
+}
+
+// With the `tracing` feature a request should be logged as a json span inside the span
+// of its connection, with its fields and latency
+#[test]
+#[cfg(feature = "tracing")]
+fn cli_tracing_spans() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4043"])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4043"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(200));
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    let content = fs::read_to_string(&stderr_path).unwrap();
+    let lines: Vec<serde_json::Value> = content
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("log line isn't json"))
+        .collect();
+    let request = lines
+        .iter()
+        .find(|line| line["span"]["name"] == "request")
+        .expect("request span not logged");
+    assert_eq!(request["span"]["op"], "set");
+    assert_eq!(request["span"]["key_len"], 4);
+    assert!(request["span"]["latency_us"].is_u64());
+    assert_eq!(request["spans"][0]["name"], "connection");
+    assert!(request["spans"][0]["peer"]
+        .as_str()
+        .unwrap()
+        .contains("127.0.0.1:4043"));
+    // the connection span closes after its request
+    assert!(lines
+        .iter()
+        .any(|line| line["span"]["name"] == "connection"));
+}
+
+// `kvs-server --readonly` should answer gets, and refuse sets and removes
+// without writing anything to the data dir
+#[test]
+fn cli_readonly() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+    let snapshot = || {
+        let mut files: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let contents = fs::read(&path).unwrap();
+                (path, contents)
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let before = snapshot();
+
+    let addr = "127.0.0.1:4045";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--readonly"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    client(&["get", "key1"]).success().stdout("value1\n");
+    client(&["set", "key2", "value2"])
+        .failure()
+        .stderr(contains("Store is read-only"));
+    client(&["rm", "key1"])
+        .failure()
+        .stderr(contains("Store is read-only"));
+    client(&["get", "key2"]).success().stdout("Key not found\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    assert_eq!(snapshot(), before);
+}
+
+// `kvs-server --readonly` should refuse the sled engine, which has no read-only mode,
+// before anything is written to the data dir
+#[test]
+#[cfg(feature = "sled-engine")]
+fn cli_readonly_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4057", "--readonly"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("read-only mode"));
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+// The endpoint of `--metrics-addr` should serve the counters of the server and the stats
+// of the store in the prometheus text format
+#[test]
+fn cli_metrics_endpoint() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, metrics_addr) = ("127.0.0.1:4046", "127.0.0.1:4047");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--metrics-addr",
+            metrics_addr,
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    client(&["set", "key1", "value1"]).success();
+    client(&["set", "key2", "value2"]).success();
+    client(&["get", "key1"]).success();
+    client(&["rm", "missing"]).failure();
+
+    let scrape = |path: &str| {
+        let mut stream = TcpStream::connect(metrics_addr).unwrap();
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: {metrics_addr}\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response = scrape("/metrics");
+    assert!(scrape("/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+    let sample = |name: &str| -> u64 {
+        let line = body
+            .lines()
+            .find(|line| line.starts_with(&format!("{name} ")))
+            .unwrap_or_else(|| panic!("{} not in {}", name, body));
+        line[name.len() + 1..].parse().unwrap()
+    };
+    assert_eq!(sample("requests_total{op=\"get\"}"), 1);
+    assert_eq!(sample("requests_total{op=\"set\"}"), 2);
+    assert_eq!(sample("requests_total{op=\"rm\"}"), 1);
+    assert_eq!(sample("errors_total"), 1);
+    assert_eq!(sample("connections_total"), 4);
+    assert_eq!(sample("compactions_total"), 0);
+    assert_eq!(sample("live_keys"), 2);
+    assert!(sample("db_bytes") > 0);
+    assert!(body.contains("# TYPE live_keys gauge\n"));
+}
@@ -0,0 +1,914 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    AsyncKvsClient, Codec, ErrorCode, KvsClient, KvsError, KvsServer, Request, Response,
+    ResponseError, Result, RetryPolicy, ServerConfig, ServerStats,
+};
+use predicates::prelude::*;
+use predicates::str::contains;
+use serde_json::Deserializer;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Kills the server when dropped, also when a test returns early
+struct Server {
+    child: Child,
+    // address the server listens on, as it logged it
+    addr: String,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.child.kill().expect("server exited before killed");
+        self.child.wait().unwrap();
+    }
+}
+
+fn start_server(temp_dir: &TempDir) -> Server {
+    start_server_bin("kvs-server", temp_dir)
+}
+
+fn start_server_bin(bin: &str, temp_dir: &TempDir) -> Server {
+    start_server_at(bin, temp_dir, "127.0.0.1:0", &["--engine", "kvs"])
+}
+
+// Starts `bin` in `temp_dir` with `args`, listening on `addr`, a port picked by the os
+// for port 0. Returns once the server logs the address it listens on, which it does
+// once the listener is bound
+fn start_server_at(bin: &str, temp_dir: &TempDir, addr: &str, args: &[&str]) -> Server {
+    let mut child = Command::cargo_bin(bin)
+        .unwrap()
+        .args(args)
+        .args(["--addr", addr])
+        .current_dir(temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(child.stderr.take().unwrap()).lines();
+    // the message is quoted in json logs of the `tracing` feature
+    let addr = lines.find_map(|line| {
+        let logged = line.ok()?.split_once("listening on ")?.1.to_owned();
+        logged.split('"').next()?.parse::<SocketAddr>().ok()
+    });
+    let server = Server {
+        child,
+        addr: addr.map(|addr| addr.to_string()).unwrap_or_default(),
+    };
+    assert!(!server.addr.is_empty(), "listening address not logged");
+    // later logs go to the same pipe, which is drained so the server never blocks on it
+    thread::spawn(move || {
+        lines
+            .map_while(|line| line.ok())
+            .for_each(|line| eprintln!("{line}"))
+    });
+    server
+}
+
+// An address nothing listens on, for a server started later
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+// Responses should line up with requests sent over one connection
+#[test]
+fn client_reuses_connection() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir);
+    let addr = server.addr.as_str();
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Pipelined responses should come back in request order
+#[test]
+fn client_pipeline() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir);
+    let addr = server.addr.as_str();
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut requests = Vec::new();
+    for i in 0..1000 {
+        requests.push(Request::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        });
+        requests.push(Request::Get {
+            key: format!("key{}", i),
+        });
+    }
+    requests.push(Request::Rm {
+        key: "missing".to_owned(),
+    });
+
+    let responses = client.pipeline(requests)?;
+    assert_eq!(responses.len(), 2001);
+    for i in 0..1000 {
+        assert_eq!(responses[2 * i].value, None);
+        assert_eq!(responses[2 * i + 1].value, Some(format!("value{}", i)));
+    }
+    assert_eq!(
+        responses[2000].error.as_ref().map(|e| e.code),
+        Some(ErrorCode::KeyNotFound)
+    );
+
+    // the connection is still usable after a pipeline
+    assert_eq!(
+        client.get("key999".to_owned())?,
+        Some("value999".to_owned())
+    );
+
+    Ok(())
+}
+
+// Multi-get should match individual gets
+#[test]
+fn client_get_many() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir);
+    let addr = server.addr.as_str();
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+
+    let keys = vec!["key3".to_owned(), "key2".to_owned(), "key1".to_owned()];
+    let values = client.get_many(keys.clone())?;
+    for (key, value) in keys.into_iter().zip(values) {
+        assert_eq!(client.get(key)?, value);
+    }
+    // free the connection, the server may only have one worker
+    drop(client);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mget", "key1", "key2", "key3", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\nKey not found\nvalue3\n");
+
+    Ok(())
+}
+
+// Pairs set with mset should all be read back with mget, an odd number of arguments is
+// refused before connecting
+#[test]
+fn client_set_many() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir);
+    let addr = server.addr.as_str();
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+    ])?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(client);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key1", "value3", "key3", "value4", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(predicate::str::is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mget", "key1", "key2", "key3", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value3\nvalue2\nvalue4\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key1", "value5", "key2", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("pairs of key and value"));
+
+    Ok(())
+}
+
+// Pairs of a tsv file should be loaded in batches and read back with get, a line without
+// a tab fails the load
+#[test]
+fn client_load() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir);
+    let addr = server.addr.as_str();
+
+    let file = temp_dir.path().join("pairs.tsv");
+    fs::write(&file, "key1\tvalue1\nkey2\tvalue\t2\n\nkey3\tvalue3\n")?;
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["load", "--batch-size", "2", "--addr", addr, "--file"])
+        .arg(&file)
+        .assert()
+        .success()
+        .stdout(contains("loaded 3 pairs"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value\t2\n");
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(client);
+
+    fs::write(&file, "key4\tvalue4\nkey5\n")?;
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["load", "--addr", addr, "--file"])
+        .arg(&file)
+        .assert()
+        .failure()
+        .stderr(contains(":2: expected a key and a value"));
+
+    Ok(())
+}
+
+// The async client should speak the protocol of the blocking server, over one connection
+#[tokio::test]
+async fn async_client() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir);
+    let addr = server.addr.as_str();
+
+    let mut client =
+        AsyncKvsClient::connect_addr(&addr.parse().unwrap(), Some(Duration::from_secs(5))).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    client.remove("key1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    assert!(matches!(
+        client.remove("key1".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(client.ping().await?, env!("CARGO_PKG_VERSION"));
+
+    Ok(())
+}
+
+// Every codec should carry requests, responses and errors to both servers, and a codec
+// unknown to the server should be answered with json
+#[test]
+fn client_codecs() -> Result<()> {
+    for bin in ["kvs-server", "kvs-server-async"] {
+        let temp_dir = TempDir::new().unwrap();
+        let server = start_server_bin(bin, &temp_dir);
+        let addr = server.addr.as_str();
+
+        for codec in [Codec::Json, Codec::Bincode, Codec::MessagePack] {
+            let mut client = KvsClient::connect_with(
+                &addr.parse().unwrap(),
+                Duration::from_secs(5),
+                None,
+                codec,
+            )?;
+            assert_eq!(client.codec(), codec);
+            client.set("key1".to_owned(), format!("{codec:?}"))?;
+            assert_eq!(client.get("key1".to_owned())?, Some(format!("{codec:?}")));
+            assert_eq!(
+                client.get_many(vec!["key1".to_owned(), "key2".to_owned()])?,
+                vec![Some(format!("{codec:?}")), None]
+            );
+            assert!(matches!(
+                client.remove("key2".to_owned()),
+                Err(KvsError::KeyNotFound)
+            ));
+            assert!(client.stats()?.requests > 0);
+            let responses = client.pipeline(vec![Request::Ping, Request::Ping])?;
+            assert_eq!(responses.len(), 2);
+        }
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--codec", "msgpack", "--addr", addr])
+            .assert()
+            .success()
+            .stdout("MessagePack\n");
+
+        let mut stream = TcpStream::connect(addr)?;
+        let mut advertised = [0u8; 2];
+        stream.read_exact(&mut advertised)?;
+        stream.write_all(&[advertised[1], 0xf0])?;
+        let mut answered = [0u8; 1];
+        stream.read_exact(&mut answered)?;
+        assert_eq!(Codec::from_byte(answered[0]), Some(Codec::Json));
+        stream.write_all(&serde_json::to_vec(&Request::Ping)?)?;
+        let response: Response = Deserializer::from_reader(&stream)
+            .into_iter()
+            .next()
+            .expect("no response received")?;
+        assert_eq!(response.value.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+    }
+
+    Ok(())
+}
+
+// Stats should count requests of all connections, by kind and failures
+#[test]
+fn client_stats() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir);
+    let addr = server.addr.as_str();
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.get("key1".to_owned())?;
+    client.get_many(vec!["key1".to_owned(), "key2".to_owned()])?;
+    client.remove("key1".to_owned())?;
+    assert!(client.remove("key1".to_owned()).is_err());
+    client.ping()?;
+    drop(client);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(
+        client.stats()?,
+        ServerStats {
+            connections: 2,
+            requests: 8,
+            gets: 2,
+            sets: 2,
+            removes: 2,
+            errors: 1,
+        }
+    );
+
+    Ok(())
+}
+
+// A malformed request should be answered with a bad request error, a request of
+// unknown shape should leave the connection usable, while invalid json closes it
+#[test]
+fn client_bad_request() -> Result<()> {
+    for bin in ["kvs-server", "kvs-server-async"] {
+        let temp_dir = TempDir::new().unwrap();
+        let server = start_server_bin(bin, &temp_dir);
+        let addr = server.addr.as_str();
+
+        let mut stream = TcpStream::connect(addr)?;
+        let mut advertised = [0u8; 2];
+        stream.read_exact(&mut advertised)?;
+        stream.write_all(&[advertised[1], Codec::Json.to_byte()])?;
+        let mut answered = [0u8; 1];
+        stream.read_exact(&mut answered)?;
+        let mut responses = Deserializer::from_reader(stream.try_clone()?).into_iter::<Response>();
+
+        stream.write_all(b"{\"Unknown\":{}}")?;
+        let response = responses.next().expect("no response received")?;
+        assert_eq!(response.error.map(|e| e.code), Some(ErrorCode::BadRequest));
+
+        stream.write_all(&serde_json::to_vec(&Request::Ping)?)?;
+        let response = responses.next().expect("no response received")?;
+        assert!(response.error.is_none());
+        assert_eq!(response.value.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+
+        stream.write_all(b"{\"Get\":]")?;
+        let response = responses.next().expect("no response received")?;
+        assert_eq!(response.error.map(|e| e.code), Some(ErrorCode::BadRequest));
+        assert!(responses.next().is_none());
+
+        // the server keeps serving other connections
+        let mut client = KvsClient::connect(addr)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A server not answering should time out instead of blocking forever
+#[test]
+fn client_timeout() -> Result<()> {
+    // accepts connections in the backlog, but never reads or answers
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    assert!(matches!(
+        KvsClient::connect_timeout(addr, Duration::from_millis(200)),
+        Err(KvsError::Timeout)
+    ));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "get",
+            "key1",
+            "--addr",
+            &addr.to_string(),
+            "--timeout",
+            "200",
+        ])
+        .assert()
+        .failure()
+        .stderr(contains("Timed out"));
+
+    Ok(())
+}
+
+// Accepts one connection, advertises protocol versions `min` to `max`,
+// and answers a ping in json if a version is chosen
+fn start_versioned_server(min: u8, max: u8) -> (String, thread::JoinHandle<Option<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(&[min, max]).unwrap();
+        let mut version = [0u8; 1];
+        stream.read_exact(&mut version).ok()?;
+        if version[0] >= 2 {
+            let mut codec = [0u8; 1];
+            stream.read_exact(&mut codec).unwrap();
+            stream.write_all(&[Codec::Json.to_byte()]).unwrap();
+        }
+
+        let _: Request = Deserializer::from_reader(&stream)
+            .into_iter()
+            .next()
+            .expect("no request received")
+            .unwrap();
+        let response = Response {
+            value: Some("fake".to_owned()),
+            ..Default::default()
+        };
+        serde_json::to_writer(&stream, &response).unwrap();
+        Some(version[0])
+    });
+    (addr, server)
+}
+
+// Accepts one connection and answers each request with the next of `errors`
+fn start_failing_server(errors: Vec<ResponseError>) -> (String, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(&[1, 1]).unwrap();
+        let mut version = [0u8; 1];
+        stream.read_exact(&mut version).unwrap();
+
+        let requests = Deserializer::from_reader(stream.try_clone().unwrap()).into_iter();
+        // errors come first, so no further request is waited for once they run out
+        for (error, request) in errors.into_iter().zip(requests) {
+            let _: Request = request.unwrap();
+            let response = Response {
+                error: Some(error),
+                ..Default::default()
+            };
+            serde_json::to_writer(&stream, &response).unwrap();
+        }
+    });
+    (addr, server)
+}
+
+// Each error code of the server should come back as the matching `KvsError`
+#[test]
+fn client_error_codes() -> Result<()> {
+    let error = |code, message: Option<&str>| ResponseError {
+        code,
+        message: message.map(str::to_owned),
+    };
+    let (addr, server) = start_failing_server(vec![
+        error(ErrorCode::KeyNotFound, None),
+        error(ErrorCode::NotAnInteger, None),
+        error(ErrorCode::ValueTooLarge, None),
+        error(ErrorCode::BadRequest, Some("missing field `key`")),
+        error(ErrorCode::Internal, Some("disk full")),
+    ]);
+
+    let mut client = KvsClient::connect(&addr)?;
+    let rm = || Request::Rm {
+        key: "key1".to_owned(),
+    };
+    assert!(matches!(
+        client.process_command(rm()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        client.process_command(Request::Incr {
+            key: "key1".to_owned(),
+            delta: 1
+        }),
+        Err(KvsError::NotAnInteger)
+    ));
+    assert!(matches!(
+        client.process_command(Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned()
+        }),
+        Err(KvsError::ValueTooLarge)
+    ));
+    assert!(matches!(
+        client.process_command(rm()),
+        Err(KvsError::BadRequest(message)) if message == "missing field `key`"
+    ));
+    assert!(matches!(
+        client.process_command(rm()),
+        Err(KvsError::Server(message)) if message == "disk full"
+    ));
+    server.join().unwrap();
+
+    Ok(())
+}
+
+// A client should pick the newest version it shares with a newer server
+#[test]
+fn client_protocol_downgrade() -> Result<()> {
+    let (addr, server) = start_versioned_server(1, 3);
+    let addr = addr.as_str();
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.protocol_version(), 2);
+    assert_eq!(client.ping()?, "fake");
+    assert_eq!(server.join().unwrap(), Some(2));
+
+    Ok(())
+}
+
+// A server predating codecs should be spoken to in json, whatever codec is asked for
+#[test]
+fn client_codec_old_server() -> Result<()> {
+    let (addr, server) = start_versioned_server(1, 1);
+    let addr = addr.as_str();
+
+    let mut client = KvsClient::connect_with(
+        &addr.parse().unwrap(),
+        Duration::from_secs(5),
+        None,
+        Codec::MessagePack,
+    )?;
+    assert_eq!(client.protocol_version(), 1);
+    assert_eq!(client.codec(), Codec::Json);
+    assert_eq!(client.ping()?, "fake");
+    assert_eq!(server.join().unwrap(), Some(1));
+
+    Ok(())
+}
+
+// A server speaking only newer versions should be refused with a clear error
+#[test]
+fn client_protocol_mismatch() -> Result<()> {
+    let (addr, server) = start_versioned_server(3, 4);
+    let addr = addr.as_str();
+
+    assert!(matches!(
+        KvsClient::connect(addr),
+        Err(KvsError::ProtocolVersion { min: 3, max: 4 })
+    ));
+    assert_eq!(server.join().unwrap(), None);
+
+    Ok(())
+}
+
+// Closes the first `refused` connections before the handshake, then answers a ping
+// on the next connection
+fn start_flaky_server(refused: usize) -> (String, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = thread::spawn(move || {
+        for _ in 0..refused {
+            drop(listener.accept().unwrap());
+        }
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(&[1, 1]).unwrap();
+        let mut version = [0u8; 1];
+        stream.read_exact(&mut version).unwrap();
+
+        let _: Request = Deserializer::from_reader(&stream)
+            .into_iter()
+            .next()
+            .expect("no request received")
+            .unwrap();
+        let response = Response {
+            value: Some("fake".to_owned()),
+            ..Default::default()
+        };
+        serde_json::to_writer(&stream, &response).unwrap();
+    });
+    (addr, server)
+}
+
+/// a server which accepts one connection per attempt and closes it
+/// after reading the request, without responding
+fn start_silent_server(attempts: usize) -> (String, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = thread::spawn(move || {
+        for _ in 0..attempts {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&[1, 1]).unwrap();
+            let mut version = [0u8; 1];
+            stream.read_exact(&mut version).unwrap();
+            let _: Request = Deserializer::from_reader(&stream)
+                .into_iter()
+                .next()
+                .expect("no request received")
+                .unwrap();
+        }
+    });
+    (addr, server)
+}
+
+// A connection closed without a response should fail the request, not panic
+#[test]
+fn client_no_response() {
+    let (addr, server) = start_silent_server(2);
+    let addr = addr.as_str();
+
+    let err = KvsClient::connect(addr)
+        .unwrap()
+        .get("key1".to_owned())
+        .unwrap_err();
+    assert!(matches!(err, KvsError::NoResponse));
+    assert!(err.is_retryable());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("closed the connection without responding"))
+        .stderr(contains("panicked").not());
+    server.join().unwrap();
+}
+
+// Failed connections should be retried with backoff, other errors should not
+#[test]
+fn client_retry() -> Result<()> {
+    let (addr, server) = start_flaky_server(2);
+    let addr = addr.as_str();
+    let ping = || KvsClient::connect(addr)?.ping();
+
+    let err = RetryPolicy::default().run(ping).unwrap_err();
+    assert!(err.is_retryable());
+    assert_eq!(
+        RetryPolicy::new(3, Duration::from_millis(10)).run(ping)?,
+        "fake"
+    );
+    server.join().unwrap();
+
+    let mut attempts = 0;
+    let result: Result<()> = RetryPolicy::new(3, Duration::from_millis(10)).run(|| {
+        attempts += 1;
+        Err(KvsError::KeyNotFound)
+    });
+    assert!(matches!(result, Err(KvsError::KeyNotFound)));
+    assert_eq!(attempts, 1);
+
+    Ok(())
+}
+
+// A write whose connection failed after it was sent shouldn't be sent again, as the
+// server may have applied it, while a read should
+#[test]
+fn client_retry_reads_only() {
+    let (addr, server) = start_silent_server(1);
+    // a retry would find no server and fail with another error
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", &addr, "--retries", "2"])
+        .args(["--retry-backoff", "10"])
+        .assert()
+        .failure()
+        .stderr(contains("closed the connection without responding"));
+    server.join().unwrap();
+
+    let (addr, server) = start_silent_server(2);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr, "--retries", "1"])
+        .args(["--retry-backoff", "10"])
+        .assert()
+        .failure()
+        .stderr(contains("closed the connection without responding"));
+    server.join().unwrap();
+}
+
+// The client should wait for a server which is starting
+#[test]
+fn client_retry_cli() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let server = thread::spawn({
+        let addr = addr.clone();
+        move || {
+            thread::sleep(Duration::from_millis(500));
+            // the data dir is kept until the server is killed
+            let server = start_server_at("kvs-server", &temp_dir, &addr, &["--engine", "kvs"]);
+            (server, temp_dir)
+        }
+    });
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ping", "--addr", &addr, "--retries", "6"])
+        .args(["--retry-backoff", "100"])
+        .output()
+        .unwrap();
+    drop(server.join().unwrap());
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("pong from"));
+}
+
+// Both servers should listen on a port picked by the os for port 0, and log it
+#[test]
+fn client_ephemeral_port() -> Result<()> {
+    for bin in ["kvs-server", "kvs-server-async"] {
+        let temp_dir = TempDir::new().unwrap();
+        let server = start_server_bin(bin, &temp_dir);
+        let addr: SocketAddr = server.addr.parse().unwrap();
+        assert_ne!(addr.port(), 0);
+
+        let mut client = KvsClient::connect(addr)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A server run in process should report the port picked by the os for port 0
+#[test]
+fn client_server_local_addr() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let config = ServerConfig {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        data_dir: temp_dir.path().to_owned(),
+        threads: Some(2),
+        ..Default::default()
+    };
+    let server = KvsServer::bind(config)?;
+    let addr = server.local_addr()?;
+    assert_ne!(addr.port(), 0);
+    // runs until the test process exits
+    thread::spawn(move || server.run());
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Both servers should reject values over the configured limit for any engine,
+// and keep serving the connection
+#[test]
+fn client_value_too_large() -> Result<()> {
+    for bin in ["kvs-server", "kvs-server-async"] {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(
+            temp_dir.path().join("server.toml"),
+            "engine = \"mem\"\n\n[store]\nmax_value_size = 1024\n",
+        )
+        .unwrap();
+        let server = start_server_at(bin, &temp_dir, "127.0.0.1:0", &["--config", "server.toml"]);
+
+        let mut client = KvsClient::connect(&server.addr)?;
+        assert!(matches!(
+            client.set("key1".to_owned(), "v".repeat(1025)),
+            Err(KvsError::ValueTooLarge)
+        ));
+        client.set("key1".to_owned(), "v".repeat(1024))?;
+        assert_eq!(client.get("key1".to_owned())?, Some("v".repeat(1024)));
+    }
+
+    Ok(())
+}
+
+// A client trusting the server's self-signed certificate should talk to it over tls,
+// clients which don't trust it or don't speak tls should fail
+#[test]
+fn client_tls() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+    fs::write(temp_dir.path().join("cert.pem"), certified.cert.pem()).unwrap();
+    fs::write(
+        temp_dir.path().join("key.pem"),
+        certified.key_pair.serialize_pem(),
+    )
+    .unwrap();
+    let other = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+    fs::write(temp_dir.path().join("other.pem"), other.cert.pem()).unwrap();
+
+    let server = start_server_at(
+        "kvs-server",
+        &temp_dir,
+        "127.0.0.1:0",
+        &[
+            "--engine",
+            "kvs",
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+        ],
+    );
+    let addr = server.addr.as_str();
+
+    let config = kvs::tls::client_config(&temp_dir.path().join("cert.pem"))?;
+    let mut client =
+        KvsClient::connect_tls(&addr.parse().unwrap(), Duration::from_secs(5), config)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    // requests are sent in windows, each answered before the next is sent
+    let requests = (0..1000)
+        .map(|i| Request::Set {
+            key: format!("pipelined{}", i),
+            value: "v".repeat(100),
+        })
+        .collect();
+    assert_eq!(client.pipeline(requests)?.len(), 1000);
+    assert_eq!(
+        client.get("pipelined999".to_owned())?,
+        Some("v".repeat(100))
+    );
+    // the connection may hold the only thread of the server's pool
+    drop(client);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "get",
+            "key1",
+            "--addr",
+            addr,
+            "--tls",
+            "--ca-cert",
+            "cert.pem",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "get",
+            "key1",
+            "--addr",
+            addr,
+            "--tls",
+            "--ca-cert",
+            "other.pem",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--timeout", "500"])
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+// A set-if-version with a stale version should be rejected by both servers
+#[test]
+fn client_set_if_version() -> Result<()> {
+    for bin in ["kvs-server", "kvs-server-async"] {
+        let temp_dir = TempDir::new().unwrap();
+        let server = start_server_bin(bin, &temp_dir);
+        let addr = server.addr.as_str();
+
+        let mut client = KvsClient::connect(addr)?;
+        assert_eq!(client.version("key1".to_owned())?, None);
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        let version = client.version("key1".to_owned())?.unwrap();
+
+        let newer = client.set_if_version("key1".to_owned(), "value2".to_owned(), version)?;
+        assert!(newer > version);
+        assert!(matches!(
+            client.set_if_version("key1".to_owned(), "value3".to_owned(), version),
+            Err(KvsError::VersionMismatch)
+        ));
+        assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(client.version("key1".to_owned())?, Some(newer));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use kvs::{DurabilityMode, Engine, KvStore, KvsEngine, KvsError, Pool, Result, ServerConfig};
+use std::fs;
+use tempfile::TempDir;
+
+// The sample config should load with the default values it documents
+#[test]
+fn sample_config() -> Result<()> {
+    let config = ServerConfig::load(concat!(env!("CARGO_MANIFEST_DIR"), "/server.toml"))?;
+    let default = ServerConfig::default();
+
+    assert_eq!(config.addr, default.addr);
+    assert_eq!(config.engine, Engine::Kvs);
+    assert_eq!(config.pool, Pool::Shared);
+    assert_eq!(config.threads, None);
+    assert_eq!(config.durability(), DurabilityMode::Buffered);
+
+    Ok(())
+}
+
+// A store opened with loaded options should compact at the configured threshold
+#[test]
+fn config_compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config_path = temp_dir.path().join("server.toml");
+    fs::write(
+        &config_path,
+        format!(
+            "engine = \"kvs\"\ndata_dir = {:?}\n\n[store]\ndurability = \"fsync\"\n\
+             compaction_policy = {{ bytes = 65536 }}\ngroup_commit = \"1ms\"\n",
+            temp_dir.path().join("data")
+        ),
+    )?;
+
+    let config = ServerConfig::load(&config_path)?;
+    assert_eq!(config.durability(), DurabilityMode::Fsync);
+    let store = KvStore::open_with_options(&config.data_dir, config.store)?;
+
+    // far below the default threshold of 4 MiB
+    for key_id in 0..2000 {
+        store.set(format!("key{}", key_id % 100), format!("value{}", key_id))?;
+    }
+    assert!(store.stats().compaction_count > 0);
+
+    Ok(())
+}
+
+// Unknown keys should be rejected rather than silently ignored
+#[test]
+fn config_unknown_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config_path = temp_dir.path().join("server.toml");
+
+    fs::write(&config_path, "[store]\ncompaction_threshold = 1024\n")?;
+    assert!(matches!(
+        ServerConfig::load(&config_path),
+        Err(KvsError::Toml(_))
+    ));
+
+    Ok(())
+}
+
+// The engine should be recorded in a data dir which doesn't exist yet
+#[test]
+fn check_engine_creates_data_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = ServerConfig {
+        engine: Engine::Kvs,
+        data_dir: temp_dir.path().join("data"),
+        ..ServerConfig::default()
+    };
+
+    config.check_engine()?;
+    assert_eq!(fs::read_to_string(config.data_dir.join("engine"))?, "kvs");
+    config.check_engine()?;
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+#[cfg(feature = "sled-engine")]
+use fs2::FileExt;
+#[cfg(feature = "sled-engine")]
+use kvs::SledKvsEngine;
+use kvs::{KvStore, KvsEngine, KvsError, MemKvsEngine, Result};
+#[cfg(feature = "sled-engine")]
+use std::{fs::File, path::Path};
+use tempfile::TempDir;
+
+// Behavior every engine should share through `KvsEngine`, `open` is called again
+// after dropping the engine to check pairs persist when `persistent` is set
+fn engine_contract<E: KvsEngine>(open: impl Fn() -> Result<E>, persistent: bool) -> Result<()> {
+    let engine = open()?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+
+    engine.remove("key2".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        engine.remove("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    assert!(engine.set_if_absent("key3".to_owned(), "value4".to_owned())?);
+    assert!(!engine.set_if_absent("key3".to_owned(), "value5".to_owned())?);
+    assert_eq!(engine.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    assert_eq!(engine.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(engine.incr("counter".to_owned(), -7)?, -2);
+    assert!(matches!(
+        engine.incr("key3".to_owned(), 1),
+        Err(KvsError::NotAnInteger)
+    ));
+
+    engine.append("key3".to_owned(), "-suffix".to_owned())?;
+    engine.append("appended".to_owned(), "suffix".to_owned())?;
+    assert_eq!(
+        engine.get_many(vec![
+            "key3".to_owned(),
+            "missing".to_owned(),
+            "appended".to_owned(),
+            "counter".to_owned(),
+        ])?,
+        vec![
+            Some("value4-suffix".to_owned()),
+            None,
+            Some("suffix".to_owned()),
+            Some("-2".to_owned()),
+        ]
+    );
+    engine.flush()?;
+
+    if persistent {
+        drop(engine);
+        let engine = open()?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(engine.get("key2".to_owned())?, None);
+        assert_eq!(
+            engine.get_many(vec!["key3".to_owned(), "counter".to_owned()])?,
+            vec![Some("value4-suffix".to_owned()), Some("-2".to_owned())]
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn kv_store_contract() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_contract(|| KvStore::open(temp_dir.path()), true)
+}
+
+#[cfg(feature = "sled-engine")]
+#[test]
+fn sled_contract() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_contract(
+        || {
+            wait_unlocked(&temp_dir.path().join("db"))?;
+            Ok(SledKvsEngine::new(sled::open(temp_dir.path())?))
+        },
+        true,
+    )
+}
+
+// Background threads of a dropped sled db may hold its file open for a moment,
+// and sled's lock on it with it
+#[cfg(feature = "sled-engine")]
+fn wait_unlocked(path: &Path) -> Result<()> {
+    if let Ok(file) = File::open(path) {
+        file.lock_exclusive()?;
+        file.unlock()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn mem_contract() -> Result<()> {
+    engine_contract(|| Ok(MemKvsEngine::new()), false)
+}
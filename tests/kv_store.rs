@@ -1,6 +1,19 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::{
+    BatchOp, BytesKvsEngine, CompactionPolicy, CompactionReport, Compression, DurabilityMode,
+    Encoding, HistoryEntry, IndexKind, KvStore, KvStoreOptions, KvsEngine, KvsError, LayoutContext,
+    LayoutStrategy, LogRecord, Result, Txn, WatchEvent, FORMAT_VERSION,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fs;
+use std::hash::Hasher;
+use std::io::Read;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Barrier, Mutex};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -209,3 +222,2643 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should read back values written with the bincode encoding
+#[test]
+fn bincode_encoding() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().encoding(Encoding::Bincode);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Generation files of different encodings should not be mixed up
+#[test]
+fn encodings_are_isolated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "json".to_owned())?;
+    drop(store);
+
+    let options = KvStoreOptions::new().encoding(Encoding::Bincode);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "bincode".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("json".to_owned()));
+
+    Ok(())
+}
+
+// Should read values through mmap, including records appended after the file was mapped
+// and records moved by compaction
+#[test]
+fn mmap_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().use_mmap(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let value = "v".repeat(1000);
+    for iter in 0..5000 {
+        store.set(format!("key{}", iter % 100), value.clone())?;
+    }
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// Should hide expired keys, also after reopening and compaction
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Overwriting without ttl should clear the expiry
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should preserve every live key after export and import
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("source"))?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..100 {
+        store.remove(format!("key{}", i))?;
+    }
+    store.set_with_ttl(
+        "ttl".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+
+    let mut snapshot = Vec::new();
+    store.export(&mut snapshot)?;
+
+    let imported = KvStore::import(temp_dir.path().join("target"), snapshot.as_slice())?;
+    for i in 0..1000 {
+        assert_eq!(
+            imported.get(format!("key{}", i))?,
+            store.get(format!("key{}", i))?
+        );
+    }
+    assert_eq!(imported.get("ttl".to_owned())?, Some("value".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(imported);
+    let imported = KvStore::open(temp_dir.path().join("target"))?;
+    assert_eq!(
+        imported.get("key999".to_owned())?,
+        Some("value999".to_owned())
+    );
+    assert_eq!(imported.get("key0".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should report live keys, disk usage and compactions
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 0);
+    assert_eq!(stats.disk_bytes, 0);
+    assert_eq!(stats.compaction_count, 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 1);
+    assert!(stats.disk_bytes > 0);
+    assert_eq!(stats.disk_bytes, stats.uncompaction_size);
+
+    let value = "v".repeat(1000);
+    while store.stats().compaction_count == 0 {
+        store.set("key1".to_owned(), value.clone())?;
+    }
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 1);
+    assert!(stats.writer_generation > 0);
+
+    Ok(())
+}
+
+// Should return the same values as individual gets, in the order of keys
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in (0..100).step_by(3) {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    store.remove("key50".to_owned())?;
+
+    let keys: Vec<String> = (0..120).rev().map(|i| format!("key{}", i)).collect();
+    let values = store.get_many(keys.clone())?;
+    assert_eq!(values.len(), keys.len());
+    for (key, value) in keys.into_iter().zip(values) {
+        assert_eq!(store.get(key)?, value);
+    }
+
+    Ok(())
+}
+
+// Should behave the same with a hash map index, also after compaction and reopening
+#[test]
+fn hash_map_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().index(IndexKind::HashMap);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    let value = "v".repeat(1000);
+    for iter in 0..5000 {
+        store.set(format!("key{}", iter % 100), value.clone())?;
+    }
+    assert!(store.stats().compaction_count > 0);
+    assert_eq!(store.stats().live_keys, 100);
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// Should find every stored key with a bloom filter, also keys beyond its initial size
+#[test]
+fn bloom_filter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().bloom_filter(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 100..5000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..5000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(store.get(format!("absent{}", i))?, None);
+    }
+    let keys = vec!["key1".to_owned(), "absent1".to_owned()];
+    assert_eq!(store.get_many(keys)?, vec![Some("value1".to_owned()), None]);
+
+    Ok(())
+}
+
+// Writes should be readable right away and after reopening in every durability mode
+#[test]
+fn durability_modes() -> Result<()> {
+    for durability in [
+        DurabilityMode::None,
+        DurabilityMode::Buffered,
+        DurabilityMode::Fsync,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().durability(durability);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        store.remove("key0".to_owned())?;
+
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+
+    Ok(())
+}
+
+// Writes flushed explicitly should survive the store never being dropped, as in a crash
+#[test]
+fn flush() -> Result<()> {
+    for durability in [DurabilityMode::None, DurabilityMode::Buffered] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().durability(durability);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+        store.flush()?;
+        // buffered records are never written out
+        mem::forget(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+
+    Ok(())
+}
+
+// Increments should be persisted, and drop the expiry of a key
+#[test]
+fn incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.incr("counter".to_owned(), -7)?, -2);
+    store.set_with_ttl("ttl".to_owned(), "1".to_owned(), Duration::from_millis(100))?;
+    assert_eq!(store.incr("ttl".to_owned(), 1)?, 2);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("ttl".to_owned())?, Some("2".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("-2".to_owned()));
+    assert_eq!(store.get("ttl".to_owned())?, Some("2".to_owned()));
+
+    Ok(())
+}
+
+// Keys and values over the limits should be rejected without writing anything
+#[test]
+fn max_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_key_size(8).max_value_size(16);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "v".repeat(16))?;
+    assert!(matches!(
+        store.set("key1".to_owned(), "v".repeat(17)),
+        Err(KvsError::ValueTooLarge)
+    ));
+    assert!(matches!(
+        store.set("k".repeat(9), "value1".to_owned()),
+        Err(KvsError::ValueTooLarge)
+    ));
+    // a transaction is written whole or not at all
+    assert!(matches!(
+        store.transaction(|txn| {
+            txn.set("key2".to_owned(), "value2".to_owned());
+            txn.set("key3".to_owned(), "v".repeat(17));
+            Ok(())
+        }),
+        Err(KvsError::ValueTooLarge)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("v".repeat(16)));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("v".repeat(16)));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Setting a key to its current value should write no record with dedup
+#[test]
+fn dedup_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().dedup_writes(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for _ in 0..1000 {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    assert_eq!(store.history("key1")?.len(), 1);
+    let disk_bytes = store.stats().disk_bytes;
+    assert_eq!(store.stats().garbage_size, 0);
+
+    // a different value or an expiry is still written
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.history("key1")?.len(), 4);
+    assert!(store.stats().disk_bytes > disk_bytes);
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Every write leaving a key unchanged should write no record with dedup, not only a set
+#[test]
+fn dedup_writes_all_apis() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().dedup_writes(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "5".to_owned())?;
+    store.set("key2".to_owned(), "5".to_owned())?;
+    let position = store.current_position();
+
+    assert_eq!(store.incr("key1".to_owned(), 0)?, 5);
+    store.append("key1".to_owned(), String::new())?;
+    store.update("key1".to_owned(), |value| value)?;
+    let version = store.version("key1").unwrap();
+    assert_eq!(
+        store.set_if_version("key1".to_owned(), "5".to_owned(), version)?,
+        version
+    );
+    store.write_batch(vec![BatchOp::Set {
+        key: "key1".to_owned(),
+        value: "5".to_owned(),
+    }])?;
+    store.transaction(|txn| {
+        txn.set("key1".to_owned(), "5".to_owned());
+        Ok(())
+    })?;
+    store.swap("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.current_position(), position);
+    assert_eq!(store.history("key1")?.len(), 1);
+
+    // a key set twice in a transaction is written, as the first set changes it
+    store.transaction(|txn| {
+        txn.set("key1".to_owned(), "6".to_owned());
+        txn.set("key1".to_owned(), "5".to_owned());
+        Ok(())
+    })?;
+    assert_eq!(store.history("key1")?.len(), 3);
+    assert_eq!(store.incr("key1".to_owned(), 1)?, 6);
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("6".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("5".to_owned()));
+
+    Ok(())
+}
+
+// Progress of replay on open and of compaction should grow up to the total bytes
+#[test]
+fn progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let recorded = calls.clone();
+    let options = KvStoreOptions::new()
+        .encoding(Encoding::Bincode)
+        .durability(DurabilityMode::None)
+        .compaction_policy(CompactionPolicy::Bytes(u64::MAX))
+        .progress(move |processed, total| recorded.lock().unwrap().push((processed, total)));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    // nothing to replay in an empty store
+    assert_eq!(
+        calls.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        [(0, 0)]
+    );
+
+    let value = "v".repeat(1000);
+    for round in 0..2 {
+        for i in 0..3000 {
+            store.set(format!("key{}", i), format!("{}{}", value, round))?;
+        }
+    }
+    drop(store);
+
+    let assert_complete = |calls: &[(u64, u64)]| {
+        let total = calls.last().unwrap().1;
+        assert!(total > 1024 * 1024);
+        assert!(calls.len() > 2);
+        assert!(calls
+            .windows(2)
+            .all(|w| w[0].0 <= w[1].0 && w[0].1 == w[1].1));
+        assert_eq!(calls.last(), Some(&(total, total)));
+        total
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let replayed = assert_complete(&calls.lock().unwrap().drain(..).collect::<Vec<_>>());
+    assert_eq!(replayed, store.stats().disk_bytes);
+
+    let report = store.compact()?;
+    let compacted = assert_complete(&calls.lock().unwrap().drain(..).collect::<Vec<_>>());
+    assert_eq!(compacted, report.bytes_after);
+
+    Ok(())
+}
+
+// The estimate of a compaction should match what the compaction reclaims
+#[test]
+fn compaction_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_policy(CompactionPolicy::Bytes(u64::MAX));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), "v".repeat(100))?;
+    }
+    for i in 0..900 {
+        store.remove(format!("key{}", i))?;
+    }
+
+    let estimate = store.compaction_estimate()?;
+    assert_eq!(estimate.live_keys, 100);
+    assert!(estimate.reclaimable_bytes > estimate.live_bytes * 10);
+    // nothing is written by the estimate
+    assert_eq!(store.compaction_estimate()?, estimate);
+
+    let report = store.compact()?;
+    assert_eq!(report.live_keys, estimate.live_keys);
+    assert_eq!(report.bytes_before, estimate.bytes_before);
+    assert_eq!(report.bytes_after, estimate.live_bytes);
+    assert_eq!(
+        report.bytes_before - report.bytes_after,
+        estimate.reclaimable_bytes
+    );
+    assert_eq!(report.generations_removed, estimate.generations);
+    assert_eq!(store.compaction_estimate()?.reclaimable_bytes, 0);
+
+    Ok(())
+}
+
+// Generations should roll over at the segment size without compacting, and
+// compaction should merge them into one
+#[test]
+fn segment_size() -> Result<()> {
+    let generation_lens = |dir: &TempDir| -> Result<Vec<u64>> {
+        let mut lens = Vec::new();
+        for entry in fs::read_dir(dir.path())? {
+            let entry = entry?;
+            let name = entry.file_name().into_string().unwrap();
+            if name
+                .strip_suffix(".json")
+                .is_some_and(|stem| stem.parse::<u64>().is_ok())
+            {
+                lens.push(entry.metadata()?.len());
+            }
+        }
+        Ok(lens)
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_policy(CompactionPolicy::Bytes(u64::MAX))
+        .segment_size(4096);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("{:0100}", i))?;
+    }
+
+    let lens = generation_lens(&temp_dir)?;
+    assert!(lens.len() > 20);
+    // a generation is left by the write which fills it
+    assert_eq!(lens.iter().filter(|&&len| len < 4096).count(), 1);
+    assert!(lens.iter().all(|&len| len < 4096 + 200));
+    assert_eq!(store.stats().compaction_count, 0);
+    for i in (0..1000).step_by(7) {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("{:0100}", i)));
+    }
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("{:0100}", i)));
+    }
+    let report = store.compact()?;
+    assert_eq!(report.generations_removed, lens.len() + 1);
+    assert_eq!(generation_lens(&temp_dir)?.len(), 1);
+    assert_eq!(
+        store.get("key999".to_owned())?,
+        Some(format!("{:0100}", 999))
+    );
+
+    Ok(())
+}
+
+// Pages of a scan should cover every live key once, in order, for both indexes
+#[test]
+fn scan_paginated() -> Result<()> {
+    for index in [IndexKind::SkipMap, IndexKind::HashMap] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store =
+            KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new().index(index))?;
+        for i in 0..1000 {
+            store.set(format!("key{:04}", i), format!("value{}", i))?;
+        }
+        // removed keys are skipped without shortening the pages
+        for i in 1000..1010 {
+            store.set(format!("key{:04}", i), "removed".to_owned())?;
+            store.remove(format!("key{:04}", i))?;
+        }
+
+        let mut pairs = Vec::new();
+        let mut cursor = None;
+        let mut pages = 0;
+        loop {
+            let (page, next) = store.scan_paginated(cursor, 100)?;
+            assert!(page.len() <= 100);
+            pages += 1;
+            pairs.extend(page);
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert!(pages <= 11);
+        let expected: Vec<_> = (0..1000)
+            .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+            .collect();
+        assert_eq!(pairs, expected);
+
+        let (page, cursor) = store.scan_paginated(Some("key0997".to_owned()), 100)?;
+        assert_eq!(page.len(), 2);
+        assert_eq!(cursor, None);
+    }
+
+    Ok(())
+}
+
+// Keys which aren't utf-8 or hold NUL bytes should be stored through `BytesKvsEngine`,
+// survive compaction and reopening, and sit beside string keys
+#[test]
+fn bytes_keys() -> Result<()> {
+    let keys: Vec<Vec<u8>> = vec![
+        b"a\0b".to_vec(),
+        vec![0xff, 0xfe, 0x00],
+        b"key\x80".to_vec(),
+        vec![0; 16],
+    ];
+    for encoding in [Encoding::Json, Encoding::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().encoding(encoding).checkpoint(true);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for (i, key) in keys.iter().enumerate() {
+            store.set_bytes(key.clone(), format!("value{}", i))?;
+        }
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(store.get_bytes(key.clone())?, Some(format!("value{}", i)));
+        }
+        // a string key is the key of its utf-8 bytes
+        assert_eq!(
+            store.get_bytes(b"key1".to_vec())?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.get("a\0b".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(store.get_bytes(b"a".to_vec())?, None);
+        // keys which aren't utf-8 are skipped by string listings
+        let pairs: Vec<_> = store.iter().collect::<Result<_>>()?;
+        assert_eq!(pairs.len(), 3);
+
+        store.remove_bytes(keys[1].clone())?;
+        assert!(matches!(
+            store.remove_bytes(keys[1].clone()),
+            Err(KvsError::KeyNotFound)
+        ));
+        store.compact()?;
+        drop(store);
+
+        // reloaded from the checkpoint, then from the generation files
+        for options in [options.clone(), options.clone().checkpoint(false)] {
+            let store = KvStore::open_with_options(temp_dir.path(), options)?;
+            assert_eq!(store.get_bytes(keys[0].clone())?, Some("value0".to_owned()));
+            assert_eq!(store.get_bytes(keys[1].clone())?, None);
+            assert_eq!(store.get_bytes(keys[2].clone())?, Some("value2".to_owned()));
+            assert_eq!(store.get_bytes(keys[3].clone())?, Some("value3".to_owned()));
+            assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        }
+    }
+
+    Ok(())
+}
+
+// A weak reader should see writes while the store is open, and report it closed
+// once every handle is dropped
+#[test]
+fn weak_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let reader = store.weak_reader();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+
+    // a reader on another thread keeps its own file handles
+    let clone = reader.clone();
+    let value = thread::spawn(move || clone.get("key1".to_owned()))
+        .join()
+        .unwrap()?;
+    assert_eq!(value, Some("value1".to_owned()));
+
+    // a clone of the store keeps it open
+    let other = store.clone();
+    drop(store);
+    other.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(other);
+    assert!(matches!(
+        reader.get("key1".to_owned()),
+        Err(KvsError::StoreClosed)
+    ));
+
+    // the store is released for the next one opening the dir
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(
+        reader.get("key1".to_owned()),
+        Err(KvsError::StoreClosed)
+    ));
+
+    Ok(())
+}
+
+// Values read into a buffer should match `get` for every encoding and compression
+#[test]
+fn get_into() -> Result<()> {
+    for (encoding, compression, use_mmap) in [
+        (Encoding::Json, Compression::None, false),
+        (Encoding::Bincode, Compression::None, false),
+        (Encoding::Json, Compression::None, true),
+        (Encoding::Bincode, Compression::Lz4, false),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .encoding(encoding)
+            .value_compression(compression)
+            .use_mmap(use_mmap)
+            .durability(DurabilityMode::None);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        let values = [
+            "value1".to_owned(),
+            "quoted \"value\"\n".to_owned(),
+            "v".repeat(4096),
+        ];
+        for (i, value) in values.iter().enumerate() {
+            store.set(format!("key{}", i), value.clone())?;
+        }
+        store.set_with_ttl(
+            "ttl".to_owned(),
+            "value".to_owned(),
+            Duration::from_millis(1),
+        )?;
+        thread::sleep(Duration::from_millis(10));
+
+        let mut buf = "stale".to_owned();
+        for (i, value) in values.iter().enumerate() {
+            assert!(store.get_into(&format!("key{}", i), &mut buf)?);
+            assert_eq!(&buf, value);
+        }
+        assert!(!store.get_into("missing", &mut buf)?);
+        assert_eq!(buf, "");
+        buf.push_str("stale");
+        assert!(!store.get_into("ttl", &mut buf)?);
+        assert_eq!(buf, "");
+
+        store.compact()?;
+        assert!(store.get_into("key0", &mut buf)?);
+        assert_eq!(buf, "value1");
+    }
+
+    Ok(())
+}
+
+// A batch should apply its ops in order, skipping removes of absent keys
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().durability(DurabilityMode::Fsync);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    store.write_batch(vec![
+        BatchOp::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        BatchOp::Remove {
+            key: "key1".to_owned(),
+        },
+        BatchOp::Remove {
+            key: "missing".to_owned(),
+        },
+        BatchOp::Set {
+            key: "key2".to_owned(),
+            value: "value3".to_owned(),
+        },
+    ])?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A failed transaction should leave the store unchanged, a successful one should apply
+// all of its writes, and a transaction without its commit record should be dropped on load
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let result = store.transaction(|txn: &mut Txn| {
+        txn.set("key3".to_owned(), "value3".to_owned());
+        txn.remove("key1".to_owned())?;
+        assert_eq!(txn.get("key3")?, Some("value3".to_owned()));
+        assert_eq!(txn.get("key1")?, None);
+        txn.remove("key1".to_owned())
+    });
+    assert!(matches!(result, Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    store.transaction(|txn| {
+        let value = txn.get("key1")?.unwrap();
+        txn.remove("key1".to_owned())?;
+        txn.set("key3".to_owned(), value);
+        txn.set("key2".to_owned(), "first".to_owned());
+        txn.set("key2".to_owned(), "second".to_owned());
+        Ok(())
+    })?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("second".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // a crash in the middle of writing the next transaction
+    let last = fs::read_dir(temp_dir.path())?
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            name.strip_suffix(".json")?.parse::<u64>().ok()
+        })
+        .max()
+        .unwrap();
+    let path = temp_dir.path().join(format!("{}.json", last));
+    let mut log = fs::read(&path)?;
+    log.extend_from_slice(br#""Begin"{"Set":{"key":"key1","value":"torn"}}"#);
+    fs::write(&path, log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("second".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Reading from a captured position should replay only the writes made after it,
+// across generations, until compaction deletes the position
+#[test]
+fn read_from() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(512)
+        .value_compression(Compression::Lz4);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let position = store.current_position();
+    assert_eq!(store.read_from(position)?.count(), 0);
+
+    store.set("key1".to_owned(), "v".repeat(1000))?;
+    store.remove("key2".to_owned())?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.transaction(|txn| {
+        txn.set("key4".to_owned(), "txn".to_owned());
+        txn.remove("key5".to_owned())
+    })?;
+    for i in 10..30 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(store.current_position().0 > position.0);
+
+    let delta: Vec<_> = store.read_from(position)?.collect::<Result<_>>()?;
+    let records: Vec<_> = delta.iter().map(|(_, record)| record.clone()).collect();
+    assert_eq!(records.len(), 25);
+    assert_eq!(
+        records[0],
+        LogRecord::Set {
+            key: b"key1".to_vec(),
+            value: "v".repeat(1000),
+            expire_at: None,
+        }
+    );
+    assert_eq!(
+        records[1],
+        LogRecord::Remove {
+            key: b"key2".to_vec()
+        }
+    );
+    assert!(matches!(
+        &records[2],
+        LogRecord::Set {
+            expire_at: Some(_),
+            ..
+        }
+    ));
+    assert_eq!(
+        records[4],
+        LogRecord::Remove {
+            key: b"key5".to_vec()
+        }
+    );
+    assert_eq!(
+        records[24],
+        LogRecord::Set {
+            key: b"key29".to_vec(),
+            value: "value29".to_owned(),
+            expire_at: None,
+        }
+    );
+
+    // resuming from a position in the middle reads the rest
+    let (resume, _) = delta[19];
+    let rest: Vec<_> = store.read_from(resume)?.collect::<Result<_>>()?;
+    assert_eq!(rest, delta[20..]);
+    let (last, _) = delta[24];
+    assert_eq!(last, store.current_position());
+
+    store.compact()?;
+    assert!(matches!(
+        store.read_from(position),
+        Err(KvsError::StalePosition)
+    ));
+    assert_eq!(store.read_from(store.current_position())?.count(), 0);
+
+    Ok(())
+}
+
+// History should list every write of a key in order, until compaction drops overwritten ones
+#[test]
+fn history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().durability(DurabilityMode::None);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..3 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+        store.set("key2".to_owned(), format!("value{}", i))?;
+    }
+    store.remove("key1".to_owned())?;
+    store.set_with_ttl("key1".to_owned(), "ttl".to_owned(), Duration::from_secs(60))?;
+    store.transaction(|txn| {
+        txn.set("key1".to_owned(), "txn".to_owned());
+        Ok(())
+    })?;
+
+    let history = store.history("key1")?;
+    assert_eq!(history.len(), 6);
+    let set = |value: &str| HistoryEntry::Set {
+        value: value.to_owned(),
+        expire_at: None,
+    };
+    assert_eq!(
+        history[..4],
+        [
+            set("value0"),
+            set("value1"),
+            set("value2"),
+            HistoryEntry::Remove
+        ]
+    );
+    assert!(matches!(
+        &history[4],
+        HistoryEntry::Set { value, expire_at: Some(_) } if value == "ttl"
+    ));
+    assert_eq!(history[5], set("txn"));
+    assert!(store.history("missing")?.is_empty());
+
+    store.compact()?;
+    assert_eq!(store.history("key1")?, vec![set("txn")]);
+    assert_eq!(store.history("key2")?, vec![set("value2")]);
+
+    Ok(())
+}
+
+// Concurrent writes persisted together should each return once persisted,
+// with errors of removes reported to their own thread and increments all adding up
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .durability(DurabilityMode::Fsync)
+        .group_commit(Duration::from_millis(1));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for j in 0..50 {
+                    store
+                        .set(format!("key{}_{}", i, j), format!("{}", j))
+                        .unwrap();
+                }
+                store.remove(format!("key{}_0", i)).unwrap();
+                assert!(matches!(
+                    store.remove(format!("missing{}", i)),
+                    Err(KvsError::KeyNotFound)
+                ));
+                store.incr("counter".to_owned(), 1).unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("counter".to_owned())?, Some("8".to_owned()));
+    for i in 0..8 {
+        assert_eq!(store.get(format!("key{}_0", i))?, None);
+        for j in 1..50 {
+            assert_eq!(
+                store.get(format!("key{}_{}", i, j))?,
+                Some(format!("{}", j))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// A get during the group commit window should read the value set by another thread,
+// though the record isn't persisted yet
+#[test]
+fn group_commit_get_during_window() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .durability(DurabilityMode::Buffered)
+        .group_commit(Duration::from_millis(200));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || store.set("key1".to_owned(), "value1".to_owned()))
+    };
+    while !writer.is_finished() {
+        match store.get("key1".to_owned())? {
+            Some(value) => assert_eq!(value, "value1"),
+            None => thread::yield_now(),
+        }
+    }
+    writer.join().unwrap()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Only keys starting with the prefix should be removed, for good and as garbage
+#[test]
+fn remove_prefix() -> Result<()> {
+    for index in [IndexKind::SkipMap, IndexKind::HashMap] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().index(index);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for key in [
+            "user1", "user1/a", "user1/b", "user10/a", "user2/a", "a/user1/",
+        ] {
+            store.set(key.to_owned(), "value".to_owned())?;
+        }
+        store.set_with_ttl(
+            "user1/ttl".to_owned(),
+            "value".to_owned(),
+            Duration::from_millis(100),
+        )?;
+        thread::sleep(Duration::from_millis(200));
+
+        let garbage_size = store.stats().garbage_size;
+        assert_eq!(store.remove_prefix("user1/")?, 2);
+        assert!(store.stats().garbage_size > garbage_size);
+        assert_eq!(store.remove_prefix("user1/")?, 0);
+
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        let mut keys: Vec<_> = store.iter().map(|pair| pair.unwrap().0).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["a/user1/", "user1", "user10/a", "user2/a"]);
+    }
+
+    Ok(())
+}
+
+// A key should be located in the generation it was written to, and in the compacted one after
+#[test]
+fn locate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.locate("key1"), None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (generation, offset) = store.locate("key1").unwrap();
+    assert_eq!(offset, 0);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let (key2_generation, key2_offset) = store.locate("key2").unwrap();
+    assert_eq!(key2_generation, generation);
+    assert!(key2_offset > offset);
+
+    store.compact()?;
+    let (compacted, _) = store.locate("key1").unwrap();
+    assert!(compacted > generation);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.locate("key1"), None);
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    assert!(store.locate("key3").is_some());
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.locate("key3"), None);
+
+    Ok(())
+}
+
+// Records read in parallel should be written at the right offsets, across several batches
+#[test]
+fn parallel_compaction() -> Result<()> {
+    for use_mmap in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .encoding(Encoding::Bincode)
+            .durability(DurabilityMode::None)
+            .compaction_policy(CompactionPolicy::Bytes(u64::MAX))
+            .use_mmap(use_mmap)
+            .parallel_compaction(true);
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        let value = |i: usize| format!("{}-{}", i % 3, "v".repeat(i % 64 * 1024));
+        for i in 0..300 {
+            store.set(format!("key{}", i), "old".to_owned())?;
+            store.set(format!("key{}", i), value(i))?;
+        }
+        store.remove("key0".to_owned())?;
+
+        let report = store.compact()?;
+        assert_eq!(report.live_keys, 299);
+        assert!(report.bytes_after > 8 * 1024 * 1024);
+        for i in 1..300 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+        }
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..300 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+        }
+    }
+
+    Ok(())
+}
+
+// Unflushed writes should fill the write buffer before reaching the file,
+// and records larger than the read buffer should be read and replayed
+#[test]
+fn buffer_sizes() -> Result<()> {
+    let value = "v".repeat(100);
+    for (write_buffer_size, flushed) in [(None, true), (Some(1024 * 1024), false)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut options = KvStoreOptions::new().durability(DurabilityMode::None);
+        if let Some(write_buffer_size) = write_buffer_size {
+            options = options.write_buffer_size(write_buffer_size);
+        }
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+        // about 13 KiB, more than the default buffer
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), value.clone())?;
+        }
+        let file_len = fs::metadata(temp_dir.path().join("0.json"))?.len();
+        assert_eq!(file_len > 0, flushed);
+        drop(store);
+
+        let options = KvStoreOptions::new().read_buffer_size(16);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+// Should not compact a log of mostly live records, but compact once many are overwritten
+#[test]
+fn garbage_ratio_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_policy(CompactionPolicy::GarbageRatio(0.4));
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    // about 6 MiB of live records, past the default 4 MiB threshold
+    let value = "v".repeat(1000);
+    for key_id in 0..6000 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    for key_id in 0..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert_eq!(store.stats().compaction_count, 0);
+
+    // garbage is counted again after reopening
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(store.stats().garbage_size > 100 * 1000);
+
+    for key_id in 100..6000 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    assert_eq!(store.stats().compaction_count, 1);
+    for key_id in 100..6000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// Should iterate over live pairs only, with their latest values
+#[test]
+fn iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut expected = BTreeMap::new();
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        expected.insert(format!("key{}", i), format!("value{}", i));
+    }
+    for i in (0..100).step_by(3) {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+        expected.insert(format!("key{}", i), format!("new{}", i));
+    }
+    for i in (0..100).step_by(7) {
+        store.remove(format!("key{}", i))?;
+        expected.remove(&format!("key{}", i));
+    }
+
+    let pairs = store.iter().collect::<Result<BTreeMap<_, _>>>()?;
+    assert_eq!(pairs, expected);
+
+    Ok(())
+}
+
+// Exactly one of many racing inserts of the same key should succeed
+#[test]
+fn set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let barrier = Arc::new(Barrier::new(100));
+    let handles: Vec<_> = (0..100)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store
+                    .set_if_absent("lock".to_owned(), format!("owner{}", i))
+                    .unwrap()
+            })
+        })
+        .collect();
+    let winners: Vec<usize> = handles
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, handle)| handle.join().unwrap().then_some(i))
+        .collect();
+
+    assert_eq!(winners.len(), 1);
+    assert_eq!(
+        store.get("lock".to_owned())?,
+        Some(format!("owner{}", winners[0]))
+    );
+
+    store.remove("lock".to_owned())?;
+    assert!(store.set_if_absent("lock".to_owned(), "again".to_owned())?);
+
+    Ok(())
+}
+
+// Racing `get_or_insert_with` calls should compute each value once and all return it
+#[test]
+fn get_or_insert_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let barrier = Arc::new(Barrier::new(100));
+    let handles: Vec<_> = (0..100)
+        .map(|i| {
+            let store = store.clone();
+            let calls = calls.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                (0..10)
+                    .map(|key_id| {
+                        store
+                            .get_or_insert_with(format!("key{}", key_id), || {
+                                calls.fetch_add(1, Ordering::SeqCst);
+                                format!("computed{}", i)
+                            })
+                            .unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let results: Vec<_> = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect();
+
+    assert_eq!(calls.load(Ordering::SeqCst), 10);
+    for key_id in 0..10 {
+        let value = store.get(format!("key{}", key_id))?.unwrap();
+        assert!(results.iter().all(|values| values[key_id] == value));
+    }
+
+    // an existing value is returned without calling the closure
+    store.set("key0".to_owned(), "set".to_owned())?;
+    assert_eq!(
+        store.get_or_insert_with("key0".to_owned(), || unreachable!())?,
+        "set"
+    );
+
+    Ok(())
+}
+
+// Concurrent increments through `update` should all be counted
+#[test]
+fn update_counter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    store
+                        .update("counter".to_owned(), |value| {
+                            let count: u64 = value.map_or(0, |value| value.parse().unwrap());
+                            Some(format!("{}", count + 1))
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+
+    // returning `None` removes the key, and does nothing for an absent key
+    store.update("counter".to_owned(), |_| None)?;
+    assert_eq!(store.get("counter".to_owned())?, None);
+    store.update("counter".to_owned(), |_| None)?;
+    assert_eq!(store.get("counter".to_owned())?, None);
+
+    Ok(())
+}
+
+// Reads, including of expired keys, should be answered while a write holds the writer lock
+#[test]
+fn get_beside_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    thread::sleep(Duration::from_millis(200));
+
+    let (tx, rx) = mpsc::channel();
+    let reader = store.clone();
+    store.update("key3".to_owned(), move |_| {
+        // `update` holds the writer lock until this returns
+        thread::spawn(move || {
+            let values = reader.get_many(vec!["key1".to_owned(), "key2".to_owned()]);
+            tx.send((
+                reader.get("key1".to_owned()),
+                reader.get("key2".to_owned()),
+                values,
+            ))
+            .unwrap();
+        });
+        let (value1, value2, values) = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("reads waited on the writer lock");
+        assert_eq!(value1.unwrap(), Some("value1".to_owned()));
+        assert_eq!(value2.unwrap(), None);
+        assert_eq!(values.unwrap(), vec![Some("value1".to_owned()), None]);
+        Some("value3".to_owned())
+    })?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+/// hash of all bytes of `reader`, read in chunks
+fn hash_reader(mut reader: impl Read) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let mut chunk = vec![0u8; 64 * 1024];
+    loop {
+        match reader.read(&mut chunk).unwrap() {
+            0 => return hasher.finish(),
+            n => hasher.write(&chunk[..n]),
+        }
+    }
+}
+
+// A large value should be streamed in chunks, also when compacted while being read
+#[test]
+fn get_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().encoding(Encoding::Bincode);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let value: String = (0..16 * 1024 * 1024)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
+    let mut hasher = DefaultHasher::new();
+    hasher.write(value.as_bytes());
+    let expected = hasher.finish();
+
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("large".to_owned(), value)?;
+    assert_eq!(
+        hash_reader(store.get_reader("large".to_owned())?.unwrap()),
+        expected
+    );
+
+    let mut small = String::new();
+    store
+        .get_reader("small".to_owned())?
+        .unwrap()
+        .read_to_string(&mut small)?;
+    assert_eq!(small, "value");
+    assert!(store.get_reader("missing".to_owned())?.is_none());
+
+    let reader = store.get_reader("large".to_owned())?.unwrap();
+    store.compact()?;
+    assert_eq!(hash_reader(reader), expected);
+    assert_eq!(
+        hash_reader(store.get_reader("large".to_owned())?.unwrap()),
+        expected
+    );
+
+    // values of records with a ttl, of batches and of reloaded records are streamed too
+    store.set_with_ttl(
+        "ttl".to_owned(),
+        "ttl value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.write_batch(vec![BatchOp::Set {
+        key: "batch".to_owned(),
+        value: "batch value".to_owned(),
+    }])?;
+    drop(store);
+    let options = KvStoreOptions::new().encoding(Encoding::Bincode);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for (key, value) in [("ttl", "ttl value"), ("batch", "batch value")] {
+        let mut read = String::new();
+        store
+            .get_reader(key.to_owned())?
+            .unwrap()
+            .read_to_string(&mut read)?;
+        assert_eq!(read, value);
+    }
+    assert_eq!(
+        hash_reader(store.get_reader("large".to_owned())?.unwrap()),
+        expected
+    );
+
+    // json values are read into memory
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "a \"quoted\" value".to_owned())?;
+    let mut value = String::new();
+    store
+        .get_reader("key".to_owned())?
+        .unwrap()
+        .read_to_string(&mut value)?;
+    assert_eq!(value, "a \"quoted\" value");
+
+    Ok(())
+}
+
+// Value lengths should be byte lengths of the values, probed or read
+#[test]
+fn value_len() -> Result<()> {
+    let values = [
+        String::new(),
+        "value1".to_owned(),
+        "h\u{e9}llo w\u{f6}rld \u{1f600}".to_owned(),
+        "v".repeat(100_000),
+    ];
+
+    for (encoding, compression) in [
+        (Encoding::Json, Compression::None),
+        (Encoding::Bincode, Compression::None),
+        (Encoding::Bincode, Compression::Lz4),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .encoding(encoding)
+            .value_compression(compression);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for (key_id, value) in values.iter().enumerate() {
+            store.set(format!("key{}", key_id), value.clone())?;
+        }
+        store.set("key1".to_owned(), "overwritten".to_owned())?;
+        store.remove("key0".to_owned())?;
+
+        assert_eq!(store.value_len("key0".to_owned())?, None);
+        assert_eq!(store.value_len("missing".to_owned())?, None);
+        for key_id in 1..values.len() {
+            let key = format!("key{}", key_id);
+            let value = store.get(key.clone())?.unwrap();
+            assert_eq!(store.value_len(key)?, Some(value.len() as u64));
+        }
+        assert_eq!(store.value_len("key1".to_owned())?, Some(11));
+        assert_eq!(store.value_len("key2".to_owned())?, Some(18));
+    }
+
+    Ok(())
+}
+
+// A partial compaction file left by a crash should be removed on open,
+// and compaction should leave only the compacted generation
+#[test]
+fn compaction_crash_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+
+    // the file compaction would have written next, cut off in the middle of a record
+    let tmp_path = temp_dir.path().join("1.json.tmp");
+    fs::write(&tmp_path, r#"{"Set":{"key":"key0","val"#)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!tmp_path.exists());
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    store.compact()?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+    let mut names: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["2.json", "json.manifest"]);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("9".to_owned()));
+
+    Ok(())
+}
+
+// A crash after a compaction renamed its file but before it deleted the old generations
+// should leave generation numbers increasing past every file on disk, gaps included
+#[test]
+fn compaction_crash_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    let old = fs::read(temp_dir.path().join("0.json"))?;
+    store.compact()?;
+    assert_eq!(store.stats().writer_generation, 2);
+    drop(store);
+
+    // generation 0 was not deleted yet, generation 1 was, and the next compaction had
+    // started writing its file. Older compactions and a checkpoint were cut short too,
+    // while another namespace is compacting
+    fs::write(temp_dir.path().join("0.json"), old)?;
+    let tmp_names = ["3.json.tmp", "1.json.tmp", "index.tmp"];
+    for name in tmp_names.iter().chain(&["ns-1.json.tmp"]) {
+        fs::write(temp_dir.path().join(name), r#"{"Set":{"key":"key0","val"#)?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().writer_generation, 3);
+    for name in tmp_names {
+        assert!(!temp_dir.path().join(name).exists());
+    }
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("new".to_owned()));
+    }
+
+    store.compact()?;
+    assert_eq!(store.stats().writer_generation, 4);
+    store.set("key0".to_owned(), "newer".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().writer_generation, 5);
+    assert_eq!(store.get("key0".to_owned())?, Some("newer".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("new".to_owned()));
+    let mut names: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec!["4.json", "5.json", "json.manifest", "ns-1.json.tmp"]
+    );
+
+    Ok(())
+}
+
+// Verify should report a corrupt tail and an orphaned generation without fixing them,
+// and repair should cut the tail and compact the log
+#[test]
+fn verify_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    drop(store);
+    // overwritten in generation 1, so generation 0 holds only garbage
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let garbage_len = fs::metadata(temp_dir.path().join("0.json"))?.len();
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.is_clean());
+    assert_eq!(report.generations, 2);
+    assert_eq!(report.live_keys, 100);
+    assert_eq!(report.orphaned_generations, vec![0]);
+    assert_eq!(report.unreachable_bytes, garbage_len);
+
+    // a record torn by a crash
+    let path = temp_dir.path().join("1.json");
+    let len = fs::metadata(&path)?.len();
+    let mut contents = fs::read(&path)?;
+    contents.extend_from_slice(br#"{"Set":{"key":"key0","val"#);
+    fs::write(&path, &contents)?;
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert_eq!(report.corrupt_records.len(), 1);
+    let corrupt = &report.corrupt_records[0];
+    assert_eq!((corrupt.generation, corrupt.offset), (1, len));
+    assert_eq!(corrupt.key, None);
+    assert_eq!(corrupt.key_bytes(), None);
+    assert_eq!(report.live_keys, 100);
+    assert_eq!(
+        report.unreachable_bytes,
+        garbage_len + contents.len() as u64 - len
+    );
+    // verify leaves the store as it is
+    assert_eq!(fs::metadata(&path)?.len(), contents.len() as u64);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    assert!(!KvStore::repair(temp_dir.path())?.is_clean());
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.is_clean());
+    assert_eq!(report.generations, 1);
+    assert_eq!(report.live_keys, 100);
+    assert!(report.orphaned_generations.is_empty());
+    assert_eq!(report.unreachable_bytes, 0);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// A corrupt length prefix of a bincode record should be reported by verify and cut by
+// repair, rather than allocated ahead of the bytes it claims
+#[test]
+fn verify_corrupt_length_prefix() -> Result<()> {
+    let options = KvStoreOptions::new().encoding(Encoding::Bincode);
+    // longer than any record allowed, and allowed but longer than the file
+    for prefix in [u32::MAX, 1 << 30] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+
+        let path = temp_dir.path().join("0.bin");
+        let len = fs::metadata(&path)?.len();
+        let mut contents = fs::read(&path)?;
+        contents.extend_from_slice(&prefix.to_le_bytes());
+        contents.extend_from_slice(b"torn");
+        fs::write(&path, &contents)?;
+
+        let report = KvStore::verify_with_options(temp_dir.path(), &options)?;
+        assert_eq!(report.corrupt_records.len(), 1);
+        let corrupt = &report.corrupt_records[0];
+        assert_eq!((corrupt.generation, corrupt.offset), (0, len));
+        assert_eq!(report.live_keys, 1);
+        assert!(KvStore::open_with_options(temp_dir.path(), options.clone()).is_err());
+
+        KvStore::repair_with_options(temp_dir.path(), options.clone())?;
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Open should write a manifest and reject generation files of another format version
+#[test]
+fn format_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let path = temp_dir.path().join("json.manifest");
+    let manifest: serde_json::Value = serde_json::from_slice(&fs::read(&path)?)?;
+    assert_eq!(manifest["format_version"], FORMAT_VERSION);
+    assert_eq!(manifest["encoding"], "json");
+    assert_eq!(manifest["value_compression"], "none");
+
+    // a store written before manifests existed
+    fs::remove_file(&path)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert!(path.exists());
+
+    // an older version is upgraded, as records of this build may follow
+    fs::write(&path, r#"{"format_version":1}"#)?;
+    let store = KvStore::open(temp_dir.path())?;
+    drop(store);
+    let manifest: serde_json::Value = serde_json::from_slice(&fs::read(&path)?)?;
+    assert_eq!(manifest["format_version"], FORMAT_VERSION);
+
+    fs::write(
+        &path,
+        format!(
+            r#"{{"format_version":{},"encoding":"zstd_frames"}}"#,
+            FORMAT_VERSION + 1
+        ),
+    )?;
+    let expected = FORMAT_VERSION;
+    let found = FORMAT_VERSION + 1;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::IncompatibleFormat { found: f, expected: e }) if f == found && e == expected
+    ));
+    assert!(matches!(
+        KvStore::verify(temp_dir.path()),
+        Err(KvsError::IncompatibleFormat { .. })
+    ));
+
+    // bincode files are a separate store with a manifest of their own
+    let options = KvStoreOptions::new().encoding(Encoding::Bincode);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(temp_dir.path().join("bin.manifest").exists());
+
+    Ok(())
+}
+
+// Namespaces in one directory should keep separate keys, survive compaction and reopening
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_policy(CompactionPolicy::Bytes(64 * 1024));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let users = store.namespace("users")?;
+
+    store.set("key".to_owned(), "default".to_owned())?;
+    users.set("key".to_owned(), "users".to_owned())?;
+    users.set("only_users".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key".to_owned())?, Some("users".to_owned()));
+    assert_eq!(store.get("only_users".to_owned())?, None);
+
+    // compaction of a namespace leaves other namespaces alone
+    for iter in 0..1000 {
+        for key_id in 0..100 {
+            users.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        if users.stats().compaction_count > 0 {
+            break;
+        }
+    }
+    assert!(users.stats().compaction_count > 0);
+    assert_eq!(store.stats().compaction_count, 0);
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+
+    assert!(matches!(
+        store.namespace("../escape"),
+        Err(KvsError::InvalidNamespace(_))
+    ));
+
+    drop(store);
+    drop(users);
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.namespace("users")?;
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key".to_owned())?, Some("users".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.stats().live_keys, 1);
+
+    Ok(())
+}
+
+// Buffered records should be flushed once the last clone of the store is dropped
+#[test]
+fn flush_on_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().durability(DurabilityMode::None);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for j in 0..100 {
+                    store
+                        .set(format!("key{}_{}", i, j), format!("value{}", j))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..4 {
+        for j in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}_{}", i, j))?,
+                Some(format!("value{}", j))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Manual compaction should report the space reclaimed from removed keys
+#[test]
+fn compaction_report() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 100..1000 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let report = store.compact()?;
+    assert_eq!(report.live_keys, 100);
+    assert!(report.bytes_after < report.bytes_before);
+    assert_eq!(report.bytes_after, store.stats().disk_bytes);
+    assert_eq!(report.generations_removed, 1);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        let expected = (key_id < 100).then(|| format!("value{}", key_id));
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+
+    Ok(())
+}
+
+// A compaction hook should observe compactions triggered by the policy and on demand
+#[test]
+fn on_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_policy(CompactionPolicy::Bytes(4 * 1024));
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    {
+        let reports = reports.clone();
+        store.on_compaction(Arc::new(move |report: &CompactionReport| {
+            reports.lock().unwrap().push(report.clone())
+        }));
+    }
+
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    let triggered = reports.lock().unwrap().len();
+    assert!(triggered > 0);
+    assert_eq!(store.stats().compaction_count, triggered as u64);
+    assert!(reports
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|report| report.bytes_after < report.bytes_before));
+
+    let report = store.compact()?;
+    let reports = reports.lock().unwrap();
+    assert_eq!(reports.len(), triggered + 1);
+    assert_eq!(reports.last(), Some(&report));
+    assert_eq!(report.live_keys, 100);
+
+    Ok(())
+}
+
+// Should drop all keys and reclaim disk space, and keep working afterwards
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(store.stats().disk_bytes > 0);
+
+    store.clear()?;
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 0);
+    assert_eq!(stats.disk_bytes, 0);
+    assert_eq!(store.get("key0".to_owned())?, None);
+
+    store.set("key0".to_owned(), "new".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().live_keys, 1);
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should open from a checkpoint, and fall back to replaying all generations
+// when the checkpoint is stale or corrupt
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().checkpoint(true);
+    let checkpoint_path = temp_dir.path().join("index.ckpt");
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            let expected = match key_id {
+                0..=9 => None,
+                10..=19 => Some(format!("new{}", key_id)),
+                _ => Some(format!("value{}", key_id)),
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        Ok(())
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    assert!(checkpoint_path.exists());
+
+    // records written after the checkpoint are replayed
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    for key_id in 10..20 {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    check(&store)?;
+    drop(store);
+
+    // compaction without checkpoints deletes the generations the checkpoint refers to
+    let store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    store.set("key99".to_owned(), "value99".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    check(&store)?;
+    drop(store);
+
+    fs::write(&checkpoint_path, "corrupt")?;
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+    assert_eq!(store.stats().live_keys, 90);
+
+    Ok(())
+}
+
+// Compressed values should take less disk, and a log written under
+// different compression settings should stay readable
+#[test]
+fn value_compression() -> Result<()> {
+    let value = |i: usize| format!("{{\"id\":{},\"payload\":\"{}\"}}", i, "abc".repeat(1000));
+
+    let mut disk_bytes = Vec::new();
+    for compression in [Compression::None, Compression::Lz4, Compression::Gzip] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .encoding(Encoding::Bincode)
+            .value_compression(compression);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), value(i))?;
+        }
+        // too short to shrink, stored as it is
+        store.set("short".to_owned(), "v".to_owned())?;
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+        }
+        assert_eq!(store.get("short".to_owned())?, Some("v".to_owned()));
+        disk_bytes.push(store.stats().disk_bytes);
+    }
+    assert!(disk_bytes[1] * 5 < disk_bytes[0]);
+    assert!(disk_bytes[2] * 5 < disk_bytes[0]);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for (i, &compression) in [Compression::Lz4, Compression::None, Compression::Gzip]
+        .iter()
+        .enumerate()
+    {
+        let options = KvStoreOptions::new().value_compression(compression);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set(format!("key{}", i), value(i))?;
+        for j in 0..=i {
+            assert_eq!(store.get(format!("key{}", j))?, Some(value(j)));
+        }
+    }
+
+    Ok(())
+}
+
+// Reads racing with writes and repeated compactions should always see a live value
+#[test]
+fn concurrent_read_during_compaction() -> Result<()> {
+    const KEYS: usize = 100;
+    const COMPACTIONS: u64 = 20;
+
+    for use_mmap in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .use_mmap(use_mmap)
+            .compaction_policy(CompactionPolicy::Bytes(64 * 1024));
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..KEYS {
+            store.set(format!("key{}", key_id), format!("{}-0", key_id))?;
+        }
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|i| {
+                let store = store.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut key_id = i;
+                    while !done.load(Ordering::SeqCst) {
+                        let value = store
+                            .get(format!("key{}", key_id))
+                            .expect("read failed during compaction")
+                            .expect("live key not found during compaction");
+                        assert!(value.starts_with(&format!("{}-", key_id)));
+                        key_id = (key_id + 7) % KEYS;
+                    }
+                })
+            })
+            .collect();
+
+        let mut round = 1;
+        while store.stats().compaction_count < COMPACTIONS {
+            for key_id in 0..KEYS {
+                store.set(format!("key{}", key_id), format!("{}-{}", key_id, round))?;
+            }
+            round += 1;
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    Ok(())
+}
+
+/// generation files under `segments/`, named `seg-N.{extension}`
+#[derive(Debug)]
+struct SegmentsLayout;
+
+impl LayoutStrategy for SegmentsLayout {
+    fn generation_path(&self, context: &LayoutContext<'_>, generation: u64) -> PathBuf {
+        let prefix = context.namespace.unwrap_or("seg");
+        context
+            .dir
+            .join("segments")
+            .join(format!("{}-{}.{}", prefix, generation, context.extension))
+    }
+
+    fn generations(&self, context: &LayoutContext<'_>) -> Result<Vec<u64>> {
+        let dir = context.dir.join("segments");
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let prefix = format!("{}-", context.namespace.unwrap_or("seg"));
+        let suffix = format!(".{}", context.extension);
+        let mut generations = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name().into_string().unwrap();
+            if let Some(generation) = name
+                .strip_prefix(&prefix)
+                .and_then(|name| name.strip_suffix(&suffix))
+                .and_then(|generation| generation.parse().ok())
+            {
+                generations.push(generation);
+            }
+        }
+        Ok(generations)
+    }
+}
+
+// A custom layout should place all generation files under `segments/`,
+// through compaction and reopening
+#[test]
+fn custom_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::new()
+            .layout(SegmentsLayout)
+            .compaction_policy(CompactionPolicy::Bytes(64 * 1024))
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert!(store.stats().compaction_count > 0);
+    drop(store);
+
+    let mut top_level: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    top_level.sort();
+    assert_eq!(top_level, vec!["json.manifest", "segments"]);
+    let segments = temp_dir.path().join("segments");
+    assert!(fs::read_dir(&segments)?.all(|entry| {
+        let name = entry.unwrap().file_name().into_string().unwrap();
+        name.starts_with("seg-") && name.ends_with(".json")
+    }));
+
+    // the default layout doesn't see the files
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Files beside the generation files should never be read or deleted as generations
+#[test]
+fn junk_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let ns = store.namespace("ns")?;
+    ns.set("key1".to_owned(), "ns".to_owned())?;
+    drop(ns);
+    drop(store);
+
+    let junk = [
+        "1.2.json",
+        ".hidden.json",
+        ".5.json",
+        "01.json",
+        "+1.json",
+        "-1.json",
+        "abc.json",
+        "a-b-1.json",
+        "ns-01.json",
+        "index.ckpt",
+        "notes.txt",
+    ];
+    for name in junk {
+        fs::write(temp_dir.path().join(name), "not a record")?;
+    }
+    fs::create_dir(temp_dir.path().join("9.json"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    let ns = store.namespace("ns")?;
+    assert_eq!(ns.get("key1".to_owned())?, Some("ns".to_owned()));
+    ns.compact()?;
+    drop(ns);
+    drop(store);
+
+    for name in junk {
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join(name))?,
+            "not a record"
+        );
+    }
+    assert!(temp_dir.path().join("9.json").is_dir());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A store opened read-only should read what was written before, and fail writes,
+// compaction and clear without touching its directory
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().checkpoint(true);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    let snapshot = || -> Result<BTreeMap<PathBuf, Vec<u8>>> {
+        let mut files = BTreeMap::new();
+        for entry in fs::read_dir(temp_dir.path())? {
+            let path = entry?.path();
+            let contents = fs::read(&path)?;
+            files.insert(path, contents);
+        }
+        Ok(files)
+    };
+    let before = snapshot()?;
+
+    let store = KvStore::open_read_only(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(
+        store.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(store.compact(), Err(KvsError::ReadOnly)));
+    assert!(matches!(store.clear(), Err(KvsError::ReadOnly)));
+    assert!(matches!(
+        store
+            .namespace("other")?
+            .set("key1".to_owned(), "value1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    store.flush()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let records = store.read_from((0, 0))?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(records.last().unwrap().0, store.current_position());
+    drop(store);
+    assert_eq!(snapshot()?, before);
+
+    Ok(())
+}
+
+// Opening a directory already open in this process should share its writer and index,
+// whatever the form of the path
+#[test]
+fn open_shares_open_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store1 = KvStore::open(temp_dir.path())?;
+    let store2 = KvStore::open(temp_dir.path().join("."))?;
+
+    store1.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store2.get("key1".to_owned())?, Some("value1".to_owned()));
+    store2.set("key2".to_owned(), "value2".to_owned())?;
+    store2.remove("key1".to_owned())?;
+    assert_eq!(store1.get("key1".to_owned())?, None);
+    assert_eq!(store1.get("key2".to_owned())?, Some("value2".to_owned()));
+    // a single writer, so a single generation file
+    assert_eq!(store1.current_position(), store2.current_position());
+    let namespace = store1.namespace("other")?;
+    namespace.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        store2.namespace("other")?.get("key3".to_owned())?,
+        Some("value3".to_owned())
+    );
+    drop((store1, store2, namespace));
+
+    // opened again once every handle is dropped
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.namespace("other")?.get("key3".to_owned())?,
+        Some("value3".to_owned())
+    );
+    drop(store);
+
+    // threads opening the store at once load it once
+    let stores = thread::scope(|scope| {
+        let handles: Vec<_> = (0..4)
+            .map(|_| scope.spawn(|| KvStore::open(temp_dir.path())))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Result<Vec<_>>>()
+    })?;
+    stores[0].set("key4".to_owned(), "value4".to_owned())?;
+    for store in &stores {
+        assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+        assert_eq!(store.current_position(), stores[0].current_position());
+    }
+
+    Ok(())
+}
+
+// Opening a store already open in this process with other options should fail rather
+// than share a store behaving otherwise, while the same options share it
+#[test]
+fn open_shared_store_options_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().max_value_size(1024);
+    let store1 = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store1.set("key1".to_owned(), "value1".to_owned())?;
+
+    for other in [
+        KvStoreOptions::default(),
+        options.clone().durability(DurabilityMode::Fsync),
+        options.clone().max_value_size(2048),
+    ] {
+        assert!(matches!(
+            KvStore::open_with_options(temp_dir.path(), other),
+            Err(KvsError::OptionsMismatch)
+        ));
+    }
+    let store2 = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store2.current_position(), store1.current_position());
+    // a read-only store is never shared
+    let store3 = KvStore::open_read_only(temp_dir.path(), KvStoreOptions::default())?;
+    assert_eq!(store3.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop((store1, store2));
+
+    // opened with other options once every handle is dropped
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Values read again should be shared by the value cache until they are overwritten,
+// evicted or moved by compaction
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        // two values
+        KvStoreOptions::default().value_cache_capacity(12),
+    )?;
+    for i in 1..=3 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+
+    let value1 = store.get_shared("key1")?.unwrap();
+    assert_eq!(&*value1, "value1");
+    assert!(Arc::ptr_eq(&value1, &store.get_shared("key1")?.unwrap()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_shared("missing")?, None);
+
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert_eq!(&*store.get_shared("key1")?.unwrap(), "value4");
+
+    // key1 is read last, so reading key3 evicts key2
+    let value2 = store.get_shared("key2")?.unwrap();
+    store.get_shared("key1")?;
+    store.get_shared("key3")?;
+    assert!(!Arc::ptr_eq(&value2, &store.get_shared("key2")?.unwrap()));
+    // larger than the whole cache
+    store.set("key5".to_owned(), "value5".repeat(3))?;
+    let value5 = store.get_shared("key5")?.unwrap();
+    assert!(!Arc::ptr_eq(&value5, &store.get_shared("key5")?.unwrap()));
+    assert!(Arc::ptr_eq(
+        &store.get_shared("key3")?.unwrap(),
+        &store.get_shared("key3")?.unwrap()
+    ));
+
+    store.compact()?;
+    assert_eq!(&*store.get_shared("key1")?.unwrap(), "value4");
+    assert_eq!(&*store.get_shared("key2")?.unwrap(), "value2");
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get_shared("key2")?, None);
+    drop(store);
+
+    // without a cache each read is a new string
+    let store = KvStore::open(temp_dir.path())?;
+    let value1 = store.get_shared("key1")?.unwrap();
+    assert_eq!(&*value1, "value4");
+    assert!(!Arc::ptr_eq(&value1, &store.get_shared("key1")?.unwrap()));
+    drop(store);
+
+    // a large cache is split among shards, each value is still cached once
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions::default().value_cache_capacity(1 << 20),
+    )?;
+    for i in 0..100 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    let values = (0..100)
+        .map(|i| store.get_shared(&format!("key{i}")))
+        .collect::<Result<Vec<_>>>()?;
+    for (i, value) in values.iter().enumerate() {
+        assert!(Arc::ptr_eq(
+            value.as_ref().unwrap(),
+            &store.get_shared(&format!("key{i}"))?.unwrap()
+        ));
+    }
+
+    Ok(())
+}
+
+// A watcher should receive the writes of its key in order, and nothing for other keys
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let events = store.watch("key1".to_owned());
+    let dropped = store.watch("key1".to_owned());
+    drop(dropped);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        events.try_recv(),
+        Ok(WatchEvent::Set {
+            value: "value1".to_owned()
+        })
+    );
+    assert!(events.try_recv().is_err());
+
+    store.remove("key1".to_owned())?;
+    store.write_batch(vec![BatchOp::Set {
+        key: "key1".to_owned(),
+        value: "value3".to_owned(),
+    }])?;
+    store.clear()?;
+    assert_eq!(
+        events.try_iter().collect::<Vec<_>>(),
+        vec![
+            WatchEvent::Remove,
+            WatchEvent::Set {
+                value: "value3".to_owned()
+            },
+            WatchEvent::Remove,
+        ]
+    );
+
+    Ok(())
+}
+
+// Swapping should exchange the values of two keys, and write nothing if one is missing
+#[test]
+fn swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+
+    store.swap("a".to_owned(), "b".to_owned())?;
+    assert_eq!(store.get("a".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("1".to_owned()));
+
+    let disk_bytes = store.stats().disk_bytes;
+    assert!(matches!(
+        store.swap("a".to_owned(), "missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.stats().disk_bytes, disk_bytes);
+    assert_eq!(store.get("a".to_owned())?, Some("2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("1".to_owned()));
+
+    Ok(())
+}
+
+// A set-if-version with a stale version should be rejected, versions should grow with
+// each write and be kept by compaction and restarts
+#[test]
+fn version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.version("key1"), None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first = store.version("key1").unwrap();
+
+    let second = store.set_if_version("key1".to_owned(), "value2".to_owned(), first)?;
+    assert!(second > first);
+    assert_eq!(store.version("key1"), Some(second));
+    assert!(matches!(
+        store.set_if_version("key1".to_owned(), "value3".to_owned(), first),
+        Err(KvsError::VersionMismatch)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(
+        store.set_if_version("key2".to_owned(), "value1".to_owned(), first),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    assert!(store.version("key2").unwrap() > second);
+    store.compact()?;
+    assert_eq!(store.version("key1"), Some(second));
+    let third = store.set_if_version("key1".to_owned(), "value3".to_owned(), second)?;
+    let key2 = store.version("key2").unwrap();
+    assert!(third > key2);
+    drop(store);
+
+    // records moved by compaction or not keep their versions across a restart
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.version("key1"), Some(third));
+    assert_eq!(store.version("key2"), Some(key2));
+    store.set_if_version("key1".to_owned(), "value4".to_owned(), third)?;
+    store.set_if_version("key2".to_owned(), "value2".to_owned(), key2)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+
+    for options in [
+        KvStoreOptions::default(),
+        KvStoreOptions::default().encoding(Encoding::Bincode),
+        KvStoreOptions::default().checkpoint(true),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for i in 0..10 {
+            store.set(format!("key{i}"), "value".to_owned())?;
+        }
+        store.write_batch(vec![
+            BatchOp::Set {
+                key: "key0".to_owned(),
+                value: "batch".to_owned(),
+            },
+            BatchOp::Remove {
+                key: "key1".to_owned(),
+            },
+            BatchOp::Set {
+                key: "key2".to_owned(),
+                value: "batch".to_owned(),
+            },
+        ])?;
+        store.remove("key3".to_owned())?;
+        store.compact()?;
+        store.set("key4".to_owned(), "value".to_owned())?;
+        let versions = |store: &KvStore| -> Vec<_> {
+            (0..10).map(|i| store.version(&format!("key{i}"))).collect()
+        };
+        let before = versions(&store);
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        assert_eq!(versions(&store), before);
+        let last = before.iter().flatten().max().copied().unwrap();
+        store.set("key5".to_owned(), "value".to_owned())?;
+        assert!(store.version("key5").unwrap() > last);
+
+        // a key written after a clear never gets a version read before it
+        let last = store.version("key5").unwrap();
+        store.clear()?;
+        store.set("key0".to_owned(), "value".to_owned())?;
+        assert!(store.version("key0").unwrap() > last);
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert!(store.version("key0").unwrap() > last);
+    }
+
+    Ok(())
+}
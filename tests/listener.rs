@@ -0,0 +1,18 @@
+use kvs::{accept_tcp, bind_tcp, Result};
+use std::net::TcpStream;
+
+// Accepted connections should have Nagle's algorithm disabled, with or without a backlog
+#[test]
+fn tcp_nodelay() -> Result<()> {
+    for backlog in [None, Some(1), Some(u32::MAX)] {
+        let listener = bind_tcp("127.0.0.1:0".parse().unwrap(), backlog)?;
+        let client = TcpStream::connect(listener.local_addr()?)?;
+        assert!(!client.nodelay()?);
+
+        let stream = accept_tcp(&listener)?;
+        assert!(stream.nodelay()?);
+        assert_eq!(stream.peer_addr()?, client.local_addr()?);
+    }
+
+    Ok(())
+}
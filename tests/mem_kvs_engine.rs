@@ -0,0 +1,82 @@
+use kvs::{KvsEngine, KvsError, MemKvsEngine, Result};
+use std::sync::{mpsc, Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+// Should get, overwrite and remove values, with clones sharing the same pairs
+#[test]
+fn get_set_remove() -> Result<()> {
+    let engine = MemKvsEngine::new();
+    let clone = engine.clone();
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(clone.get("key1".to_owned())?, Some("value1".to_owned()));
+    clone.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.len(), 1);
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(clone.get("key1".to_owned())?, None);
+    assert!(matches!(
+        clone.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(engine.is_empty());
+
+    Ok(())
+}
+
+// Reads racing with overwrites should always see one of the values
+#[test]
+fn concurrent_overwrite() -> Result<()> {
+    let engine = MemKvsEngine::new();
+    engine.set("key".to_owned(), "0".to_owned())?;
+
+    let barrier = Arc::new(Barrier::new(4));
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let engine = engine.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for j in 0..10_000 {
+                    if i == 0 {
+                        engine.set("key".to_owned(), format!("{}", j)).unwrap();
+                    } else {
+                        assert!(engine.get("key".to_owned()).unwrap().is_some());
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert!(!engine.set_if_absent("key".to_owned(), "new".to_owned())?);
+    assert!(engine.set_if_absent("other".to_owned(), "new".to_owned())?);
+
+    Ok(())
+}
+
+// Reads should be answered while a write holds the write lock
+#[test]
+fn get_beside_write() -> Result<()> {
+    let engine = MemKvsEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+
+    let (tx, rx) = mpsc::channel();
+    let reader = engine.clone();
+    let value = engine.get_or_insert_with("key2".to_owned(), move || {
+        // `get_or_insert_with` holds the write lock until this returns
+        thread::spawn(move || tx.send(reader.get("key1".to_owned())).unwrap());
+        let value1 = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("reads waited on the write lock");
+        assert_eq!(value1.unwrap(), Some("value1".to_owned()));
+        "value2".to_owned()
+    })?;
+    assert_eq!(value, "value2");
+
+    Ok(())
+}
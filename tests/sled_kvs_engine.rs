@@ -0,0 +1,219 @@
+#![cfg(feature = "sled-engine")]
+
+use kvs::{BatchOp, KvsEngine, KvsError, Result, SledKvsEngine};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Should hide expired keys
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+
+    engine.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    engine.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.remove("key1".to_owned()).is_err());
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Exactly one of many racing inserts of the same key should succeed
+#[test]
+fn set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+
+    let handles: Vec<_> = (0..100)
+        .map(|i| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                engine
+                    .set_if_absent("lock".to_owned(), format!("owner{}", i))
+                    .unwrap()
+            })
+        })
+        .collect();
+    let winners = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|inserted| *inserted)
+        .count();
+    assert_eq!(winners, 1);
+
+    // an expired key counts as absent
+    engine.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    assert!(!engine.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    thread::sleep(Duration::from_millis(200));
+    assert!(engine.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should drop all keys
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+
+    for key_id in 0..100 {
+        engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    engine.clear()?;
+
+    assert_eq!(engine.db.len(), 0);
+    assert_eq!(engine.get("key0".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should list live pairs under a prefix in key order
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+
+    engine.set("user:2".to_owned(), "bob".to_owned())?;
+    engine.set("user:1".to_owned(), "alice".to_owned())?;
+    engine.set("order:1".to_owned(), "book".to_owned())?;
+    engine.set_with_ttl(
+        "user:3".to_owned(),
+        "carol".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    thread::sleep(Duration::from_millis(200));
+
+    let users = engine.scan_prefix("user:").collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        users,
+        vec![
+            ("user:1".to_owned(), "alice".to_owned()),
+            ("user:2".to_owned(), "bob".to_owned()),
+        ]
+    );
+    assert_eq!(engine.scan_prefix("none:").count(), 0);
+
+    Ok(())
+}
+
+// Should apply all writes of a batch, in order
+#[test]
+fn batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    engine.set("a".to_owned(), "old".to_owned())?;
+
+    engine.batch(vec![
+        BatchOp::Remove {
+            key: "a".to_owned(),
+        },
+        BatchOp::Remove {
+            key: "missing".to_owned(),
+        },
+        BatchOp::Set {
+            key: "b".to_owned(),
+            value: "first".to_owned(),
+        },
+        BatchOp::Set {
+            key: "b".to_owned(),
+            value: "second".to_owned(),
+        },
+        BatchOp::Set {
+            key: "c".to_owned(),
+            value: "new".to_owned(),
+        },
+        BatchOp::Remove {
+            key: "c".to_owned(),
+        },
+    ])?;
+    assert_eq!(engine.get("a".to_owned())?, None);
+    assert_eq!(engine.get("b".to_owned())?, Some("second".to_owned()));
+    assert_eq!(engine.get("c".to_owned())?, None);
+
+    Ok(())
+}
+
+// A failed transaction should leave the db unchanged, a successful one should apply
+// all of its writes
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    engine.set("a".to_owned(), "old".to_owned())?;
+
+    let result = engine.transaction(|txn| {
+        txn.set("b".to_owned(), "new".to_owned());
+        txn.remove("a".to_owned())?;
+        assert_eq!(txn.get("b")?, Some("new".to_owned()));
+        txn.remove("missing".to_owned())
+    });
+    assert!(matches!(result, Err(KvsError::KeyNotFound)));
+    assert_eq!(engine.get("a".to_owned())?, Some("old".to_owned()));
+    assert_eq!(engine.get("b".to_owned())?, None);
+
+    engine.transaction(|txn| {
+        let value = txn.get("a")?.unwrap();
+        txn.remove("a".to_owned())?;
+        txn.set("b".to_owned(), value);
+        Ok(())
+    })?;
+    assert_eq!(engine.get("a".to_owned())?, None);
+    assert_eq!(engine.get("b".to_owned())?, Some("old".to_owned()));
+
+    Ok(())
+}
+
+// Concurrent increments through `update` should all be counted
+#[test]
+fn update_counter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    engine
+                        .update("counter".to_owned(), |value| {
+                            let count: u64 = value.map_or(0, |value| value.parse().unwrap());
+                            Some(format!("{}", count + 1))
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(engine.get("counter".to_owned())?, Some("800".to_owned()));
+
+    // returning `None` removes the key, and does nothing for an absent key
+    engine.update("counter".to_owned(), |_| None)?;
+    assert_eq!(engine.get("counter".to_owned())?, None);
+    engine.update("counter".to_owned(), |_| None)?;
+    assert_eq!(engine.get("counter".to_owned())?, None);
+
+    Ok(())
+}
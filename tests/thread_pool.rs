@@ -1,8 +1,10 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsError, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -68,3 +70,140 @@ fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn naive_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<NaiveThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_drop_joins_workers() -> Result<()> {
+    const TASK_NUM: usize = 20;
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let pool = SharedQueueThreadPool::new(4)?;
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_drop_joins_after_panic() -> Result<()> {
+    const TASK_NUM: usize = 20;
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let pool = SharedQueueThreadPool::new(2)?;
+    for i in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            if i % 2 == 0 {
+                panic_control::disable_hook_in_current_thread();
+                panic!();
+            }
+            thread::sleep(Duration::from_millis(20));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM / 2);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_bounded_queue() -> Result<()> {
+    const QUEUE_CAP: usize = 4;
+
+    let (block_sender, block_receiver) = crossbeam::channel::bounded::<()>(0);
+    let counter = Arc::new(AtomicUsize::new(0));
+    let pool = SharedQueueThreadPool::with_capacity(1, QUEUE_CAP)?;
+
+    // occupy the only worker until the flood is over
+    pool.spawn(move || block_receiver.recv().unwrap());
+    thread::sleep(Duration::from_millis(100));
+
+    let mut accepted = 0;
+    for _ in 0..1000 {
+        let counter = Arc::clone(&counter);
+        if pool
+            .try_spawn(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .is_ok()
+        {
+            accepted += 1;
+        }
+    }
+    assert_eq!(accepted, QUEUE_CAP);
+
+    block_sender.send(()).unwrap();
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), QUEUE_CAP);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_metrics() -> Result<()> {
+    const THREADS: usize = 2;
+    const QUEUED: usize = 3;
+
+    let (block_sender, block_receiver) = crossbeam::channel::unbounded::<()>();
+    let pool = SharedQueueThreadPool::new(THREADS as u32)?;
+    assert_eq!(pool.active_workers(), 0);
+    assert_eq!(pool.queued_jobs(), 0);
+
+    for _ in 0..THREADS + QUEUED {
+        let block_receiver = block_receiver.clone();
+        pool.spawn(move || block_receiver.recv().unwrap());
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(pool.active_workers(), THREADS);
+    assert_eq!(pool.queued_jobs(), QUEUED);
+
+    for _ in 0..THREADS + QUEUED {
+        block_sender.send(()).unwrap();
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(pool.active_workers(), 0);
+    assert_eq!(pool.queued_jobs(), 0);
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool_metrics() -> Result<()> {
+    let pool = RayonThreadPool::new(3)?;
+    assert_eq!(pool.current_num_threads(), 3);
+    Ok(())
+}
+
+// Pools of a fixed number of workers should refuse none, the naive pool spawns a thread
+// per job whatever it is given
+#[test]
+fn thread_pool_without_threads() {
+    assert!(NaiveThreadPool::new(0).is_ok());
+    assert!(matches!(
+        SharedQueueThreadPool::new(0),
+        Err(KvsError::ThreadPool(_))
+    ));
+    assert!(matches!(
+        SharedQueueThreadPool::with_capacity(0, 16),
+        Err(KvsError::ThreadPool(_))
+    ));
+    assert!(matches!(
+        RayonThreadPool::new(0),
+        Err(KvsError::ThreadPool(_))
+    ));
+}